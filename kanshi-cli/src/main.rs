@@ -0,0 +1,335 @@
+use std::{
+    collections::HashMap,
+    env,
+    ffi::OsString,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::{Command, ExitCode},
+    time::Duration,
+};
+
+use futures::StreamExt;
+use kanshi::{
+    diff_snapshots, FileSystemEvent, Kanshi, KanshiEngines, KanshiError, KanshiImpl, KanshiOptions,
+    KanshiStreamItem, WatchedTree,
+};
+
+const USAGE: &str = "Usage: kanshi-cli [--format text|json|csv|tsv] [--dir <path>] [--engine <engine>] -- <command> [args...]
+       kanshi-cli diff [--format text|json|csv|tsv] <before> <after>";
+
+// Time given to the tracer to drain events that were queued just before the child exited.
+const SETTLE_TIME: Duration = Duration::from_millis(250);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Text,
+    Json,
+    Csv,
+    Tsv,
+}
+
+impl Format {
+    fn from(string: &str) -> Result<Format, String> {
+        match string {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            "tsv" => Ok(Format::Tsv),
+            _ => Err(format!(
+                "Invalid format '{string}'. Allowed values are: 'text', 'json', 'csv', 'tsv'."
+            )),
+        }
+    }
+}
+
+enum Mode {
+    /// Run a command and report every file touched while it ran.
+    Run(Vec<OsString>),
+    /// Report the changes needed to turn one directory into another.
+    Diff(PathBuf, PathBuf),
+}
+
+struct Args {
+    format: Format,
+    dir: PathBuf,
+    engine: Option<String>,
+    mode: Mode,
+}
+
+impl Args {
+    fn parse(args: impl Iterator<Item = OsString>) -> Result<Args, String> {
+        let mut args = args.peekable();
+        let mut format = Format::Text;
+        let mut dir = None;
+        let mut engine = None;
+        let mut positional = Vec::new();
+
+        let is_diff = args.peek().is_some_and(|arg| arg == "diff");
+        if is_diff {
+            args.next();
+        }
+
+        while let Some(arg) = args.next() {
+            match arg.to_str() {
+                Some("--") => break,
+                Some("--format") => {
+                    let value = args.next().ok_or("--format requires a value")?;
+                    format = Format::from(&value.to_string_lossy())?;
+                }
+                Some("--dir") => {
+                    dir = Some(PathBuf::from(args.next().ok_or("--dir requires a value")?));
+                }
+                Some("--engine") => {
+                    let value = args.next().ok_or("--engine requires a value")?;
+                    engine = Some(value.to_string_lossy().into_owned());
+                }
+                _ if is_diff => positional.push(PathBuf::from(arg)),
+                _ => return Err(format!("Unexpected argument {:?}", arg)),
+            }
+        }
+
+        let mode = if is_diff {
+            // Neither applies to comparing two directories.
+            if dir.is_some() || engine.is_some() {
+                return Err("--dir and --engine can't be used with diff".to_owned());
+            }
+            let after = positional.pop();
+            let before = positional.pop();
+            match (before, after) {
+                (Some(before), Some(after)) if positional.is_empty() => Mode::Diff(before, after),
+                _ => return Err("diff requires exactly two directories".to_owned()),
+            }
+        } else {
+            let command: Vec<OsString> = args.collect();
+            if command.is_empty() {
+                return Err("No command supplied".to_owned());
+            }
+            Mode::Run(command)
+        };
+
+        let dir = match dir {
+            Some(dir) => dir,
+            None => env::current_dir().map_err(|e| e.to_string())?,
+        };
+
+        Ok(Args {
+            format,
+            dir,
+            engine,
+            mode,
+        })
+    }
+}
+
+/// Every distinct path touched by the command, in the order it was first seen,
+/// alongside the distinct event types observed on it.
+#[derive(Default)]
+struct Report {
+    entries: Vec<(String, Vec<String>)>,
+    index: HashMap<String, usize>,
+}
+
+impl Report {
+    fn record(&mut self, event: &FileSystemEvent) {
+        let Some(target) = event.target.as_ref() else {
+            return;
+        };
+
+        let path = target.path.to_string_lossy().into_owned();
+        let event_type = event.event_type.name().to_owned();
+
+        let idx = *self.index.entry(path.clone()).or_insert_with(|| {
+            self.entries.push((path, Vec::new()));
+            self.entries.len() - 1
+        });
+
+        let event_types = &mut self.entries[idx].1;
+        if !event_types.contains(&event_type) {
+            event_types.push(event_type);
+        }
+    }
+
+    fn write(&self, format: Format, out: &mut impl Write) -> io::Result<()> {
+        match format {
+            Format::Text => {
+                for (path, _) in self.entries.iter() {
+                    writeln!(out, "{path}")?;
+                }
+            }
+            Format::Json => {
+                writeln!(out, "[")?;
+                for (idx, (path, event_types)) in self.entries.iter().enumerate() {
+                    let event_types = event_types
+                        .iter()
+                        .map(|x| json_string(x))
+                        .collect::<Vec<_>>()
+                        .join(",");
+                    let separator = if idx + 1 == self.entries.len() {
+                        ""
+                    } else {
+                        ","
+                    };
+                    writeln!(
+                        out,
+                        "  {{\"path\":{},\"events\":[{}]}}{}",
+                        json_string(path),
+                        event_types,
+                        separator
+                    )?;
+                }
+                writeln!(out, "]")?;
+            }
+            Format::Csv => {
+                writeln!(out, "path,events")?;
+                for (path, event_types) in self.entries.iter() {
+                    writeln!(
+                        out,
+                        "{},{}",
+                        csv_field(path),
+                        csv_field(&event_types.join(";"))
+                    )?;
+                }
+            }
+            Format::Tsv => {
+                writeln!(out, "path\tevents")?;
+                for (path, event_types) in self.entries.iter() {
+                    writeln!(out, "{}\t{}", tsv_field(path), event_types.join(";"))?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+fn tsv_field(value: &str) -> String {
+    value.replace(['\t', '\n', '\r'], " ")
+}
+
+fn run_diff(args: &Args, before: &Path, after: &Path) -> Result<ExitCode, KanshiError> {
+    let before = WatchedTree::capture(before)?;
+    let after = WatchedTree::capture(after)?;
+    let events = diff_snapshots(&before, &after);
+
+    let mut out = io::stdout().lock();
+    if args.format == Format::Text {
+        for event in events.iter() {
+            if let Some(target) = event.target.as_ref() {
+                writeln!(
+                    out,
+                    "{}\t{}",
+                    event.event_type.name(),
+                    target.path.to_string_lossy()
+                )?;
+            }
+        }
+    } else {
+        let mut report = Report::default();
+        for event in events.iter() {
+            report.record(event);
+        }
+        report.write(args.format, &mut out)?;
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+async fn run(args: Args) -> Result<ExitCode, KanshiError> {
+    let command = match &args.mode {
+        Mode::Run(command) => command.clone(),
+        Mode::Diff(before, after) => return run_diff(&args, before, after),
+    };
+
+    let mut opts = KanshiOptions::default();
+    if let Some(engine) = args.engine.as_ref() {
+        opts.force_engine = Some(KanshiEngines::from(engine)?);
+    }
+
+    let kanshi = Kanshi::new(opts)?;
+    kanshi.watch(&args.dir).await?;
+
+    // Subscribe before starting so no events are missed.
+    let mut stream = kanshi.get_events_stream();
+    let collector = tokio::task::spawn(async move {
+        let mut report = Report::default();
+        // A failed tracer is reported below, through the result of `start`.
+        while let Some(KanshiStreamItem::Event(event)) = stream.next().await {
+            report.record(&event);
+        }
+        report
+    });
+
+    let kan = kanshi.clone();
+    let tracer = tokio::task::spawn(async move { kan.start().await });
+
+    let program = command[0].clone();
+    let status =
+        tokio::task::spawn_blocking(move || Command::new(&command[0]).args(&command[1..]).status())
+            .await
+            .map_err(|e| KanshiError::InvalidCommand(e.to_string()))?
+            .map_err(|e| KanshiError::InvalidCommand(format!("{:?}: {e}", program)))?;
+
+    tokio::time::sleep(SETTLE_TIME).await;
+    kanshi.close()?;
+
+    if let Ok(Err(e)) = tracer.await {
+        return Err(e);
+    }
+
+    let report = collector
+        .await
+        .map_err(|e| KanshiError::InvalidCommand(e.to_string()))?;
+
+    let mut out = io::stdout().lock();
+    report.write(args.format, &mut out)?;
+
+    Ok(match status.code() {
+        Some(code) => ExitCode::from(u8::try_from(code).unwrap_or(1)),
+        None => ExitCode::FAILURE,
+    })
+}
+
+#[tokio::main(flavor = "multi_thread")]
+async fn main() -> ExitCode {
+    let args = match Args::parse(env::args_os().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}\n{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+
+    match run(args).await {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("kanshi-cli: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
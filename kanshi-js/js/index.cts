@@ -24,10 +24,10 @@ type KanshiEventTypes =
 interface KanshiEvent {
   eventType: KanshiEventTypes;
   target?: {
-    /// Only set if eventType == "moved_from"
+    /// Only set if eventType is "moved_to" or "moved_from"
     previousPath?: string;
-    /// Only set if eventType == "moved_to"
-    newPath?: string;
+    /// Only set if eventType is "moved_to" or "moved_from"
+    nextPath?: string;
     path: string;
     kind: "directory" | "file";
   };
@@ -3,6 +3,7 @@ use std::sync::{Arc, OnceLock};
 use futures::StreamExt;
 use kanshi::{
     FileSystemEventType, FileSystemTargetKind, Kanshi, KanshiEngines, KanshiImpl, KanshiOptions,
+    KanshiStreamItem,
 };
 use neon::prelude::*;
 use tokio::runtime::Runtime;
@@ -13,7 +14,8 @@ struct KanshiJS {
 
 impl Finalize for KanshiJS {
     fn finalize<'a, C: Context<'a>>(self, _: &mut C) {
-        self.engine.close();
+        // Nothing is left to report an error to once the object is collected.
+        let _ = self.engine.close();
     }
 }
 
@@ -36,7 +38,7 @@ fn runtime<'a, C: Context<'a>>(cx: &mut C) -> NeonResult<&'static Runtime> {
 impl KanshiJS {
     fn js_new(mut cx: FunctionContext) -> JsResult<JsBox<KanshiJS>> {
         let js_opts = cx.argument::<JsObject>(0)?;
-        let mut kanshi_opts = KanshiOptions { force_engine: None };
+        let mut kanshi_opts = KanshiOptions::default();
 
         if let Ok(Some(force_engine)) = js_opts.get_opt::<JsString, _, _>(&mut cx, "forceEngine") {
             if let Ok(force_engine_str) = force_engine.to_string(&mut cx) {
@@ -98,7 +100,8 @@ impl KanshiJS {
         let mut stream = kanshi.get_events_stream();
 
         rt.spawn(async move {
-            while let Some(event) = stream.next().await {
+            // Errors reject the promise returned by `start` instead.
+            while let Some(KanshiStreamItem::Event(event)) = stream.next().await {
                 let callback = js_callback.clone();
                 let handle = sub_thread_channel
                     .send(move |mut cx| {
@@ -110,17 +113,15 @@ impl KanshiJS {
 
                         let event_type = &event.event_type;
                         let event_type_str = match event_type {
-                            FileSystemEventType::MovedFrom(path) => {
-                                let js_string = JsString::new(&mut cx, path.to_str().unwrap());
+                            FileSystemEventType::MovedFrom { from, to }
+                            | FileSystemEventType::MovedTo { from, to } => {
+                                let js_string = JsString::new(&mut cx, from.to_str().unwrap());
                                 js_event_target.set(&mut cx, "previousPath", js_string)?;
-                                event.event_type.to_string()
-                            }
-                            FileSystemEventType::MovedTo(path) => {
-                                let js_string = JsString::new(&mut cx, path.to_str().unwrap());
+                                let js_string = JsString::new(&mut cx, to.to_str().unwrap());
                                 js_event_target.set(&mut cx, "nextPath", js_string)?;
-                                event.event_type.to_string()
+                                event.event_type.name()
                             }
-                            x => x.to_string(),
+                            x => x.name(),
                         };
 
                         let js_string = JsString::new(&mut cx, event_type_str);
@@ -135,6 +136,12 @@ impl KanshiJS {
                                     JsString::new(&mut cx, "directory")
                                 }
                                 FileSystemTargetKind::File => JsString::new(&mut cx, "file"),
+                                FileSystemTargetKind::Symlink => {
+                                    JsString::new(&mut cx, "symlink")
+                                }
+                                FileSystemTargetKind::HardLink { .. } => {
+                                    JsString::new(&mut cx, "hardlink")
+                                }
                             };
                             js_event_target.set(&mut cx, "kind", kind)?;
                         }
@@ -173,7 +180,7 @@ impl KanshiJS {
 
     fn js_close(mut cx: FunctionContext) -> JsResult<JsBoolean> {
         let kanshi_js = cx.this::<JsBox<KanshiJS>>()?;
-        let ret = kanshi_js.engine.close();
+        let ret = kanshi_js.engine.close().is_ok();
 
         Ok(cx.boolean(ret))
     }
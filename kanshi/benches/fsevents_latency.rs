@@ -0,0 +1,88 @@
+//! Measures the CPU time kanshi spends while a file is written to at a
+//! constant rate, with `fsevents_latency_seconds` set to 0.0 and to 1.0.
+//!
+//! Each iteration writes to a file every 5ms for one second and reports the
+//! user and system CPU time used by the whole process in that second, rather
+//! than wall time. The writer's own share is the same for both settings, so
+//! the difference is the cost of the extra FSEvents wake-ups. macOS only.
+
+#[cfg(target_os = "macos")]
+use std::{
+    fs,
+    time::{Duration, Instant},
+};
+
+#[cfg(target_os = "macos")]
+use criterion::{criterion_group, criterion_main, Criterion};
+#[cfg(target_os = "macos")]
+use futures::StreamExt;
+#[cfg(target_os = "macos")]
+use kanshi::{spawn_watcher, KanshiImpl, KanshiOptions};
+
+#[cfg(target_os = "macos")]
+const WRITE_INTERVAL: Duration = Duration::from_millis(5);
+
+#[cfg(target_os = "macos")]
+const LOAD_DURATION: Duration = Duration::from_secs(1);
+
+/// User plus system CPU time used by this process so far.
+#[cfg(target_os = "macos")]
+fn cpu_time() -> Duration {
+    let mut usage = unsafe { std::mem::zeroed::<libc::rusage>() };
+    unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) };
+
+    let to_duration = |x: libc::timeval| Duration::new(x.tv_sec as u64, x.tv_usec as u32 * 1000);
+    to_duration(usage.ru_utime) + to_duration(usage.ru_stime)
+}
+
+#[cfg(target_os = "macos")]
+fn cpu_usage(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let dir_path = dir.path().canonicalize().unwrap();
+    let file_path = dir_path.join("load");
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let mut group = c.benchmark_group("cpu_under_write_load");
+    group.sample_size(10);
+
+    for latency in [0.0, 1.0] {
+        let options = KanshiOptions::default().with_fsevents_latency_seconds(latency);
+        let (kanshi, mut stream, handle) =
+            runtime.block_on(spawn_watcher(&dir_path, options)).unwrap();
+
+        // Drain events in the background so the channel never fills up.
+        let drain = runtime.spawn(async move { while stream.next().await.is_some() {} });
+
+        group.bench_function(format!("latency_{latency}s"), |b| {
+            b.iter_custom(|iters| {
+                (0..iters)
+                    .map(|_| {
+                        let before = cpu_time();
+                        let start = Instant::now();
+                        let mut idx = 0u64;
+                        while start.elapsed() < LOAD_DURATION {
+                            fs::write(&file_path, idx.to_string()).unwrap();
+                            idx += 1;
+                            std::thread::sleep(WRITE_INTERVAL);
+                        }
+                        cpu_time() - before
+                    })
+                    .sum()
+            })
+        });
+
+        kanshi.close().unwrap();
+        runtime.block_on(handle).unwrap().unwrap();
+        drain.abort();
+    }
+
+    group.finish();
+}
+
+#[cfg(target_os = "macos")]
+criterion_group!(benches, cpu_usage);
+#[cfg(target_os = "macos")]
+criterion_main!(benches);
+
+#[cfg(not(target_os = "macos"))]
+fn main() {}
@@ -0,0 +1,148 @@
+//! Measures the time from `File::create` to the first event for that file,
+//! for kanshi and for the `notify` crate (4.x and 5.x) watching the same
+//! directory.
+//!
+//! On Linux, run as root so kanshi uses fanotify; notify always uses inotify.
+//! On macOS, both use FSEvents. See `BENCHMARKS.md` for how to run it.
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use std::{
+    ffi::OsStr,
+    fs,
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc,
+    },
+    time::{Duration, Instant},
+};
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use criterion::{criterion_group, criterion_main, Criterion};
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use futures::StreamExt;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use kanshi::{spawn_watcher, KanshiImpl, KanshiOptions};
+
+// Gives up on an event after this long, so a lost event fails the run.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+const EVENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+static FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Creates a new, uniquely named file in `dir`, calls `wait` with its name and
+/// returns the time it took from creating the file until `wait` returned.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn create_and_wait(dir: &Path, mut wait: impl FnMut(&OsStr)) -> Duration {
+    let name = format!("file-{}", FILE_COUNTER.fetch_add(1, Ordering::Relaxed));
+    let path = dir.join(&name);
+
+    let start = Instant::now();
+    fs::File::create(&path).unwrap();
+    wait(OsStr::new(&name));
+    start.elapsed()
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn latency(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let dir_path = dir.path().canonicalize().unwrap();
+
+    let mut group = c.benchmark_group("create_to_event");
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let (kanshi, mut stream, handle) = runtime
+        .block_on(spawn_watcher(&dir_path, KanshiOptions::default()))
+        .unwrap();
+    group.bench_function("kanshi", |b| {
+        b.iter_custom(|iters| {
+            (0..iters)
+                .map(|_| {
+                    create_and_wait(&dir_path, |name| {
+                        runtime.block_on(async {
+                            loop {
+                                let event = tokio::time::timeout(EVENT_TIMEOUT, stream.next())
+                                    .await
+                                    .unwrap()
+                                    .unwrap()
+                                    .into_result()
+                                    .unwrap();
+                                let target = event.target.unwrap();
+                                if Path::new(&target.path).file_name() == Some(name) {
+                                    break;
+                                }
+                            }
+                        })
+                    })
+                })
+                .sum()
+        })
+    });
+    kanshi.close().unwrap();
+    runtime.block_on(handle).unwrap().unwrap();
+
+    {
+        use notify4::Watcher;
+
+        let (tx, rx) = mpsc::channel();
+        let mut watcher = notify4::raw_watcher(tx).unwrap();
+        watcher
+            .watch(&dir_path, notify4::RecursiveMode::Recursive)
+            .unwrap();
+
+        group.bench_function("notify_4", |b| {
+            b.iter_custom(|iters| {
+                (0..iters)
+                    .map(|_| {
+                        create_and_wait(&dir_path, |name| loop {
+                            let event = rx.recv_timeout(EVENT_TIMEOUT).unwrap();
+                            if event.path.as_ref().and_then(|x| x.file_name()) == Some(name) {
+                                break;
+                            }
+                        })
+                    })
+                    .sum()
+            })
+        });
+    }
+
+    {
+        use notify5::Watcher;
+
+        let (tx, rx) = mpsc::channel();
+        let mut watcher = notify5::recommended_watcher(move |event| {
+            let _ = tx.send(event);
+        })
+        .unwrap();
+        watcher
+            .watch(&dir_path, notify5::RecursiveMode::Recursive)
+            .unwrap();
+
+        group.bench_function("notify_5", |b| {
+            b.iter_custom(|iters| {
+                (0..iters)
+                    .map(|_| {
+                        create_and_wait(&dir_path, |name| loop {
+                            let event: notify5::Event =
+                                rx.recv_timeout(EVENT_TIMEOUT).unwrap().unwrap();
+                            if event.paths.iter().any(|x| x.file_name() == Some(name)) {
+                                break;
+                            }
+                        })
+                    })
+                    .sum()
+            })
+        });
+    }
+
+    group.finish();
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+criterion_group!(benches, latency);
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+criterion_main!(benches);
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn main() {}
@@ -0,0 +1,106 @@
+//! Measures how long kanshi takes to deliver a burst of file creations made
+//! at 100,000 per second, with `LinuxBackend::Epoll` and `LinuxBackend::IoUring`.
+//!
+//! Each iteration creates `BURST` files, paced to the target rate, and stops
+//! once a `Create` event was received for every one of them. Criterion
+//! reports the result as events per second. Run as root to measure fanotify,
+//! otherwise inotify is used. Linux only.
+
+#[cfg(target_os = "linux")]
+use std::{
+    fs,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+#[cfg(target_os = "linux")]
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+#[cfg(target_os = "linux")]
+use futures::StreamExt;
+#[cfg(target_os = "linux")]
+use kanshi::{spawn_watcher, FileSystemEventType, KanshiImpl, KanshiOptions, LinuxBackend};
+
+// Files created per iteration.
+#[cfg(target_os = "linux")]
+const BURST: usize = 10_000;
+
+// The time between two creations, for 100,000 per second.
+#[cfg(target_os = "linux")]
+const CREATE_INTERVAL: Duration = Duration::from_micros(10);
+
+// Gives up on an event after this long, so a lost event fails the run.
+#[cfg(target_os = "linux")]
+const EVENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[cfg(target_os = "linux")]
+static FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(target_os = "linux")]
+fn throughput(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let dir_path = dir.path().canonicalize().unwrap();
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let mut group = c.benchmark_group("burst_at_100k_per_second");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(BURST as u64));
+
+    for (name, backend) in [
+        ("epoll", LinuxBackend::Epoll),
+        ("io_uring", LinuxBackend::IoUring),
+    ] {
+        let options = KanshiOptions::default()
+            .with_backend(backend)
+            .with_channel_capacity(BURST * 2);
+        let (kanshi, mut stream, handle) =
+            runtime.block_on(spawn_watcher(&dir_path, options)).unwrap();
+
+        group.bench_function(name, |b| {
+            b.iter_custom(|iters| {
+                let mut total = Duration::ZERO;
+                for _ in 0..iters {
+                    let start = Instant::now();
+                    let mut next = start;
+                    for _ in 0..BURST {
+                        while Instant::now() < next {
+                            std::hint::spin_loop();
+                        }
+                        let n = FILE_COUNTER.fetch_add(1, Ordering::Relaxed);
+                        fs::File::create(dir_path.join(format!("file-{n}"))).unwrap();
+                        next += CREATE_INTERVAL;
+                    }
+
+                    runtime.block_on(async {
+                        let mut created = 0;
+                        while created < BURST {
+                            let event = tokio::time::timeout(EVENT_TIMEOUT, stream.next())
+                                .await
+                                .unwrap()
+                                .unwrap()
+                                .into_result()
+                                .unwrap();
+                            if event.event_type == FileSystemEventType::Create {
+                                created += 1;
+                            }
+                        }
+                    });
+                    total += start.elapsed();
+                }
+                total
+            })
+        });
+
+        kanshi.close().unwrap();
+        runtime.block_on(handle).unwrap().unwrap();
+    }
+
+    group.finish();
+}
+
+#[cfg(target_os = "linux")]
+criterion_group!(benches, throughput);
+#[cfg(target_os = "linux")]
+criterion_main!(benches);
+
+#[cfg(not(target_os = "linux"))]
+fn main() {}
@@ -0,0 +1,82 @@
+//! Measures how long `watch` takes to walk and mark a large directory tree,
+//! with and without `parallel_scan`.
+//!
+//! The tree size defaults to 10,000 directories and can be changed with
+//! `KANSHI_BENCH_DIRS`, e.g. `KANSHI_BENCH_DIRS=1000000 cargo bench` for a
+//! 1M directory scan. Run as root so the fanotify engine is used; inotify is
+//! limited by `fs.inotify.max_user_watches`. `parallel_scan` is Linux only.
+
+#[cfg(target_os = "linux")]
+use std::{env, fs, path::Path};
+
+#[cfg(target_os = "linux")]
+use criterion::{criterion_group, criterion_main, Criterion};
+#[cfg(target_os = "linux")]
+use kanshi::{Kanshi, KanshiImpl, KanshiOptions};
+
+// Number of subdirectories created in each directory.
+#[cfg(target_os = "linux")]
+const FAN_OUT: usize = 10;
+
+#[cfg(target_os = "linux")]
+fn create_tree(root: &Path, dirs: usize) {
+    let mut level = vec![root.to_path_buf()];
+    let mut created = 0;
+
+    while created < dirs {
+        let mut next = Vec::new();
+        for parent in level.iter() {
+            for idx in 0..FAN_OUT {
+                if created == dirs {
+                    break;
+                }
+                let dir = parent.join(idx.to_string());
+                fs::create_dir(&dir).unwrap();
+                next.push(dir);
+                created += 1;
+            }
+        }
+        level = next;
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn scan(c: &mut Criterion) {
+    let dirs = env::var("KANSHI_BENCH_DIRS")
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(10_000);
+
+    let tree = tempfile::tempdir().unwrap();
+    create_tree(tree.path(), dirs);
+    let root = tree.path().to_string_lossy().into_owned();
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let mut group = c.benchmark_group(format!("scan_{dirs}_dirs"));
+    group.sample_size(10);
+
+    for parallel_scan in [false, true] {
+        let name = if parallel_scan { "parallel" } else { "serial" };
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let opts = KanshiOptions {
+                    parallel_scan,
+                    ..KanshiOptions::default()
+                };
+                let kanshi = Kanshi::new(opts).unwrap();
+                runtime.block_on(kanshi.watch(&root)).unwrap();
+                kanshi.close().unwrap();
+            })
+        });
+    }
+
+    group.finish();
+}
+
+#[cfg(target_os = "linux")]
+criterion_group!(benches, scan);
+#[cfg(target_os = "linux")]
+criterion_main!(benches);
+
+#[cfg(not(target_os = "linux"))]
+fn main() {}
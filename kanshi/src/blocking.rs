@@ -0,0 +1,195 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use futures::StreamExt;
+use tokio::runtime::Runtime;
+
+use crate::{FileSystemEvent, KanshiError, KanshiImpl, KanshiStreamItem};
+
+type WatchFn = Box<dyn Fn(PathBuf) -> Result<(), KanshiError> + Send>;
+
+/// An iterator over the events of a tracer, for callers that don't use async.
+///
+/// The tracer is started on a small tokio runtime owned by the iterator, on a
+/// thread of its own, and its events are forwarded to the iterator over a
+/// channel. Iteration ends once the tracer stops. If it stopped because it
+/// failed, the error is kept for [`KanshiBlockingIter::error`]. Dropping the
+/// iterator closes the tracer and waits for that thread to finish.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use kanshi::{Kanshi, KanshiBlockingIter, KanshiError, KanshiImpl, KanshiOptions};
+///
+/// fn main() -> Result<(), KanshiError> {
+///     let kanshi = Kanshi::new(KanshiOptions::default())?;
+///     let events = KanshiBlockingIter::new(kanshi, Some(Duration::from_secs(10)))?;
+///     events.watch("/tmp")?;
+///
+///     // Ends after 10 seconds without any event.
+///     for event in events {
+///         println!("{:?}", event.event_type);
+///     }
+///     Ok(())
+/// }
+/// ```
+pub struct KanshiBlockingIter {
+    receiver: mpsc::Receiver<FileSystemEvent>,
+    timeout: Option<Duration>,
+    error: Arc<Mutex<Option<KanshiError>>>,
+    watch: WatchFn,
+    close: Box<dyn Fn() -> Result<(), KanshiError> + Send>,
+    thread: Option<thread::JoinHandle<()>>,
+    runtime: Option<Runtime>,
+}
+
+impl KanshiBlockingIter {
+    /// Starts `tracer` and iterates over its events. With a `timeout`, `next`
+    /// returns `None` when no event arrives within it, without closing the
+    /// tracer, so iteration can carry on afterwards. Fails if the runtime or
+    /// its thread can't be created.
+    pub fn new<Opts, T>(
+        tracer: T,
+        timeout: Option<Duration>,
+    ) -> Result<KanshiBlockingIter, KanshiError>
+    where
+        T: KanshiImpl<Opts> + 'static,
+    {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()?;
+        let (sender, receiver) = mpsc::channel();
+        let error = Arc::new(Mutex::new(None));
+
+        // Subscribed before the tracer starts, so no events are missed.
+        let mut stream = tracer.get_events_stream();
+        let handle = runtime.handle().clone();
+        let thread = {
+            let tracer = tracer.clone();
+            let error = error.clone();
+            thread::Builder::new()
+                .name("kanshi-blocking".to_owned())
+                .spawn(move || {
+                    let forward = async {
+                        while let Some(item) = stream.next().await {
+                            match item {
+                                KanshiStreamItem::Event(event) => {
+                                    // The iterator is gone, so nobody is listening anymore.
+                                    if sender.send(event).is_err() {
+                                        if let Err(e) = tracer.close() {
+                                            tracing::warn!(error = %e, "failed to close the tracer");
+                                        }
+                                        break;
+                                    }
+                                }
+                                KanshiStreamItem::Error(e) => {
+                                    *error.lock().unwrap() = Some(e);
+                                    break;
+                                }
+                                // Allowed once dropped, since it can't be
+                                // answered through the iterator.
+                                KanshiStreamItem::PermissionRequest(_) => {}
+                            }
+                        }
+                        // Ends the iteration once every event is received.
+                        drop(sender);
+                    };
+                    let (result, ()) =
+                        handle.block_on(async { tokio::join!(tracer.start(), forward) });
+                    if let Err(e) = result {
+                        error.lock().unwrap().get_or_insert(e);
+                    }
+                })?
+        };
+
+        let handle = runtime.handle().clone();
+        let watcher = tracer.clone();
+        Ok(KanshiBlockingIter {
+            receiver,
+            timeout,
+            error,
+            watch: Box::new(move |dir| handle.block_on(watcher.watch(dir))),
+            close: Box::new(move || tracer.close()),
+            thread: Some(thread),
+            runtime: Some(runtime),
+        })
+    }
+
+    /// Watches `dir`, blocking until `KanshiImpl::watch` returns.
+    pub fn watch(&self, dir: impl AsRef<Path>) -> Result<(), KanshiError> {
+        (self.watch)(dir.as_ref().to_path_buf())
+    }
+
+    /// The error the tracer stopped with, if it failed.
+    pub fn error(&self) -> Option<KanshiError> {
+        self.error.lock().unwrap().clone()
+    }
+}
+
+impl Iterator for KanshiBlockingIter {
+    type Item = FileSystemEvent;
+
+    fn next(&mut self) -> Option<FileSystemEvent> {
+        match self.timeout {
+            Some(timeout) => self.receiver.recv_timeout(timeout).ok(),
+            None => self.receiver.recv().ok(),
+        }
+    }
+}
+
+impl Drop for KanshiBlockingIter {
+    fn drop(&mut self) {
+        if let Err(e) = (self.close)() {
+            tracing::warn!(error = %e, "failed to close the tracer");
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        // Unlike dropping it, this also works from within another runtime.
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+#[cfg(test)]
+#[cfg(target_os = "linux")]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{FileSystemEventType, Kanshi, KanshiImpl, KanshiOptions};
+
+    use super::KanshiBlockingIter;
+
+    #[test]
+    fn iterates_without_a_runtime() {
+        let dir = tempfile::tempdir().unwrap();
+        let top = dir.path().canonicalize().unwrap();
+
+        let kanshi = Kanshi::new(KanshiOptions::default()).unwrap();
+        let mut events = KanshiBlockingIter::new(kanshi, Some(Duration::from_secs(5))).unwrap();
+        events.watch(&top).unwrap();
+
+        std::fs::write(top.join("a"), "a").unwrap();
+
+        let event = events.next().unwrap();
+        assert_eq!(event.event_type, FileSystemEventType::Create);
+        assert_eq!(event.target.unwrap().path, top.join("a").into_os_string());
+        assert!(events.error().is_none());
+    }
+
+    #[test]
+    fn times_out_without_events() {
+        let kanshi = Kanshi::new(KanshiOptions::default()).unwrap();
+        let mut events = kanshi.into_blocking_iter().unwrap();
+        events.timeout = Some(Duration::from_millis(100));
+        assert!(events.next().is_none());
+    }
+}
@@ -0,0 +1,243 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+    task::{Context, Poll},
+};
+
+use futures::StreamExt;
+use tokio::sync::mpsc;
+
+use crate::{runtime, FileSystemEvent, KanshiError, KanshiImpl, KanshiStreamItem};
+
+/// The capacity of the streams of a `Fanout` created with `KanshiImpl::new`,
+/// the same as the default `KanshiOptions::channel_capacity`.
+const DEFAULT_CAPACITY: usize = 1024;
+
+type Filter = Box<dyn Fn(&FileSystemEvent) -> bool + Send + Sync>;
+
+/// Hands the events of a tracer to several [`FilteredStream`]s, each with a
+/// filter and a buffer of its own.
+///
+/// A stream that falls `capacity` events behind loses the events that don't
+/// fit, counted by [`FilteredStream::dropped_count`], without holding back
+/// the other streams. Streams are removed once dropped. Errors are sent to
+/// every stream, which then ends, and every stream ends when the tracer is
+/// closed.
+///
+/// `Fanout` implements [`KanshiImpl`] by forwarding to the tracer, and its
+/// `get_events_stream` is an unfiltered `FilteredStream`, so it can be used
+/// wherever a tracer is expected.
+///
+/// # Examples
+///
+/// ```
+/// use kanshi::{Fanout, FileSystemEventType, Kanshi, KanshiError, KanshiImpl, KanshiOptions};
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), KanshiError> {
+///     let fanout = Fanout::wrap(Kanshi::new(KanshiOptions::default())?, 64);
+///     let creates = fanout.filtered(|x| x.event_type == FileSystemEventType::Create);
+///     let everything = fanout.get_events_stream();
+///     # drop((creates, everything));
+///     # fanout.close()?;
+///     Ok(())
+/// }
+/// ```
+#[derive(Clone)]
+pub struct Fanout<T> {
+    tracer: T,
+    capacity: usize,
+    state: Arc<Mutex<FanoutState>>,
+}
+
+/// The streams of a `Fanout`, shared with the task feeding them.
+#[derive(Default)]
+struct FanoutState {
+    routes: Vec<Route>,
+    // Whether the task feeding the streams was spawned.
+    started: bool,
+    // Whether it stopped, after which new streams end right away.
+    finished: bool,
+}
+
+struct Route {
+    filter: Filter,
+    sender: mpsc::Sender<KanshiStreamItem>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl<T> Fanout<T> {
+    /// Wraps `tracer`, giving every stream room for `capacity` events, or one
+    /// if `capacity` is zero.
+    pub fn wrap(tracer: T, capacity: usize) -> Fanout<T> {
+        Fanout {
+            tracer,
+            capacity: capacity.max(1),
+            state: Arc::default(),
+        }
+    }
+
+    /// The wrapped tracer.
+    pub fn inner(&self) -> &T {
+        &self.tracer
+    }
+
+    /// A stream of the events `filter` accepts. Permission requests are
+    /// filtered by their event. The tracer is subscribed to when the first
+    /// stream is created.
+    pub fn filtered<Opts>(
+        &self,
+        filter: impl Fn(&FileSystemEvent) -> bool + Send + Sync + 'static,
+    ) -> FilteredStream
+    where
+        T: KanshiImpl<Opts>,
+    {
+        let (sender, receiver) = mpsc::channel(self.capacity);
+        let dropped = Arc::new(AtomicU64::new(0));
+
+        let mut state = self.state.lock().unwrap();
+        if !state.finished {
+            state.routes.push(Route {
+                filter: Box::new(filter),
+                sender,
+                dropped: dropped.clone(),
+            });
+            if !state.started {
+                state.started = true;
+                self.spawn(self.tracer.get_events_stream());
+            }
+        }
+
+        FilteredStream { receiver, dropped }
+    }
+
+    /// Feeds the streams from `events` until it ends, an error is sent, or
+    /// every clone of the fanout is gone.
+    fn spawn(&self, mut events: Pin<Box<dyn futures::Stream<Item = KanshiStreamItem> + Send>>) {
+        let state = Arc::downgrade(&self.state);
+        runtime::spawn(async move {
+            while let Some(item) = events.next().await {
+                let failed = item.is_error();
+                if !send(&state, item) || failed {
+                    break;
+                }
+            }
+            if let Some(state) = state.upgrade() {
+                let mut state = state.lock().unwrap();
+                state.finished = true;
+                // Ends the streams.
+                state.routes.clear();
+            }
+        });
+    }
+}
+
+/// Sends `item` to the streams it passes the filter of, skipping the full
+/// ones, and removes the dropped streams. `false` once the fanout is gone.
+fn send(state: &Weak<Mutex<FanoutState>>, item: KanshiStreamItem) -> bool {
+    let Some(state) = state.upgrade() else {
+        return false;
+    };
+    let mut state = state.lock().unwrap();
+    state.routes.retain(|x| !x.sender.is_closed());
+    for route in state.routes.iter() {
+        let accepted = match &item {
+            KanshiStreamItem::Event(event) => (route.filter)(event),
+            KanshiStreamItem::PermissionRequest(request) => (route.filter)(&request.event),
+            KanshiStreamItem::Error(_) => true,
+        };
+        if accepted && route.sender.try_send(item.clone()).is_err() {
+            route.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    true
+}
+
+/// A stream of the events of a [`Fanout`] that passed its filter.
+pub struct FilteredStream {
+    receiver: mpsc::Receiver<KanshiStreamItem>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl FilteredStream {
+    /// Number of events so far that passed the filter but were dropped
+    /// because the stream was full.
+    pub fn dropped_count(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl futures::Stream for FilteredStream {
+    type Item = KanshiStreamItem;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+impl<Opts, T> KanshiImpl<Opts> for Fanout<T>
+where
+    T: KanshiImpl<Opts> + 'static,
+{
+    /// Creates the tracer and wraps it, giving every stream room for 1024
+    /// events.
+    fn new(opts: Opts) -> Result<Fanout<T>, KanshiError> {
+        Ok(Fanout::wrap(T::new(opts)?, DEFAULT_CAPACITY))
+    }
+
+    async fn watch(&self, dir: impl AsRef<Path> + Send) -> Result<(), KanshiError> {
+        self.tracer.watch(dir).await
+    }
+
+    async fn watch_with_exclude_dirs(
+        &self,
+        dir: impl AsRef<Path> + Send,
+        exclude: HashSet<PathBuf>,
+    ) -> Result<(), KanshiError> {
+        self.tracer.watch_with_exclude_dirs(dir, exclude).await
+    }
+
+    async fn unwatch(&self, dir: impl AsRef<Path> + Send) -> Result<(), KanshiError> {
+        self.tracer.unwatch(dir).await
+    }
+
+    fn get_watched_paths(&self) -> Vec<PathBuf> {
+        self.tracer.get_watched_paths()
+    }
+
+    fn get_events_stream(&self) -> Pin<Box<dyn futures::Stream<Item = KanshiStreamItem> + Send>> {
+        Box::pin(self.filtered(|_| true))
+    }
+
+    fn on_event<F>(&self, callback: F)
+    where
+        F: Fn(FileSystemEvent) + Send + Sync + 'static,
+    {
+        self.tracer.on_event(callback)
+    }
+
+    async fn start(&self) -> Result<(), KanshiError> {
+        self.tracer.start().await
+    }
+
+    fn close(&self) -> Result<(), KanshiError> {
+        self.tracer.close()
+    }
+
+    fn pause(&self) {
+        self.tracer.pause()
+    }
+
+    fn resume(&self) {
+        self.tracer.resume()
+    }
+
+    fn is_paused(&self) -> bool {
+        self.tracer.is_paused()
+    }
+}
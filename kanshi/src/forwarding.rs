@@ -0,0 +1,468 @@
+//! Forwarding of events to the clients of a socket, for consumers that cannot
+//! read a Rust stream, e.g. shell scripts, services written in other
+//! languages, or tools on another machine.
+
+use std::{
+    future::Future,
+    io,
+    net::{SocketAddr, ToSocketAddrs},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::StreamExt;
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::mpsc::{self, error::TrySendError},
+    task::JoinHandle,
+};
+use tokio_util::sync::{CancellationToken, DropGuard};
+
+use crate::{FileSystemEvent, KanshiError};
+
+/// The events a forwarder sends, e.g. a tracer's `get_events_stream` with
+/// its errors filtered out.
+pub type EventStream = Pin<Box<dyn futures::Stream<Item = FileSystemEvent> + Send>>;
+
+/// Number of serialized events queued for each client. Once a client's queue
+/// is full, further events are dropped for it until it catches up.
+const CLIENT_QUEUE_LEN: usize = 1024;
+
+/// How long to wait before accepting clients again after accepting one
+/// failed, e.g. because the process ran out of file descriptors. Doubles with
+/// every failure in a row.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Sends events to every client connected to a socket.
+///
+/// Each client has its own queue of events, written to it by a task of its
+/// own, so a slow client never holds back the others or the forwarder. When a
+/// client's queue is full, events are dropped for it rather than waited on,
+/// and counted in [`EventForwarder::dropped_count`]. Clients that disconnect
+/// are removed once writing to them fails, and may connect again at any time.
+///
+/// Forwarding stops once `events` ends or the forwarder is dropped. The
+/// forwarder is also a future, which resolves once forwarding stopped, so it
+/// can be awaited or passed to `tokio::spawn` to keep it running.
+pub trait EventForwarder<A>: Sized {
+    /// Starts listening on `address` and forwarding `events` to the clients
+    /// that connect to it, in the background. Must be called within a tokio
+    /// runtime.
+    fn spawn(events: EventStream, address: A) -> Result<Self, KanshiError>;
+
+    /// Number of events dropped so far because a client's queue was full,
+    /// counted once per client.
+    fn dropped_count(&self) -> u64;
+}
+
+/// Forwards events to the clients of a TCP socket. Each event is framed as
+/// its length in bytes, a 4-byte big-endian integer, followed by the event
+/// serialized as JSON.
+///
+/// # Examples
+///
+/// ```no_run
+/// use futures::StreamExt;
+/// use kanshi::{
+///     forwarding::{EventForwarder, TcpForwarder},
+///     Kanshi, KanshiError, KanshiImpl, KanshiOptions,
+/// };
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), KanshiError> {
+///     let kanshi = Kanshi::new(KanshiOptions::default())?;
+///     kanshi.watch("/tmp").await?;
+///
+///     let events = kanshi
+///         .get_events_stream()
+///         .filter_map(|x| futures::future::ready(x.into_event()));
+///     let forwarder = TcpForwarder::spawn(Box::pin(events), "0.0.0.0:7878")?;
+///     println!("listening on {}", forwarder.local_addr());
+///
+///     kanshi.start().await
+/// }
+/// ```
+pub struct TcpForwarder {
+    forwarding: Forwarding,
+    local_addr: SocketAddr,
+}
+
+impl TcpForwarder {
+    /// The address the forwarder listens on, e.g. to find the port chosen
+    /// when binding to port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl<A: ToSocketAddrs> EventForwarder<A> for TcpForwarder {
+    fn spawn(events: EventStream, address: A) -> Result<TcpForwarder, KanshiError> {
+        let listener = std::net::TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(listener)?;
+        let local_addr = listener.local_addr()?;
+
+        let forwarding = Forwarding::spawn(|dropped, cancel_token| {
+            forward(listener, events, length_prefixed, dropped, cancel_token)
+        });
+        Ok(TcpForwarder {
+            forwarding,
+            local_addr,
+        })
+    }
+
+    fn dropped_count(&self) -> u64 {
+        self.forwarding.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Future for TcpForwarder {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        Pin::new(&mut self.forwarding).poll(cx)
+    }
+}
+
+#[cfg(unix)]
+pub use unix::SocketForwarder;
+
+#[cfg(unix)]
+mod unix {
+    use std::{
+        future::Future,
+        io,
+        path::Path,
+        pin::Pin,
+        sync::atomic::Ordering,
+        task::{Context, Poll},
+    };
+
+    use tokio::net::{UnixListener, UnixStream};
+
+    use crate::{FileSystemEvent, KanshiError};
+
+    use super::{forward, EventForwarder, EventStream, Forwarding, Listener};
+
+    /// Forwards events to the clients of a Unix domain socket. Each event is
+    /// written as a line of JSON, in the format `EventLogReplayer` reads.
+    ///
+    /// Fails if the socket path already exists. The socket file is removed
+    /// once forwarding stops.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use futures::StreamExt;
+    /// use kanshi::{
+    ///     forwarding::{EventForwarder, SocketForwarder},
+    ///     Kanshi, KanshiError, KanshiImpl, KanshiOptions,
+    /// };
+    ///
+    /// #[tokio::main]
+    /// async fn main() -> Result<(), KanshiError> {
+    ///     let kanshi = Kanshi::new(KanshiOptions::default())?;
+    ///     kanshi.watch("/tmp").await?;
+    ///
+    ///     let events = kanshi
+    ///         .get_events_stream()
+    ///         .filter_map(|x| futures::future::ready(x.into_event()));
+    ///     let _forwarder = SocketForwarder::spawn(Box::pin(events), "/tmp/kanshi.sock")?;
+    ///
+    ///     kanshi.start().await
+    /// }
+    /// ```
+    pub struct SocketForwarder {
+        forwarding: Forwarding,
+    }
+
+    impl<A: AsRef<Path>> EventForwarder<A> for SocketForwarder {
+        fn spawn(events: EventStream, address: A) -> Result<SocketForwarder, KanshiError> {
+            let socket_path = address.as_ref().to_path_buf();
+            let listener = UnixListener::bind(&socket_path)?;
+
+            let forwarding = Forwarding::spawn(|dropped, cancel_token| async move {
+                forward(listener, events, json_line, dropped, cancel_token).await;
+                let _ = std::fs::remove_file(&socket_path);
+            });
+            Ok(SocketForwarder { forwarding })
+        }
+
+        fn dropped_count(&self) -> u64 {
+            self.forwarding.dropped.load(Ordering::Relaxed)
+        }
+    }
+
+    impl Future for SocketForwarder {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            Pin::new(&mut self.forwarding).poll(cx)
+        }
+    }
+
+    impl Listener for UnixListener {
+        type Client = UnixStream;
+
+        async fn accept(&self) -> io::Result<UnixStream> {
+            UnixListener::accept(self).await.map(|(client, _)| client)
+        }
+    }
+
+    fn json_line(event: &FileSystemEvent) -> serde_json::Result<Vec<u8>> {
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+        Ok(line)
+    }
+}
+
+/// A socket clients connect to.
+trait Listener: Send + 'static {
+    type Client: AsyncWrite + Unpin + Send + 'static;
+
+    fn accept(&self) -> impl Future<Output = io::Result<Self::Client>> + Send;
+}
+
+impl Listener for TcpListener {
+    type Client = TcpStream;
+
+    async fn accept(&self) -> io::Result<TcpStream> {
+        let (client, _) = TcpListener::accept(self).await?;
+        // Events are small and should arrive as soon as they are sent.
+        let _ = client.set_nodelay(true);
+        Ok(client)
+    }
+}
+
+fn length_prefixed(event: &FileSystemEvent) -> serde_json::Result<Vec<u8>> {
+    let body = serde_json::to_vec(event)?;
+    let mut frame = Vec::with_capacity(4 + body.len());
+    frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&body);
+    Ok(frame)
+}
+
+/// The task forwarding events, shared by the forwarders. Dropping it stops
+/// forwarding.
+struct Forwarding {
+    dropped: Arc<AtomicU64>,
+    task: JoinHandle<()>,
+    _guard: DropGuard,
+}
+
+impl Forwarding {
+    fn spawn<F: Future<Output = ()> + Send + 'static>(
+        run: impl FnOnce(Arc<AtomicU64>, CancellationToken) -> F,
+    ) -> Forwarding {
+        let dropped = Arc::new(AtomicU64::new(0));
+        let cancellation_token = CancellationToken::new();
+        let task = tokio::spawn(run(dropped.clone(), cancellation_token.clone()));
+
+        Forwarding {
+            dropped,
+            task,
+            _guard: cancellation_token.drop_guard(),
+        }
+    }
+}
+
+impl Future for Forwarding {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        Pin::new(&mut self.task).poll(cx).map(|_| ())
+    }
+}
+
+/// Accepts clients of `listener` and queues every event of `events`,
+/// serialized with `encode`, for each of them, until `events` ends or
+/// `cancel_token` is cancelled.
+async fn forward<L: Listener>(
+    listener: L,
+    mut events: EventStream,
+    encode: fn(&FileSystemEvent) -> serde_json::Result<Vec<u8>>,
+    dropped: Arc<AtomicU64>,
+    cancel_token: CancellationToken,
+) {
+    let mut clients: Vec<mpsc::Sender<Arc<[u8]>>> = Vec::new();
+    let mut backoff = ACCEPT_BACKOFF;
+    let mut accept_after = None;
+
+    loop {
+        let deadline = accept_after;
+        let event = tokio::select! {
+            _ = cancel_token.cancelled() => break,
+            accepted = async {
+                if let Some(deadline) = deadline {
+                    tokio::time::sleep_until(deadline).await;
+                }
+                listener.accept().await
+            } => {
+                match accepted {
+                    Ok(client) => {
+                        backoff = ACCEPT_BACKOFF;
+                        accept_after = None;
+                        clients.push(spawn_writer(client));
+                    }
+                    Err(e) => {
+                        tracing::warn!(error = %e, ?backoff, "failed to accept a client, retrying");
+                        accept_after = Some(tokio::time::Instant::now() + backoff);
+                        backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+                    }
+                }
+                continue;
+            }
+            event = events.next() => event,
+        };
+        let Some(event) = event else {
+            break;
+        };
+
+        let frame: Arc<[u8]> = match encode(&event) {
+            Ok(frame) => frame.into(),
+            Err(e) => {
+                tracing::warn!(error = %e, "failed to serialize an event");
+                continue;
+            }
+        };
+        clients.retain(|client| match client.try_send(frame.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                dropped.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            Err(TrySendError::Closed(_)) => false,
+        });
+    }
+}
+
+/// Writes the frames queued on the returned sender to `client`, until the
+/// sender is dropped or writing fails.
+fn spawn_writer(mut client: impl AsyncWrite + Unpin + Send + 'static) -> mpsc::Sender<Arc<[u8]>> {
+    let (sender, mut receiver) = mpsc::channel::<Arc<[u8]>>(CLIENT_QUEUE_LEN);
+    tokio::spawn(async move {
+        while let Some(frame) = receiver.recv().await {
+            if let Err(e) = client.write_all(&frame).await {
+                tracing::debug!(error = %e, "dropping a client");
+                break;
+            }
+        }
+    });
+    sender
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::StreamExt;
+    use tokio::{io::AsyncReadExt, net::TcpStream, sync::mpsc};
+
+    use crate::{FileSystemEvent, FileSystemEventType, FileSystemTarget, FileSystemTargetKind};
+
+    use super::{EventForwarder, TcpForwarder};
+
+    fn event(event_type: FileSystemEventType, path: &str) -> FileSystemEvent {
+        FileSystemEvent {
+            event_type,
+            target: Some(FileSystemTarget {
+                kind: FileSystemTargetKind::File,
+                path: path.into(),
+                device_inode: None,
+                metadata: None,
+            }),
+            pid: None,
+            timestamp: None,
+            sequence: 0,
+        }
+    }
+
+    async fn read_frame(client: &mut TcpStream) -> Option<FileSystemEvent> {
+        let mut len = [0; 4];
+        client.read_exact(&mut len).await.ok()?;
+        let mut body = vec![0; u32::from_be_bytes(len) as usize];
+        client.read_exact(&mut body).await.unwrap();
+        Some(serde_json::from_slice(&body).unwrap())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn forwards_events_to_every_socket_client() {
+        use tokio::{
+            io::{AsyncBufReadExt, BufReader},
+            net::UnixStream,
+        };
+
+        use super::SocketForwarder;
+
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = dir.path().join("events.sock");
+        let events = [
+            event(FileSystemEventType::Create, "/tmp/a"),
+            event(FileSystemEventType::Delete, "/tmp/a"),
+        ];
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let stream = tokio_stream::wrappers::UnboundedReceiverStream::new(receiver);
+        let forwarder = SocketForwarder::spawn(stream.boxed(), &socket_path).unwrap();
+
+        let mut readers = Vec::new();
+        for _ in 0..2 {
+            let client = UnixStream::connect(&socket_path).await.unwrap();
+            readers.push(BufReader::new(client).lines());
+        }
+        // A client that disconnects before any event is sent.
+        drop(UnixStream::connect(&socket_path).await.unwrap());
+        // Lets the forwarder accept every client before the first event.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        for event in events.iter() {
+            sender.send(event.clone()).unwrap();
+        }
+        drop(sender);
+
+        for reader in readers.iter_mut() {
+            for event in events.iter() {
+                let line = reader.next_line().await.unwrap().unwrap();
+                assert_eq!(line, serde_json::to_string(event).unwrap());
+            }
+            assert!(reader.next_line().await.unwrap().is_none());
+        }
+
+        forwarder.await;
+        assert!(!socket_path.exists());
+    }
+
+    #[tokio::test]
+    async fn forwards_length_prefixed_events_to_reconnected_tcp_clients() {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let stream = tokio_stream::wrappers::UnboundedReceiverStream::new(receiver);
+        let forwarder = TcpForwarder::spawn(stream.boxed(), "127.0.0.1:0").unwrap();
+        let address = forwarder.local_addr();
+
+        let created = event(FileSystemEventType::Create, "/tmp/a");
+        let mut client = TcpStream::connect(address).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        sender.send(created.clone()).unwrap();
+        assert_eq!(read_frame(&mut client).await, Some(created));
+
+        drop(client);
+        let mut client = TcpStream::connect(address).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let deleted = event(FileSystemEventType::Delete, "/tmp/a");
+        sender.send(deleted.clone()).unwrap();
+        drop(sender);
+        assert_eq!(read_frame(&mut client).await, Some(deleted));
+        assert_eq!(read_frame(&mut client).await, None);
+
+        forwarder.await;
+    }
+}
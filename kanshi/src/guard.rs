@@ -0,0 +1,57 @@
+use std::ops::{Deref, DerefMut};
+
+use crate::KanshiError;
+
+/// Closes the tracer it holds when dropped, e.g. when an error or a panic
+/// unwinds past it. Returned by `KanshiImpl::guarded`, and derefs to the
+/// tracer, so it can be used in its place.
+///
+/// Clones of a tracer share it, so dropping the guard closes every clone,
+/// including one running `start` on another task.
+///
+/// # Examples
+///
+/// ```
+/// use kanshi::{Kanshi, KanshiError, KanshiImpl, KanshiOptions};
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), KanshiError> {
+///     let dir = tempfile::tempdir()?;
+///     let kanshi = Kanshi::new(KanshiOptions::default())?.guarded();
+///     kanshi.watch(dir.path()).await?;
+///     // `close` is called here, even if `watch` failed.
+///     Ok(())
+/// }
+/// ```
+pub struct WatchGuard<T> {
+    tracer: T,
+    close: fn(&T) -> Result<(), KanshiError>,
+}
+
+impl<T> WatchGuard<T> {
+    pub(crate) fn new(tracer: T, close: fn(&T) -> Result<(), KanshiError>) -> WatchGuard<T> {
+        WatchGuard { tracer, close }
+    }
+}
+
+impl<T> Deref for WatchGuard<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.tracer
+    }
+}
+
+impl<T> DerefMut for WatchGuard<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.tracer
+    }
+}
+
+impl<T> Drop for WatchGuard<T> {
+    fn drop(&mut self) {
+        if let Err(e) = (self.close)(&self.tracer) {
+            tracing::warn!(error = %e, "failed to close the tracer");
+        }
+    }
+}
@@ -1,15 +1,63 @@
+#[cfg(feature = "runtime-tokio")]
+mod blocking;
+mod fanout;
+#[cfg(feature = "net")]
+pub mod forwarding;
+mod guard;
+mod mux;
+#[cfg(feature = "serde")]
+mod os_string_serde;
+mod permission;
+mod pipeline;
 mod platforms;
+mod queue;
+#[cfg(feature = "serde")]
+mod replay;
+mod runtime;
+mod sender;
+mod snapshot;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod util;
+mod watch_set;
 
+#[cfg(feature = "runtime-tokio")]
+pub use blocking::KanshiBlockingIter;
+pub use fanout::{Fanout, FilteredStream};
+pub use guard::WatchGuard;
+pub use mux::MultiStreamMux;
+pub use permission::PermissionEvent;
+pub use pipeline::PipelineBuilder;
 pub use platforms::*;
+pub use queue::{EventQueue, QueuedEvent};
+#[cfg(feature = "serde")]
+pub use replay::{EventLogReplayer, LoggedEvent, ReplayOptions};
+pub use snapshot::{diff_snapshots, TreeEntry, WatchedTree};
+pub use watch_set::WatchSet;
 
-use std::{ffi::OsString, io, pin::Pin};
+use std::{
+    backtrace::Backtrace,
+    collections::{BTreeSet, HashSet},
+    ffi::OsString,
+    fmt, io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
 
 use thiserror::Error;
 
 #[cfg(unix)]
 use nix::errno::Errno;
 
+/// Errors returned by Kanshi.
+///
+/// Errors from the operating system are kept as `Io` or `Nix`, unless they
+/// concern a single path, in which case the common ones get their own variant:
+/// `PathNotFound`, `PermissionDenied`, `Stale` and `MarkQuotaExceeded`.
 #[derive(Error, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum KanshiError {
     #[error("unable to attach ptrace to subprocess thread: {0}")]
     PTraceError(String),
@@ -17,97 +65,1151 @@ pub enum KanshiError {
     #[error("invalid command supplied: {0}")]
     InvalidCommand(String),
 
-    #[error("file system error {0}")]
-    FileSystemError(String),
+    /// An I/O error. Carries a backtrace of where the error was created when
+    /// backtraces are enabled with `RUST_BACKTRACE` or `RUST_LIB_BACKTRACE`,
+    /// which is shown in the `Debug` output.
+    #[error("i/o error: {0}")]
+    Io(
+        #[source]
+        #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_display"))]
+        Arc<io::Error>,
+        #[cfg_attr(feature = "serde", serde(skip))] Arc<Backtrace>,
+    ),
+
+    /// A failed system call, with a backtrace like `Io`.
+    #[cfg(unix)]
+    #[error("system call failed: {0}")]
+    Nix(
+        #[source]
+        #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_display"))]
+        Errno,
+        #[cfg_attr(feature = "serde", serde(skip))] Arc<Backtrace>,
+    ),
+
+    #[error("path does not exist: {0:?}")]
+    PathNotFound(PathBuf),
+
+    #[error("permission denied: {0:?}")]
+    PermissionDenied(PathBuf),
+
+    /// The kernel refused to add another watch, e.g. because
+    /// `fs.inotify.max_user_watches` or the fanotify mark limit was reached.
+    #[error("no more watches can be added")]
+    MarkQuotaExceeded,
+
+    /// More than `KanshiOptions::mark_quota_warn_threshold` of the marks the
+    /// fanotify group may hold are in use. Reported once through
+    /// `KanshiOptions::on_error`.
+    #[error("{used} of {limit} fanotify marks are in use")]
+    MarkQuotaNearlyExhausted { used: usize, limit: usize },
+
+    /// A file handle no longer refers to an existing entry (`ESTALE`).
+    #[error("stale file handle: {0:?}")]
+    Stale(PathBuf),
 
     #[error("the file system listener was closed")]
-    StreamClosedError,
+    StreamClosed,
 
     #[error("listener has already started")]
-    ListenerStartedError,
+    ListenerAlreadyStarted,
 
     #[error("invalid parameter supplied: {0}")]
     InvalidParameter(String),
+
+    #[error("invalid pattern supplied: {0}")]
+    InvalidPattern(String),
+
+    #[error("unknown event mask received: {0}")]
+    UnknownEventMask(String),
+
+    #[error("path is not watched: {0:?}")]
+    PathNotWatched(PathBuf),
+
+    /// No matching event arrived in time, e.g. in `util::wait_for`.
+    #[error("timed out waiting for an event")]
+    Timeout,
+
+    /// A path that is not valid UTF-8 was passed to `watch` on macOS, where
+    /// FSEvents only accepts paths it can turn into a `CFString`.
+    #[error("path is not valid UTF-8: {0:?}")]
+    PathNotUtf8(OsString),
+
+    /// Several steps of an operation that carries on after a failed step,
+    /// such as `KanshiImpl::close`, failed.
+    #[error("{} errors occurred: {}", .0.len(), join_errors(.0))]
+    MultipleErrors(Vec<KanshiError>),
+}
+
+impl KanshiError {
+    /// Turns an error that occurred while accessing `path` into
+    /// `PathNotFound`, `PermissionDenied` or `Stale` where possible.
+    pub(crate) fn from_io_at(error: io::Error, path: impl Into<PathBuf>) -> KanshiError {
+        match error.kind() {
+            io::ErrorKind::NotFound => KanshiError::PathNotFound(path.into()),
+            io::ErrorKind::PermissionDenied => KanshiError::PermissionDenied(path.into()),
+            #[cfg(unix)]
+            _ => match error.raw_os_error() {
+                Some(code) => KanshiError::from_errno_at(Errno::from_raw(code), path),
+                None => KanshiError::from(error),
+            },
+            #[cfg(not(unix))]
+            _ => KanshiError::from(error),
+        }
+    }
+
+    /// Like `from_io_at`, for a failed system call. `ENOSPC` is reported as
+    /// `MarkQuotaExceeded`, since it is how inotify and fanotify refuse new
+    /// watches.
+    #[cfg(unix)]
+    pub(crate) fn from_errno_at(errno: Errno, path: impl Into<PathBuf>) -> KanshiError {
+        match errno {
+            Errno::ENOENT => KanshiError::PathNotFound(path.into()),
+            Errno::EACCES | Errno::EPERM => KanshiError::PermissionDenied(path.into()),
+            Errno::ESTALE => KanshiError::Stale(path.into()),
+            Errno::ENOSPC => KanshiError::MarkQuotaExceeded,
+            _ => KanshiError::from(errno),
+        }
+    }
+
+    /// `Ok` without any errors, the error itself if there is only one, and
+    /// `MultipleErrors` otherwise.
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    pub(crate) fn from_errors(mut errors: Vec<KanshiError>) -> Result<(), KanshiError> {
+        match errors.len() {
+            0 => Ok(()),
+            1 => Err(errors.remove(0)),
+            _ => Err(KanshiError::MultipleErrors(errors)),
+        }
+    }
+}
+
+fn join_errors(errors: &[KanshiError]) -> String {
+    let errors: Vec<_> = errors.iter().map(|x| x.to_string()).collect();
+    errors.join("; ")
+}
+
+#[cfg(feature = "serde")]
+fn serialize_display<S: serde::Serializer>(
+    value: &impl std::fmt::Display,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(value)
+}
+
+#[cfg(test)]
+thread_local! {
+    // Makes `capture_backtrace` capture on this thread whatever the
+    // environment says, so tests don't have to change it for every test.
+    static FORCE_BACKTRACE: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+/// Like `Backtrace::capture`, but reads the environment on every call instead
+/// of only once per process.
+fn capture_backtrace() -> Backtrace {
+    #[cfg(test)]
+    if FORCE_BACKTRACE.get() {
+        return Backtrace::force_capture();
+    }
+
+    let enabled = std::env::var_os("RUST_LIB_BACKTRACE")
+        .or_else(|| std::env::var_os("RUST_BACKTRACE"))
+        .is_some_and(|x| x != "0");
+
+    if enabled {
+        Backtrace::force_capture()
+    } else {
+        Backtrace::disabled()
+    }
+}
+
+/// Callback invoked with errors that do not stop the tracer, such as an event
+/// whose path could not be resolved. See `KanshiOptions::on_error`.
+pub type ErrorHandler = Arc<dyn Fn(KanshiError) + Send + Sync>;
+
+pub(crate) fn default_error_handler() -> ErrorHandler {
+    Arc::new(|e| tracing::error!(error = %e, "tracer error"))
 }
 
 impl From<io::Error> for KanshiError {
     fn from(value: io::Error) -> Self {
-        KanshiError::FileSystemError(value.to_string())
+        KanshiError::Io(Arc::new(value), Arc::new(capture_backtrace()))
     }
 }
 
-
 #[cfg(unix)]
 impl From<Errno> for KanshiError {
     fn from(value: Errno) -> Self {
-        KanshiError::FileSystemError(value.to_string())
+        KanshiError::Nix(value, Arc::new(capture_backtrace()))
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+/// With the `serde` feature, the paths carried by `MovedTo` and `MovedFrom` are
+/// serialized as strings, or as `{"base64": "..."}` if they are not valid
+/// UTF-8.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
 pub enum FileSystemEventType {
     Create,
     Delete,
     Modify,
     Move,
-    MovedTo(OsString),
-    MovedFrom(OsString),
+    /// The target, at `from`, was renamed to `to`. Reported together with a
+    /// `MovedFrom` for the same rename, whose target is at `to`.
+    ///
+    /// Moves across filesystems, e.g. `mv` between two mounts, are not renames:
+    /// the target is copied to a new inode on the other device and the
+    /// original deleted. They are reported as a `Create` and a `Delete` with
+    /// different `device_inode`s, which nothing ties back together.
+    MovedTo {
+        #[cfg_attr(feature = "serde", serde(with = "os_string_serde"))]
+        from: OsString,
+        #[cfg_attr(feature = "serde", serde(with = "os_string_serde"))]
+        to: OsString,
+    },
+    /// The target, at `to`, was renamed from `from`. Reported together with a
+    /// `MovedTo` for the same rename, whose target is at `from`.
+    MovedFrom {
+        #[cfg_attr(feature = "serde", serde(with = "os_string_serde"))]
+        from: OsString,
+        #[cfg_attr(feature = "serde", serde(with = "os_string_serde"))]
+        to: OsString,
+    },
+    /// A file opened for writing was closed, meaning a write has completed.
+    /// Only reported on Linux, with `KanshiOptions::watch_close_write` or
+    /// `KanshiOptions::watch_open_file_deletion`. FSEvents has no equivalent;
+    /// on macOS, debouncing `Modify` events (see `PipelineBuilder::debounce`)
+    /// is the closest approximation, but cannot tell whether the file is
+    /// still open.
+    CloseWrite,
+    /// A file was read. Only reported with `KanshiOptions::watch_access`.
+    Access,
+    /// A file or directory was opened. Only reported on Linux, with
+    /// `KanshiOptions::watch_open`.
+    Open,
+    /// The metadata of an entry changed, e.g. its permissions, owner,
+    /// timestamps or extended attributes, but not its contents.
+    AttributeChange,
     Unknown,
+    /// Several events for the same path, merged into one. Only reported with
+    /// `KanshiOptions::coalesce_window_ms`.
+    Compound(BTreeSet<FileSystemEventType>),
+    /// More events arrived than `KanshiOptions::max_events_per_second`
+    /// allows, and `dropped` of them were discarded. Reported once the rate
+    /// falls back below the limit.
+    RateLimitExceeded { dropped: u64 },
 }
 
-impl ToString for FileSystemEventType {
-    fn to_string(&self) -> String {
+impl FileSystemEventType {
+    /// The name of the event type in snake case, e.g. `moved_to`, as reported
+    /// by the CLI and the bindings.
+    pub fn name(&self) -> &'static str {
         match self {
-            FileSystemEventType::MovedTo(_) => "moved_to",
-            FileSystemEventType::MovedFrom(_) => "moved_from",
+            FileSystemEventType::MovedTo { .. } => "moved_to",
+            FileSystemEventType::MovedFrom { .. } => "moved_from",
             FileSystemEventType::Create => "create",
             FileSystemEventType::Delete => "delete",
             FileSystemEventType::Modify => "modify",
             FileSystemEventType::Move => "move",
+            FileSystemEventType::CloseWrite => "close_write",
+            FileSystemEventType::Access => "access",
+            FileSystemEventType::Open => "open",
+            FileSystemEventType::AttributeChange => "attribute_change",
             FileSystemEventType::Unknown => "unknown",
+            FileSystemEventType::Compound(_) => "compound",
+            FileSystemEventType::RateLimitExceeded { .. } => "rate_limit_exceeded",
+        }
+    }
+}
+
+/// Formats the name in upper case, e.g. `CREATE`, followed by both paths of
+/// `MovedTo` and `MovedFrom`, the merged event types of `Compound` or the
+/// number of events dropped by `RateLimitExceeded` in parentheses. Paths that
+/// aren't valid UTF-8 are converted lossily.
+impl fmt::Display for FileSystemEventType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name().to_ascii_uppercase())?;
+        match self {
+            FileSystemEventType::MovedTo { from, to }
+            | FileSystemEventType::MovedFrom { from, to } => {
+                write!(f, "({} -> {})", from.to_string_lossy(), to.to_string_lossy())
+            }
+            FileSystemEventType::Compound(event_types) => {
+                let event_types: Vec<_> = event_types.iter().map(|x| x.to_string()).collect();
+                write!(f, "({})", event_types.join(", "))
+            }
+            FileSystemEventType::RateLimitExceeded { dropped } => write!(f, "({dropped} dropped)"),
+            _ => Ok(()),
+        }
+    }
+}
+
+bitflags::bitflags! {
+    /// A set of event types, used to select which events to receive.
+    /// `MOVE` covers `Move`, `MovedTo` and `MovedFrom`.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+    pub struct EventTypeMask: u32 {
+        const CREATE = 1 << 0;
+        const DELETE = 1 << 1;
+        const MODIFY = 1 << 2;
+        const MOVE = 1 << 3;
+        const CLOSE_WRITE = 1 << 4;
+        const UNKNOWN = 1 << 5;
+        const ACCESS = 1 << 6;
+        const ATTRIBUTE_CHANGE = 1 << 7;
+        const RATE_LIMIT_EXCEEDED = 1 << 8;
+        const OPEN = 1 << 9;
+    }
+}
+
+impl EventTypeMask {
+    /// Whether `event_type` is part of this mask.
+    pub fn matches(&self, event_type: &FileSystemEventType) -> bool {
+        self.intersects(event_type.mask())
+    }
+}
+
+impl FileSystemEventType {
+    /// The [`EventTypeMask`] bit for this event type.
+    pub fn mask(&self) -> EventTypeMask {
+        match self {
+            FileSystemEventType::Create => EventTypeMask::CREATE,
+            FileSystemEventType::Delete => EventTypeMask::DELETE,
+            FileSystemEventType::Modify => EventTypeMask::MODIFY,
+            FileSystemEventType::Move
+            | FileSystemEventType::MovedTo { .. }
+            | FileSystemEventType::MovedFrom { .. } => EventTypeMask::MOVE,
+            FileSystemEventType::CloseWrite => EventTypeMask::CLOSE_WRITE,
+            FileSystemEventType::Access => EventTypeMask::ACCESS,
+            FileSystemEventType::Open => EventTypeMask::OPEN,
+            FileSystemEventType::AttributeChange => EventTypeMask::ATTRIBUTE_CHANGE,
+            FileSystemEventType::Unknown => EventTypeMask::UNKNOWN,
+            FileSystemEventType::Compound(event_types) => event_types
+                .iter()
+                .fold(EventTypeMask::empty(), |mask, x| mask | x.mask()),
+            FileSystemEventType::RateLimitExceeded { .. } => EventTypeMask::RATE_LIMIT_EXCEEDED,
         }
-        .to_owned()
     }
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum FileSystemTargetKind {
     Directory,
     File,
+    /// A symbolic link, which is never followed. inotify and fanotify can't
+    /// tell links from files, so links that are gone by the time the event is
+    /// read are reported as `File` by those engines.
+    Symlink,
+    /// A file whose inode has other links besides this one, with
+    /// `KanshiOptions::detect_hard_links`. Only reported for `Create` events.
+    HardLink {
+        /// The inode shared by all the links.
+        original_inode: u64,
+    },
 }
 
-#[derive(Clone, Debug)]
+/// Formats the kind in lower case, e.g. `file` or `hardlink`.
+impl fmt::Display for FileSystemTargetKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FileSystemTargetKind::Directory => "directory",
+            FileSystemTargetKind::File => "file",
+            FileSystemTargetKind::Symlink => "symlink",
+            FileSystemTargetKind::HardLink { .. } => "hardlink",
+        })
+    }
+}
+
+/// With the `serde` feature, `path` is serialized as a string, or as
+/// `{"base64": "..."}` holding its raw bytes if it is not valid UTF-8, so that
+/// every path survives a round trip on Unix.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct FileSystemTarget {
     pub kind: FileSystemTargetKind,
+    #[cfg_attr(feature = "serde", serde(with = "os_string_serde"))]
     pub path: OsString,
+    /// The `(st_dev, st_ino)` pair identifying the target, if it could be read.
+    /// Unlike `path`, this stays the same across renames, so it can be used to
+    /// correlate events for the same file. This is `None` when the target no
+    /// longer exists by the time the event is processed (e.g. after a delete).
+    pub device_inode: Option<(u64, u64)>,
+    /// The state of the target right after the event was received. Only read
+    /// for `Create` and `Modify` events with `KanshiOptions::include_metadata`,
+    /// or `Modify` events with `KanshiOptions::compute_hash_on_modify`, and
+    /// `None` if the target was gone by then.
+    pub metadata: Option<EventMetadata>,
 }
 
-#[derive(Clone, Debug)]
+/// Formats the kind and the path, e.g. `file /home/user/notes.txt`, converting
+/// a path that isn't valid UTF-8 lossily.
+impl fmt::Display for FileSystemTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.kind, self.path.to_string_lossy())
+    }
+}
+
+/// File metadata read when an event is received. See
+/// `FileSystemTarget::metadata`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EventMetadata {
+    /// The size in bytes.
+    pub size: u64,
+    /// The last modification time.
+    pub modified: SystemTime,
+    /// The permission bits and file type, as in `st_mode`. On Windows, this is
+    /// `0o444` for read-only files and `0o666` otherwise.
+    pub mode: u32,
+    /// The SHA-256 hash of the contents, only read for `Modify` events with
+    /// `KanshiOptions::compute_hash_on_modify`. `None` if the target is not a
+    /// regular file, is larger than 64 MiB, or could not be read, e.g. because
+    /// it was deleted first.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub hash: Option<[u8; 32]>,
+}
+
+impl EventMetadata {
+    /// Files larger than this are not hashed.
+    pub(crate) const MAX_HASHED_SIZE: u64 = 64 * 1024 * 1024;
+
+    /// Reads the metadata of `path` without following symlinks.
+    pub(crate) fn read(path: impl AsRef<Path>) -> Option<EventMetadata> {
+        let metadata = std::fs::symlink_metadata(path).ok()?;
+
+        #[cfg(unix)]
+        let mode = std::os::unix::fs::MetadataExt::mode(&metadata);
+        #[cfg(not(unix))]
+        let mode = if metadata.permissions().readonly() {
+            0o444
+        } else {
+            0o666
+        };
+
+        Some(EventMetadata {
+            size: metadata.len(),
+            modified: metadata.modified().ok()?,
+            mode,
+            hash: None,
+        })
+    }
+
+    /// Hashes the contents of the regular file at `path` with SHA-256. On
+    /// Linux, the file is opened with `O_NOATIME` where permitted, so reading
+    /// it doesn't update its access time and cause an `Attrib` event.
+    pub(crate) fn hash(path: impl AsRef<Path>) -> Option<[u8; 32]> {
+        use sha2::{Digest, Sha256};
+
+        let path = path.as_ref();
+        // Opening a FIFO for reading would block until it has a writer.
+        let metadata = std::fs::symlink_metadata(path).ok()?;
+        if !metadata.is_file() || metadata.len() > EventMetadata::MAX_HASHED_SIZE {
+            return None;
+        }
+
+        #[cfg(target_os = "linux")]
+        let file = {
+            use std::os::unix::fs::OpenOptionsExt;
+
+            // Only the owner of the file may open it with `O_NOATIME`.
+            std::fs::OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_NOATIME)
+                .open(path)
+                .or_else(|_| std::fs::File::open(path))
+        };
+        #[cfg(not(target_os = "linux"))]
+        let file = std::fs::File::open(path);
+
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut file.ok()?, &mut hasher).ok()?;
+        Some(hasher.finalize().into())
+    }
+}
+
+/// A directory watched with `Kanshi::try_watch`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WatchHandle {
+    /// The canonical path of the watched directory.
+    pub path: PathBuf,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct FileSystemEvent {
     pub event_type: FileSystemEventType,
     pub target: Option<FileSystemTarget>,
+    /// The id of the process that caused the event. Only reported by the
+    /// fanotify engine; inotify and FSEvents do not expose it. The process may
+    /// have exited, and its id been reused, by the time the event is read.
+    pub pid: Option<u32>,
+    /// When the event was read from the kernel, with
+    /// `KanshiOptions::approximate_timestamp`. Neither fanotify, inotify,
+    /// FSEvents nor kqueue report when the event itself happened.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub timestamp: Option<SystemTime>,
+    /// The position of the event among all the events read by the tracer,
+    /// starting from `KanshiOptions::initial_sequence`. Numbers are assigned
+    /// before events are filtered, so gaps mean events were dropped.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub sequence: u64,
+}
+
+/// Formats the event type and the kind of the target in brackets, followed by
+/// the target's path, e.g. `[CREATE file] /home/user/notes.txt`. Events
+/// without a target, such as `RateLimitExceeded`, only show the event type.
+impl fmt::Display for FileSystemEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.target.as_ref() {
+            Some(target) => write!(
+                f,
+                "[{} {}] {}",
+                self.event_type,
+                target.kind,
+                target.path.to_string_lossy()
+            ),
+            None => write!(f, "[{}]", self.event_type),
+        }
+    }
+}
+
+/// An item of a stream returned by `KanshiImpl::get_events_stream`.
+///
+/// A stream ends right after yielding an `Error`, which is the error the
+/// tracer failed with and `start` returns. A stream that ends without one was
+/// closed normally.
+#[derive(Clone, Debug)]
+pub enum KanshiStreamItem {
+    Event(FileSystemEvent),
+    Error(KanshiError),
+    /// An access waiting to be allowed or denied, only reported by the
+    /// fanotify engine with `KanshiOptions::permission_mode`.
+    PermissionRequest(PermissionEvent),
+}
+
+impl KanshiStreamItem {
+    /// The event, or `None` for an error. A permission request is turned into
+    /// its event, which allows the access unless a clone of the request is
+    /// still around.
+    pub fn into_event(self) -> Option<FileSystemEvent> {
+        match self {
+            KanshiStreamItem::Event(event) => Some(event),
+            KanshiStreamItem::Error(_) => None,
+            KanshiStreamItem::PermissionRequest(request) => Some(request.event.clone()),
+        }
+    }
+
+    /// The event, or the error the tracer failed with. Permission requests
+    /// are turned into their event, as with `into_event`.
+    pub fn into_result(self) -> Result<FileSystemEvent, KanshiError> {
+        match self {
+            KanshiStreamItem::Error(e) => Err(e),
+            item => Ok(item.into_event().unwrap()),
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        matches!(self, KanshiStreamItem::Error(_))
+    }
+}
+
+impl From<FileSystemEvent> for KanshiStreamItem {
+    fn from(value: FileSystemEvent) -> Self {
+        KanshiStreamItem::Event(value)
+    }
+}
+
+/// A snapshot of a tracer's health, returned by `stats`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EventStatistics {
+    /// Events broadcast to the streams so far.
+    pub events_sent: u64,
+    /// Events lost so far: skipped by a stream that fell more than
+    /// `KanshiOptions::channel_capacity` events behind, counted once per
+    /// stream, or dropped from the buffer kept while no stream is subscribed.
+    pub events_dropped: u64,
+    /// Directories currently marked or, with FSEvents, paths currently
+    /// watched.
+    pub marks_active: usize,
+    /// When the last event was broadcast, if any was.
+    pub last_event_at: Option<Instant>,
+    /// Time since the tracer was created.
+    pub uptime: Duration,
+    /// `KanshiOptions::channel_capacity`.
+    pub channel_capacity: usize,
+}
+
+#[cfg(feature = "metrics")]
+impl EventStatistics {
+    /// The statistics in the Prometheus text exposition format.
+    pub(crate) fn to_prometheus(&self) -> String {
+        let mut metrics = vec![
+            (
+                "events_sent_total",
+                "counter",
+                "Events broadcast to the streams.",
+                self.events_sent as f64,
+            ),
+            (
+                "events_dropped_total",
+                "counter",
+                "Events lost by lagging streams or while no stream was subscribed.",
+                self.events_dropped as f64,
+            ),
+            (
+                "marks_active",
+                "gauge",
+                "Directories currently marked.",
+                self.marks_active as f64,
+            ),
+            (
+                "uptime_seconds",
+                "gauge",
+                "Time since the tracer was created.",
+                self.uptime.as_secs_f64(),
+            ),
+            (
+                "channel_capacity",
+                "gauge",
+                "Events a stream can fall behind before it loses some.",
+                self.channel_capacity as f64,
+            ),
+        ];
+        if let Some(last_event_at) = self.last_event_at {
+            metrics.push((
+                "seconds_since_last_event",
+                "gauge",
+                "Time since the last event was broadcast.",
+                last_event_at.elapsed().as_secs_f64(),
+            ));
+        }
+
+        let mut text = String::new();
+        for (name, kind, help, value) in metrics {
+            text.push_str(&format!(
+                "# HELP kanshi_{name} {help}\n# TYPE kanshi_{name} {kind}\nkanshi_{name} {value}\n"
+            ));
+        }
+        text
+    }
 }
 
 pub trait KanshiImpl<Opts>: Clone + Send + Sync {
     /// Creates a new Kanshi instance.
     /// Warning: This method blocks the thread until its finished!
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kanshi::{Kanshi, KanshiImpl, KanshiOptions};
+    ///
+    /// let kanshi = Kanshi::new(KanshiOptions::default()).unwrap();
+    /// # kanshi.close().unwrap();
+    /// ```
     fn new(opts: Opts) -> Result<Self, KanshiError>
     where
         Self: Sized + Clone;
 
-    /// Watches a new directory.
+    /// Watches a new directory. Accepts anything that can be used as a path,
+    /// such as `&str`, `String`, `&Path` or `PathBuf`.
+    ///
+    /// `dir` can also be a single file. Its parent directory is watched
+    /// instead, and only events for the file itself are reported, so the file
+    /// keeps being reported after another file is renamed over it. Watching a
+    /// file is not supported by the fanotify engine with
+    /// `PathResolutionMode::NameOnly`.
     /// Warning: This method blocks the thread until its finished!
-    fn watch(&self, dir: &str) -> impl futures::Future<Output = Result<(), KanshiError>>;
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kanshi::{Kanshi, KanshiError, KanshiImpl, KanshiOptions};
+    ///
+    /// #[tokio::main]
+    /// async fn main() -> Result<(), KanshiError> {
+    ///     let dir = tempfile::tempdir()?;
+    ///     let kanshi = Kanshi::new(KanshiOptions::default())?;
+    ///
+    ///     kanshi.watch(dir.path()).await?;
+    ///     # kanshi.close().unwrap();
+    ///     Ok(())
+    /// }
+    /// ```
+    fn watch(
+        &self,
+        dir: impl AsRef<Path> + Send,
+    ) -> impl futures::Future<Output = Result<(), KanshiError>>;
+
+    /// Same as `watch`, kept for code written against the previous `&str`
+    /// signature. See `MIGRATING.md`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kanshi::{Kanshi, KanshiError, KanshiImpl, KanshiOptions};
+    ///
+    /// #[tokio::main]
+    /// async fn main() -> Result<(), KanshiError> {
+    ///     let dir = tempfile::tempdir()?;
+    ///     let kanshi = Kanshi::new(KanshiOptions::default())?;
+    ///
+    ///     kanshi.watch_str(&dir.path().to_string_lossy()).await?;
+    ///     # kanshi.close().unwrap();
+    ///     Ok(())
+    /// }
+    /// ```
+    fn watch_str(&self, dir: &str) -> impl futures::Future<Output = Result<(), KanshiError>> {
+        self.watch(dir)
+    }
+
+    /// Watches every path in `dirs` like `watch`, as a single operation: if any
+    /// of them fails, the paths watched so far by this call are unwatched
+    /// again and the error is returned. Paths that were already watched before
+    /// the call stay watched.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kanshi::{Kanshi, KanshiError, KanshiImpl, KanshiOptions};
+    ///
+    /// #[tokio::main]
+    /// async fn main() -> Result<(), KanshiError> {
+    ///     let first = tempfile::tempdir()?;
+    ///     let second = tempfile::tempdir()?;
+    ///     let kanshi = Kanshi::new(KanshiOptions::default())?;
+    ///
+    ///     kanshi.watch_many(&[first.path(), second.path()]).await?;
+    ///     # kanshi.close().unwrap();
+    ///     Ok(())
+    /// }
+    /// ```
+    ///
+    /// Any collection of paths works, e.g. a [`WatchSet`]:
+    ///
+    /// ```
+    /// use kanshi::{Kanshi, KanshiError, KanshiImpl, KanshiOptions, WatchSet};
+    ///
+    /// #[tokio::main]
+    /// async fn main() -> Result<(), KanshiError> {
+    ///     let dir = tempfile::tempdir()?;
+    ///     std::fs::create_dir(dir.path().join("src"))?;
+    ///     std::fs::create_dir(dir.path().join("tests"))?;
+    ///     let kanshi = Kanshi::new(KanshiOptions::default())?;
+    ///
+    ///     let dirs = WatchSet::from_dir(dir.path(), "{src,tests}")?;
+    ///     kanshi.watch_many(dirs).await?;
+    ///     assert_eq!(kanshi.get_watched_paths().len(), 2);
+    ///     # kanshi.close().unwrap();
+    ///     Ok(())
+    /// }
+    /// ```
+    fn watch_many(
+        &self,
+        dirs: impl IntoIterator<Item = impl AsRef<Path>> + Send,
+    ) -> impl futures::Future<Output = Result<(), KanshiError>> {
+        let dirs: Vec<PathBuf> = dirs.into_iter().map(|x| x.as_ref().to_path_buf()).collect();
+        async move {
+            let watched = self.get_watched_paths();
+            let mut added = Vec::new();
+            for dir in dirs.iter() {
+                let dir = dir.as_path();
+                let already_watched = dir.canonicalize().is_ok_and(|x| watched.contains(&x));
+                if let Err(e) = self.watch(dir).await {
+                    // Paths that were watched before this call stay watched.
+                    for dir in added.into_iter().rev() {
+                        let _ = self.unwatch(dir).await;
+                    }
+                    return Err(e);
+                }
+                if !already_watched {
+                    added.push(dir);
+                }
+            }
+            Ok(())
+        }
+    }
+
+    /// Watches a new directory like `watch`, but skips every directory in
+    /// `exclude` along with everything below it. Paths are compared after
+    /// canonicalisation, so relative paths and symlinks are accepted.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::collections::HashSet;
+    ///
+    /// use kanshi::{Kanshi, KanshiError, KanshiImpl, KanshiOptions};
+    ///
+    /// #[tokio::main]
+    /// async fn main() -> Result<(), KanshiError> {
+    ///     let dir = tempfile::tempdir()?;
+    ///     std::fs::create_dir(dir.path().join("target"))?;
+    ///     let kanshi = Kanshi::new(KanshiOptions::default())?;
+    ///
+    ///     let exclude = HashSet::from([dir.path().join("target")]);
+    ///     kanshi
+    ///         .watch_with_exclude_dirs(dir.path(), exclude)
+    ///         .await?;
+    ///     # kanshi.close().unwrap();
+    ///     Ok(())
+    /// }
+    /// ```
+    fn watch_with_exclude_dirs(
+        &self,
+        dir: impl AsRef<Path> + Send,
+        exclude: HashSet<PathBuf>,
+    ) -> impl futures::Future<Output = Result<(), KanshiError>>;
+
+    /// Stops watching a directory that was passed to `watch`, along with
+    /// everything below it. Directories below `dir` that were watched on their
+    /// own keep being watched, and nothing is removed while a watched directory
+    /// above `dir` still covers it. Events that were already received are
+    /// still delivered to every stream. Returns `PathNotWatched` if `dir` was
+    /// never watched.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kanshi::{Kanshi, KanshiError, KanshiImpl, KanshiOptions};
+    ///
+    /// #[tokio::main]
+    /// async fn main() -> Result<(), KanshiError> {
+    ///     let dir = tempfile::tempdir()?;
+    ///     let kanshi = Kanshi::new(KanshiOptions::default())?;
+    ///
+    ///     kanshi.watch(dir.path()).await?;
+    ///     kanshi.unwatch(dir.path()).await?;
+    ///     assert!(matches!(
+    ///         kanshi.unwatch(dir.path()).await,
+    ///         Err(KanshiError::PathNotWatched(_))
+    ///     ));
+    ///     # kanshi.close().unwrap();
+    ///     Ok(())
+    /// }
+    /// ```
+    fn unwatch(
+        &self,
+        dir: impl AsRef<Path> + Send,
+    ) -> impl futures::Future<Output = Result<(), KanshiError>>;
+
+    /// The paths passed to `watch` that are still watched, canonicalized and
+    /// sorted. Directories that are only watched because they are below one of
+    /// them are not included.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kanshi::{Kanshi, KanshiError, KanshiImpl, KanshiOptions};
+    ///
+    /// #[tokio::main]
+    /// async fn main() -> Result<(), KanshiError> {
+    ///     let dir = tempfile::tempdir()?;
+    ///     let kanshi = Kanshi::new(KanshiOptions::default())?;
+    ///
+    ///     kanshi.watch(dir.path()).await?;
+    ///     assert_eq!(kanshi.get_watched_paths(), [dir.path().canonicalize()?]);
+    ///     # kanshi.close().unwrap();
+    ///     Ok(())
+    /// }
+    /// ```
+    fn get_watched_paths(&self) -> Vec<PathBuf>;
+
+    /// Captures the current state of `dir` by walking it recursively on a
+    /// blocking thread. Taking a snapshot after `watch` and subscribing to
+    /// `get_events_stream` gives a starting state that the events can then be
+    /// applied to. Entries changed while the walk is running may show up both
+    /// in the snapshot and as events.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::path::Path;
+    ///
+    /// use kanshi::{Kanshi, KanshiError, KanshiImpl, KanshiOptions};
+    ///
+    /// #[tokio::main]
+    /// async fn main() -> Result<(), KanshiError> {
+    ///     let dir = tempfile::tempdir()?;
+    ///     std::fs::write(dir.path().join("hello.txt"), "hello")?;
+    ///
+    ///     let kanshi = Kanshi::new(KanshiOptions::default())?;
+    ///     kanshi.watch(dir.path()).await?;
+    ///
+    ///     let tree = kanshi.snapshot_directory(dir.path()).await?;
+    ///     assert!(tree.entries.contains_key(Path::new("hello.txt")));
+    ///     # kanshi.close().unwrap();
+    ///     Ok(())
+    /// }
+    /// ```
+    fn snapshot_directory(
+        &self,
+        dir: impl AsRef<Path> + Send,
+    ) -> impl futures::Future<Output = Result<WatchedTree, KanshiError>> {
+        let dir = dir.as_ref().to_path_buf();
+        async move {
+            runtime::spawn_blocking(move || WatchedTree::capture(dir)).await?
+        }
+    }
 
     /// Get a new stream where events can be received.
     /// This method does not block and is safe to use in an async context.
-    fn get_events_stream(&self) -> Pin<Box<dyn futures::Stream<Item = FileSystemEvent> + Send>>;
+    ///
+    /// # Ordering
+    ///
+    /// Every stream receives events in the order the tracer read them.
+    ///
+    /// - **inotify**: events arrive in the order they happened. The kernel only
+    ///   drops an event that is identical to the one queued right before it.
+    /// - **fanotify**: events for different entries arrive in the order they
+    ///   happened. The kernel merges unread events for the same entry into one,
+    ///   so those are reported in their most likely order: `Create`, `Access`,
+    ///   `Modify`, `AttributeChange`, `CloseWrite`, then `Delete`, or `Delete`
+    ///   first if the entry exists again.
+    /// - **FSEvents**: events are delivered in batches ordered by event id, but
+    ///   changes to the same path within a batch are coalesced into one event
+    ///   (e.g. a file created and then modified is reported only as `Create`).
+    ///
+    /// Options that hold events back, like `grace_period`, can deliver those
+    /// events after ones that happened later.
+    ///
+    /// # Errors
+    ///
+    /// If the tracer fails, e.g. because reading from the kernel failed, every
+    /// stream receives the error as a [`KanshiStreamItem::Error`] and then
+    /// ends. Streams end without an error when the tracer is closed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use futures::StreamExt;
+    /// use kanshi::{FileSystemEventType, Kanshi, KanshiError, KanshiImpl, KanshiOptions};
+    ///
+    /// #[tokio::main]
+    /// async fn main() -> Result<(), KanshiError> {
+    ///     let dir = tempfile::tempdir()?;
+    ///     let kanshi = Kanshi::new(KanshiOptions::default())?;
+    ///     kanshi.watch(dir.path()).await?;
+    ///
+    ///     // Subscribe before starting so no events are missed.
+    ///     let mut stream = kanshi.get_events_stream();
+    ///     let tracer = kanshi.clone();
+    ///     tokio::spawn(async move { tracer.start().await });
+    ///
+    ///     std::fs::write(dir.path().join("hello.txt"), "hello")?;
+    ///
+    ///     let event = stream.next().await.unwrap().into_result()?;
+    ///     assert_eq!(event.event_type, FileSystemEventType::Create);
+    ///
+    ///     kanshi.close().unwrap();
+    ///     Ok(())
+    /// }
+    /// ```
+    fn get_events_stream(&self) -> Pin<Box<dyn futures::Stream<Item = KanshiStreamItem> + Send>>;
 
-    /// Start listening for events. Kanshi will ignore all events until this method is run.
+    /// Calls `callback` with every event, straight from the tracer's event
+    /// loop instead of through a stream. Events pass the same filters,
+    /// debouncing and pausing as they do for streams. Callbacks can be
+    /// registered at any time, and can be used alongside
+    /// `get_events_stream`.
+    ///
+    /// The callback runs on the tracer's thread, holding up further events
+    /// until it returns, and must not call `on_event` itself. While any
+    /// callback is registered, the tracer keeps running without a stream
+    /// instead of failing with `StreamClosed`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::sync::{
+    ///     atomic::{AtomicUsize, Ordering},
+    ///     Arc,
+    /// };
+    ///
+    /// use kanshi::{Kanshi, KanshiImpl, KanshiOptions};
+    ///
+    /// let kanshi = Kanshi::new(KanshiOptions::default()).unwrap();
+    /// let count = Arc::new(AtomicUsize::new(0));
+    /// let counter = count.clone();
+    /// kanshi.on_event(move |_| {
+    ///     counter.fetch_add(1, Ordering::SeqCst);
+    /// });
+    /// # kanshi.close().unwrap();
+    /// ```
+    fn on_event<F>(&self, callback: F)
+    where
+        F: Fn(FileSystemEvent) + Send + Sync + 'static;
+
+    /// Start listening for events.
     /// Warning: This method blocks the thread until its finished!
+    ///
+    /// `watch` and `start` can be called from separate tasks, in either order.
+    /// The usual pattern of calling `watch` and then spawning `start` leaves a
+    /// window between the two, and whether the events in it are reported
+    /// depends on the backend:
+    ///
+    /// - fanotify, inotify and kqueue queue events in the kernel as soon as
+    ///   `watch` marks a path, and `start` reads them when it runs, so nothing
+    ///   is missed as long as the kernel queue doesn't overflow. None of them
+    ///   has event IDs to replay from, so events from before `watch` can't be
+    ///   recovered.
+    /// - The poll engine compares against the snapshot `watch` takes, so the
+    ///   changes made in between are reported by its first scan.
+    /// - FSEvents only reports what happens after `start` created its stream.
+    ///   Set `KanshiOptions::start_from` to `FSEventsStartPoint::current()`,
+    ///   taken before `watch`, to report the events in between as well.
+    ///
+    /// Subscribing with `get_events_stream` before `start` also keeps the
+    /// first events from being buffered, or dropped, while no stream is
+    /// subscribed.
+    ///
+    /// If the tracer fails, the error is also sent to every stream returned by
+    /// `get_events_stream` before it is returned.
+    ///
+    /// Clones of a tracer are handles to the same tracer: they share its OS
+    /// resources, streams and cancellation token, so `get_events_stream` on
+    /// any clone receives the events of the running tracer without calling
+    /// `start` again. `start` must only run on one of them at a time; calling
+    /// it on another clone while it runs fails with `ListenerAlreadyStarted`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// use kanshi::{Kanshi, KanshiError, KanshiImpl, KanshiOptions};
+    ///
+    /// #[tokio::main]
+    /// async fn main() -> Result<(), KanshiError> {
+    ///     let dir = tempfile::tempdir()?;
+    ///     let kanshi = Kanshi::new(KanshiOptions::default())?;
+    ///     kanshi.watch(dir.path()).await?;
+    ///
+    ///     // `start` only returns once `close` is called or an error occurs.
+    ///     let closer = kanshi.clone();
+    ///     tokio::spawn(async move {
+    ///         tokio::time::sleep(Duration::from_millis(100)).await;
+    ///         closer.close().unwrap();
+    ///     });
+    ///
+    ///     kanshi.start().await
+    /// }
+    /// ```
     fn start(&self) -> impl futures::Future<Output = Result<(), KanshiError>>;
 
-    fn close(&self) -> bool;
+    /// Stops listening for events and ends every stream returned by
+    /// `get_events_stream`. The tracer is stopped even if the underlying
+    /// watcher could not be cleaned up, in which case the error is returned,
+    /// or `MultipleErrors` if several cleanup steps failed. Closing a tracer
+    /// that is already closed does nothing.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kanshi::{Kanshi, KanshiImpl, KanshiOptions};
+    ///
+    /// let kanshi = Kanshi::new(KanshiOptions::default()).unwrap();
+    /// kanshi.close().unwrap();
+    /// ```
+    fn close(&self) -> Result<(), KanshiError>;
+
+    /// Wraps the tracer in a [`WatchGuard`], which calls `close` when it is
+    /// dropped. An error from `close` is logged with `tracing::warn!`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kanshi::{Kanshi, KanshiImpl, KanshiOptions};
+    ///
+    /// let kanshi = Kanshi::new(KanshiOptions::default()).unwrap().guarded();
+    /// assert!(!kanshi.is_paused());
+    /// ```
+    fn guarded(self) -> WatchGuard<Self>
+    where
+        Self: Sized,
+    {
+        WatchGuard::new(self, |tracer| tracer.close())
+    }
+
+    /// Stops delivering events until `resume` is called, without removing any
+    /// watches or ending any stream. Events received in the meantime are
+    /// discarded, or delivered after `resume` with
+    /// `KanshiOptions::buffer_while_paused`. Events held back by options like
+    /// `debounce_ms` before the pause are still delivered. Does nothing if the
+    /// tracer is already paused.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kanshi::{Kanshi, KanshiImpl, KanshiOptions};
+    ///
+    /// let kanshi = Kanshi::new(KanshiOptions::default()).unwrap();
+    /// kanshi.pause();
+    /// assert!(kanshi.is_paused());
+    /// kanshi.resume();
+    /// assert!(!kanshi.is_paused());
+    /// # kanshi.close().unwrap();
+    /// ```
+    fn pause(&self);
+
+    /// Delivers events again after `pause`. Does nothing if the tracer is not
+    /// paused.
+    fn resume(&self);
+
+    /// Whether the tracer was paused with `pause`.
+    fn is_paused(&self) -> bool;
+}
+
+/// The tracer, its event stream and the task running it, as returned by
+/// [`spawn_watcher`].
+#[cfg(feature = "runtime-tokio")]
+pub type SpawnedWatcher = (
+    Kanshi,
+    Pin<Box<dyn futures::Stream<Item = KanshiStreamItem> + Send>>,
+    tokio::task::JoinHandle<Result<(), KanshiError>>,
+);
+
+/// Creates a tracer with `opts`, watches `dir`, subscribes to its events and
+/// starts it on a new task. The stream is subscribed before the tracer starts,
+/// so no events are missed. Call `close` on the returned tracer to stop it;
+/// the task then finishes with the result of `start`.
+///
+/// Must be called from a multi-threaded tokio runtime.
+///
+/// # Examples
+///
+/// ```
+/// use futures::StreamExt;
+/// use kanshi::{spawn_watcher, FileSystemEventType, KanshiError, KanshiImpl, KanshiOptions};
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), KanshiError> {
+///     let dir = tempfile::tempdir()?;
+///     let (kanshi, mut stream, handle) =
+///         spawn_watcher(dir.path(), KanshiOptions::default()).await?;
+///
+///     std::fs::write(dir.path().join("hello.txt"), "hello")?;
+///
+///     let event = stream.next().await.unwrap().into_result()?;
+///     assert_eq!(event.event_type, FileSystemEventType::Create);
+///
+///     kanshi.close().unwrap();
+///     handle.await.unwrap()
+/// }
+/// ```
+#[cfg(feature = "runtime-tokio")]
+pub async fn spawn_watcher(
+    dir: impl AsRef<Path> + Send,
+    opts: KanshiOptions,
+) -> Result<SpawnedWatcher, KanshiError> {
+    let kanshi = Kanshi::new(opts)?;
+    kanshi.watch(dir).await?;
+
+    let stream = kanshi.get_events_stream();
+    let tracer = kanshi.clone();
+    let handle = tokio::task::spawn(async move { tracer.start().await });
+
+    Ok((kanshi, stream, handle))
 }
 
 #[cfg(test)]
@@ -116,11 +1218,11 @@ mod tests {
 
     use futures::StreamExt;
 
-    use crate::{Kanshi, KanshiImpl, KanshiOptions};
+    use crate::{Kanshi, KanshiImpl, KanshiOptions, KanshiStreamItem};
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
     async fn main() {
-        let kanshi = Kanshi::new(KanshiOptions { force_engine: None });
+        let kanshi = Kanshi::new(KanshiOptions::default());
         if let Err(e) = kanshi {
             panic!("{e}");
         }
@@ -133,7 +1235,11 @@ mod tests {
         let kan = kanshi.clone();
         tokio::task::spawn(async move {
             let mut stream = kan.get_events_stream();
-            while let Some(event) = stream.next().await {
+            while let Some(item) = stream.next().await {
+                let event = match item {
+                    KanshiStreamItem::Error(e) => panic!("{e}"),
+                    item => item.into_event().unwrap(),
+                };
                 let event_type = event.event_type;
                 if let Some(target) = event.target {
                     println!("{:?} - {:?}", event_type, target.path)
@@ -146,7 +1252,7 @@ mod tests {
         let kan = kanshi.clone();
         tokio::task::spawn(async move {
             tokio::time::sleep(std::time::Duration::from_secs(30)).await;
-            kan.close();
+            kan.close().unwrap();
         });
 
         tokio::task::spawn(async move { kanshi.start().await });
@@ -164,12 +1270,123 @@ mod tests {
 #[cfg(target_os = "linux")]
 mod tests {
 
-    use crate::{Kanshi, KanshiImpl, KanshiOptions};
+    use crate::{Kanshi, KanshiError, KanshiImpl, KanshiOptions, KanshiStreamItem};
     use futures::StreamExt;
 
+    #[test]
+    fn io_error_captures_backtrace() {
+        crate::FORCE_BACKTRACE.set(true);
+        let err = KanshiError::from(std::io::Error::other("failed"));
+        crate::FORCE_BACKTRACE.set(false);
+
+        let KanshiError::Io(_, backtrace) = &err else {
+            unreachable!();
+        };
+        assert_eq!(backtrace.status(), std::backtrace::BacktraceStatus::Captured);
+        assert!(format!("{err:?}").contains("line:"));
+    }
+
+    #[tokio::test]
+    async fn watch_many_keeps_paths_watched_before_the_call() {
+        use crate::platforms::poll::PollTracer;
+
+        let dir = tempfile::tempdir().unwrap();
+        let first = dir.path().join("first");
+        let second = dir.path().join("second");
+        std::fs::create_dir(&first).unwrap();
+        std::fs::create_dir(&second).unwrap();
+
+        // The poll engine uses the default `watch_many`.
+        let tracer = PollTracer::new(KanshiOptions::default()).unwrap();
+        tracer.watch(&first).await.unwrap();
+
+        let missing = dir.path().join("missing");
+        assert!(tracer
+            .watch_many(&[&first, &second, &missing])
+            .await
+            .is_err());
+        assert_eq!(tracer.get_watched_paths(), [first.canonicalize().unwrap()]);
+        tracer.close().unwrap();
+    }
+
+    #[test]
+    fn events_display_readably() {
+        use std::{collections::BTreeSet, ffi::OsString, os::unix::ffi::OsStringExt};
+
+        use crate::{FileSystemEvent, FileSystemEventType, FileSystemTarget, FileSystemTargetKind};
+
+        let mut event = FileSystemEvent {
+            event_type: FileSystemEventType::Create,
+            target: Some(FileSystemTarget {
+                kind: FileSystemTargetKind::File,
+                path: "/home/user/notes.txt".into(),
+                device_inode: None,
+                metadata: None,
+            }),
+            pid: None,
+            timestamp: None,
+            sequence: 0,
+        };
+        assert_eq!(event.to_string(), "[CREATE file] /home/user/notes.txt");
+
+        event.event_type = FileSystemEventType::MovedTo {
+            from: "/home/user/notes.txt".into(),
+            to: OsString::from_vec(b"/a\xff".to_vec()),
+        };
+        assert_eq!(
+            event.to_string(),
+            "[MOVED_TO(/home/user/notes.txt -> /a\u{fffd}) file] /home/user/notes.txt"
+        );
+
+        event.event_type = FileSystemEventType::Compound(BTreeSet::from([
+            FileSystemEventType::Create,
+            FileSystemEventType::Modify,
+        ]));
+        event.target = None;
+        assert_eq!(event.to_string(), "[COMPOUND(CREATE, MODIFY)]");
+        assert_eq!(FileSystemEventType::Compound(BTreeSet::new()).name(), "compound");
+    }
+
+    #[test]
+    fn multiple_errors_list_every_error() {
+        assert!(KanshiError::from_errors(Vec::new()).is_ok());
+        assert!(matches!(
+            KanshiError::from_errors(vec![KanshiError::StreamClosed]),
+            Err(KanshiError::StreamClosed)
+        ));
+
+        let errors = vec![KanshiError::StreamClosed, KanshiError::MarkQuotaExceeded];
+        let err = KanshiError::from_errors(errors).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "2 errors occurred: the file system listener was closed; no more watches can be added"
+        );
+    }
+
+    #[test]
+    fn errors_keep_their_cause() {
+        use std::error::Error;
+
+        use nix::errno::Errno;
+
+        let err = KanshiError::from_errno_at(Errno::ENOENT, "/a");
+        assert!(matches!(err, KanshiError::PathNotFound(ref x) if x.as_os_str() == "/a"));
+        assert!(matches!(
+            KanshiError::from_errno_at(Errno::ENOSPC, "/a"),
+            KanshiError::MarkQuotaExceeded
+        ));
+
+        let io = std::io::Error::from_raw_os_error(libc::ESTALE);
+        assert!(matches!(KanshiError::from_io_at(io, "/a"), KanshiError::Stale(_)));
+
+        let err = KanshiError::from(Errno::EMFILE);
+        assert!(matches!(err, KanshiError::Nix(Errno::EMFILE, _)));
+        assert!(err.source().is_some());
+    }
+
     #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
     async fn main() {
-        let kanshi = Kanshi::new(KanshiOptions { force_engine: None });
+        let kanshi = Kanshi::new(KanshiOptions::default());
         if let Err(e) = kanshi {
             panic!("{e}");
         }
@@ -182,7 +1399,11 @@ mod tests {
         let kan = kanshi.clone();
         tokio::task::spawn(async move {
             let mut stream = kan.get_events_stream();
-            while let Some(event) = stream.next().await {
+            while let Some(item) = stream.next().await {
+                let event = match item {
+                    KanshiStreamItem::Error(e) => panic!("{e}"),
+                    item => item.into_event().unwrap(),
+                };
                 let event_type = event.event_type;
                 if let Some(target) = event.target {
                     println!("{:?} - {:?}", event_type, target.path)
@@ -195,7 +1416,7 @@ mod tests {
         let kan = kanshi.clone();
         tokio::task::spawn(async move {
             tokio::time::sleep(std::time::Duration::from_secs(30)).await;
-            kan.close();
+            kan.close().unwrap();
         });
 
         if let Err(e) = kanshi.start().await {
@@ -0,0 +1,163 @@
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use futures::StreamExt;
+use tokio::sync::mpsc::Sender;
+use tokio_util::sync::{CancellationToken, DropGuard};
+
+use crate::{
+    runtime, EventTypeMask, FileSystemEvent, Kanshi, KanshiError, KanshiImpl, KanshiStreamItem,
+};
+
+/// Routes events from a single Kanshi stream to several channels by event type.
+///
+/// Every event is sent to each route whose mask matches it. Sending waits for
+/// room in each matching channel, so a slow consumer holds back the others.
+/// Routes whose receiver was dropped are skipped, and the mux stops once the
+/// tracer is closed or fails, every receiver is gone, or the mux itself is
+/// dropped. The error a failed tracer reported is kept for [`MultiStreamMux::error`].
+pub struct MultiStreamMux {
+    unmatched: Arc<AtomicU64>,
+    error: Arc<Mutex<Option<KanshiError>>>,
+    _guard: DropGuard,
+}
+
+impl MultiStreamMux {
+    /// Subscribes to `tracer` and starts routing events to `routes`.
+    pub fn new(
+        tracer: &Kanshi,
+        routes: Vec<(EventTypeMask, Sender<FileSystemEvent>)>,
+    ) -> MultiStreamMux {
+        MultiStreamMux::from_stream(tracer.get_events_stream(), routes)
+    }
+
+    fn from_stream(
+        mut stream: Pin<Box<dyn futures::Stream<Item = KanshiStreamItem> + Send>>,
+        mut routes: Vec<(EventTypeMask, Sender<FileSystemEvent>)>,
+    ) -> MultiStreamMux {
+        let unmatched = Arc::new(AtomicU64::new(0));
+        let error = Arc::new(Mutex::new(None));
+        let cancellation_token = CancellationToken::new();
+
+        let cancel_token = cancellation_token.clone();
+        let unmatched_count = unmatched.clone();
+        let stream_error = error.clone();
+
+        runtime::spawn(async move {
+            loop {
+                let event = tokio::select! {
+                    _ = cancel_token.cancelled() => break,
+                    event = stream.next() => event,
+                };
+                let event = match event {
+                    Some(KanshiStreamItem::Event(event)) => event,
+                    Some(KanshiStreamItem::Error(e)) => {
+                        *stream_error.lock().unwrap() = Some(e);
+                        break;
+                    }
+                    // Routed as its event, which allows the access.
+                    Some(KanshiStreamItem::PermissionRequest(request)) => request.event.clone(),
+                    None => break,
+                };
+
+                let mut matched = false;
+                for (mask, sender) in routes.iter() {
+                    if mask.matches(&event.event_type) && !sender.is_closed() {
+                        matched = true;
+                        let _ = sender.send(event.clone()).await;
+                    }
+                }
+
+                if !matched {
+                    unmatched_count.fetch_add(1, Ordering::Relaxed);
+                }
+
+                routes.retain(|(_, sender)| !sender.is_closed());
+                if routes.is_empty() {
+                    break;
+                }
+            }
+        });
+
+        MultiStreamMux {
+            unmatched,
+            error,
+            _guard: cancellation_token.drop_guard(),
+        }
+    }
+
+    /// Number of events so far that did not match any route.
+    pub fn unmatched_count(&self) -> u64 {
+        self.unmatched.load(Ordering::Relaxed)
+    }
+
+    /// The error the tracer failed with, once the mux has stopped because of
+    /// it.
+    pub fn error(&self) -> Option<KanshiError> {
+        self.error.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use crate::{EventTypeMask, FileSystemEvent, FileSystemEventType, KanshiStreamItem};
+
+    use super::MultiStreamMux;
+
+    fn event(event_type: FileSystemEventType) -> FileSystemEvent {
+        FileSystemEvent {
+            event_type,
+            target: None,
+            pid: None,
+            timestamp: None,
+            sequence: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn routes_by_event_type() {
+        let events = vec![
+            event(FileSystemEventType::Create),
+            event(FileSystemEventType::MovedTo {
+                from: "a".into(),
+                to: "b".into(),
+            }),
+            event(FileSystemEventType::Delete),
+        ];
+        let (creates_tx, mut creates) = mpsc::channel(8);
+        let (changes_tx, mut changes) = mpsc::channel(8);
+
+        let mux = MultiStreamMux::from_stream(
+            Box::pin(futures::stream::iter(events.into_iter().map(KanshiStreamItem::Event))),
+            vec![
+                (EventTypeMask::CREATE, creates_tx),
+                (EventTypeMask::CREATE | EventTypeMask::MOVE, changes_tx),
+            ],
+        );
+
+        assert_eq!(
+            creates.recv().await.unwrap().event_type,
+            FileSystemEventType::Create
+        );
+        assert!(creates.recv().await.is_none());
+
+        assert_eq!(
+            changes.recv().await.unwrap().event_type,
+            FileSystemEventType::Create
+        );
+        assert!(matches!(
+            changes.recv().await.unwrap().event_type,
+            FileSystemEventType::MovedTo { .. }
+        ));
+        assert!(changes.recv().await.is_none());
+
+        assert_eq!(mux.unmatched_count(), 1);
+    }
+}
@@ -0,0 +1,145 @@
+//! Serializes `OsString`s for use with `#[serde(with)]`.
+//!
+//! Serde can only represent `OsString` in a platform-specific form, so paths
+//! that are valid UTF-8 are written as plain strings, and any other path as
+//! `{"base64": "..."}` holding its raw bytes. Both forms are accepted when
+//! deserializing, so every path survives a round trip on Unix. Elsewhere,
+//! invalid sequences are replaced with `U+FFFD`.
+
+use std::ffi::{OsStr, OsString};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum EncodedOsString {
+    Utf8(String),
+    Base64 { base64: String },
+}
+
+pub(crate) fn serialize<S: Serializer>(value: &OsStr, serializer: S) -> Result<S::Ok, S::Error> {
+    let encoded = match value.to_str() {
+        Some(x) => EncodedOsString::Utf8(x.to_owned()),
+        None => EncodedOsString::Base64 {
+            base64: STANDARD.encode(value.as_encoded_bytes()),
+        },
+    };
+    encoded.serialize(serializer)
+}
+
+pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<OsString, D::Error> {
+    match EncodedOsString::deserialize(deserializer)? {
+        EncodedOsString::Utf8(x) => Ok(OsString::from(x)),
+        EncodedOsString::Base64 { base64 } => {
+            let bytes = STANDARD.decode(base64).map_err(D::Error::custom)?;
+            Ok(from_bytes(bytes))
+        }
+    }
+}
+
+#[cfg(unix)]
+fn from_bytes(bytes: Vec<u8>) -> OsString {
+    use std::os::unix::ffi::OsStringExt;
+
+    OsString::from_vec(bytes)
+}
+
+#[cfg(not(unix))]
+fn from_bytes(bytes: Vec<u8>) -> OsString {
+    OsString::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use crate::{
+        FileSystemEvent, FileSystemEventType, FileSystemTarget, FileSystemTargetKind, KanshiError,
+    };
+
+    fn round_trip(event: FileSystemEvent) {
+        let json = serde_json::to_string(&event).unwrap();
+        let decoded: FileSystemEvent = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, event);
+    }
+
+    #[test]
+    fn events_round_trip() {
+        let target = FileSystemTarget {
+            kind: FileSystemTargetKind::File,
+            path: "/tmp/a".into(),
+            device_inode: Some((1, 2)),
+            metadata: None,
+        };
+
+        for event_type in [
+            FileSystemEventType::Create,
+            FileSystemEventType::MovedTo {
+                from: "/tmp/a".into(),
+                to: "/tmp/b".into(),
+            },
+            FileSystemEventType::Compound(BTreeSet::from([
+                FileSystemEventType::Create,
+                FileSystemEventType::Modify,
+            ])),
+            FileSystemEventType::RateLimitExceeded { dropped: 3 },
+        ] {
+            round_trip(FileSystemEvent {
+                event_type,
+                target: Some(target.clone()),
+                pid: None,
+                timestamp: None,
+                sequence: 0,
+            });
+        }
+        round_trip(FileSystemEvent {
+            event_type: FileSystemEventType::Unknown,
+            target: None,
+            pid: None,
+            timestamp: None,
+            sequence: 0,
+        });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_paths_round_trip() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let target = FileSystemTarget {
+            kind: FileSystemTargetKind::File,
+            path: OsStr::from_bytes(b"/tmp/\xff").into(),
+            device_inode: None,
+            metadata: None,
+        };
+        let json = serde_json::to_string(&target).unwrap();
+        assert!(json.contains(r#""path":{"base64":"L3RtcC//"}"#), "{json}");
+        let decoded: FileSystemTarget = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, target);
+    }
+
+    #[test]
+    fn utf8_paths_are_strings() {
+        let event_type = FileSystemEventType::MovedTo {
+            from: "/tmp/a".into(),
+            to: "/tmp/b".into(),
+        };
+        let json = serde_json::to_string(&event_type).unwrap();
+        assert_eq!(json, r#"{"MovedTo":{"from":"/tmp/a","to":"/tmp/b"}}"#);
+    }
+
+    #[test]
+    fn errors_serialize() {
+        // The backtrace is skipped.
+        let error = KanshiError::from(std::io::Error::other("failed"));
+        let json = serde_json::to_string(&error).unwrap();
+        assert!(json.starts_with(r#"{"Io":"#));
+        assert!(json.contains("failed"));
+
+        let json = serde_json::to_string(&KanshiError::StreamClosed).unwrap();
+        assert_eq!(json, r#""StreamClosed""#);
+    }
+}
@@ -0,0 +1,119 @@
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use crate::{FileSystemEvent, KanshiError};
+
+type Respond = Box<dyn FnOnce(bool) -> Result<(), KanshiError> + Send>;
+
+/// An access to a file that is held up until it is allowed or denied, reported
+/// by the fanotify engine with `KanshiOptions::permission_mode`.
+///
+/// The process that made the access is blocked until `respond` is called, so
+/// requests should be answered promptly. Clones share the same request and
+/// only the first response counts. Once every clone is dropped without a
+/// response, the access is allowed, so a request that nobody handles, e.g.
+/// because there was no subscriber or the tracer is paused, never blocks a
+/// process for good.
+#[derive(Clone)]
+pub struct PermissionEvent {
+    /// The access, as an `Open` or `Access` event.
+    pub event: FileSystemEvent,
+    response: Arc<Response>,
+}
+
+struct Response(Mutex<Option<Respond>>);
+
+impl PermissionEvent {
+    pub(crate) fn new(
+        event: FileSystemEvent,
+        respond: impl FnOnce(bool) -> Result<(), KanshiError> + Send + 'static,
+    ) -> PermissionEvent {
+        PermissionEvent {
+            event,
+            response: Arc::new(Response(Mutex::new(Some(Box::new(respond))))),
+        }
+    }
+
+    /// Allows or denies the access. Responses after the first one, from this
+    /// request or any of its clones, are ignored.
+    pub fn respond(&self, allow: bool) -> Result<(), KanshiError> {
+        let respond = self.response.0.lock().unwrap().take();
+        match respond {
+            Some(respond) => respond(allow),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether `respond` was already called on this request or a clone.
+    pub fn is_answered(&self) -> bool {
+        self.response.0.lock().unwrap().is_none()
+    }
+}
+
+impl fmt::Debug for PermissionEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PermissionEvent")
+            .field("event", &self.event)
+            .field("answered", &self.is_answered())
+            .finish()
+    }
+}
+
+impl Drop for Response {
+    fn drop(&mut self) {
+        if let Some(respond) = self.0.get_mut().unwrap().take() {
+            let _ = respond(true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use crate::{FileSystemEvent, FileSystemEventType};
+
+    use super::PermissionEvent;
+
+    fn request(responses: &Arc<Mutex<Vec<bool>>>) -> PermissionEvent {
+        let event = FileSystemEvent {
+            event_type: FileSystemEventType::Open,
+            target: None,
+            pid: None,
+            timestamp: None,
+            sequence: 0,
+        };
+        let responses = responses.clone();
+        PermissionEvent::new(event, move |allow| {
+            responses.lock().unwrap().push(allow);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn only_the_first_response_counts() {
+        let responses = Arc::new(Mutex::new(Vec::new()));
+        let request = request(&responses);
+        let clone = request.clone();
+
+        request.respond(false).unwrap();
+        clone.respond(true).unwrap();
+        drop((request, clone));
+
+        assert_eq!(*responses.lock().unwrap(), [false]);
+    }
+
+    #[test]
+    fn dropping_unanswered_requests_allows_them() {
+        let responses = Arc::new(Mutex::new(Vec::new()));
+        let request = request(&responses);
+        let clone = request.clone();
+
+        drop(request);
+        assert!(responses.lock().unwrap().is_empty());
+        drop(clone);
+        assert_eq!(*responses.lock().unwrap(), [true]);
+    }
+}
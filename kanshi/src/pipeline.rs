@@ -0,0 +1,365 @@
+use std::{
+    collections::VecDeque,
+    ffi::OsString,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use async_stream::stream;
+use futures::{Stream, StreamExt};
+
+use crate::{runtime, EventTypeMask, FileSystemEvent, KanshiStreamItem};
+
+/// Fluent builder for common transformations of a Kanshi event stream.
+///
+/// Steps are applied in the order they are added. Errors are passed through
+/// every step unchanged, and as soon as they arrive.
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use futures::StreamExt;
+/// use kanshi::{
+///     EventTypeMask, FileSystemEventType, Kanshi, KanshiError, KanshiImpl, KanshiOptions,
+///     PipelineBuilder,
+/// };
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), KanshiError> {
+///     let dir = tempfile::tempdir()?;
+///     let kanshi = Kanshi::new(KanshiOptions::default())?;
+///     kanshi.watch(dir.path()).await?;
+///
+///     let stream = PipelineBuilder::new(kanshi.get_events_stream())
+///         .filter_event_type(EventTypeMask::CREATE)
+///         .strip_prefix(dir.path().canonicalize()?)
+///         .debounce(Duration::from_millis(50))
+///         .build();
+///     let mut stream = Box::pin(stream);
+///
+///     let tracer = kanshi.clone();
+///     tokio::spawn(async move { tracer.start().await });
+///
+///     std::fs::write(dir.path().join("hello.txt"), "hello")?;
+///
+///     let event = stream.next().await.unwrap().into_result()?;
+///     assert_eq!(event.event_type, FileSystemEventType::Create);
+///     assert_eq!(event.target.unwrap().path, "hello.txt");
+///
+///     kanshi.close().unwrap();
+///     Ok(())
+/// }
+/// ```
+pub struct PipelineBuilder<S: Stream<Item = KanshiStreamItem>> {
+    stream: S,
+}
+
+impl<S: Stream<Item = KanshiStreamItem>> PipelineBuilder<S> {
+    /// Starts a pipeline over `stream`, usually one returned by
+    /// `KanshiImpl::get_events_stream`.
+    pub fn new(stream: S) -> PipelineBuilder<S> {
+        PipelineBuilder { stream }
+    }
+
+    /// Only keeps events whose type is part of `mask`.
+    pub fn filter_event_type(
+        self,
+        mask: EventTypeMask,
+    ) -> PipelineBuilder<impl Stream<Item = KanshiStreamItem>> {
+        PipelineBuilder::new(
+            self.stream.filter(move |item| {
+                futures::future::ready(match item {
+                    KanshiStreamItem::Event(event) => mask.matches(&event.event_type),
+                    KanshiStreamItem::Error(_) => true,
+                    KanshiStreamItem::PermissionRequest(request) => {
+                        mask.matches(&request.event.event_type)
+                    }
+                })
+            }),
+        )
+    }
+
+    /// Holds each event until its path has seen no further events for
+    /// `duration`, then emits only the latest one. Events without a target are
+    /// passed through immediately.
+    pub fn debounce(
+        self,
+        duration: Duration,
+    ) -> PipelineBuilder<impl Stream<Item = KanshiStreamItem>> {
+        let stream = self.stream;
+
+        PipelineBuilder::new(stream! {
+            futures::pin_mut!(stream);
+
+            // Every event waits the same duration, so this stays sorted by deadline.
+            let mut pending = VecDeque::<(Instant, FileSystemEvent)>::new();
+
+            loop {
+                let deadline = pending.front().map(|(deadline, _)| *deadline);
+                // `None` when the oldest pending event is due.
+                let next = tokio::select! {
+                    next = stream.next() => Some(next),
+                    _ = runtime::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => None,
+                };
+
+                let Some(next) = next else {
+                    if let Some((_, event)) = pending.pop_front() {
+                        yield KanshiStreamItem::Event(event);
+                    }
+                    continue;
+                };
+                let event = match next {
+                    Some(KanshiStreamItem::Event(event)) => event,
+                    Some(error) => {
+                        yield error;
+                        continue;
+                    }
+                    None => break,
+                };
+
+                match target_path(&event).cloned() {
+                    Some(path) => {
+                        pending.retain(|(_, x)| target_path(x) != Some(&path));
+                        pending.push_back((Instant::now() + duration, event));
+                    }
+                    None => yield KanshiStreamItem::Event(event),
+                }
+            }
+
+            for (_, event) in pending {
+                yield KanshiStreamItem::Event(event);
+            }
+        })
+    }
+
+    /// Collects events into windows of `duration`, starting with the first
+    /// event after the previous window, and emits each window with repeated
+    /// events of the same type on the same path removed.
+    pub fn coalesce(
+        self,
+        duration: Duration,
+    ) -> PipelineBuilder<impl Stream<Item = KanshiStreamItem>> {
+        let stream = self.stream;
+
+        PipelineBuilder::new(stream! {
+            futures::pin_mut!(stream);
+
+            let mut window = Vec::<FileSystemEvent>::new();
+            let mut window_end = None;
+
+            loop {
+                // `None` when the current window has ended.
+                let next = tokio::select! {
+                    next = stream.next() => Some(next),
+                    _ = runtime::sleep_until(window_end.unwrap_or_else(Instant::now)), if window_end.is_some() => None,
+                };
+
+                let Some(next) = next else {
+                    window_end = None;
+                    for event in window.drain(..) {
+                        yield KanshiStreamItem::Event(event);
+                    }
+                    continue;
+                };
+                let event = match next {
+                    Some(KanshiStreamItem::Event(event)) => event,
+                    Some(error) => {
+                        yield error;
+                        continue;
+                    }
+                    None => break,
+                };
+
+                window_end.get_or_insert_with(|| Instant::now() + duration);
+                let seen = window.iter().any(|x| {
+                    x.event_type == event.event_type && target_path(x) == target_path(&event)
+                });
+                if !seen {
+                    window.push(event);
+                }
+            }
+
+            for event in window {
+                yield KanshiStreamItem::Event(event);
+            }
+        })
+    }
+
+    /// Emits at most `n` events per second, delaying the rest. Events are never
+    /// dropped, so a sustained burst is spread out over time. An `n` of zero
+    /// is treated as one.
+    pub fn rate_limit(self, n: u32) -> PipelineBuilder<impl Stream<Item = KanshiStreamItem>> {
+        let stream = self.stream;
+
+        PipelineBuilder::new(stream! {
+            let mut ticker = runtime::Interval::new(Duration::from_secs(1) / n.max(1));
+
+            for await item in stream {
+                if !item.is_error() {
+                    ticker.tick().await;
+                }
+                yield item;
+            }
+        })
+    }
+
+    /// Makes target paths relative to `prefix`. Paths outside of `prefix` are
+    /// left unchanged.
+    pub fn strip_prefix(
+        self,
+        prefix: impl Into<PathBuf>,
+    ) -> PipelineBuilder<impl Stream<Item = KanshiStreamItem>> {
+        let prefix = prefix.into();
+
+        self.map_path(move |path| match path.strip_prefix(&prefix) {
+            Ok(relative) => relative.to_path_buf(),
+            Err(_) => path.to_path_buf(),
+        })
+    }
+
+    /// Replaces every target path with the result of `f`.
+    pub fn map_path<F>(self, mut f: F) -> PipelineBuilder<impl Stream<Item = KanshiStreamItem>>
+    where
+        F: FnMut(&Path) -> PathBuf,
+    {
+        PipelineBuilder::new(self.stream.map(move |mut item| {
+            if let KanshiStreamItem::Event(event) = &mut item {
+                if let Some(target) = event.target.as_mut() {
+                    target.path = f(Path::new(&target.path)).into_os_string();
+                }
+            }
+            item
+        }))
+    }
+
+    /// Returns the transformed stream.
+    pub fn build(self) -> S {
+        self.stream
+    }
+}
+
+fn target_path(event: &FileSystemEvent) -> Option<&OsString> {
+    event.target.as_ref().map(|x| &x.path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::StreamExt;
+
+    use futures::Stream;
+
+    use crate::{
+        EventTypeMask, FileSystemEvent, FileSystemEventType, FileSystemTarget, FileSystemTargetKind,
+        KanshiError, KanshiStreamItem,
+    };
+
+    use super::PipelineBuilder;
+
+    fn event(event_type: FileSystemEventType, path: &str) -> FileSystemEvent {
+        FileSystemEvent {
+            event_type,
+            target: Some(FileSystemTarget {
+                kind: FileSystemTargetKind::File,
+                path: path.into(),
+                device_inode: None,
+                metadata: None,
+            }),
+            pid: None,
+            timestamp: None,
+            sequence: 0,
+        }
+    }
+
+    fn items(events: Vec<FileSystemEvent>) -> impl Stream<Item = KanshiStreamItem> {
+        futures::stream::iter(events.into_iter().map(KanshiStreamItem::Event))
+    }
+
+    #[tokio::test]
+    async fn filters_and_maps_paths() {
+        let events = vec![
+            event(FileSystemEventType::Create, "/w/a"),
+            event(FileSystemEventType::Modify, "/w/a"),
+            event(FileSystemEventType::Create, "/elsewhere/b"),
+        ];
+
+        let paths: Vec<_> = PipelineBuilder::new(items(events))
+            .filter_event_type(EventTypeMask::CREATE)
+            .strip_prefix("/w")
+            .build()
+            .map(|x| x.into_event().unwrap())
+            .map(|x| x.target.unwrap().path)
+            .collect()
+            .await;
+
+        assert_eq!(paths, ["a", "/elsewhere/b"]);
+    }
+
+    #[tokio::test]
+    async fn debounce_keeps_latest_event_per_path() {
+        let events = vec![
+            event(FileSystemEventType::Create, "/a"),
+            event(FileSystemEventType::Modify, "/b"),
+            event(FileSystemEventType::Modify, "/a"),
+        ];
+
+        let events: Vec<_> = PipelineBuilder::new(items(events))
+            .debounce(Duration::from_millis(10))
+            .build()
+            .map(|x| x.into_event().unwrap())
+            .map(|x| (x.event_type, x.target.unwrap().path))
+            .collect()
+            .await;
+
+        assert_eq!(
+            events,
+            [
+                (FileSystemEventType::Modify, "/b".into()),
+                (FileSystemEventType::Modify, "/a".into())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn coalesce_removes_repeats_within_window() {
+        let events = vec![
+            event(FileSystemEventType::Modify, "/a"),
+            event(FileSystemEventType::Modify, "/a"),
+            event(FileSystemEventType::Delete, "/a"),
+        ];
+
+        let events: Vec<_> = PipelineBuilder::new(items(events))
+            .coalesce(Duration::from_millis(10))
+            .build()
+            .map(|x| x.into_event().unwrap())
+            .map(|x| x.event_type)
+            .collect()
+            .await;
+
+        assert_eq!(
+            events,
+            [FileSystemEventType::Modify, FileSystemEventType::Delete]
+        );
+    }
+
+    #[tokio::test]
+    async fn errors_pass_through_immediately() {
+        let items = vec![
+            KanshiStreamItem::Event(event(FileSystemEventType::Create, "/a")),
+            KanshiStreamItem::Error(KanshiError::StreamClosed),
+        ];
+
+        let items: Vec<_> = PipelineBuilder::new(futures::stream::iter(items))
+            .filter_event_type(EventTypeMask::MODIFY)
+            .debounce(Duration::from_secs(60))
+            .build()
+            .collect()
+            .await;
+
+        assert_eq!(items.len(), 1);
+        assert!(items[0].is_error());
+    }
+}
@@ -0,0 +1,688 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    fs::File,
+    os::{
+        fd::{IntoRawFd, RawFd},
+        unix::fs::MetadataExt,
+    },
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use async_stream::stream;
+use nix::sys::event::{EventFilter, EventFlag, FilterFlag, KEvent, Kqueue};
+use tokio::sync::broadcast::error::RecvError;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    platforms::{
+        canonicalize_all, close_on_sigint, create_watched_dir, device_inode, filter::PathFilter,
+        report_error, RunningGuard,
+    },
+    runtime,
+    sender::{EventCallbacks, EventSender},
+    FileSystemEvent, FileSystemEventType, FileSystemTarget, FileSystemTargetKind, KanshiError,
+    KanshiImpl, KanshiStreamItem,
+};
+
+use super::KanshiOptions;
+
+/// Watches paths with `kqueue(2)`.
+///
+/// kqueue reports changes to open descriptors rather than to names, so every
+/// watched directory and every file inside one is opened and registered with
+/// `EVFILT_VNODE`. A write to a directory only says that its entries changed,
+/// so the directory is listed again and compared with its previous listing to
+/// find what was created, deleted or renamed. Renames are only recognised
+/// within a single directory; moving an entry between directories is reported
+/// as a `Delete` followed by a `Create`.
+#[derive(Clone)]
+pub struct KqueueTracer {
+    kqueue: Arc<Kqueue>,
+    sender: tokio::sync::broadcast::Sender<KanshiStreamItem>,
+    cancellation_token: CancellationToken,
+    paused: Arc<AtomicBool>,
+    // Set while `start` runs, shared with clones.
+    running: Arc<AtomicBool>,
+    callbacks: EventCallbacks,
+    sequence: Arc<AtomicU64>,
+    options: Arc<KanshiOptions>,
+    watches: Arc<Mutex<Watches>>,
+    excluded: Arc<Mutex<HashSet<PathBuf>>>,
+    // `KanshiOptions::exclude_patterns` and `exclude_regexes`, compiled once
+    // in `new`.
+    exclude_filter: Arc<PathFilter>,
+    // Canonical paths of the directories passed to `watch`.
+    watched: Arc<Mutex<HashSet<PathBuf>>>,
+    // Canonical paths of the files passed to `watch`.
+    files: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+#[derive(Default)]
+struct Watches {
+    // The descriptor registered for each watched path.
+    fds: HashMap<PathBuf, RawFd>,
+    paths: HashMap<RawFd, PathBuf>,
+    // The last listing of each watched directory.
+    entries: HashMap<PathBuf, HashMap<OsString, Entry>>,
+}
+
+#[derive(Clone)]
+struct Entry {
+    kind: FileSystemTargetKind,
+    inode: u64,
+}
+
+impl KqueueTracer {
+    /// The options this tracer was created with.
+    pub(crate) fn options(&self) -> &KanshiOptions {
+        &self.options
+    }
+
+    /// Opens `path` and registers it with the kqueue, unless it is watched
+    /// already.
+    fn add(&self, watches: &mut Watches, path: &Path) -> Result<(), KanshiError> {
+        if watches.fds.contains_key(path) {
+            return Ok(());
+        }
+
+        let fd = File::open(path)?.into_raw_fd();
+        let event = KEvent::new(
+            fd as usize,
+            EventFilter::EVFILT_VNODE,
+            EventFlag::EV_ADD | EventFlag::EV_CLEAR,
+            FilterFlag::NOTE_WRITE
+                | FilterFlag::NOTE_EXTEND
+                | FilterFlag::NOTE_DELETE
+                | FilterFlag::NOTE_RENAME
+                | FilterFlag::NOTE_ATTRIB,
+            0,
+            0,
+        );
+        if let Err(e) = self.kqueue.kevent(&[event], &mut [], None) {
+            close_fd(fd);
+            return Err(e.into());
+        }
+
+        watches.fds.insert(path.to_path_buf(), fd);
+        watches.paths.insert(fd, path.to_path_buf());
+        Ok(())
+    }
+
+    /// Watches `dir`, which is `depth` levels below a watched directory, along
+    /// with the files in it and the directories below it within
+    /// `KanshiOptions::max_depth`. Files that cannot be opened are reported to
+    /// `on_error` and skipped.
+    fn add_dir(
+        &self,
+        watches: &mut Watches,
+        dir: &Path,
+        depth: usize,
+        excluded: &HashSet<PathBuf>,
+    ) -> Result<(), KanshiError> {
+        self.add(watches, dir)?;
+
+        let entries = read_entries(dir)?;
+        for (name, entry) in entries.iter() {
+            let path = dir.join(name);
+            match entry.kind {
+                FileSystemTargetKind::Directory => {
+                    if self.should_descend(&path, depth + 1, excluded) {
+                        self.add_dir(watches, &path, depth + 1, excluded)?;
+                    }
+                }
+                FileSystemTargetKind::File => {
+                    if !self.exclude_filter.is_match(&path) {
+                        if let Err(e) = self.add(watches, &path) {
+                            (self.options.on_error)(e);
+                        }
+                    }
+                }
+                // Watching a link would watch its target instead.
+                FileSystemTargetKind::Symlink => {}
+                // Only ever set on events, not on listed entries.
+                FileSystemTargetKind::HardLink { .. } => {}
+            }
+        }
+        watches.entries.insert(dir.to_path_buf(), entries);
+        Ok(())
+    }
+
+    /// Whether the directory at `path`, `depth` levels below a watched
+    /// directory, should be watched.
+    fn should_descend(&self, path: &Path, depth: usize, excluded: &HashSet<PathBuf>) -> bool {
+        depth <= self.options.depth_limit().unwrap_or(usize::MAX)
+            && !excluded.contains(path)
+            && !self.exclude_filter.is_match_dir(path)
+    }
+
+    /// Lists `dir` again and returns the events for what changed since the
+    /// last listing, watching new entries along the way.
+    fn rescan(&self, watches: &mut Watches, dir: &Path) -> Vec<FileSystemEvent> {
+        let mut events = Vec::new();
+        let Ok(new) = read_entries(dir) else {
+            // The directory itself is gone, which its own descriptor reports.
+            return events;
+        };
+        let old = watches.entries.remove(dir).unwrap_or_default();
+
+        let changed = |from: &HashMap<OsString, Entry>, to: &HashMap<OsString, Entry>| {
+            from.iter()
+                .filter(|(name, entry)| to.get(*name).map(|x| x.inode) != Some(entry.inode))
+                .map(|(name, entry)| (name.clone(), entry.clone()))
+                .collect::<Vec<_>>()
+        };
+        let removed = changed(&old, &new);
+        let mut added = changed(&new, &old);
+
+        // Pair up renames by inode first, so that an entry renamed over
+        // another one is not closed along with the entry it replaced.
+        let mut renames = Vec::new();
+        for (name, entry) in removed {
+            let from = dir.join(&name);
+            match added.iter().position(|(_, x)| x.inode == entry.inode) {
+                Some(idx) => renames.push((from, dir.join(added.swap_remove(idx).0), entry.kind)),
+                None => {
+                    remove_below(watches, &from, |_| false);
+                    events.push(event(FileSystemEventType::Delete, from, entry.kind, None));
+                }
+            }
+        }
+
+        for (from, to, kind) in renames {
+            rename_below(watches, &from, &to);
+
+            let identity = device_inode(&to);
+            let moved_to = FileSystemEventType::MovedTo {
+                from: from.clone().into(),
+                to: to.clone().into(),
+            };
+            let moved_from = FileSystemEventType::MovedFrom {
+                from: from.clone().into(),
+                to: to.clone().into(),
+            };
+            events.push(event(moved_to, from, kind.clone(), identity));
+            events.push(event(moved_from, to, kind, identity));
+        }
+
+        let depth = depth_below(&self.watched.lock().unwrap(), dir).unwrap_or(0);
+        let excluded = self.excluded.lock().unwrap();
+        for (name, entry) in added {
+            let path = dir.join(&name);
+            let watch = match entry.kind {
+                FileSystemTargetKind::Directory
+                    if self.should_descend(&path, depth + 1, &excluded) =>
+                {
+                    self.add_dir(watches, &path, depth + 1, &excluded)
+                }
+                FileSystemTargetKind::File if !self.exclude_filter.is_match(&path) =>
+                {
+                    self.add(watches, &path)
+                }
+                _ => Ok(()),
+            };
+            // The entry may already be gone again.
+            if let Err(e) = watch {
+                (self.options.on_error)(e);
+            }
+
+            let identity = device_inode(&path);
+            events.push(event(
+                FileSystemEventType::Create,
+                path,
+                entry.kind,
+                identity,
+            ));
+        }
+
+        watches.entries.insert(dir.to_path_buf(), new);
+        events
+    }
+
+    /// Turns a kqueue notification for `fd` into events.
+    fn handle(&self, fd: RawFd, flags: FilterFlag) -> Vec<FileSystemEvent> {
+        let mut watches = self.watches.lock().unwrap();
+        let Some(path) = watches.paths.get(&fd).cloned() else {
+            // Closed while the notification was queued.
+            return Vec::new();
+        };
+
+        let is_dir = watches.entries.contains_key(&path);
+        let kind = if is_dir {
+            FileSystemTargetKind::Directory
+        } else {
+            FileSystemTargetKind::File
+        };
+
+        let mut events = Vec::new();
+        if is_dir && flags.contains(FilterFlag::NOTE_WRITE) {
+            events.extend(self.rescan(&mut watches, &path));
+        } else if !is_dir && flags.intersects(FilterFlag::NOTE_WRITE | FilterFlag::NOTE_EXTEND) {
+            let identity = device_inode(&path);
+            events.push(event(
+                FileSystemEventType::Modify,
+                path.clone(),
+                kind.clone(),
+                identity,
+            ));
+        }
+        if flags.contains(FilterFlag::NOTE_ATTRIB) {
+            let identity = device_inode(&path);
+            events.push(event(
+                FileSystemEventType::AttributeChange,
+                path.clone(),
+                kind.clone(),
+                identity,
+            ));
+        }
+
+        if flags.intersects(FilterFlag::NOTE_DELETE | FilterFlag::NOTE_RENAME) {
+            // Entries inside a watched directory are reported when the
+            // directory is listed again. Only paths passed to `watch` have no
+            // parent to report them.
+            let is_root = self.watched.lock().unwrap().contains(&path)
+                || self.files.lock().unwrap().contains(&path);
+            if is_root {
+                let event_type = if flags.contains(FilterFlag::NOTE_DELETE) {
+                    FileSystemEventType::Delete
+                } else {
+                    FileSystemEventType::Move
+                };
+                events.push(event(event_type, path.clone(), kind, None));
+            }
+            if is_root || flags.contains(FilterFlag::NOTE_DELETE) {
+                remove_below(&mut watches, &path, |_| false);
+            }
+        }
+
+        events
+    }
+
+    /// Whether `event` should be dropped because of
+    /// `KanshiOptions::exclude_patterns` or `exclude_regexes`. Moves are only
+    /// dropped when both names are excluded.
+    fn is_filtered(&self, event: &FileSystemEvent) -> bool {
+        let Some(target) = event.target.as_ref() else {
+            return false;
+        };
+        let other = match &event.event_type {
+            FileSystemEventType::MovedTo { to, .. } => Some(to),
+            FileSystemEventType::MovedFrom { from, .. } => Some(from),
+            _ => None,
+        };
+        self.exclude_filter.is_match(&target.path)
+            && other.is_none_or(|x| self.exclude_filter.is_match(x))
+    }
+
+    /// The body of `start`, whose error `start` forwards to the streams.
+    async fn run(&self) -> Result<(), KanshiError> {
+        let _running = RunningGuard::acquire(&self.running)?;
+        let cancel_token = self.cancellation_token.clone();
+        let mut sender = EventSender::new(
+            self.sender.clone(),
+            self.options.reconnect_timeout,
+            self.options
+                .reconnect_buffer_size
+                .min(self.options.channel_capacity),
+            self.options.grace_period,
+            self.options.debounce_ms.map(Duration::from_millis),
+            self.options.coalesce_window_ms.map(Duration::from_millis),
+            self.options.max_events_per_second,
+        )
+        .with_metadata(self.options.include_metadata)
+        .with_hashes(self.options.compute_hash_on_modify)
+        .with_hard_links(self.options.detect_hard_links)
+        .with_timestamps(self.options.approximate_timestamp)
+        .with_pause(self.paused.clone(), self.options.buffer_while_paused)
+        .with_callbacks(self.callbacks.clone())
+        .with_sequence(self.sequence.clone());
+
+        let _sigint = self.options.handle_sigint.then(|| {
+            let tracer = self.clone();
+            close_on_sigint(cancel_token.clone(), move || {
+                if let Err(e) = tracer.close() {
+                    (tracer.options.on_error)(e);
+                }
+            })
+        });
+
+        let empty = KEvent::new(
+            0,
+            EventFilter::EVFILT_VNODE,
+            EventFlag::empty(),
+            FilterFlag::empty(),
+            0,
+            0,
+        );
+        let mut events = [empty; 64];
+        let timeout = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 16_000_000,
+        };
+
+        while !cancel_token.is_cancelled() {
+            sender.poll()?;
+
+            let count = runtime::block_in_place(|| {
+                self.kqueue.kevent(&[], &mut events, Some(timeout))
+            })?;
+            for kevent in &events[..count] {
+                for event in self.handle(kevent.ident() as RawFd, kevent.fflags()) {
+                    if !self.is_filtered(&event) {
+                        sender.send(event)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl KanshiImpl<KanshiOptions> for KqueueTracer {
+    fn new(opts: KanshiOptions) -> Result<KqueueTracer, KanshiError> {
+        let exclude_filter = PathFilter::new(
+            &opts.exclude_patterns,
+            &opts.exclude_regexes,
+            opts.use_gitignore,
+        )?;
+        let kqueue = Kqueue::new()?;
+        let (tx, _rx) = tokio::sync::broadcast::channel(opts.channel_capacity.max(1));
+
+        Ok(KqueueTracer {
+            kqueue: Arc::new(kqueue),
+            sender: tx,
+            cancellation_token: CancellationToken::new(),
+            paused: Arc::new(AtomicBool::new(false)),
+            running: Arc::new(AtomicBool::new(false)),
+            callbacks: EventCallbacks::default(),
+            sequence: Arc::new(AtomicU64::new(opts.initial_sequence)),
+            options: Arc::new(opts),
+            watches: Arc::new(Mutex::new(Watches::default())),
+            excluded: Arc::new(Mutex::new(HashSet::new())),
+            exclude_filter: Arc::new(exclude_filter),
+            watched: Arc::new(Mutex::new(HashSet::new())),
+            files: Arc::new(Mutex::new(HashSet::new())),
+        })
+    }
+
+    async fn watch(&self, dir: impl AsRef<Path> + Send) -> Result<(), KanshiError> {
+        self.watch_with_exclude_dirs(dir, HashSet::new()).await
+    }
+
+    async fn watch_with_exclude_dirs(
+        &self,
+        dir: impl AsRef<Path> + Send,
+        exclude: HashSet<PathBuf>,
+    ) -> Result<(), KanshiError> {
+        let dir = dir.as_ref();
+        if self.cancellation_token.is_cancelled() {
+            return Err(KanshiError::StreamClosed);
+        }
+
+        if self.options.create_watched_dirs {
+            create_watched_dir(dir)?;
+        }
+
+        let path = dir.canonicalize()?;
+
+        // `watches` is always locked first, as in `handle`.
+        let mut watches = self.watches.lock().unwrap();
+        let mut excluded = self.excluded.lock().unwrap();
+        excluded.extend(canonicalize_all(exclude));
+
+        if path.is_file() {
+            self.add(&mut watches, &path)?;
+            self.files.lock().unwrap().insert(path);
+            return Ok(());
+        }
+
+        self.exclude_filter.add_ignore_files(&path);
+        if let Err(e) = self.add_dir(&mut watches, &path, 0, &excluded) {
+            // Undo the watches added so far, unless another watched directory
+            // still needs them.
+            let watched = self.watched.lock().unwrap();
+            let files = self.files.lock().unwrap();
+            if !watched.iter().any(|x| path.starts_with(x)) {
+                remove_below(&mut watches, &path, |x| is_needed(&watched, &files, x));
+            }
+            return Err(e);
+        }
+        self.watched.lock().unwrap().insert(path);
+        Ok(())
+    }
+
+    async fn unwatch(&self, dir: impl AsRef<Path> + Send) -> Result<(), KanshiError> {
+        let path = dir
+            .as_ref()
+            .canonicalize()
+            .unwrap_or_else(|_| dir.as_ref().to_path_buf());
+
+        // Locked in the same order as in `watch_with_exclude_dirs`.
+        let mut watches = self.watches.lock().unwrap();
+        let mut watched = self.watched.lock().unwrap();
+        let mut files = self.files.lock().unwrap();
+        if files.remove(&path) {
+            // The file stays watched while its directory is.
+            let listed = path
+                .parent()
+                .is_some_and(|parent| watches.entries.contains_key(parent));
+            if !listed {
+                remove_below(&mut watches, &path, |_| false);
+            }
+            return Ok(());
+        }
+        if !watched.remove(&path) {
+            return Err(KanshiError::PathNotWatched(dir.as_ref().to_path_buf()));
+        }
+        if watched.iter().any(|x| path.starts_with(x)) {
+            return Ok(());
+        }
+
+        remove_below(&mut watches, &path, |x| is_needed(&watched, &files, x));
+        Ok(())
+    }
+
+    fn get_watched_paths(&self) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = self.watched.lock().unwrap().iter().cloned().collect();
+        paths.extend(self.files.lock().unwrap().iter().cloned());
+        paths.sort();
+        paths
+    }
+
+    fn get_events_stream(&self) -> Pin<Box<dyn futures::Stream<Item = KanshiStreamItem> + Send>> {
+        let mut listener = self.sender.subscribe();
+        let cancel_token = self.cancellation_token.clone();
+
+        Box::pin(stream! {
+            loop {
+                tokio::select! {
+                    _ = cancel_token.cancelled() => {
+                        break;
+                    }
+                    val = listener.recv() => {
+                        match val {
+                            Ok(x) => {
+                                let failed = x.is_error();
+                                yield x;
+                                if failed {
+                                    break;
+                                }
+                            }
+                            Err(e) => match e {
+                                RecvError::Closed => break,
+                                _ => ()
+                            }
+                        }
+                    }
+                }
+            }
+        })
+    }
+
+    async fn start(&self) -> Result<(), KanshiError> {
+        let result = self.run().await;
+        report_error(&self.sender, result)
+    }
+
+    fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+    }
+
+    fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    fn on_event<F>(&self, callback: F)
+    where
+        F: Fn(FileSystemEvent) + Send + Sync + 'static,
+    {
+        self.callbacks.write().unwrap().push(Box::new(callback));
+    }
+
+    fn close(&self) -> Result<(), KanshiError> {
+        if self.cancellation_token.is_cancelled() {
+            return Ok(());
+        }
+
+        self.cancellation_token.cancel();
+
+        // Closing a descriptor also removes it from the kqueue, which itself is
+        // closed on drop.
+        let mut watches = self.watches.lock().unwrap();
+        for (_, fd) in watches.fds.drain() {
+            close_fd(fd);
+        }
+        watches.paths.clear();
+        watches.entries.clear();
+
+        Ok(())
+    }
+}
+
+/// Lists the entries of `dir` without following symlinks. Entries that
+/// disappear while listing are skipped.
+fn read_entries(dir: &Path) -> Result<HashMap<OsString, Entry>, KanshiError> {
+    let mut entries = HashMap::new();
+    for dir_item in std::fs::read_dir(dir)? {
+        let Ok(dir_item) = dir_item else {
+            continue;
+        };
+        let Ok(metadata) = dir_item.path().symlink_metadata() else {
+            continue;
+        };
+        let kind = if metadata.is_dir() {
+            FileSystemTargetKind::Directory
+        } else if metadata.is_symlink() {
+            FileSystemTargetKind::Symlink
+        } else {
+            FileSystemTargetKind::File
+        };
+        entries.insert(
+            dir_item.file_name(),
+            Entry {
+                kind,
+                inode: metadata.ino(),
+            },
+        );
+    }
+    Ok(entries)
+}
+
+/// Closes the descriptors of `path` and everything below it, except for paths
+/// for which `keep` returns `true` and the entries below them.
+fn remove_below(watches: &mut Watches, path: &Path, keep: impl Fn(&Path) -> bool) {
+    let kept: Vec<PathBuf> = watches
+        .fds
+        .keys()
+        .filter(|x| x.starts_with(path) && keep(x))
+        .cloned()
+        .collect();
+    let is_removed = |x: &Path| x.starts_with(path) && !kept.iter().any(|y| x.starts_with(y));
+
+    watches.fds.retain(|x, fd| {
+        if !is_removed(x) {
+            return true;
+        }
+        close_fd(*fd);
+        false
+    });
+    watches.paths.retain(|_, x| !is_removed(x));
+    watches.entries.retain(|x, _| !is_removed(x));
+}
+
+/// Moves the watches of `from` and everything below it to `to`. The
+/// descriptors stay valid across a rename, only the paths change.
+fn rename_below(watches: &mut Watches, from: &Path, to: &Path) {
+    let renamed = |x: &Path| x.strip_prefix(from).ok().map(|relative| to.join(relative));
+
+    watches.fds = watches
+        .fds
+        .drain()
+        .map(|(x, fd)| (renamed(&x).unwrap_or(x), fd))
+        .collect();
+    for x in watches.paths.values_mut() {
+        if let Some(path) = renamed(x) {
+            *x = path;
+        }
+    }
+    watches.entries = watches
+        .entries
+        .drain()
+        .map(|(x, entries)| (renamed(&x).unwrap_or(x), entries))
+        .collect();
+}
+
+/// Whether the watches of `path` are still needed by a directory or file
+/// passed to `watch`.
+fn is_needed(watched: &HashSet<PathBuf>, files: &HashSet<PathBuf>, path: &Path) -> bool {
+    watched.contains(path) || files.contains(path)
+}
+
+/// How many levels `path` is below the closest of `roots`, or `None` if it is
+/// not below any of them.
+fn depth_below(roots: &HashSet<PathBuf>, path: &Path) -> Option<usize> {
+    roots
+        .iter()
+        .filter_map(|root| path.strip_prefix(root).ok())
+        .map(|relative| relative.components().count())
+        .min()
+}
+
+fn event(
+    event_type: FileSystemEventType,
+    path: PathBuf,
+    kind: FileSystemTargetKind,
+    device_inode: Option<(u64, u64)>,
+) -> FileSystemEvent {
+    FileSystemEvent {
+        event_type,
+        target: Some(FileSystemTarget {
+            kind,
+            path: path.into(),
+            device_inode,
+            metadata: None,
+        }),
+        pid: None,
+        timestamp: None,
+        sequence: 0,
+    }
+}
+
+fn close_fd(fd: RawFd) {
+    // SAFETY: `fd` was opened by `KqueueTracer::add` and is closed only once,
+    // when it is removed from `Watches`.
+    unsafe {
+        libc::close(fd);
+    }
+}
@@ -1,6 +1,17 @@
-use std::{borrow::Borrow, pin::Pin};
+use std::{
+    borrow::Borrow,
+    collections::HashSet,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use crate::{KanshiError, KanshiImpl};
+use crate::{
+    default_error_handler,
+    platforms::{validate_watch_dir, WatchFilters},
+    ErrorHandler, EventTypeMask, FileSystemEvent, KanshiError, KanshiImpl, WatchHandle,
+};
 
 pub enum KanshiEngines {
     FSEvents,
@@ -18,11 +29,413 @@ impl KanshiEngines {
     }
 }
 
+/// Where the FSEvents stream created by `start` begins, set with
+/// `KanshiOptions::start_from`. Event IDs are shared by every volume and only
+/// ever increase, except when they wrap around, which FSEvents reports.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FSEventsStartPoint {
+    /// Only reports the events that happen after `start` created the stream.
+    #[default]
+    SinceNow,
+    /// Replays every event FSEvents still remembers for the watched paths
+    /// before reporting new ones. This can be a very large number of events.
+    SinceBeginning,
+    /// Replays the events recorded after this event ID, e.g. one returned by
+    /// `FSEventsStartPoint::current` before `watch` was called.
+    SinceEventId(u64),
+}
+
+impl FSEventsStartPoint {
+    /// Starts from the most recent event ID, so that the events happening from
+    /// now on are reported even if `start` runs later.
+    pub fn current() -> FSEventsStartPoint {
+        FSEventsStartPoint::SinceEventId(unsafe { core_foundation::FSEventsGetCurrentEventId() })
+    }
+
+    /// The `sinceWhen` argument of `FSEventStreamCreate`.
+    pub(crate) fn event_id(self) -> u64 {
+        match self {
+            FSEventsStartPoint::SinceNow => core_foundation::types::kFSEventStreamEventIdSinceNow,
+            FSEventsStartPoint::SinceBeginning => 0,
+            FSEventsStartPoint::SinceEventId(id) => id,
+        }
+    }
+}
+
 mod core_foundation;
 mod fsevents;
 
 pub struct KanshiOptions {
     pub force_engine: Option<KanshiEngines>,
+    /// Called whenever a non-fatal error occurs while processing events, e.g. a
+    /// path that could not be resolved or an unknown event mask. Defaults to
+    /// logging the error with `tracing::error!`.
+    pub on_error: ErrorHandler,
+    /// Close the tracer when SIGINT (Ctrl+C) is received while `start` is
+    /// running, so `start` returns `Ok(())` with all marks removed instead of
+    /// the process being killed. Installing the handler stops Ctrl+C from
+    /// terminating the process, so the application is expected to exit once
+    /// `start` returns. Defaults to `false`.
+    pub handle_sigint: bool,
+    /// Number of events each stream can fall behind the tracer before it
+    /// starts missing events. Every slot holds one event, so a larger capacity
+    /// uses more memory but lets slow consumers ride out bursts such as a
+    /// `git checkout` touching thousands of files. Defaults to 1024.
+    pub channel_capacity: usize,
+    /// How long the tracer keeps running while no stream is subscribed before
+    /// giving up with `StreamClosed`. Events received in the meantime are
+    /// buffered and delivered to the next subscriber. Defaults to 5 seconds.
+    pub reconnect_timeout: Duration,
+    /// Maximum number of events buffered while no stream is subscribed. The
+    /// oldest events are dropped first. At most `channel_capacity` events are
+    /// kept, since the next subscriber would miss the others anyway. Defaults
+    /// to 1024.
+    pub reconnect_buffer_size: usize,
+    /// How long `Delete` events are held back before being emitted. A delete
+    /// is dropped if a `Create` for the same path arrives within this window,
+    /// so atomic saves (write to a temporary file, then rename it over the
+    /// original) only report the `Create`. Held deletes are emitted after any
+    /// events that arrive while they are held. Defaults to zero, which
+    /// disables holding.
+    pub grace_period: Duration,
+    /// Debounce window in milliseconds. Events are held per path and only
+    /// the last one is emitted, once the path has seen no further events for
+    /// this long. Events without a target path are not delayed. Defaults to
+    /// `None`, which emits every event as it arrives.
+    pub debounce_ms: Option<u64>,
+    /// Coalescing window in milliseconds. All events a path sees within this
+    /// long of its first event are merged into a single event, reported as
+    /// `FileSystemEventType::Compound` if there was more than one kind. A path
+    /// created and deleted within the window reports nothing. Moves are never
+    /// merged. Defaults to `None`, which disables coalescing.
+    pub coalesce_window_ms: Option<u64>,
+    /// Maximum number of events reported per second, with bursts of up to a
+    /// second's worth allowed. Past that, events are discarded until the rate
+    /// falls back below the limit, after which a single
+    /// `FileSystemEventType::RateLimitExceeded` reports how many were dropped.
+    /// Defaults to `None`, which reports every event.
+    pub max_events_per_second: Option<u32>,
+    /// Read the size, modification time and mode of the target of `Create`
+    /// and `Modify` events as soon as they are received, and report them in
+    /// `FileSystemTarget::metadata`. This costs a `stat` call per event.
+    /// Defaults to `false`.
+    pub include_metadata: bool,
+    /// Hash the contents of the target of `Modify` events with SHA-256, and
+    /// report the hash in `EventMetadata::hash`, along with the rest of
+    /// `FileSystemTarget::metadata`. Files are read in full on a separate
+    /// thread, and the events behind a `Modify` wait for its hash to keep
+    /// them in order. Files larger than 64 MiB are not hashed. Defaults to
+    /// `false`.
+    pub compute_hash_on_modify: bool,
+    /// Report files created with more than one link, i.e. new hard links to
+    /// an existing file, as `FileSystemTargetKind::HardLink`. This costs a
+    /// `stat` call per `Create` event. Defaults to `false`.
+    pub detect_hard_links: bool,
+    /// Fill in `FileSystemEvent::timestamp` with the time each event was read
+    /// from the kernel. None of the backends report when an event happened,
+    /// so this is only an approximation, which can lag behind by however long
+    /// the event was queued. Defaults to `false`.
+    pub approximate_timestamp: bool,
+    /// The number given to the first event in `FileSystemEvent::sequence`,
+    /// e.g. to carry on from the last event a previous tracer reported.
+    /// Defaults to `0`.
+    pub initial_sequence: u64,
+    /// Keep the events received while the tracer is paused with `pause`, and
+    /// deliver them after `resume`, instead of discarding them. At most
+    /// `reconnect_buffer_size` events are kept, dropping the oldest. Defaults
+    /// to `false`.
+    pub buffer_while_paused: bool,
+    /// Leave out the events caused by this process, e.g. so that a tracer
+    /// writing to the directories it watches does not see its own writes
+    /// (`kFSEventStreamCreateFlagIgnoreSelf`). Defaults to `false`.
+    pub ignore_self: bool,
+    /// Where the stream created by `start` begins. FSEvents only reports the
+    /// events recorded after it, so with `FSEventsStartPoint::SinceNow` the
+    /// events between `watch` and `start` are missed. Use
+    /// `FSEventsStartPoint::current()` taken before `watch` to report them as
+    /// well. Streams replaced by `watch`, `unwatch` or `restart` while the
+    /// tracer runs always start from the time they are replaced. Defaults to
+    /// `FSEventsStartPoint::SinceNow`.
+    pub start_from: FSEventsStartPoint,
+    /// Accepted for compatibility with Linux, where it reports `Open` events.
+    /// FSEvents cannot observe opens, so setting it only logs a warning when
+    /// the tracer is created. Defaults to `false`.
+    pub watch_open: bool,
+    /// Create a watched directory, along with any missing parents, when it does
+    /// not exist yet instead of failing. Directories are created with the
+    /// default permissions (`0o777` masked by the umask, usually `0o755`).
+    /// `watch` returns the error if creation fails. Defaults to `false`.
+    pub create_watched_dirs: bool,
+    /// Glob patterns, e.g. `**/target/**`, matched against the full path of
+    /// every event. Matching events are dropped before they reach any stream.
+    /// FSEvents always watches the whole tree, so they are still received.
+    /// Invalid patterns make `new` fail with `InvalidParameter`. Defaults to
+    /// no patterns.
+    pub exclude_patterns: Vec<String>,
+    /// Regular expressions, e.g. `\.sw[po]$`, matched against the full path
+    /// of every event in the same way as `exclude_patterns`. An event is
+    /// dropped if either a pattern or a regex matches. Invalid regexes make
+    /// `new` fail with `InvalidPattern`. Defaults to no regexes.
+    pub exclude_regexes: Vec<String>,
+    /// Also exclude the paths ignored by the `.gitignore` and `.ignore` files
+    /// in the watched directories, and by `.git/info/exclude`, along with
+    /// `.git` directories themselves. Ignored directories are not watched.
+    /// The ignore files are read once by `watch`, so later changes to them
+    /// are not picked up. Defaults to `false`.
+    pub use_gitignore: bool,
+    /// Report events anywhere below a watched directory. When `false`, only
+    /// events for entries directly inside a watched directory are reported.
+    /// FSEvents always watches the whole tree, so the other events are still
+    /// received and then dropped. Defaults to `true`.
+    pub recursive: bool,
+    /// How many levels of directories below a watched directory to watch.
+    /// `Some(0)` only reports entries directly inside the watched directory,
+    /// the same as turning off `recursive`, and `Some(1)` also reports entries
+    /// inside its subdirectories. `None` watches the whole tree. Only applies
+    /// when `recursive` is set. Defaults to `None`.
+    pub max_depth: Option<usize>,
+    /// Seconds FSEvents waits after an event before delivering it, so that
+    /// more events can be delivered in the same batch. Higher values, e.g.
+    /// 1 to 5 seconds for backups or CI, lower CPU usage at the cost of later
+    /// events. Must not be negative, and values above 30 seconds log a
+    /// warning. Defaults to `0.1`.
+    pub fsevents_latency_seconds: f64,
+}
+
+impl KanshiOptions {
+    /// Sets [`KanshiOptions::force_engine`].
+    pub fn with_engine(mut self, engine: KanshiEngines) -> KanshiOptions {
+        self.force_engine = Some(engine);
+        self
+    }
+
+    /// Sets [`KanshiOptions::on_error`].
+    pub fn with_on_error(
+        mut self,
+        on_error: impl Fn(KanshiError) + Send + Sync + 'static,
+    ) -> KanshiOptions {
+        self.on_error = Arc::new(on_error);
+        self
+    }
+
+    /// Sets [`KanshiOptions::handle_sigint`].
+    pub fn with_handle_sigint(mut self, handle_sigint: bool) -> KanshiOptions {
+        self.handle_sigint = handle_sigint;
+        self
+    }
+
+    /// Sets [`KanshiOptions::channel_capacity`].
+    pub fn with_channel_capacity(mut self, channel_capacity: usize) -> KanshiOptions {
+        self.channel_capacity = channel_capacity;
+        self
+    }
+
+    /// Sets [`KanshiOptions::reconnect_timeout`].
+    pub fn with_reconnect_timeout(mut self, reconnect_timeout: Duration) -> KanshiOptions {
+        self.reconnect_timeout = reconnect_timeout;
+        self
+    }
+
+    /// Sets [`KanshiOptions::reconnect_buffer_size`].
+    pub fn with_reconnect_buffer_size(mut self, reconnect_buffer_size: usize) -> KanshiOptions {
+        self.reconnect_buffer_size = reconnect_buffer_size;
+        self
+    }
+
+    /// Sets [`KanshiOptions::grace_period`].
+    pub fn with_grace_period(mut self, grace_period: Duration) -> KanshiOptions {
+        self.grace_period = grace_period;
+        self
+    }
+
+    /// Sets [`KanshiOptions::debounce_ms`].
+    pub fn with_debounce_ms(mut self, debounce_ms: u64) -> KanshiOptions {
+        self.debounce_ms = Some(debounce_ms);
+        self
+    }
+
+    /// Sets [`KanshiOptions::coalesce_window_ms`].
+    pub fn with_coalesce_window_ms(mut self, coalesce_window_ms: u64) -> KanshiOptions {
+        self.coalesce_window_ms = Some(coalesce_window_ms);
+        self
+    }
+
+    /// Sets [`KanshiOptions::max_events_per_second`].
+    pub fn with_max_events_per_second(mut self, max_events_per_second: u32) -> KanshiOptions {
+        self.max_events_per_second = Some(max_events_per_second);
+        self
+    }
+
+    /// Sets [`KanshiOptions::include_metadata`].
+    pub fn with_include_metadata(mut self, include_metadata: bool) -> KanshiOptions {
+        self.include_metadata = include_metadata;
+        self
+    }
+
+    /// Sets [`KanshiOptions::compute_hash_on_modify`].
+    pub fn with_compute_hash_on_modify(mut self, compute_hash_on_modify: bool) -> KanshiOptions {
+        self.compute_hash_on_modify = compute_hash_on_modify;
+        self
+    }
+
+    /// Sets [`KanshiOptions::detect_hard_links`].
+    pub fn with_detect_hard_links(mut self, detect_hard_links: bool) -> KanshiOptions {
+        self.detect_hard_links = detect_hard_links;
+        self
+    }
+
+    /// Sets [`KanshiOptions::approximate_timestamp`].
+    pub fn with_approximate_timestamp(mut self, approximate_timestamp: bool) -> KanshiOptions {
+        self.approximate_timestamp = approximate_timestamp;
+        self
+    }
+
+    /// Sets [`KanshiOptions::initial_sequence`].
+    pub fn with_initial_sequence(mut self, initial_sequence: u64) -> KanshiOptions {
+        self.initial_sequence = initial_sequence;
+        self
+    }
+
+    /// Sets [`KanshiOptions::buffer_while_paused`].
+    pub fn with_buffer_while_paused(mut self, buffer_while_paused: bool) -> KanshiOptions {
+        self.buffer_while_paused = buffer_while_paused;
+        self
+    }
+
+    /// Sets [`KanshiOptions::ignore_self`].
+    pub fn with_ignore_self(mut self, ignore_self: bool) -> KanshiOptions {
+        self.ignore_self = ignore_self;
+        self
+    }
+
+    /// Sets [`KanshiOptions::start_from`].
+    pub fn with_start_from(mut self, start_from: FSEventsStartPoint) -> KanshiOptions {
+        self.start_from = start_from;
+        self
+    }
+
+    /// Sets [`KanshiOptions::watch_open`].
+    pub fn with_watch_open(mut self, watch_open: bool) -> KanshiOptions {
+        self.watch_open = watch_open;
+        self
+    }
+
+    /// Sets [`KanshiOptions::create_watched_dirs`].
+    pub fn with_create_watched_dirs(mut self, create_watched_dirs: bool) -> KanshiOptions {
+        self.create_watched_dirs = create_watched_dirs;
+        self
+    }
+
+    /// Sets [`KanshiOptions::exclude_patterns`].
+    pub fn with_exclude_patterns(
+        mut self,
+        exclude_patterns: impl IntoIterator<Item = impl Into<String>>,
+    ) -> KanshiOptions {
+        self.exclude_patterns = exclude_patterns.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Sets [`KanshiOptions::exclude_regexes`].
+    pub fn with_exclude_regexes(
+        mut self,
+        exclude_regexes: impl IntoIterator<Item = impl Into<String>>,
+    ) -> KanshiOptions {
+        self.exclude_regexes = exclude_regexes.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Sets [`KanshiOptions::use_gitignore`].
+    pub fn with_use_gitignore(mut self, use_gitignore: bool) -> KanshiOptions {
+        self.use_gitignore = use_gitignore;
+        self
+    }
+
+    /// Sets [`KanshiOptions::recursive`].
+    pub fn with_recursive(mut self, recursive: bool) -> KanshiOptions {
+        self.recursive = recursive;
+        self
+    }
+
+    /// Sets [`KanshiOptions::max_depth`].
+    pub fn with_max_depth(mut self, max_depth: usize) -> KanshiOptions {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    /// The deepest level below a watched directory that is watched, taking
+    /// both `recursive` and `max_depth` into account.
+    pub(crate) fn depth_limit(&self) -> Option<usize> {
+        if self.recursive {
+            self.max_depth
+        } else {
+            Some(0)
+        }
+    }
+
+    /// Sets [`KanshiOptions::fsevents_latency_seconds`].
+    pub fn with_fsevents_latency_seconds(mut self, fsevents_latency_seconds: f64) -> KanshiOptions {
+        self.fsevents_latency_seconds = fsevents_latency_seconds;
+        self
+    }
+
+    /// Checks the options for invalid values and returns them.
+    pub fn build(self) -> Result<KanshiOptions, KanshiError> {
+        if self.channel_capacity == 0 {
+            return Err(KanshiError::InvalidParameter(
+                "channel_capacity must be at least 1.".to_owned(),
+            ));
+        }
+        if self.max_events_per_second == Some(0) {
+            return Err(KanshiError::InvalidParameter(
+                "max_events_per_second must be at least 1.".to_owned(),
+            ));
+        }
+        validate_latency(self.fsevents_latency_seconds)?;
+        Ok(self)
+    }
+}
+
+/// Checks that `latency` is a valid `KanshiOptions::fsevents_latency_seconds`.
+pub(crate) fn validate_latency(latency: f64) -> Result<(), KanshiError> {
+    if !latency.is_finite() || latency < 0.0 {
+        return Err(KanshiError::InvalidParameter(
+            "fsevents_latency_seconds must be a non-negative number of seconds.".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+impl Default for KanshiOptions {
+    fn default() -> Self {
+        KanshiOptions {
+            force_engine: None,
+            on_error: default_error_handler(),
+            handle_sigint: false,
+            channel_capacity: 1024,
+            reconnect_timeout: Duration::from_secs(5),
+            reconnect_buffer_size: 1024,
+            grace_period: Duration::ZERO,
+            debounce_ms: None,
+            coalesce_window_ms: None,
+            max_events_per_second: None,
+            include_metadata: false,
+            compute_hash_on_modify: false,
+            detect_hard_links: false,
+            approximate_timestamp: false,
+            initial_sequence: 0,
+            buffer_while_paused: false,
+            ignore_self: false,
+            start_from: FSEventsStartPoint::SinceNow,
+            watch_open: false,
+            create_watched_dirs: false,
+            exclude_patterns: Vec::new(),
+            exclude_regexes: Vec::new(),
+            use_gitignore: false,
+            recursive: true,
+            max_depth: None,
+            fsevents_latency_seconds: 0.1,
+        }
+    }
 }
 
 pub use fsevents::FSEventsTracer;
@@ -35,6 +448,81 @@ enum Engines {
 #[derive(Clone)]
 pub struct Kanshi {
     engine: Engines,
+    // Canonical paths of the directories passed to `watch`.
+    watched: Arc<Mutex<HashSet<PathBuf>>>,
+    filters: WatchFilters,
+}
+
+impl Kanshi {
+    /// Watches `dir` like `watch`, but first checks that it can be watched
+    /// without changing any kernel state: `dir` must be an existing, readable
+    /// directory that is not watched yet. If any check fails, nothing is
+    /// marked. The directory can still change between the checks and marking,
+    /// so `watch` errors are possible but unlikely. `dir` is never created, even
+    /// with `KanshiOptions::create_watched_dirs`.
+    pub async fn try_watch(
+        &self,
+        dir: impl AsRef<Path> + Send,
+    ) -> Result<WatchHandle, KanshiError> {
+        let path = validate_watch_dir(dir.as_ref())?;
+        if self.watched.lock().unwrap().contains(&path) {
+            return Err(KanshiError::InvalidParameter(format!(
+                "{:?} is already watched",
+                path
+            )));
+        }
+
+        self.watch(&path).await?;
+        Ok(WatchHandle { path })
+    }
+
+    /// Watches `dir` like `watch`, but only reports the event types in
+    /// `filter` for it. A path below several watched directories is reported
+    /// for the event types of any of them, and watching `dir` again replaces
+    /// its filter. FSEvents can't select event types per path, so events are
+    /// filtered as they are received.
+    pub async fn watch_with_filter(
+        &self,
+        dir: impl AsRef<Path> + Send,
+        filter: EventTypeMask,
+    ) -> Result<(), KanshiError> {
+        self.watch_masked(dir.as_ref(), HashSet::new(), filter)
+            .await
+    }
+
+    /// `watch_with_exclude_dirs`, reporting only the event types in `filter`.
+    async fn watch_masked(
+        &self,
+        dir: &Path,
+        exclude: HashSet<PathBuf>,
+        filter: EventTypeMask,
+    ) -> Result<(), KanshiError> {
+        match self.engine.borrow() {
+            Engines::FSEvents(fsevents) => fsevents.watch_with_exclude_dirs(dir, exclude).await?,
+        }
+
+        if let Ok(path) = dir.canonicalize() {
+            self.filters.set(path.clone(), filter);
+            self.watched.lock().unwrap().insert(path);
+        }
+        Ok(())
+    }
+
+    /// Replaces the FSEvents stream with a new one for the watched paths, e.g.
+    /// after a watched volume was unmounted and mounted again. Event streams
+    /// keep working across the restart. See [`FSEventsTracer::restart`].
+    pub async fn restart(&self) -> Result<(), KanshiError> {
+        match self.engine.borrow() {
+            Engines::FSEvents(fsevents) => fsevents.restart().await,
+        }
+    }
+
+    /// Starts the tracer and returns an iterator over its events, for callers
+    /// that don't use async. See [`KanshiBlockingIter`](crate::KanshiBlockingIter).
+    #[cfg(feature = "runtime-tokio")]
+    pub fn into_blocking_iter(self) -> Result<crate::KanshiBlockingIter, KanshiError> {
+        crate::KanshiBlockingIter::new(self, None)
+    }
 }
 
 impl KanshiImpl<KanshiOptions> for Kanshi {
@@ -44,6 +532,8 @@ impl KanshiImpl<KanshiOptions> for Kanshi {
     {
         Ok(Kanshi {
             engine: Engines::FSEvents(FSEventsTracer::new(opts)?),
+            watched: Arc::new(Mutex::new(HashSet::new())),
+            filters: WatchFilters::default(),
         })
     }
 
@@ -53,16 +543,58 @@ impl KanshiImpl<KanshiOptions> for Kanshi {
         }
     }
 
-    async fn watch(&self, dir: &str) -> Result<(), KanshiError> {
+    async fn watch(&self, dir: impl AsRef<Path> + Send) -> Result<(), KanshiError> {
+        self.watch_with_exclude_dirs(dir, HashSet::new()).await
+    }
+
+    async fn watch_many(
+        &self,
+        dirs: impl IntoIterator<Item = impl AsRef<Path>> + Send,
+    ) -> Result<(), KanshiError> {
+        let dirs: Vec<PathBuf> = dirs.into_iter().map(|x| x.as_ref().to_path_buf()).collect();
+        match self.engine.borrow() {
+            Engines::FSEvents(fsevents) => fsevents.watch_many(&dirs).await?,
+        }
+
+        let mut watched = self.watched.lock().unwrap();
+        for path in dirs.iter().filter_map(|x| x.canonicalize().ok()) {
+            self.filters.set(path.clone(), EventTypeMask::all());
+            watched.insert(path);
+        }
+        Ok(())
+    }
+
+    async fn watch_with_exclude_dirs(
+        &self,
+        dir: impl AsRef<Path> + Send,
+        exclude: HashSet<PathBuf>,
+    ) -> Result<(), KanshiError> {
+        self.watch_masked(dir.as_ref(), exclude, EventTypeMask::all())
+            .await
+    }
+
+    async fn unwatch(&self, dir: impl AsRef<Path> + Send) -> Result<(), KanshiError> {
+        let dir = dir.as_ref();
+        match self.engine.borrow() {
+            Engines::FSEvents(fsevents) => fsevents.unwatch(dir).await?,
+        }
+
+        let path = dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf());
+        self.filters.remove(&path);
+        self.watched.lock().unwrap().remove(&path);
+        Ok(())
+    }
+
+    fn get_watched_paths(&self) -> Vec<PathBuf> {
         match self.engine.borrow() {
-            Engines::FSEvents(fsevents) => fsevents.watch(dir).await,
+            Engines::FSEvents(fsevents) => fsevents.get_watched_paths(),
         }
     }
 
     fn get_events_stream(
         &self,
-    ) -> Pin<Box<dyn futures::Stream<Item = crate::FileSystemEvent> + Send>> {
-        let events_stream: Pin<Box<dyn futures::Stream<Item = crate::FileSystemEvent> + Send>>;
+    ) -> Pin<Box<dyn futures::Stream<Item = crate::KanshiStreamItem> + Send>> {
+        let events_stream: Pin<Box<dyn futures::Stream<Item = crate::KanshiStreamItem> + Send>>;
 
         match self.engine.borrow() {
             Engines::FSEvents(fsevents) => {
@@ -70,10 +602,38 @@ impl KanshiImpl<KanshiOptions> for Kanshi {
             }
         };
 
-        events_stream
+        self.filters.apply(events_stream)
+    }
+
+    fn pause(&self) {
+        match self.engine.borrow() {
+            Engines::FSEvents(fsevents) => fsevents.pause(),
+        }
+    }
+
+    fn resume(&self) {
+        match self.engine.borrow() {
+            Engines::FSEvents(fsevents) => fsevents.resume(),
+        }
+    }
+
+    fn is_paused(&self) -> bool {
+        match self.engine.borrow() {
+            Engines::FSEvents(fsevents) => fsevents.is_paused(),
+        }
+    }
+
+    fn on_event<F>(&self, callback: F)
+    where
+        F: Fn(FileSystemEvent) + Send + Sync + 'static,
+    {
+        let callback = self.filters.wrap(callback);
+        match self.engine.borrow() {
+            Engines::FSEvents(fsevents) => fsevents.on_event(callback),
+        }
     }
 
-    fn close(&self) -> bool {
+    fn close(&self) -> Result<(), KanshiError> {
         match self.engine.borrow() {
             Engines::FSEvents(fsevents) => fsevents.close(),
         }
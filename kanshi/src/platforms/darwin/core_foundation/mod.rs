@@ -32,6 +32,12 @@ extern "C" {
 
     /// https://developer.apple.com/documentation/coreservices/1445989-fseventstreamrelease?language=objc
     pub fn FSEventStreamRelease(streamRef: FSEventStreamRef);
+
+    /// Synchronously hands every event recorded so far to the stream's callback.
+    pub fn FSEventStreamFlushSync(streamRef: FSEventStreamRef);
+
+    /// https://developer.apple.com/documentation/coreservices/1442917-fseventsgetcurrenteventid?language=objc
+    pub fn FSEventsGetCurrentEventId() -> FSEventStreamId;
 }
 
 // Implements https://developer.apple.com/documentation/coreservices/file_system_events?language=objc
@@ -1,13 +1,15 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsString;
+use std::io;
 use std::os::raw::c_void;
 use std::path::{self, Path, PathBuf};
 use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use async_stream::stream;
 use tokio::sync::broadcast::error::RecvError;
-use tokio::sync::broadcast::Sender;
 use tokio::sync::{Mutex, RwLock};
 use tokio_util::sync::CancellationToken;
 
@@ -15,24 +17,68 @@ use super::core_foundation::types::{
     dispatch_queue_t, CFIndex, CFMutableArrayRef, FSEventStreamEventFlags, FSEventStreamRef,
 };
 use super::core_foundation::{self as CoreFoundation, types as CFTypes};
-use super::KanshiOptions;
+use super::{validate_latency, KanshiOptions};
 use crate::platforms::darwin::core_foundation::types::{
     kCFNumberSInt64Type, kFSEventStreamEventExtendedDataPathKey,
     kFSEventStreamEventExtendedFileIDKey,
 };
 use crate::platforms::darwin::core_foundation::{CFArrayGetValueAtIndex, CFDictionaryGetValue};
+use crate::platforms::{
+    canonicalize_all, create_watched_dir, device_inode, filter::PathFilter, report_error, sigint,
+    RunningGuard,
+};
+use crate::{
+    runtime,
+    sender::{EventCallbacks, EventSender, EventStats},
+};
 use crate::{
-    FileSystemEvent, FileSystemEventType, FileSystemTarget, FileSystemTargetKind, KanshiError,
-    KanshiImpl,
+    ErrorHandler, EventStatistics, FileSystemEvent, FileSystemEventType, FileSystemTarget,
+    FileSystemTargetKind, KanshiError, KanshiImpl, KanshiStreamItem,
 };
 
 #[derive(Clone)]
 pub struct FSEventsTracer {
     stream: Arc<RwLock<Option<WrappedEventStreamRef>>>,
     dispatch_queue: Arc<RwLock<Option<WrappedDispatchQueue>>>,
-    sender: tokio::sync::broadcast::Sender<FileSystemEvent>,
+    sender: tokio::sync::broadcast::Sender<KanshiStreamItem>,
     cancellation_token: CancellationToken,
+    paused: Arc<AtomicBool>,
+    // Set while `start` runs, shared with clones.
+    running: Arc<AtomicBool>,
+    callbacks: EventCallbacks,
     paths_to_watch: Arc<Mutex<Vec<PathBuf>>>,
+    context: Arc<CallbackContext>,
+    options: Arc<KanshiOptions>,
+    // The counters behind `stats`.
+    stats: Arc<EventStats>,
+}
+
+/// State shared with `callback` through `FSEventStreamContext->info`.
+struct CallbackContext {
+    sender: std::sync::Mutex<EventSender>,
+    on_error: ErrorHandler,
+    /// Canonical paths of excluded directories. Events at or below them are dropped.
+    excluded: std::sync::RwLock<HashSet<PathBuf>>,
+    /// `KanshiOptions::exclude_patterns` and `exclude_regexes`. Events for
+    /// matching paths are dropped.
+    exclude_filter: PathFilter,
+    /// Canonical paths of the watched directories. Events for entries deeper
+    /// below them than `max_depth` are dropped.
+    roots: std::sync::RwLock<HashSet<PathBuf>>,
+    /// Canonical paths of the watched files. FSEvents only watches
+    /// directories, so their parent directories are watched instead and events
+    /// for the other entries in them are dropped.
+    files: std::sync::RwLock<HashSet<PathBuf>>,
+    /// `KanshiOptions::depth_limit`.
+    max_depth: Option<usize>,
+    /// Renames whose other half has not been received yet, by inode, along
+    /// with when they were received. FSEvents reports the old and the new
+    /// path of a rename as separate events, which can arrive in different
+    /// callbacks. Entries older than `move_timeout` are sent on their own as
+    /// `Move` by `start`.
+    pending_moves: std::sync::Mutex<HashMap<i64, (FileSystemEvent, Instant)>>,
+    /// Twice `KanshiOptions::fsevents_latency_seconds`.
+    move_timeout: Duration,
 }
 
 pub struct WrappedEventStreamRef(FSEventStreamRef);
@@ -51,9 +97,15 @@ extern "C" fn callback(
     event_flags: *const CFTypes::FSEventStreamEventFlags, // eventFlags - Array of EventFlags corresponding to each event
     _event_ids: *const CFTypes::FSEventStreamId, // eventIds - Array of EventIds corresponding to each event. This Id is guaranteed to always be increasing.
 ) {
-    let sender = info as *const Sender<FileSystemEvent>;
-    let mut inode_map = HashMap::<i64, FileSystemEvent>::new();
+    let context = unsafe { &*(info as *const CallbackContext) };
+    let mut pending_moves = context.pending_moves.lock().unwrap();
     for idx in 0..num_event {
+        let flag = unsafe { *event_flags.add(idx) };
+        // Marks the end of the past events replayed for `start_from`.
+        if flag.contains(FSEventStreamEventFlags::kFSEventStreamEventFlagHistoryDone) {
+            continue;
+        }
+
         let dict = unsafe { CFArrayGetValueAtIndex(event_paths, idx as CFIndex) };
         let path = unsafe {
             CoreFoundation::cfstr_to_str(
@@ -62,6 +114,39 @@ extern "C" fn callback(
             )
         };
 
+        let is_excluded = {
+            let excluded = context.excluded.read().unwrap();
+            !excluded.is_empty() && Path::new(&path).ancestors().any(|x| excluded.contains(x))
+        };
+        if is_excluded || context.exclude_filter.is_match(&path) {
+            continue;
+        }
+
+        let (is_watched_file, has_files) = {
+            let files = context.files.read().unwrap();
+            (files.contains(Path::new(&path)), !files.is_empty())
+        };
+        if has_files && !is_watched_file {
+            // Only the parent directories of watched files are watched.
+            let roots = context.roots.read().unwrap();
+            if !roots.iter().any(|root| Path::new(&path).starts_with(root)) {
+                continue;
+            }
+        }
+
+        if let (Some(max_depth), false) = (context.max_depth, is_watched_file) {
+            // Entries directly inside a watched directory are one level below it.
+            let roots = context.roots.read().unwrap();
+            let depth = roots
+                .iter()
+                .filter_map(|root| Path::new(&path).strip_prefix(root).ok())
+                .map(|relative| relative.components().count())
+                .min();
+            if depth.map_or(true, |depth| depth > max_depth + 1) {
+                continue;
+            }
+        }
+
         let inode = unsafe {
             let mut value: i64 = 0;
             let ok = CoreFoundation::CFNumberGetValue(
@@ -76,10 +161,16 @@ extern "C" fn callback(
             }
         };
 
-        let flag = unsafe { *event_flags.add(idx) };
+        // FSEvents reports the inode through the extended file ID, but not the device.
+        let device_inode = match (device_inode(&path), inode) {
+            (Some((device, _)), Some(inode)) => Some((device, inode as u64)),
+            (identity, _) => identity,
+        };
 
         let kind = if flag.contains(FSEventStreamEventFlags::kFSEventStreamEventFlagItemIsDir) {
             FileSystemTargetKind::Directory
+        } else if flag.contains(FSEventStreamEventFlags::kFSEventStreamEventFlagItemIsSymlink) {
+            FileSystemTargetKind::Symlink
         } else {
             FileSystemTargetKind::File
         };
@@ -103,46 +194,65 @@ extern "C" fn callback(
             x if x.contains(FSEventStreamEventFlags::kFSEventStreamEventFlagItemRenamed) => {
                 FileSystemEventType::Move
             }
+            x if x.intersects(
+                FSEventStreamEventFlags::kFSEventStreamEventFlagItemInodeMetaMod
+                    | FSEventStreamEventFlags::kFSEventStreamEventFlagItemXattrMod
+                    | FSEventStreamEventFlags::kFSEventStreamEventFlagItemFinderInfoMod
+                    | FSEventStreamEventFlags::kFSEventStreamEventFlagItemChangeOwner,
+            ) =>
+            {
+                FileSystemEventType::AttributeChange
+            }
             x => {
-                eprintln!("Unknown Mask Received - {:?}", x);
+                (context.on_error)(KanshiError::UnknownEventMask(format!("{:?}", x)));
                 FileSystemEventType::Unknown
             }
         };
 
         if event_type == FileSystemEventType::Move && inode.is_some() {
             let inode = inode.unwrap();
-            if inode_map.contains_key(&inode) {
-                let mut old_event = inode_map.remove(&inode).unwrap();
-                old_event.event_type = FileSystemEventType::MovedTo(OsString::from(path.clone()));
-                event_type =
-                    FileSystemEventType::MovedFrom(old_event.target.as_ref().unwrap().path.clone());
+            if let Some((mut old_event, _)) = pending_moves.remove(&inode) {
+                let from = old_event.target.as_ref().unwrap().path.clone();
+                let to = OsString::from(path.clone());
+                old_event.event_type = FileSystemEventType::MovedTo {
+                    from: from.clone(),
+                    to: to.clone(),
+                };
+                event_type = FileSystemEventType::MovedFrom { from, to };
+                if let Some(target) = old_event.target.as_mut() {
+                    target.device_inode = target.device_inode.or(device_inode);
+                }
 
                 let event = FileSystemEvent {
                     event_type,
                     target: Some(FileSystemTarget {
                         kind,
                         path: OsString::from(path),
+                        device_inode,
+                        metadata: None,
                     }),
+                    pid: None,
+                    timestamp: None,
+                    sequence: 0,
                 };
 
-                if let Err(e) = unsafe { (*sender).send(old_event) } {
-                    eprintln!("Send Error Occurred - {:?}", e.to_string());
-                }
-
-                if let Err(e) = unsafe { (*sender).send(event) } {
-                    eprintln!("Send Error Occurred - {:?}", e.to_string());
-                }
+                send(context, old_event);
+                send(context, event);
             } else {
-                // event_type =
                 let event = FileSystemEvent {
                     event_type,
                     target: Some(FileSystemTarget {
                         kind,
                         path: OsString::from(path),
+                        device_inode,
+                        metadata: None,
                     }),
+                    pid: None,
+                    timestamp: None,
+                    sequence: 0,
                 };
 
-                inode_map.insert(inode, event);
+                pending_moves.insert(inode, (event, Instant::now()));
             }
         } else {
             let event = FileSystemEvent {
@@ -150,155 +260,230 @@ extern "C" fn callback(
                 target: Some(FileSystemTarget {
                     kind,
                     path: OsString::from(path),
+                    device_inode,
+                    metadata: None,
                 }),
+                pid: None,
+                timestamp: None,
+                sequence: 0,
             };
 
-            if let Err(e) = unsafe { (*sender).send(event) } {
-                eprintln!("Send Error Occurred - {:?}", e.to_string());
-            }
+            send(context, event);
         }
     }
 }
 
-impl KanshiImpl<KanshiOptions> for FSEventsTracer {
-    fn new(_opts: KanshiOptions) -> Result<FSEventsTracer, KanshiError> {
-        let (tx, _rx) = tokio::sync::broadcast::channel(32);
+fn send(context: &CallbackContext, event: FileSystemEvent) {
+    // A reconnect timeout is reported by `start`, which polls the sender.
+    let _ = context.sender.lock().unwrap().send(event);
+}
 
-        Ok(FSEventsTracer {
-            stream: Arc::new(RwLock::new(None)),
-            sender: tx,
-            cancellation_token: CancellationToken::new(),
-            paths_to_watch: Arc::new(Mutex::new(Vec::new())),
-            dispatch_queue: Arc::new(RwLock::new(None)),
-        })
+/// Sends the renames in `CallbackContext::pending_moves` that are older than
+/// `move_timeout` as unpaired `Move` events, oldest first.
+fn flush_pending_moves(context: &CallbackContext) {
+    let mut expired = Vec::new();
+    {
+        let mut pending_moves = context.pending_moves.lock().unwrap();
+        pending_moves.retain(|_, (event, received_at)| {
+            if received_at.elapsed() < context.move_timeout {
+                return true;
+            }
+            expired.push((*received_at, event.clone()));
+            false
+        });
     }
 
-    async fn watch(&self, dir: &str) -> Result<(), KanshiError> {
-        if let Some(_) = *self.stream.read().await {
-            return Err(KanshiError::ListenerStartedError);
+    expired.sort_by_key(|(received_at, _)| *received_at);
+    for (_, event) in expired {
+        send(context, event);
+    }
+}
+
+impl FSEventsTracer {
+    /// A snapshot of the events sent and dropped so far. `marks_active` counts
+    /// the watched directories and files.
+    pub fn stats(&self) -> EventStatistics {
+        let marks_active =
+            self.context.roots.read().unwrap().len() + self.context.files.read().unwrap().len();
+        self.stats
+            .snapshot(marks_active, self.options.channel_capacity)
+    }
+
+    /// `stats` in the Prometheus text exposition format.
+    #[cfg(feature = "metrics")]
+    pub fn stats_as_prometheus(&self) -> String {
+        self.stats().to_prometheus()
+    }
+
+    /// Adds `dir` to `paths_to_watch`, or its parent directory if `dir` is a
+    /// file, and records it in the callback context.
+    fn add_path(
+        &self,
+        dir: &Path,
+        exclude: HashSet<PathBuf>,
+        paths_to_watch: &mut Vec<PathBuf>,
+    ) -> Result<(), KanshiError> {
+        if self.options.create_watched_dirs {
+            create_watched_dir(dir)?;
         }
 
-        let mut paths_to_watch = self.paths_to_watch.lock().await;
-        let path = path::absolute(Path::new(dir));
-        if let Ok(path) = path {
-            if !path.exists() {
-                Err(KanshiError::FileSystemError(
-                    "ENOENT Directory does not exist".to_owned(),
-                ))
-            } else {
-                paths_to_watch.push(path);
-                Ok(())
+        let path = path::absolute(dir)?;
+        if !path.exists() {
+            return Err(KanshiError::PathNotFound(path));
+        }
+
+        let mut excluded = self.context.excluded.write().unwrap();
+        excluded.extend(canonicalize_all(exclude));
+
+        let canon_path = path.canonicalize()?;
+        if canon_path.to_str().is_none() {
+            return Err(KanshiError::PathNotUtf8(canon_path.into_os_string()));
+        }
+        if !canon_path.is_dir() {
+            let parent = canon_path.parent().unwrap_or(Path::new("/"));
+            if !paths_to_watch.iter().any(|x| x == parent) {
+                paths_to_watch.push(parent.to_path_buf());
             }
-        } else {
-            Err(KanshiError::FileSystemError(
-                path.err().unwrap().to_string(),
-            ))
+            self.context.files.write().unwrap().insert(canon_path);
+        } else if !excluded.contains(&canon_path) {
+            self.context.exclude_filter.add_ignore_files(&canon_path);
+            paths_to_watch.push(path);
+            self.context.roots.write().unwrap().insert(canon_path);
         }
+        Ok(())
     }
 
-    fn get_events_stream(&self) -> Pin<Box<dyn futures::Stream<Item = FileSystemEvent> + Send>> {
-        let mut listener = self.sender.subscribe();
-        let cancel_token = self.cancellation_token.clone();
+    /// Creates a stream for `paths_to_watch`, which still has to be scheduled
+    /// and started. It reports the events recorded after `since`.
+    fn create_stream(
+        &self,
+        paths_to_watch: &[PathBuf],
+        since: CFTypes::FSEventStreamId,
+    ) -> Result<FSEventStreamRef, KanshiError> {
+        let ptr: *const CallbackContext = Arc::as_ptr(&self.context);
+
+        let context = CFTypes::FSEventStreamContext {
+            version: 0 as *mut i64,
+            copy_description: None,
+            retain: None,
+            release: None,
+            info: ptr as *mut c_void,
+        };
 
-        Box::pin(stream! {
-            'outer: loop {
-                tokio::select! {
-                    _ = cancel_token.cancelled() => {
-                        break 'outer;
-                    }
-                    val = listener.recv() => {
-                        match val {
-                            Ok(x) => {
-                              yield x
-                            },
-                            Err(e) => {
-                              match e {
-                                RecvError::Closed => break 'outer,
-                                _ => ()
-                            }}
-                        }
-                    }
+        // drop(ptr);
+
+        let paths_to_watch = unsafe {
+            let paths: CFMutableArrayRef = CoreFoundation::CFArrayCreateMutable(
+                CFTypes::kCFAllocatorDefault,
+                0 as CFIndex,
+                &CoreFoundation::kCFTypeArrayCallBacks,
+            );
+
+            for path in paths_to_watch.iter() {
+                if !path.exists() {
+                    return Err(KanshiError::PathNotFound(path.clone()));
+                }
+
+                let canon_path = path.canonicalize()?;
+                let Some(path_as_str) = canon_path.to_str() else {
+                    return Err(KanshiError::PathNotUtf8(canon_path.into_os_string()));
+                };
+                let err: CFTypes::CFErrorRef = std::ptr::null_mut();
+                let cf_path = CoreFoundation::rust_str_to_cf_string(path_as_str, err);
+                if cf_path.is_null() {
+                    CoreFoundation::CFRelease(err as CFTypes::CFRef);
+                    return Err(KanshiError::PathNotFound(path.clone()));
+                } else {
+                    CoreFoundation::CFArrayAppendValue(paths, cf_path);
+                    CoreFoundation::CFRelease(cf_path);
                 }
             }
-        })
-    }
 
-    async fn start(&self) -> Result<(), KanshiError> {
-        if let Some(_) = *self.stream.read().await {
-            return Err(KanshiError::ListenerStartedError);
+            Ok(paths)
+        };
+
+        let paths_to_watch = paths_to_watch?;
+
+        let mut flags = CFTypes::FSEventStreamCreateFlags::kFSEventStreamCreateFlagFileEvents
+            | CFTypes::FSEventStreamCreateFlags::kFSEventStreamCreateFlagNoDefer
+            | CFTypes::FSEventStreamCreateFlags::kFSEventStreamCreateFlagUseExtendedData
+            | CFTypes::FSEventStreamCreateFlags::kFSEventStreamCreateFlagUseCFTypes;
+        if self.options.ignore_self {
+            flags |= CFTypes::FSEventStreamCreateFlags::kFSEventStreamCreateFlagIgnoreSelf;
         }
 
-        {
-            let paths_to_watch = self.paths_to_watch.lock().await;
-            // let sender = self.sender.clone();
-            let ptr: *const Sender<FileSystemEvent> = &self.sender;
-
-            let context = CFTypes::FSEventStreamContext {
-                version: 0 as *mut i64,
-                copy_description: None,
-                retain: None,
-                release: None,
-                info: ptr as *mut c_void,
-            };
+        let stream = unsafe {
+            CoreFoundation::FSEventStreamCreate(
+                CFTypes::kCFAllocatorDefault,
+                callback,
+                &context,
+                paths_to_watch,
+                since,
+                self.options.fsevents_latency_seconds,
+                flags,
+            )
+        };
+        Ok(stream)
+    }
 
-            // drop(ptr);
-
-            let paths_to_watch = unsafe {
-                let paths: CFMutableArrayRef = CoreFoundation::CFArrayCreateMutable(
-                    CFTypes::kCFAllocatorDefault,
-                    0 as CFIndex,
-                    &CoreFoundation::kCFTypeArrayCallBacks,
-                );
-
-                for path in paths_to_watch.iter() {
-                    if !path.exists() {
-                        return Err(KanshiError::FileSystemError(format!(
-                            "{:?} does not exist",
-                            path
-                        )));
-                    }
+    /// Stops the running stream, if any, and starts a new one for
+    /// `paths_to_watch` on the same dispatch queue. Does nothing before
+    /// `start`. Flushing first delivers the events the old stream has already
+    /// recorded.
+    async fn replace_stream(&self, paths_to_watch: &[PathBuf]) -> Result<(), KanshiError> {
+        let mut stream = self.stream.write().await;
+        // A previous replacement may have failed and left no stream behind.
+        if let Some(old_stream) = stream.take() {
+            unsafe {
+                CoreFoundation::FSEventStreamFlushSync(old_stream.0);
+                CoreFoundation::FSEventStreamStop(old_stream.0);
+                CoreFoundation::FSEventStreamInvalidate(old_stream.0);
+                CoreFoundation::FSEventStreamRelease(old_stream.0);
+            }
+        }
 
-                    let canon_path = path.canonicalize()?;
-                    let path_as_str = canon_path.to_str().unwrap();
-                    let err: CFTypes::CFErrorRef = std::ptr::null_mut();
-                    let cf_path = CoreFoundation::rust_str_to_cf_string(path_as_str, err);
-                    if cf_path.is_null() {
-                        CoreFoundation::CFRelease(err as CFTypes::CFRef);
-                        return Err(KanshiError::FileSystemError(format!(
-                            "{:?} does not exist",
-                            path
-                        )));
-                    } else {
-                        CoreFoundation::CFArrayAppendValue(paths, cf_path);
-                        CoreFoundation::CFRelease(cf_path);
-                    }
-                }
+        if paths_to_watch.is_empty() {
+            return Ok(());
+        }
+        let dispatch_queue = self.dispatch_queue.read().await;
+        let Some(dispatch_queue) = dispatch_queue.as_ref() else {
+            return Ok(());
+        };
+        let new_stream =
+            self.create_stream(paths_to_watch, CFTypes::kFSEventStreamEventIdSinceNow)?;
+        unsafe {
+            CoreFoundation::FSEventStreamSetDispatchQueue(new_stream, dispatch_queue.0);
+            CoreFoundation::FSEventStreamStart(new_stream);
+        }
+        *stream = Some(WrappedEventStreamRef(new_stream));
+        Ok(())
+    }
 
-                Ok(paths)
-            };
+    /// Replaces the running stream with a new one for the same paths, e.g.
+    /// after a watched volume was unmounted and mounted again. The event
+    /// streams are kept, so subscribers keep receiving events without
+    /// subscribing again. Does nothing before `start`.
+    ///
+    /// Fails with `PathNotFound` if a watched path doesn't exist, in which
+    /// case nothing is reported until a later `restart` succeeds.
+    pub async fn restart(&self) -> Result<(), KanshiError> {
+        if self.cancellation_token.is_cancelled() {
+            return Err(KanshiError::StreamClosed);
+        }
+        let paths_to_watch = self.paths_to_watch.lock().await;
+        self.replace_stream(&paths_to_watch).await
+    }
 
-            if let Err(e) = paths_to_watch {
-                return Err(e);
-            }
+    /// The body of `start`, whose error `start` forwards to the streams.
+    async fn run(&self) -> Result<(), KanshiError> {
+        let _running = RunningGuard::acquire(&self.running)?;
+        if let Some(_) = *self.stream.read().await {
+            return Err(KanshiError::ListenerAlreadyStarted);
+        }
 
-            let paths_to_watch = paths_to_watch.ok().unwrap();
-
-            let flags = CFTypes::FSEventStreamCreateFlags::kFSEventStreamCreateFlagFileEvents
-                | CFTypes::FSEventStreamCreateFlags::kFSEventStreamCreateFlagNoDefer
-                | CFTypes::FSEventStreamCreateFlags::kFSEventStreamCreateFlagUseExtendedData
-                | CFTypes::FSEventStreamCreateFlags::kFSEventStreamCreateFlagUseCFTypes;
-
-            let stream = unsafe {
-                CoreFoundation::FSEventStreamCreate(
-                    CFTypes::kCFAllocatorDefault,
-                    callback,
-                    &context,
-                    paths_to_watch,
-                    CFTypes::kFSEventStreamEventIdSinceNow,
-                    0.0,
-                    flags,
-                )
-            };
+        {
+            let paths_to_watch = self.paths_to_watch.lock().await;
+            let stream = self.create_stream(&paths_to_watch, self.options.start_from.event_id())?;
 
             let dispatch_queue = unsafe {
                 CoreFoundation::dispatch_queue_create(
@@ -319,53 +504,290 @@ impl KanshiImpl<KanshiOptions> for FSEventsTracer {
             }
         }
 
-        self.cancellation_token.cancelled().await;
+        // Periodically hand events buffered while nothing was subscribed to new
+        // subscribers, and stop if none appear within the reconnect timeout.
+        // Renames whose other half never arrived are sent here as well.
+        let mut ticker = runtime::Interval::new(Duration::from_millis(100));
+        let sigint = sigint(self.options.handle_sigint);
+        tokio::pin!(sigint);
+        loop {
+            tokio::select! {
+                _ = self.cancellation_token.cancelled() => break,
+                _ = &mut sigint => {
+                    if let Err(e) = self.close() {
+                        (self.options.on_error)(e);
+                    }
+                    break;
+                }
+                _ = ticker.tick() => {
+                    flush_pending_moves(&self.context);
+                    let polled = self.context.sender.lock().unwrap().poll();
+                    if let Err(e) = polled {
+                        if let Err(close_error) = self.close() {
+                            (self.options.on_error)(close_error);
+                        }
+                        return Err(e);
+                    }
+                }
+            }
+        }
 
         // Free the DispatchQueue
         // unsafe { dispatch_release(dispatch_queue) };
 
         Ok(())
     }
+}
+
+impl KanshiImpl<KanshiOptions> for FSEventsTracer {
+    fn new(opts: KanshiOptions) -> Result<FSEventsTracer, KanshiError> {
+        validate_latency(opts.fsevents_latency_seconds)?;
+        if opts.fsevents_latency_seconds > 30.0 {
+            tracing::warn!(
+                latency_seconds = opts.fsevents_latency_seconds,
+                "fsevents_latency_seconds is above 30s, events will be delivered late"
+            );
+        }
+        if opts.watch_open {
+            tracing::warn!("watch_open is not supported by FSEvents, no Open events will be reported");
+        }
+
+        let exclude_filter = PathFilter::new(
+            &opts.exclude_patterns,
+            &opts.exclude_regexes,
+            opts.use_gitignore,
+        )?;
+        let (tx, _rx) = tokio::sync::broadcast::channel(opts.channel_capacity.max(1));
+        let paused = Arc::new(AtomicBool::new(false));
+        let callbacks = EventCallbacks::default();
+        let stats = Arc::new(EventStats::default());
+
+        Ok(FSEventsTracer {
+            stream: Arc::new(RwLock::new(None)),
+            context: Arc::new(CallbackContext {
+                sender: std::sync::Mutex::new(EventSender::new(
+                    tx.clone(),
+                    opts.reconnect_timeout,
+                    opts.reconnect_buffer_size.min(opts.channel_capacity),
+                    opts.grace_period,
+                    opts.debounce_ms.map(Duration::from_millis),
+                    opts.coalesce_window_ms.map(Duration::from_millis),
+                    opts.max_events_per_second,
+                )
+                .with_metadata(opts.include_metadata)
+                .with_hashes(opts.compute_hash_on_modify)
+                .with_hard_links(opts.detect_hard_links)
+                .with_timestamps(opts.approximate_timestamp)
+                .with_pause(paused.clone(), opts.buffer_while_paused)
+                .with_callbacks(callbacks.clone())
+                .with_sequence(Arc::new(AtomicU64::new(opts.initial_sequence)))
+                .with_stats(stats.clone())),
+                on_error: opts.on_error.clone(),
+                excluded: std::sync::RwLock::new(HashSet::new()),
+                exclude_filter,
+                roots: std::sync::RwLock::new(HashSet::new()),
+                files: std::sync::RwLock::new(HashSet::new()),
+                max_depth: opts.depth_limit(),
+                pending_moves: std::sync::Mutex::new(HashMap::new()),
+                move_timeout: Duration::from_secs_f64(opts.fsevents_latency_seconds * 2.0),
+            }),
+            sender: tx,
+            cancellation_token: CancellationToken::new(),
+            paused,
+            running: Arc::new(AtomicBool::new(false)),
+            callbacks,
+            paths_to_watch: Arc::new(Mutex::new(Vec::new())),
+            dispatch_queue: Arc::new(RwLock::new(None)),
+            options: Arc::new(opts),
+            stats,
+        })
+    }
+
+    async fn watch(&self, dir: impl AsRef<Path> + Send) -> Result<(), KanshiError> {
+        self.watch_with_exclude_dirs(dir, HashSet::new()).await
+    }
+
+    async fn watch_with_exclude_dirs(
+        &self,
+        dir: impl AsRef<Path> + Send,
+        exclude: HashSet<PathBuf>,
+    ) -> Result<(), KanshiError> {
+        let dir = dir.as_ref();
+        // The stream itself is gone once `unwatch` removes the last path.
+        if let Some(_) = *self.dispatch_queue.read().await {
+            return Err(KanshiError::ListenerAlreadyStarted);
+        }
+
+        let mut paths_to_watch = self.paths_to_watch.lock().await;
+        self.add_path(dir, exclude, &mut paths_to_watch)
+    }
+
+    async fn watch_many(
+        &self,
+        dirs: impl IntoIterator<Item = impl AsRef<Path>> + Send,
+    ) -> Result<(), KanshiError> {
+        if let Some(_) = *self.dispatch_queue.read().await {
+            return Err(KanshiError::ListenerAlreadyStarted);
+        }
+
+        // The stream is only created by `start`, so it is created once for
+        // all of `dirs`. Paths are added to a copy that is only kept once all
+        // of them were added.
+        let mut paths_to_watch = self.paths_to_watch.lock().await;
+        let mut pending = paths_to_watch.clone();
+        let roots = self.context.roots.read().unwrap().clone();
+        let files = self.context.files.read().unwrap().clone();
+
+        let added = dirs
+            .into_iter()
+            .try_for_each(|dir| self.add_path(dir.as_ref(), HashSet::new(), &mut pending));
+        if added.is_ok() {
+            *paths_to_watch = pending;
+        } else {
+            *self.context.roots.write().unwrap() = roots;
+            *self.context.files.write().unwrap() = files;
+        }
+        added
+    }
+
+    async fn unwatch(&self, dir: impl AsRef<Path> + Send) -> Result<(), KanshiError> {
+        let dir = dir.as_ref();
+        let path = path::absolute(dir)?;
+
+        let mut paths_to_watch = self.paths_to_watch.lock().await;
+        let file = dir.canonicalize().unwrap_or_else(|_| path.clone());
+        let mut files = self.context.files.write().unwrap();
+        if files.remove(&file) {
+            // The parent directory stays watched while it is still needed.
+            let parent = file.parent().unwrap_or(Path::new("/"));
+            let needed = files.iter().any(|x| x.parent() == Some(parent))
+                || self.context.roots.read().unwrap().contains(parent);
+            drop(files);
+            if needed {
+                return Ok(());
+            }
+            if let Some(index) = paths_to_watch.iter().position(|x| x == parent) {
+                paths_to_watch.remove(index);
+            }
+        } else {
+            drop(files);
+            let Some(index) = paths_to_watch.iter().position(|x| *x == path) else {
+                return Err(KanshiError::PathNotWatched(dir.to_path_buf()));
+            };
+            let removed = paths_to_watch.remove(index);
+            if let Ok(canon_path) = removed.canonicalize() {
+                self.context.roots.write().unwrap().remove(&canon_path);
+            }
+        }
+        if self.cancellation_token.is_cancelled() {
+            return Ok(());
+        }
+
+        // Streams can't be changed once created, so a running stream is
+        // replaced by one for the remaining paths.
+        self.replace_stream(&paths_to_watch).await
+    }
+
+    fn get_watched_paths(&self) -> Vec<PathBuf> {
+        // `paths_to_watch` holds parent directories in place of watched files.
+        let mut paths: Vec<PathBuf> = self.context.roots.read().unwrap().iter().cloned().collect();
+        paths.extend(self.context.files.read().unwrap().iter().cloned());
+        paths.sort();
+        paths
+    }
+
+    fn get_events_stream(&self) -> Pin<Box<dyn futures::Stream<Item = KanshiStreamItem> + Send>> {
+        let mut listener = self.sender.subscribe();
+        let cancel_token = self.cancellation_token.clone();
+        let stats = self.stats.clone();
+
+        Box::pin(stream! {
+            'outer: loop {
+                tokio::select! {
+                    _ = cancel_token.cancelled() => {
+                        break 'outer;
+                    }
+                    val = listener.recv() => {
+                        match val {
+                            Ok(x) => {
+                              let failed = x.is_error();
+                              yield x;
+                              if failed {
+                                break 'outer;
+                              }
+                            },
+                            Err(RecvError::Closed) => break 'outer,
+                            Err(RecvError::Lagged(count)) => stats.record_dropped(count),
+                        }
+                    }
+                }
+            }
+        })
+    }
+
+    async fn start(&self) -> Result<(), KanshiError> {
+        let result = self.run().await;
+        report_error(&self.sender, result)
+    }
+
+    fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+    }
 
-    fn close(&self) -> bool {
+    fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    fn on_event<F>(&self, callback: F)
+    where
+        F: Fn(FileSystemEvent) + Send + Sync + 'static,
+    {
+        self.callbacks.write().unwrap().push(Box::new(callback));
+    }
+
+    fn close(&self) -> Result<(), KanshiError> {
         if self.cancellation_token.is_cancelled() {
-            return true;
+            return Ok(());
         }
 
         self.cancellation_token.cancel();
 
-        let mut has_errored = false;
+        let mut errors = Vec::new();
 
-        let stream_ref = self.stream.try_read();
-        if let Ok(stream) = stream_ref {
-            if stream.is_some() {
-                let stream = stream.as_ref().unwrap();
-                unsafe {
-                    CoreFoundation::FSEventStreamStop(stream.0);
-                    CoreFoundation::FSEventStreamInvalidate(stream.0);
-                    CoreFoundation::FSEventStreamRelease(stream.0);
-                };
+        match self.stream.try_read() {
+            Ok(stream) => {
+                if let Some(stream) = stream.as_ref() {
+                    unsafe {
+                        CoreFoundation::FSEventStreamStop(stream.0);
+                        CoreFoundation::FSEventStreamInvalidate(stream.0);
+                        CoreFoundation::FSEventStreamRelease(stream.0);
+                    };
+                }
+            }
+            Err(e) => {
+                let e = io::Error::other(format!("failed to release the FSEvents stream: {e}"));
+                errors.push(e.into());
             }
-        } else {
-            let e = stream_ref.err().unwrap();
-            eprintln!("error occurred releasing stream {e}");
-            has_errored = true;
         }
 
-        let dq_ref = self.dispatch_queue.try_read();
-        if let Ok(dq) = dq_ref {
-            if dq.is_some() {
-                let dq = dq.as_ref().unwrap();
-                unsafe {
-                    CoreFoundation::dispatch_release(dq.0);
-                };
+        match self.dispatch_queue.try_read() {
+            Ok(dq) => {
+                if let Some(dq) = dq.as_ref() {
+                    unsafe {
+                        CoreFoundation::dispatch_release(dq.0);
+                    };
+                }
+            }
+            Err(e) => {
+                let e = io::Error::other(format!("failed to release the dispatch queue: {e}"));
+                errors.push(e.into());
             }
-        } else {
-            let e = dq_ref.err().unwrap();
-            eprintln!("error occurred releasing stream {e}");
-            has_errored = true;
         }
 
-        !has_errored
+        KanshiError::from_errors(errors)
     }
 }
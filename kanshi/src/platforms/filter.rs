@@ -0,0 +1,153 @@
+use std::{
+    collections::BTreeMap,
+    os::unix::ffi::OsStrExt,
+    path::Path,
+    sync::RwLock,
+};
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::{
+    gitignore::{Gitignore, GitignoreBuilder},
+    Match, WalkBuilder,
+};
+use regex::bytes::RegexSet;
+
+use crate::KanshiError;
+
+/// `KanshiOptions::exclude_patterns` and `KanshiOptions::exclude_regexes`,
+/// compiled once when a tracer is created. A path is excluded if either of
+/// them matches its full path.
+///
+/// With `KanshiOptions::use_gitignore`, paths ignored by the ignore files
+/// found by `add_ignore_files` are excluded as well, along with `.git`
+/// directories.
+pub(crate) struct PathFilter {
+    patterns: GlobSet,
+    regexes: RegexSet,
+    use_gitignore: bool,
+    /// One matcher per directory with ignore files, deepest first.
+    gitignores: RwLock<Vec<Gitignore>>,
+}
+
+impl PathFilter {
+    /// Compiles the glob `patterns` and `regexes`. Invalid globs are reported
+    /// as `InvalidParameter` and invalid regexes as `InvalidPattern`.
+    pub(crate) fn new(
+        patterns: &[String],
+        regexes: &[String],
+        use_gitignore: bool,
+    ) -> Result<PathFilter, KanshiError> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in patterns {
+            let glob =
+                Glob::new(pattern).map_err(|e| KanshiError::InvalidParameter(e.to_string()))?;
+            builder.add(glob);
+        }
+
+        Ok(PathFilter {
+            patterns: builder
+                .build()
+                .map_err(|e| KanshiError::InvalidParameter(e.to_string()))?,
+            regexes: RegexSet::new(regexes)
+                .map_err(|e| KanshiError::InvalidPattern(e.to_string()))?,
+            use_gitignore,
+            gitignores: RwLock::new(Vec::new()),
+        })
+    }
+
+    /// With `use_gitignore`, reads the `.gitignore` and `.ignore` files below
+    /// the watched directory `root`, and the `.git/info/exclude` of any
+    /// repository in it. Directories that are already ignored are not
+    /// searched. Ignore files that can't be read are skipped with a warning.
+    pub(crate) fn add_ignore_files(&self, root: &Path) {
+        if !self.use_gitignore {
+            return;
+        }
+
+        let root = root.canonicalize().unwrap_or_else(|_| root.to_path_buf());
+        // The root always gets a matcher, so its `.git` is excluded as well.
+        let mut found = BTreeMap::from([(root.clone(), Vec::new())]);
+        let walk = WalkBuilder::new(&root)
+            .hidden(false)
+            .parents(false)
+            .require_git(false)
+            .filter_entry(|entry| entry.file_name() != ".git")
+            .build();
+        for entry in walk.flatten() {
+            let path = entry.path();
+            if entry.file_type().is_some_and(|x| x.is_dir()) {
+                let exclude = path.join(".git/info/exclude");
+                if exclude.is_file() {
+                    found.entry(path.to_path_buf()).or_default().push(exclude);
+                }
+            } else if entry.file_name() == ".gitignore" || entry.file_name() == ".ignore" {
+                let dir = path.parent().unwrap_or(&root).to_path_buf();
+                found.entry(dir).or_default().push(path.to_path_buf());
+            }
+        }
+
+        let mut gitignores = self.gitignores.write().unwrap();
+        gitignores.retain(|x| !found.contains_key(x.path()));
+        for (dir, mut files) in found {
+            // Later files take precedence: `.git/info/exclude`, then
+            // `.gitignore`, then `.ignore`, as with git and ripgrep.
+            files.sort_by_key(|x| x.ends_with(".ignore"));
+            let mut builder = GitignoreBuilder::new(&dir);
+            for file in files {
+                if let Some(e) = builder.add(&file) {
+                    tracing::warn!(file = %file.display(), error = %e, "failed to read an ignore file");
+                }
+            }
+            match builder.build() {
+                Ok(gitignore) => gitignores.push(gitignore),
+                Err(e) => tracing::warn!(
+                    dir = %dir.display(),
+                    error = %e,
+                    "failed to read the ignore files"
+                ),
+            }
+        }
+        gitignores.sort_by_key(|x| std::cmp::Reverse(x.path().components().count()));
+    }
+
+    /// Whether `path` is ignored by the closest ignore file that has a rule
+    /// for it, or is inside a `.git` directory.
+    fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        if !self.use_gitignore {
+            return false;
+        }
+
+        for gitignore in self.gitignores.read().unwrap().iter() {
+            let Ok(relative) = path.strip_prefix(gitignore.path()) else {
+                continue;
+            };
+            if relative.components().any(|x| x.as_os_str() == ".git") {
+                return true;
+            }
+            match gitignore.matched_path_or_any_parents(path, is_dir) {
+                Match::None => {}
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+            }
+        }
+        false
+    }
+
+    /// Whether `path` is excluded.
+    pub(crate) fn is_match(&self, path: impl AsRef<Path>) -> bool {
+        let path = path.as_ref();
+        self.patterns.is_match(path)
+            || self.regexes.is_match(path.as_os_str().as_bytes())
+            // Patterns like `build/` only match directories.
+            || (self.use_gitignore && self.is_ignored(path, path.is_dir()))
+    }
+
+    /// Whether the directory at `path` is excluded, either itself or as a
+    /// parent of the entries below it, so that `**/target/**` excludes the
+    /// `target` directory as well as everything inside it.
+    pub(crate) fn is_match_dir(&self, path: &Path) -> bool {
+        let is_empty = self.patterns.is_empty() && self.regexes.is_empty();
+        (!is_empty && (self.is_match(path) || self.is_match(path.join(""))))
+            || self.is_ignored(path, true)
+    }
+}
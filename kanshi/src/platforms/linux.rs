@@ -1,11 +1,24 @@
-use std::{borrow::Borrow, pin::Pin};
+use std::{
+    borrow::Borrow,
+    collections::HashSet,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use crate::{KanshiError, KanshiImpl};
+use crate::{
+    default_error_handler,
+    platforms::{validate_watch_dir, PollTracer, WatchFilters},
+    ErrorHandler, EventTypeMask, FileSystemEvent, KanshiError, KanshiImpl, WatchHandle,
+};
 
 #[derive(Clone)]
 pub enum KanshiEngines {
     Fanotify,
     Inotify,
+    /// Compares periodic scans of the watched directories. See [`PollTracer`].
+    Poll,
 }
 
 impl KanshiEngines {
@@ -13,33 +26,790 @@ impl KanshiEngines {
         match string {
             "fanotify" => Ok(KanshiEngines::Fanotify),
             "inotify" => Ok(KanshiEngines::Inotify),
+            "poll" => Ok(KanshiEngines::Poll),
             _ => Err(KanshiError::InvalidParameter(
-                "Invalid engine. Allowed values are: 'fanotify', 'inotify'.".to_owned(),
+                "Invalid engine. Allowed values are: 'fanotify', 'inotify', 'poll'.".to_owned(),
             )),
         }
     }
 }
 
+mod availability;
 mod fanotify;
 mod inotify;
+mod path_remap;
+mod resolve;
+mod traverse;
+mod uring;
 
 use async_stream::stream;
+pub use availability::*;
 pub use fanotify::*;
 pub use inotify::*;
 
+/// Strategies the fanotify engine can use to resolve the path of an event.
+/// The inotify engine always knows the path of its watches and ignores this.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PathResolutionMode {
+    /// Opens the parent directory by handle, reads its path from
+    /// `/proc/self/fd` and appends the entry name from the event.
+    #[default]
+    ProcFs,
+    /// Only reports the entry name from the event, without opening anything.
+    /// This is the fastest mode, but paths are relative to an unknown parent,
+    /// `device_inode` is never set, and new subdirectories are not watched.
+    NameOnly,
+    /// Like `ProcFs`, but also opens the entry itself and reads its resolved
+    /// path from `/proc/self/fd`, falling back to `ProcFs` behaviour when the
+    /// entry no longer exists.
+    Full,
+    /// Resolves the parent directory relative to the watched directories with
+    /// `openat2(RESOLVE_BENEATH)` instead of reading `/proc/self/fd`. Useful in
+    /// containers where `/proc` is unavailable or restricted.
+    Beneath,
+}
+
+/// How the fanotify and inotify engines wait for their file descriptors to
+/// become readable.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LinuxBackend {
+    /// Waits in `epoll_wait` for a few milliseconds at a time, which holds
+    /// up the runtime worker thread the tracer runs on while it waits.
+    #[default]
+    Epoll,
+    /// Submits `IORING_OP_POLL_ADD` requests to an io_uring and awaits their
+    /// completions through an eventfd registered with the ring, without
+    /// blocking a worker thread. Requires Linux 5.1, and io_uring must not be
+    /// disabled with `kernel.io_uring_disabled`. With async-std, the eventfd
+    /// is waited on from its blocking thread pool instead.
+    IoUring,
+}
+
 pub struct KanshiOptions {
+    /// The engine to use. When `None`, fanotify is used when running as root
+    /// and inotify otherwise. Root falls back to inotify if it is not
+    /// permitted to use fanotify, e.g. in a container without
+    /// `CAP_SYS_ADMIN`, or if the kernel lacks the fanotify features the
+    /// engine needs. Polling is only used if inotify is unavailable as well.
+    /// Defaults to `None`.
     pub force_engine: Option<KanshiEngines>,
+    /// How the fanotify and inotify engines wait for events. The poll engine
+    /// ignores this. `Kanshi::new` fails if [`LinuxBackend::IoUring`] is
+    /// chosen on a kernel older than 5.1. Defaults to
+    /// [`LinuxBackend::Epoll`].
+    pub backend: LinuxBackend,
+    /// Called whenever a non-fatal error occurs while processing events, e.g. a
+    /// path that could not be resolved or an unknown event mask. Defaults to
+    /// logging the error with `tracing::error!`.
+    pub on_error: ErrorHandler,
+    /// How the fanotify engine turns event records into paths. Defaults to
+    /// [`PathResolutionMode::ProcFs`].
+    pub path_resolution_mode: PathResolutionMode,
+    /// Make the fanotify engine watch a directory with a single
+    /// `FAN_MARK_FILESYSTEM` mark on the filesystem it lives on, found with
+    /// `statvfs`, instead of marking every directory below it. This avoids
+    /// running out of marks on large trees, but the kernel then reports every
+    /// change on that filesystem, and events outside the watched directories
+    /// are only dropped afterwards. Falls back to marking every directory on
+    /// kernels older than 4.20. Not supported with
+    /// [`PathResolutionMode::NameOnly`]. Defaults to `false`.
+    pub use_filesystem_mark: bool,
+    /// Make the fanotify engine watch a directory with a single
+    /// `FAN_MARK_MOUNT` mark on the mount it lives on, found in
+    /// `/proc/self/mountinfo`, instead of marking every directory below it.
+    /// Useful for a dedicated partition or container volume. Mount marks can't
+    /// report directory entry events, so only `Modify`, and `CloseWrite`,
+    /// `Access` and `Open` when enabled, are reported, never `Create`,
+    /// `Delete`, `Move` or `AttributeChange`. Events outside the watched
+    /// directories are dropped after the kernel reports them. Can't be
+    /// combined with `use_filesystem_mark` or
+    /// [`PathResolutionMode::NameOnly`]. Defaults to `false`.
+    pub mount_level_watch: bool,
+    /// Follow symlinked directories while walking a watched tree and watch
+    /// their targets as well. Directories reachable through more than one link
+    /// are only watched once, which also stops links that point back up the
+    /// tree from looping. A watched directory that is itself a symlink is
+    /// always resolved to its target, whose paths events are reported for.
+    /// Defaults to `false`.
+    pub follow_symlinks: bool,
+    /// Watch every directory below a watched directory, including ones
+    /// created later. When `false`, only events for entries directly inside a
+    /// watched directory are reported. Defaults to `true`.
+    pub recursive: bool,
+    /// How many levels of directories below a watched directory to watch.
+    /// `Some(0)` only reports entries directly inside the watched directory,
+    /// the same as turning off `recursive`, and `Some(1)` also reports entries
+    /// inside its subdirectories. `None` watches the whole tree. Only applies
+    /// when `recursive` is set. Defaults to `None`.
+    pub max_depth: Option<usize>,
+    /// Read directories in parallel on the rayon thread pool while walking a
+    /// watched tree. Marks are still added one at a time. Speeds up watching
+    /// very large trees. Defaults to `false`.
+    pub parallel_scan: bool,
+    /// Create a watched directory, along with any missing parents, when it does
+    /// not exist yet instead of failing. Directories are created with the
+    /// default permissions (`0o777` masked by the umask, usually `0o755`).
+    /// `watch` returns the error if creation fails. Defaults to `false`.
+    pub create_watched_dirs: bool,
+    /// Glob patterns, e.g. `**/target/**`, matched against the full path of
+    /// every event. Matching events are dropped before they reach any stream,
+    /// and matching directories are not watched at all, which keeps large
+    /// ignored trees from using up marks. With `use_filesystem_mark` or
+    /// `mount_level_watch`, the kernel still reports events below excluded
+    /// directories and they are only dropped here, since fanotify can't
+    /// filter by path. Invalid patterns make `new` fail with
+    /// `InvalidParameter`. Defaults to no patterns.
+    pub exclude_patterns: Vec<String>,
+    /// Regular expressions, e.g. `\.sw[po]$`, matched against the full path
+    /// of every event in the same way as `exclude_patterns`. An event is
+    /// dropped if either a pattern or a regex matches. Invalid regexes make
+    /// `new` fail with `InvalidPattern`. Defaults to no regexes.
+    pub exclude_regexes: Vec<String>,
+    /// Also exclude the paths ignored by the `.gitignore` and `.ignore` files
+    /// in the watched directories, and by `.git/info/exclude`, along with
+    /// `.git` directories themselves. Ignored directories are not watched.
+    /// The ignore files are read once by `watch`, so later changes to them
+    /// are not picked up. Defaults to `false`.
+    pub use_gitignore: bool,
+    /// Report the deletion of files that are still held open (`FAN_DELETE_SELF`)
+    /// and when a file opened for writing is closed (`FAN_CLOSE_WRITE`). Files
+    /// are marked individually once they are created, so a file that is
+    /// created, unlinked and then closed reports `Create`, `Delete`,
+    /// `CloseWrite` and finally `Delete` again once its last link and
+    /// descriptor are gone. Files that existed before they were watched are
+    /// not marked. Only supported by the fanotify engine. Defaults to `false`.
+    pub watch_open_file_deletion: bool,
+    /// Report `CloseWrite` when a file that was opened for writing is closed
+    /// (`FAN_CLOSE_WRITE`/`IN_CLOSE_WRITE`), which signals that a write has
+    /// completed. Defaults to `false`.
+    pub watch_close_write: bool,
+    /// Report `Access` events when a file is read (`FAN_ACCESS`/`IN_ACCESS`).
+    /// Reads are far more frequent than writes, so this can easily fill up
+    /// the channel of a slow consumer. Defaults to `false`.
+    pub watch_access: bool,
+    /// Report `Open` events when a file or directory is opened
+    /// (`FAN_OPEN`/`IN_OPEN`), before it is read from or written to. Nearly
+    /// every file operation starts with an open, so on a busy system this
+    /// produces more events than any other option. The fanotify engine skips
+    /// the `Open` when the kernel merged it with an `Access` or `Modify` of
+    /// the same file, since those imply it. Not supported by the poll engine.
+    /// Defaults to `false`.
+    pub watch_open: bool,
+    /// Hold up opens and reads of the watched files (`FAN_OPEN_PERM` and
+    /// `FAN_ACCESS_PERM`) until they are allowed or denied through a
+    /// `KanshiStreamItem::PermissionRequest`. The requests come from a second
+    /// fanotify group of class `FAN_CLASS_PRE_CONTENT`, since the kernel
+    /// doesn't allow permission events in the notification group that
+    /// reports directory entries. Only supported by the fanotify engine, and
+    /// not with `use_filesystem_mark` or `mount_level_watch`. Defaults to
+    /// `false`.
+    pub permission_mode: bool,
+    /// The share of the fanotify mark limit, between 0 and 1, past which
+    /// `KanshiError::MarkQuotaNearlyExhausted` is passed to `on_error`, once.
+    /// See [`FanotifyTracer::marks_remaining`]. Values outside that range make
+    /// `build` fail with `InvalidParameter`. The fanotify groups are created
+    /// with `FAN_UNLIMITED_MARKS`, which exempts them from the limit, unless
+    /// this is set. Defaults to `None`.
+    pub mark_quota_warn_threshold: Option<f32>,
+    /// Close the tracer when SIGINT (Ctrl+C) is received while `start` is
+    /// running, so `start` returns `Ok(())` with all marks removed instead of
+    /// the process being killed. Installing the handler stops Ctrl+C from
+    /// terminating the process, so the application is expected to exit once
+    /// `start` returns. Defaults to `false`.
+    pub handle_sigint: bool,
+    /// Number of events each stream can fall behind the tracer before it
+    /// starts missing events. Every slot holds one event, so a larger capacity
+    /// uses more memory but lets slow consumers ride out bursts such as a
+    /// `git checkout` touching thousands of files. Defaults to 1024.
+    pub channel_capacity: usize,
+    /// How long the tracer keeps running while no stream is subscribed before
+    /// giving up with `StreamClosed`. Events received in the meantime are
+    /// buffered and delivered to the next subscriber. Defaults to 5 seconds.
+    pub reconnect_timeout: Duration,
+    /// Maximum number of events buffered while no stream is subscribed. The
+    /// oldest events are dropped first. At most `channel_capacity` events are
+    /// kept, since the next subscriber would miss the others anyway. Defaults
+    /// to 1024.
+    pub reconnect_buffer_size: usize,
+    /// How long `Delete` events are held back before being emitted. A delete
+    /// is dropped if a `Create` for the same path arrives within this window,
+    /// so atomic saves (write to a temporary file, then rename it over the
+    /// original) only report the `Create`. Held deletes are emitted after any
+    /// events that arrive while they are held. Defaults to zero, which
+    /// disables holding.
+    pub grace_period: Duration,
+    /// Debounce window in milliseconds. Events are held per path and only
+    /// the last one is emitted, once the path has seen no further events for
+    /// this long. Events without a target path are not delayed. Defaults to
+    /// `None`, which emits every event as it arrives.
+    pub debounce_ms: Option<u64>,
+    /// Coalescing window in milliseconds. All events a path sees within this
+    /// long of its first event are merged into a single event, reported as
+    /// `FileSystemEventType::Compound` if there was more than one kind. A path
+    /// created and deleted within the window reports nothing. Moves are never
+    /// merged. Defaults to `None`, which disables coalescing.
+    pub coalesce_window_ms: Option<u64>,
+    /// Maximum number of events reported per second, with bursts of up to a
+    /// second's worth allowed. Past that, events are discarded until the rate
+    /// falls back below the limit, after which a single
+    /// `FileSystemEventType::RateLimitExceeded` reports how many were dropped.
+    /// Defaults to `None`, which reports every event.
+    pub max_events_per_second: Option<u32>,
+    /// Read the size, modification time and mode of the target of `Create`
+    /// and `Modify` events as soon as they are received, and report them in
+    /// `FileSystemTarget::metadata`. This costs a `stat` call per event.
+    /// Defaults to `false`.
+    pub include_metadata: bool,
+    /// Hash the contents of the target of `Modify` events with SHA-256, and
+    /// report the hash in `EventMetadata::hash`, along with the rest of
+    /// `FileSystemTarget::metadata`. Files are read in full on a separate
+    /// thread, and the events behind a `Modify` wait for its hash to keep
+    /// them in order. Files larger than 64 MiB are not hashed. Defaults to
+    /// `false`.
+    pub compute_hash_on_modify: bool,
+    /// Report files created with more than one link, i.e. new hard links to
+    /// an existing file, as `FileSystemTargetKind::HardLink`. This costs a
+    /// `stat` call per `Create` event. Defaults to `false`.
+    pub detect_hard_links: bool,
+    /// Fill in `FileSystemEvent::timestamp` with the time each event was read
+    /// from the kernel. None of the backends report when an event happened,
+    /// so this is only an approximation, which can lag behind by however long
+    /// the event was queued. Defaults to `false`.
+    pub approximate_timestamp: bool,
+    /// The number given to the first event in `FileSystemEvent::sequence`,
+    /// e.g. to carry on from the last event a previous tracer reported.
+    /// Defaults to `0`.
+    pub initial_sequence: u64,
+    /// Keep the events received while the tracer is paused with `pause`, and
+    /// deliver them after `resume`, instead of discarding them. At most
+    /// `reconnect_buffer_size` events are kept, dropping the oldest. Defaults
+    /// to `false`.
+    pub buffer_while_paused: bool,
+    /// Accepted for compatibility with macOS, where it leaves out the events
+    /// caused by this process. None of the Linux engines can do that: fanotify
+    /// only skips the events caused through the file descriptors it hands out
+    /// with each event, and inotify does not report which process caused an
+    /// event. Setting it only logs a warning when the tracer is created. With
+    /// fanotify, events caused by this process can be told apart by comparing
+    /// `FileSystemEvent::pid` with `std::process::id()`. Defaults to `false`.
+    pub ignore_self: bool,
+    /// How often the poll engine scans the watched directories, in
+    /// milliseconds. Changes are reported up to this long after they happen.
+    /// Ignored by the other engines. Defaults to 1000.
+    pub poll_interval_ms: u64,
+    /// How many more times the fanotify engine tries to open the directory of
+    /// an event when its file handle is stale (`ESTALE`), which happens when
+    /// the directory is deleted right after the event. If every attempt fails,
+    /// an `Unknown` event is reported with only the entry name as its path.
+    /// Defaults to 1.
+    pub stale_retry_count: usize,
+    /// Make the fanotify engine report paths below an overlay mount, such as
+    /// the root of a Docker container, for files whose paths resolve into one
+    /// of the mount's upper or lower directories instead. The overlay mounts
+    /// are read from `/proc/self/mountinfo` when the tracer is created. The
+    /// inotify and poll engines report paths below the watched directories
+    /// already. Defaults to `false`.
+    pub overlay_remap: bool,
+}
+
+impl KanshiOptions {
+    /// Sets [`KanshiOptions::force_engine`].
+    pub fn with_engine(mut self, engine: KanshiEngines) -> KanshiOptions {
+        self.force_engine = Some(engine);
+        self
+    }
+
+    /// Sets [`KanshiOptions::backend`].
+    pub fn with_backend(mut self, backend: LinuxBackend) -> KanshiOptions {
+        self.backend = backend;
+        self
+    }
+
+    /// Sets [`KanshiOptions::on_error`].
+    pub fn with_on_error(
+        mut self,
+        on_error: impl Fn(KanshiError) + Send + Sync + 'static,
+    ) -> KanshiOptions {
+        self.on_error = Arc::new(on_error);
+        self
+    }
+
+    /// Sets [`KanshiOptions::path_resolution_mode`].
+    pub fn with_path_resolution_mode(mut self, mode: PathResolutionMode) -> KanshiOptions {
+        self.path_resolution_mode = mode;
+        self
+    }
+
+    /// Same as [`KanshiOptions::with_path_resolution_mode`].
+    pub fn path_resolution_mode(self, mode: PathResolutionMode) -> KanshiOptions {
+        self.with_path_resolution_mode(mode)
+    }
+
+    /// Sets [`KanshiOptions::use_filesystem_mark`].
+    pub fn with_use_filesystem_mark(mut self, use_filesystem_mark: bool) -> KanshiOptions {
+        self.use_filesystem_mark = use_filesystem_mark;
+        self
+    }
+
+    /// Sets [`KanshiOptions::mount_level_watch`].
+    pub fn with_mount_level_watch(mut self, mount_level_watch: bool) -> KanshiOptions {
+        self.mount_level_watch = mount_level_watch;
+        self
+    }
+
+    /// Sets [`KanshiOptions::follow_symlinks`].
+    pub fn with_follow_symlinks(mut self, follow_symlinks: bool) -> KanshiOptions {
+        self.follow_symlinks = follow_symlinks;
+        self
+    }
+
+    /// Sets [`KanshiOptions::recursive`].
+    pub fn with_recursive(mut self, recursive: bool) -> KanshiOptions {
+        self.recursive = recursive;
+        self
+    }
+
+    /// Sets [`KanshiOptions::max_depth`].
+    pub fn with_max_depth(mut self, max_depth: usize) -> KanshiOptions {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    /// The deepest level below a watched directory that is watched, taking
+    /// both `recursive` and `max_depth` into account.
+    pub(crate) fn depth_limit(&self) -> Option<usize> {
+        if self.recursive {
+            self.max_depth
+        } else {
+            Some(0)
+        }
+    }
+
+    /// Sets [`KanshiOptions::parallel_scan`].
+    pub fn with_parallel_scan(mut self, parallel_scan: bool) -> KanshiOptions {
+        self.parallel_scan = parallel_scan;
+        self
+    }
+
+    /// Sets [`KanshiOptions::create_watched_dirs`].
+    pub fn with_create_watched_dirs(mut self, create_watched_dirs: bool) -> KanshiOptions {
+        self.create_watched_dirs = create_watched_dirs;
+        self
+    }
+
+    /// Sets [`KanshiOptions::exclude_patterns`].
+    pub fn with_exclude_patterns(
+        mut self,
+        exclude_patterns: impl IntoIterator<Item = impl Into<String>>,
+    ) -> KanshiOptions {
+        self.exclude_patterns = exclude_patterns.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Sets [`KanshiOptions::exclude_regexes`].
+    pub fn with_exclude_regexes(
+        mut self,
+        exclude_regexes: impl IntoIterator<Item = impl Into<String>>,
+    ) -> KanshiOptions {
+        self.exclude_regexes = exclude_regexes.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Sets [`KanshiOptions::use_gitignore`].
+    pub fn with_use_gitignore(mut self, use_gitignore: bool) -> KanshiOptions {
+        self.use_gitignore = use_gitignore;
+        self
+    }
+
+    /// Sets [`KanshiOptions::watch_open_file_deletion`].
+    pub fn with_watch_open_file_deletion(mut self, watch_open_file_deletion: bool) -> KanshiOptions {
+        self.watch_open_file_deletion = watch_open_file_deletion;
+        self
+    }
+
+    /// Sets [`KanshiOptions::watch_close_write`].
+    pub fn with_watch_close_write(mut self, watch_close_write: bool) -> KanshiOptions {
+        self.watch_close_write = watch_close_write;
+        self
+    }
+
+    /// Sets [`KanshiOptions::watch_access`].
+    pub fn with_watch_access(mut self, watch_access: bool) -> KanshiOptions {
+        self.watch_access = watch_access;
+        self
+    }
+
+    /// Sets [`KanshiOptions::watch_open`].
+    pub fn with_watch_open(mut self, watch_open: bool) -> KanshiOptions {
+        self.watch_open = watch_open;
+        self
+    }
+
+    /// Sets [`KanshiOptions::mark_quota_warn_threshold`].
+    pub fn with_mark_quota_warn_threshold(
+        mut self,
+        mark_quota_warn_threshold: f32,
+    ) -> KanshiOptions {
+        self.mark_quota_warn_threshold = Some(mark_quota_warn_threshold);
+        self
+    }
+
+    /// Sets [`KanshiOptions::permission_mode`].
+    pub fn with_permission_mode(mut self, permission_mode: bool) -> KanshiOptions {
+        self.permission_mode = permission_mode;
+        self
+    }
+
+    /// Sets [`KanshiOptions::handle_sigint`].
+    pub fn with_handle_sigint(mut self, handle_sigint: bool) -> KanshiOptions {
+        self.handle_sigint = handle_sigint;
+        self
+    }
+
+    /// Sets [`KanshiOptions::channel_capacity`].
+    pub fn with_channel_capacity(mut self, channel_capacity: usize) -> KanshiOptions {
+        self.channel_capacity = channel_capacity;
+        self
+    }
+
+    /// Sets [`KanshiOptions::reconnect_timeout`].
+    pub fn with_reconnect_timeout(mut self, reconnect_timeout: Duration) -> KanshiOptions {
+        self.reconnect_timeout = reconnect_timeout;
+        self
+    }
+
+    /// Sets [`KanshiOptions::reconnect_buffer_size`].
+    pub fn with_reconnect_buffer_size(mut self, reconnect_buffer_size: usize) -> KanshiOptions {
+        self.reconnect_buffer_size = reconnect_buffer_size;
+        self
+    }
+
+    /// Sets [`KanshiOptions::grace_period`].
+    pub fn with_grace_period(mut self, grace_period: Duration) -> KanshiOptions {
+        self.grace_period = grace_period;
+        self
+    }
+
+    /// Sets [`KanshiOptions::debounce_ms`].
+    pub fn with_debounce_ms(mut self, debounce_ms: u64) -> KanshiOptions {
+        self.debounce_ms = Some(debounce_ms);
+        self
+    }
+
+    /// Sets [`KanshiOptions::coalesce_window_ms`].
+    pub fn with_coalesce_window_ms(mut self, coalesce_window_ms: u64) -> KanshiOptions {
+        self.coalesce_window_ms = Some(coalesce_window_ms);
+        self
+    }
+
+    /// Sets [`KanshiOptions::max_events_per_second`].
+    pub fn with_max_events_per_second(mut self, max_events_per_second: u32) -> KanshiOptions {
+        self.max_events_per_second = Some(max_events_per_second);
+        self
+    }
+
+    /// Sets [`KanshiOptions::include_metadata`].
+    pub fn with_include_metadata(mut self, include_metadata: bool) -> KanshiOptions {
+        self.include_metadata = include_metadata;
+        self
+    }
+
+    /// Sets [`KanshiOptions::compute_hash_on_modify`].
+    pub fn with_compute_hash_on_modify(mut self, compute_hash_on_modify: bool) -> KanshiOptions {
+        self.compute_hash_on_modify = compute_hash_on_modify;
+        self
+    }
+
+    /// Sets [`KanshiOptions::detect_hard_links`].
+    pub fn with_detect_hard_links(mut self, detect_hard_links: bool) -> KanshiOptions {
+        self.detect_hard_links = detect_hard_links;
+        self
+    }
+
+    /// Sets [`KanshiOptions::approximate_timestamp`].
+    pub fn with_approximate_timestamp(mut self, approximate_timestamp: bool) -> KanshiOptions {
+        self.approximate_timestamp = approximate_timestamp;
+        self
+    }
+
+    /// Sets [`KanshiOptions::initial_sequence`].
+    pub fn with_initial_sequence(mut self, initial_sequence: u64) -> KanshiOptions {
+        self.initial_sequence = initial_sequence;
+        self
+    }
+
+    /// Sets [`KanshiOptions::buffer_while_paused`].
+    pub fn with_buffer_while_paused(mut self, buffer_while_paused: bool) -> KanshiOptions {
+        self.buffer_while_paused = buffer_while_paused;
+        self
+    }
+
+    /// Sets [`KanshiOptions::ignore_self`].
+    pub fn with_ignore_self(mut self, ignore_self: bool) -> KanshiOptions {
+        self.ignore_self = ignore_self;
+        self
+    }
+
+    /// Sets [`KanshiOptions::poll_interval_ms`].
+    pub fn with_poll_interval_ms(mut self, poll_interval_ms: u64) -> KanshiOptions {
+        self.poll_interval_ms = poll_interval_ms;
+        self
+    }
+
+    /// Sets [`KanshiOptions::stale_retry_count`].
+    pub fn with_stale_retry_count(mut self, stale_retry_count: usize) -> KanshiOptions {
+        self.stale_retry_count = stale_retry_count;
+        self
+    }
+
+    /// Sets [`KanshiOptions::overlay_remap`].
+    pub fn with_overlay_remap(mut self, overlay_remap: bool) -> KanshiOptions {
+        self.overlay_remap = overlay_remap;
+        self
+    }
+
+    /// Checks the options for invalid values and returns them.
+    pub fn build(self) -> Result<KanshiOptions, KanshiError> {
+        if self.channel_capacity == 0 {
+            return Err(KanshiError::InvalidParameter(
+                "channel_capacity must be at least 1.".to_owned(),
+            ));
+        }
+        if self.max_events_per_second == Some(0) {
+            return Err(KanshiError::InvalidParameter(
+                "max_events_per_second must be at least 1.".to_owned(),
+            ));
+        }
+        if self.poll_interval_ms == 0 {
+            return Err(KanshiError::InvalidParameter(
+                "poll_interval_ms must be at least 1.".to_owned(),
+            ));
+        }
+        if self.use_filesystem_mark && self.path_resolution_mode == PathResolutionMode::NameOnly {
+            return Err(KanshiError::InvalidParameter(
+                "use_filesystem_mark is not supported with PathResolutionMode::NameOnly.".to_owned(),
+            ));
+        }
+        if self.mount_level_watch && self.path_resolution_mode == PathResolutionMode::NameOnly {
+            return Err(KanshiError::InvalidParameter(
+                "mount_level_watch is not supported with PathResolutionMode::NameOnly.".to_owned(),
+            ));
+        }
+        if self.mount_level_watch && self.use_filesystem_mark {
+            return Err(KanshiError::InvalidParameter(
+                "mount_level_watch can't be combined with use_filesystem_mark.".to_owned(),
+            ));
+        }
+        if self
+            .mark_quota_warn_threshold
+            .is_some_and(|x| !(0.0..=1.0).contains(&x))
+        {
+            return Err(KanshiError::InvalidParameter(
+                "mark_quota_warn_threshold must be between 0 and 1.".to_owned(),
+            ));
+        }
+        if self.permission_mode && (self.use_filesystem_mark || self.mount_level_watch) {
+            return Err(KanshiError::InvalidParameter(
+                "permission_mode can't be combined with use_filesystem_mark or mount_level_watch."
+                    .to_owned(),
+            ));
+        }
+        Ok(self)
+    }
+}
+
+impl Default for KanshiOptions {
+    fn default() -> Self {
+        KanshiOptions {
+            force_engine: None,
+            backend: LinuxBackend::default(),
+            on_error: default_error_handler(),
+            path_resolution_mode: PathResolutionMode::default(),
+            use_filesystem_mark: false,
+            mount_level_watch: false,
+            follow_symlinks: false,
+            recursive: true,
+            max_depth: None,
+            parallel_scan: false,
+            create_watched_dirs: false,
+            exclude_patterns: Vec::new(),
+            exclude_regexes: Vec::new(),
+            use_gitignore: false,
+            watch_open_file_deletion: false,
+            watch_close_write: false,
+            watch_access: false,
+            watch_open: false,
+            permission_mode: false,
+            mark_quota_warn_threshold: None,
+            handle_sigint: false,
+            channel_capacity: 1024,
+            reconnect_timeout: Duration::from_secs(5),
+            reconnect_buffer_size: 1024,
+            grace_period: Duration::ZERO,
+            debounce_ms: None,
+            coalesce_window_ms: None,
+            max_events_per_second: None,
+            include_metadata: false,
+            compute_hash_on_modify: false,
+            detect_hard_links: false,
+            approximate_timestamp: false,
+            initial_sequence: 0,
+            buffer_while_paused: false,
+            ignore_self: false,
+            poll_interval_ms: 1000,
+            stale_retry_count: 1,
+            overlay_remap: false,
+        }
+    }
 }
 
 #[derive(Clone)]
 enum Engines {
     Fanotify(FanotifyTracer),
     INotify(INotifyTracer),
+    Poll(PollTracer),
 }
 
 #[derive(Clone)]
 pub struct Kanshi {
     engine: Engines,
+    // Canonical paths of the directories passed to `watch`.
+    watched: Arc<Mutex<HashSet<PathBuf>>>,
+    filters: WatchFilters,
+}
+
+impl Kanshi {
+    /// The options this tracer was created with.
+    fn options(&self) -> &KanshiOptions {
+        match self.engine.borrow() {
+            Engines::Fanotify(fan) => fan.options(),
+            Engines::INotify(notify) => notify.options(),
+            Engines::Poll(poll) => poll.options(),
+        }
+    }
+
+    /// Watches `dir` like `watch`, but first checks that it can be watched
+    /// without changing any kernel state: `dir` must be an existing, readable
+    /// directory that is not watched yet, and, with the inotify engine, watching
+    /// it must not exceed `fs.inotify.max_user_watches`, which fails with
+    /// `MarkQuotaExceeded`. If any check fails, nothing is marked. The
+    /// directory can still change between the checks and marking, so `watch`
+    /// errors are possible but unlikely. `dir` is never created, even with
+    /// `KanshiOptions::create_watched_dirs`.
+    pub async fn try_watch(
+        &self,
+        dir: impl AsRef<Path> + Send,
+    ) -> Result<WatchHandle, KanshiError> {
+        let path = validate_watch_dir(dir.as_ref())?;
+        if self.watched.lock().unwrap().contains(&path) {
+            return Err(KanshiError::InvalidParameter(format!(
+                "{:?} is already watched",
+                path
+            )));
+        }
+
+        // fanotify groups are created with `FAN_UNLIMITED_MARKS`, unless a
+        // quota warning was asked for.
+        if let Engines::INotify(notify) = self.engine.borrow() {
+            notify.check_watch_limit(&path).await?;
+        }
+
+        self.watch(&path).await?;
+        Ok(WatchHandle { path })
+    }
+
+    /// Watches `dir` like `watch`, but only reports the event types in
+    /// `filter` for it. A path below several watched directories is reported
+    /// for the event types of any of them, and watching `dir` again replaces
+    /// its filter. Events are filtered as they are received, since the event
+    /// types left out are still needed to keep track of the watched tree,
+    /// e.g. `Create` to watch new subdirectories.
+    pub async fn watch_with_filter(
+        &self,
+        dir: impl AsRef<Path> + Send,
+        filter: EventTypeMask,
+    ) -> Result<(), KanshiError> {
+        self.watch_masked(dir.as_ref(), HashSet::new(), filter)
+            .await
+    }
+
+    /// `watch_with_exclude_dirs`, reporting only the event types in `filter`.
+    async fn watch_masked(
+        &self,
+        dir: &Path,
+        exclude: HashSet<PathBuf>,
+        filter: EventTypeMask,
+    ) -> Result<(), KanshiError> {
+        match self.engine.borrow() {
+            Engines::Fanotify(fan) => fan.watch_with_exclude_dirs(dir, exclude).await?,
+            Engines::INotify(notify) => notify.watch_with_exclude_dirs(dir, exclude).await?,
+            Engines::Poll(poll) => poll.watch_with_exclude_dirs(dir, exclude).await?,
+        }
+
+        if let Ok(path) = dir.canonicalize() {
+            self.filters.set(path.clone(), filter);
+            self.watched.lock().unwrap().insert(path);
+        }
+        Ok(())
+    }
+
+    /// Watches the entire filesystem that `path` lives on. See
+    /// [`FanotifyTracer::watch_filesystem_device`]. Only supported by the
+    /// fanotify engine.
+    pub fn watch_filesystem_device(&self, path: &str) -> Result<(), KanshiError> {
+        match self.engine.borrow() {
+            Engines::Fanotify(fan) => fan.watch_filesystem_device(path),
+            Engines::INotify(_) | Engines::Poll(_) => Err(KanshiError::InvalidParameter(
+                "Watching a whole filesystem requires the fanotify engine.".to_owned(),
+            )),
+        }
+    }
+
+    /// Removes every fanotify mark or inotify watch and adds them again for
+    /// the watched paths, e.g. after a watched filesystem was unmounted and
+    /// mounted again. Event streams keep working across the restart. See
+    /// [`FanotifyTracer::restart`] and [`INotifyTracer::restart`].
+    pub async fn restart(&self) -> Result<(), KanshiError> {
+        match self.engine.borrow() {
+            Engines::Fanotify(fan) => fan.restart().await,
+            Engines::INotify(notify) => notify.restart().await,
+            // Every scan reads the watched paths anew, so there is nothing to
+            // rebuild.
+            Engines::Poll(_) => Ok(()),
+        }
+    }
+
+    /// Starts the tracer and returns an iterator over its events, for callers
+    /// that don't use async. See [`KanshiBlockingIter`](crate::KanshiBlockingIter).
+    #[cfg(feature = "runtime-tokio")]
+    pub fn into_blocking_iter(self) -> Result<crate::KanshiBlockingIter, KanshiError> {
+        crate::KanshiBlockingIter::new(self, None)
+    }
+}
+
+/// The engine used when none is forced: fanotify when running as root, if it
+/// is permitted and the kernel supports it, and inotify otherwise. Polling is
+/// only used when inotify is unavailable too.
+fn default_engine() -> KanshiEngines {
+    if unsafe { libc::geteuid() } == 0 {
+        // Root inside a container usually lacks `CAP_SYS_ADMIN`, which
+        // fanotify needs.
+        match check_fanotify_availability() {
+            Ok(capabilities) if capabilities.supports_engine() => return KanshiEngines::Fanotify,
+            Ok(_) => {
+                tracing::warn!("fanotify is not fully supported by this kernel, using inotify")
+            }
+            Err(e) => tracing::warn!(error = %e, "fanotify is not permitted, using inotify"),
+        }
+    }
+
+    match check_inotify_availability() {
+        Ok(()) => KanshiEngines::Inotify,
+        Err(e) => {
+            tracing::warn!(error = %e, "inotify is not available, falling back to polling");
+            KanshiEngines::Poll
+        }
+    }
 }
 
 impl KanshiImpl<KanshiOptions> for Kanshi {
@@ -47,23 +817,29 @@ impl KanshiImpl<KanshiOptions> for Kanshi {
     where
         Self: Sized + Clone,
     {
-        let chosen_engine: KanshiEngines = if let Some(engine) = opts.force_engine.as_ref() {
-            engine.clone()
-        } else {
-            let uid = unsafe { libc::geteuid() };
-
-            if uid == 0 {
-                KanshiEngines::Fanotify
-            } else {
-                KanshiEngines::Inotify
-            }
+        let chosen_engine = match opts.force_engine.as_ref() {
+            Some(engine) => engine.clone(),
+            None => default_engine(),
         };
+        if opts.permission_mode && !matches!(chosen_engine, KanshiEngines::Fanotify) {
+            return Err(KanshiError::InvalidParameter(
+                "permission_mode is only supported by the fanotify engine.".to_owned(),
+            ));
+        }
+        if opts.ignore_self {
+            tracing::warn!(
+                "ignore_self is not supported on Linux, events caused by this process are reported"
+            );
+        }
 
         Ok(Kanshi {
             engine: match chosen_engine {
                 KanshiEngines::Inotify => Engines::INotify(INotifyTracer::new(opts)?),
                 KanshiEngines::Fanotify => Engines::Fanotify(FanotifyTracer::new(opts)?),
+                KanshiEngines::Poll => Engines::Poll(PollTracer::new(opts)?),
             },
+            watched: Arc::new(Mutex::new(HashSet::new())),
+            filters: WatchFilters::default(),
         })
     }
 
@@ -71,20 +847,81 @@ impl KanshiImpl<KanshiOptions> for Kanshi {
         match self.engine.borrow() {
             Engines::Fanotify(fan) => fan.start().await,
             Engines::INotify(notify) => notify.start().await,
+            Engines::Poll(poll) => poll.start().await,
+        }
+    }
+
+    async fn watch(&self, dir: impl AsRef<Path> + Send) -> Result<(), KanshiError> {
+        self.watch_with_exclude_dirs(dir, HashSet::new()).await
+    }
+
+    async fn watch_many(
+        &self,
+        dirs: impl IntoIterator<Item = impl AsRef<Path>> + Send,
+    ) -> Result<(), KanshiError> {
+        let dirs: Vec<PathBuf> = dirs.into_iter().map(|x| x.as_ref().to_path_buf()).collect();
+        // Checked before anything is marked, so that a missing path does not
+        // need a rollback.
+        if !self.options().create_watched_dirs {
+            for dir in dirs.iter() {
+                std::fs::metadata(dir).map_err(|e| KanshiError::from_io_at(e, dir))?;
+            }
+        }
+
+        let mut added = Vec::new();
+        for dir in dirs.iter() {
+            let already_watched = dir
+                .canonicalize()
+                .is_ok_and(|x| self.watched.lock().unwrap().contains(&x));
+            if let Err(e) = self.watch(dir).await {
+                // Paths that were watched before this call stay watched.
+                for dir in added.into_iter().rev() {
+                    let _ = self.unwatch(dir).await;
+                }
+                return Err(e);
+            }
+            if !already_watched {
+                added.push(dir);
+            }
+        }
+        Ok(())
+    }
+
+    async fn watch_with_exclude_dirs(
+        &self,
+        dir: impl AsRef<Path> + Send,
+        exclude: HashSet<PathBuf>,
+    ) -> Result<(), KanshiError> {
+        self.watch_masked(dir.as_ref(), exclude, EventTypeMask::all())
+            .await
+    }
+
+    async fn unwatch(&self, dir: impl AsRef<Path> + Send) -> Result<(), KanshiError> {
+        let dir = dir.as_ref();
+        match self.engine.borrow() {
+            Engines::Fanotify(fan) => fan.unwatch(dir).await?,
+            Engines::INotify(notify) => notify.unwatch(dir).await?,
+            Engines::Poll(poll) => poll.unwatch(dir).await?,
         }
+
+        let path = dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf());
+        self.filters.remove(&path);
+        self.watched.lock().unwrap().remove(&path);
+        Ok(())
     }
 
-    async fn watch(&self, dir: &str) -> Result<(), KanshiError> {
+    fn get_watched_paths(&self) -> Vec<PathBuf> {
         match self.engine.borrow() {
-            Engines::Fanotify(fan) => fan.watch(dir).await,
-            Engines::INotify(notify) => notify.watch(dir).await,
+            Engines::Fanotify(fan) => fan.get_watched_paths(),
+            Engines::INotify(notify) => notify.get_watched_paths(),
+            Engines::Poll(poll) => poll.get_watched_paths(),
         }
     }
 
     fn get_events_stream(
         &self,
-    ) -> Pin<Box<dyn futures::Stream<Item = crate::FileSystemEvent> + Send>> {
-        let events_stream: Pin<Box<dyn futures::Stream<Item = crate::FileSystemEvent> + Send>>;
+    ) -> Pin<Box<dyn futures::Stream<Item = crate::KanshiStreamItem> + Send>> {
+        let events_stream: Pin<Box<dyn futures::Stream<Item = crate::KanshiStreamItem> + Send>>;
 
         match self.engine.borrow() {
             Engines::Fanotify(fan) => {
@@ -97,21 +934,1670 @@ impl KanshiImpl<KanshiOptions> for Kanshi {
                 // pin_mut!(stream);
                 events_stream = Box::pin(stream);
             }
+            Engines::Poll(poll) => {
+                events_stream = poll.get_events_stream();
+            }
         };
 
         // let events_stream = *events_stream;
 
-        Box::pin(stream! {
+        self.filters.apply(Box::pin(stream! {
           for await item in events_stream {
             yield item
           }
-        })
+        }))
+    }
+
+    fn pause(&self) {
+        match self.engine.borrow() {
+            Engines::Fanotify(fan) => fan.pause(),
+            Engines::INotify(notify) => notify.pause(),
+            Engines::Poll(poll) => poll.pause(),
+        }
+    }
+
+    fn resume(&self) {
+        match self.engine.borrow() {
+            Engines::Fanotify(fan) => fan.resume(),
+            Engines::INotify(notify) => notify.resume(),
+            Engines::Poll(poll) => poll.resume(),
+        }
+    }
+
+    fn is_paused(&self) -> bool {
+        match self.engine.borrow() {
+            Engines::Fanotify(fan) => fan.is_paused(),
+            Engines::INotify(notify) => notify.is_paused(),
+            Engines::Poll(poll) => poll.is_paused(),
+        }
+    }
+
+    fn on_event<F>(&self, callback: F)
+    where
+        F: Fn(FileSystemEvent) + Send + Sync + 'static,
+    {
+        let callback = self.filters.wrap(callback);
+        match self.engine.borrow() {
+            Engines::Fanotify(fan) => fan.on_event(callback),
+            Engines::INotify(notify) => notify.on_event(callback),
+            Engines::Poll(poll) => poll.on_event(callback),
+        }
     }
 
-    fn close(&self) -> bool {
+    fn close(&self) -> Result<(), KanshiError> {
         match self.engine.borrow() {
             Engines::Fanotify(fan) => fan.close(),
             Engines::INotify(notify) => notify.close(),
+            Engines::Poll(poll) => poll.close(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, path::PathBuf, time::Duration};
+
+    use futures::{Stream, StreamExt};
+    use tokio::task::JoinHandle;
+
+    use crate::{
+        util::{merge, wait_for},
+        EventTypeMask, Fanout, FileSystemEvent, FileSystemEventType, FileSystemTargetKind,
+        KanshiError, KanshiImpl, KanshiStreamItem,
+    };
+
+    use super::{
+        check_fanotify_availability, default_engine, FanotifyTracer, Kanshi, KanshiEngines,
+        KanshiOptions, LinuxBackend, PathResolutionMode,
+    };
+
+    /// Runs `start` of a clone of `kanshi` on a new task.
+    fn start_in_background(kanshi: &Kanshi) -> JoinHandle<Result<(), KanshiError>> {
+        let tracer = kanshi.clone();
+        tokio::spawn(async move { tracer.start().await })
+    }
+
+    /// The next event of `stream`, or `None` if none arrives within `timeout`
+    /// or the stream yields something else.
+    async fn next_event(
+        stream: &mut (impl Stream<Item = KanshiStreamItem> + Unpin),
+        timeout: Duration,
+    ) -> Option<FileSystemEvent> {
+        match tokio::time::timeout(timeout, stream.next()).await {
+            Ok(Some(KanshiStreamItem::Event(event))) => Some(event),
+            _ => None,
+        }
+    }
+
+    /// The events of `stream` until none arrives within `timeout`.
+    async fn collect_events(
+        stream: &mut (impl Stream<Item = KanshiStreamItem> + Unpin),
+        timeout: Duration,
+    ) -> Vec<FileSystemEvent> {
+        let mut events = Vec::new();
+        while let Some(event) = next_event(stream, timeout).await {
+            events.push(event);
+        }
+        events
+    }
+
+    /// The paths of the targets of `events`.
+    fn target_paths(events: Vec<FileSystemEvent>) -> Vec<PathBuf> {
+        events
+            .into_iter()
+            .filter_map(|x| x.target)
+            .map(|x| PathBuf::from(x.path))
+            .collect()
+    }
+
+    async fn create_then_modify(engine: KanshiEngines) -> Vec<FileSystemEventType> {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().canonicalize().unwrap().join("x");
+
+        let kanshi = Kanshi::new(KanshiOptions {
+            force_engine: Some(engine),
+            ..KanshiOptions::default()
+        })
+        .unwrap();
+        kanshi.watch(dir.path()).await.unwrap();
+
+        let mut stream = kanshi.get_events_stream();
+        start_in_background(&kanshi);
+
+        let mut file = std::fs::File::create(&file_path).unwrap();
+        file.write_all(b"x").unwrap();
+
+        // Stop at the first `Modify`, or give up after a while without events.
+        let mut event_types = Vec::new();
+        while !event_types.contains(&FileSystemEventType::Modify) {
+            let Some(event) = next_event(&mut stream, Duration::from_secs(1)).await else {
+                break;
+            };
+            if event.target.is_some_and(|x| x.path == file_path) {
+                event_types.push(event.event_type);
+            }
+        }
+
+        kanshi.close().unwrap();
+        event_types
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn create_precedes_modify() {
+        let expected = [FileSystemEventType::Create, FileSystemEventType::Modify];
+
+        let inotify = create_then_modify(KanshiEngines::Inotify).await;
+        assert_eq!(inotify, expected);
+
+        // fanotify needs CAP_SYS_ADMIN.
+        if unsafe { libc::geteuid() } == 0 {
+            let fanotify = create_then_modify(KanshiEngines::Fanotify).await;
+            assert_eq!(fanotify, expected);
+        }
+    }
+
+    /// Watches two directories, unwatches the first and returns the paths of
+    /// the files created in both afterwards that events were received for.
+    async fn create_after_unwatch(engine: KanshiEngines) -> Vec<PathBuf> {
+        let dir = tempfile::tempdir().unwrap();
+        let removed = dir.path().canonicalize().unwrap().join("removed");
+        let kept = dir.path().canonicalize().unwrap().join("kept");
+        std::fs::create_dir_all(removed.join("sub")).unwrap();
+        std::fs::create_dir(&kept).unwrap();
+
+        let kanshi = Kanshi::new(KanshiOptions {
+            force_engine: Some(engine),
+            ..KanshiOptions::default()
+        })
+        .unwrap();
+        kanshi.watch(&removed).await.unwrap();
+        kanshi.watch(&kept).await.unwrap();
+
+        let mut stream = kanshi.get_events_stream();
+        start_in_background(&kanshi);
+
+        kanshi.unwatch(&removed).await.unwrap();
+        assert!(matches!(
+            kanshi.unwatch(&removed).await,
+            Err(KanshiError::PathNotWatched(_))
+        ));
+
+        std::fs::File::create(removed.join("x")).unwrap();
+        std::fs::File::create(removed.join("sub/x")).unwrap();
+        std::fs::File::create(kept.join("x")).unwrap();
+
+        let events = collect_events(&mut stream, Duration::from_millis(500)).await;
+        kanshi.close().unwrap();
+        target_paths(events)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn unwatch_stops_events_below_dir() {
+        let paths = create_after_unwatch(KanshiEngines::Inotify).await;
+        assert!(paths.iter().all(|x| x.ends_with("kept/x")), "{paths:?}");
+        assert!(!paths.is_empty());
+
+        if unsafe { libc::geteuid() } == 0 {
+            let paths = create_after_unwatch(KanshiEngines::Fanotify).await;
+            assert!(paths.iter().all(|x| x.ends_with("kept/x")), "{paths:?}");
+            assert!(!paths.is_empty());
+        }
+    }
+
+    /// Watches a directory containing `sub/deeper`, creates `entries` below
+    /// it and returns the relative paths events were received for. Entries
+    /// ending in `/` are created as directories.
+    async fn create_below(options: KanshiOptions, entries: &[&str]) -> Vec<PathBuf> {
+        let dir = tempfile::tempdir().unwrap();
+        let top = dir.path().canonicalize().unwrap();
+        std::fs::create_dir_all(top.join("sub/deeper")).unwrap();
+
+        let kanshi = Kanshi::new(options).unwrap();
+        kanshi.watch(&top).await.unwrap();
+
+        let mut stream = kanshi.get_events_stream();
+        start_in_background(&kanshi);
+
+        for entry in entries {
+            if entry.ends_with('/') {
+                std::fs::create_dir(top.join(entry)).unwrap();
+                // Give the tracer time to mark the new directory.
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            } else {
+                std::fs::File::create(top.join(entry)).unwrap();
+            }
+        }
+
+        let events = collect_events(&mut stream, Duration::from_millis(500)).await;
+        kanshi.close().unwrap();
+        target_paths(events)
+            .into_iter()
+            .map(|x| x.strip_prefix(&top).unwrap().to_path_buf())
+            .collect()
+    }
+
+    #[test]
+    fn default_engine_falls_back_to_inotify() {
+        let fanotify = unsafe { libc::geteuid() } == 0
+            && check_fanotify_availability().is_ok_and(|x| x.supports_engine());
+        match default_engine() {
+            KanshiEngines::Fanotify => assert!(fanotify),
+            KanshiEngines::Inotify => assert!(!fanotify),
+            KanshiEngines::Poll => panic!("inotify should be available"),
+        }
+    }
+
+    fn engines() -> Vec<KanshiEngines> {
+        // fanotify needs CAP_SYS_ADMIN.
+        if unsafe { libc::geteuid() } == 0 {
+            vec![KanshiEngines::Inotify, KanshiEngines::Fanotify]
+        } else {
+            vec![KanshiEngines::Inotify]
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn non_recursive_ignores_subdirectories() {
+        for engine in engines() {
+            let options = KanshiOptions::default().with_engine(engine).with_recursive(false);
+            let paths = create_below(options, &["sub/file", "new/", "new/file", "file"]).await;
+            assert_eq!(paths, [PathBuf::from("new"), PathBuf::from("file")]);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn close_succeeds_once_and_then_does_nothing() {
+        for engine in engines() {
+            let dir = tempfile::tempdir().unwrap();
+            let kanshi = Kanshi::new(KanshiOptions::default().with_engine(engine)).unwrap();
+            kanshi.watch(dir.path()).await.unwrap();
+
+            kanshi.close().unwrap();
+            kanshi.close().unwrap();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn io_uring_backend_reports_events() {
+        for engine in engines() {
+            let options = KanshiOptions::default()
+                .with_engine(engine)
+                .with_backend(LinuxBackend::IoUring);
+            let paths = create_below(options, &["new/", "new/file", "file"]).await;
+            assert_eq!(
+                paths,
+                [PathBuf::from("new"), PathBuf::from("new/file"), PathBuf::from("file")]
+            );
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn max_depth_limits_watched_levels() {
+        for engine in engines() {
+            let options = KanshiOptions::default().with_engine(engine).with_max_depth(1);
+            let entries = ["sub/deeper/file", "new/", "new/newer/", "new/newer/file", "sub/file"];
+            let paths = create_below(options, &entries).await;
+            assert_eq!(
+                paths,
+                [
+                    PathBuf::from("new"),
+                    PathBuf::from("new/newer"),
+                    PathBuf::from("sub/file")
+                ]
+            );
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn symlinked_directories_are_watched_under_their_target() {
+        for engine in engines() {
+            let dir = tempfile::tempdir().unwrap();
+            let top = dir.path().canonicalize().unwrap();
+            std::fs::create_dir_all(top.join("real/sub")).unwrap();
+            std::os::unix::fs::symlink(top.join("real"), top.join("link")).unwrap();
+            // Points back up the tree, which must not be followed forever.
+            std::os::unix::fs::symlink(top.join("real"), top.join("real/sub/loop")).unwrap();
+
+            let options = KanshiOptions::default()
+                .with_engine(engine)
+                .with_follow_symlinks(true);
+            let kanshi = Kanshi::new(options).unwrap();
+            kanshi.watch(top.join("link")).await.unwrap();
+            start_in_background(&kanshi);
+
+            let file_path = top.join("real/sub/x").into_os_string();
+            let created = wait_for(
+                &kanshi,
+                move |x| x.target.as_ref().is_some_and(|x| x.path == file_path),
+                Some(Duration::from_secs(5)),
+            );
+            std::fs::File::create(top.join("link/sub/x")).unwrap();
+            assert!(created.await.is_ok());
+            kanshi.unwatch(top.join("link")).await.unwrap();
+            kanshi.close().unwrap();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn watches_non_ascii_and_non_utf8_paths() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        for engine in engines() {
+            let dir = tempfile::tempdir().unwrap();
+            let top = dir.path().canonicalize().unwrap();
+            let japanese = top.join("監視");
+            let non_utf8 = top.join(OsStr::from_bytes(b"\xff\xfe"));
+            std::fs::create_dir(&japanese).unwrap();
+            std::fs::create_dir(&non_utf8).unwrap();
+
+            let kanshi = Kanshi::new(KanshiOptions::default().with_engine(engine)).unwrap();
+            kanshi.watch(&japanese).await.unwrap();
+            kanshi.watch(non_utf8.as_os_str()).await.unwrap();
+
+            let mut stream = kanshi.get_events_stream();
+            start_in_background(&kanshi);
+
+            let expected = [
+                japanese.join("ファイル"),
+                non_utf8.join(OsStr::from_bytes(b"\x80")),
+            ];
+            for path in expected.iter() {
+                std::fs::File::create(path).unwrap();
+            }
+            let mut paths = Vec::new();
+            while !expected.iter().all(|x| paths.contains(x)) {
+                let Some(event) = next_event(&mut stream, Duration::from_secs(5)).await else {
+                    break;
+                };
+                paths.extend(target_paths(vec![event]));
+            }
+
+            assert!(expected.iter().all(|x| paths.contains(x)), "{paths:?}");
+            kanshi.unwatch(non_utf8.as_os_str()).await.unwrap();
+            kanshi.close().unwrap();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn directories_created_before_their_parent_is_marked_are_watched() {
+        for engine in engines() {
+            let dir = tempfile::tempdir().unwrap();
+            let top = dir.path().canonicalize().unwrap();
+            let file_path = top.join("new/a/b/file");
+
+            let kanshi = Kanshi::new(KanshiOptions::default().with_engine(engine)).unwrap();
+            kanshi.watch(&top).await.unwrap();
+            start_in_background(&kanshi);
+
+            let target = file_path.clone().into_os_string();
+            let created = wait_for(
+                &kanshi,
+                move |x| x.target.as_ref().is_some_and(|x| x.path == target),
+                Some(Duration::from_secs(5)),
+            );
+            // Creates `new/a` and `new/a/b` before the tracer can mark `new`.
+            std::fs::create_dir_all(top.join("new/a/b")).unwrap();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            std::fs::File::create(&file_path).unwrap();
+
+            let created = created.await;
+            kanshi.close().unwrap();
+            assert!(created.is_ok());
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn exclude_patterns_drop_matching_paths() {
+        for engine in engines() {
+            let options = KanshiOptions::default()
+                .with_engine(engine)
+                .with_exclude_patterns(["**/sub/**", "**/*.tmp"]);
+            let entries = ["sub/file", "sub/deeper/file", "file.tmp", "file"];
+            let paths = create_below(options, &entries).await;
+            assert_eq!(paths, [PathBuf::from("file")]);
+        }
+
+        let invalid = KanshiOptions::default().with_exclude_patterns(["a[b"]);
+        assert!(matches!(
+            Kanshi::new(invalid),
+            Err(KanshiError::InvalidParameter(_))
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn exclude_regexes_drop_matching_paths() {
+        for engine in engines() {
+            let options = KanshiOptions::default()
+                .with_engine(engine)
+                .with_exclude_patterns(["**/*.tmp"])
+                .with_exclude_regexes([r"\.sw[po]$", r"\.\d+\.part$"]);
+            let entries = ["file.swp", "file.1.part", "file.tmp", "file"];
+            let paths = create_below(options, &entries).await;
+            assert_eq!(paths, [PathBuf::from("file")]);
+        }
+
+        let invalid = KanshiOptions::default().with_exclude_regexes(["("]);
+        assert!(matches!(
+            Kanshi::new(invalid),
+            Err(KanshiError::InvalidPattern(_))
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn use_gitignore_drops_ignored_paths() {
+        for engine in engines() {
+            let dir = tempfile::tempdir().unwrap();
+            let top = dir.path().canonicalize().unwrap();
+            std::fs::write(top.join(".gitignore"), "build/\n").unwrap();
+            std::fs::create_dir_all(top.join("build/out")).unwrap();
+            std::fs::create_dir(top.join("src")).unwrap();
+
+            let options = KanshiOptions::default()
+                .with_engine(engine)
+                .with_use_gitignore(true);
+            let kanshi = Kanshi::new(options).unwrap();
+            kanshi.watch(&top).await.unwrap();
+
+            let mut stream = kanshi.get_events_stream();
+            start_in_background(&kanshi);
+
+            std::fs::write(top.join("build/a"), "a").unwrap();
+            std::fs::write(top.join("build/out/b"), "b").unwrap();
+            std::fs::write(top.join("src/c"), "c").unwrap();
+
+            let paths = target_paths(collect_events(&mut stream, Duration::from_millis(500)).await);
+            kanshi.close().unwrap();
+
+            assert!(paths.contains(&top.join("src/c")));
+            assert!(!paths.iter().any(|x| x.starts_with(top.join("build"))));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn watch_access_reports_reads() {
+        for engine in engines() {
+            let dir = tempfile::tempdir().unwrap();
+            let file_path = dir.path().canonicalize().unwrap().join("x");
+            std::fs::write(&file_path, "x").unwrap();
+
+            let options = KanshiOptions::default().with_engine(engine).with_watch_access(true);
+            let kanshi = Kanshi::new(options).unwrap();
+            kanshi.watch(dir.path()).await.unwrap();
+            start_in_background(&kanshi);
+
+            let accessed = wait_for(
+                &kanshi,
+                |x| {
+                    x.event_type == FileSystemEventType::Access
+                        && x.target.as_ref().is_some_and(|x| x.path == file_path)
+                },
+                Some(Duration::from_secs(5)),
+            );
+            std::fs::read(&file_path).unwrap();
+
+            let accessed = accessed.await;
+            kanshi.close().unwrap();
+            assert!(accessed.is_ok());
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn get_watched_paths_lists_watches() {
+        for engine in engines() {
+            let first = tempfile::tempdir().unwrap();
+            let second = tempfile::tempdir().unwrap();
+
+            let kanshi = Kanshi::new(KanshiOptions::default().with_engine(engine)).unwrap();
+            kanshi.watch(first.path()).await.unwrap();
+            kanshi.watch(second.path()).await.unwrap();
+            assert_eq!(kanshi.get_watched_paths().len(), 2);
+
+            kanshi.unwatch(first.path()).await.unwrap();
+            assert_eq!(
+                kanshi.get_watched_paths(),
+                [second.path().canonicalize().unwrap()]
+            );
+            kanshi.close().unwrap();
+        }
+    }
+
+    /// Creates `before` while paused and `after` once resumed, and returns
+    /// the file names events were received for.
+    async fn create_while_paused(options: KanshiOptions) -> Vec<String> {
+        let dir = tempfile::tempdir().unwrap();
+        let top = dir.path().canonicalize().unwrap();
+
+        let kanshi = Kanshi::new(options).unwrap();
+        kanshi.watch(&top).await.unwrap();
+
+        let mut stream = kanshi.get_events_stream();
+        start_in_background(&kanshi);
+
+        kanshi.pause();
+        assert!(kanshi.is_paused());
+        std::fs::File::create(top.join("before")).unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        kanshi.resume();
+        assert!(!kanshi.is_paused());
+        std::fs::File::create(top.join("after")).unwrap();
+
+        let events = collect_events(&mut stream, Duration::from_millis(500)).await;
+        kanshi.close().unwrap();
+
+        let mut names: Vec<_> = target_paths(events)
+            .into_iter()
+            .map(|x| x.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        names.dedup();
+        names
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn rapid_create_and_delete_reports_every_entry() {
+        // Only fanotify resolves paths through file handles, which go stale.
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let top = dir.path().canonicalize().unwrap();
+
+        let options = KanshiOptions::default().with_engine(KanshiEngines::Fanotify);
+        let kanshi = Kanshi::new(options).unwrap();
+        kanshi.watch(&top).await.unwrap();
+
+        let mut stream = kanshi.get_events_stream();
+        let handle = start_in_background(&kanshi);
+
+        const COUNT: usize = 50;
+        for i in 0..COUNT {
+            let sub = top.join(format!("d{i}"));
+            std::fs::create_dir(&sub).unwrap();
+            std::fs::File::create(sub.join("x")).unwrap();
+            std::fs::remove_file(sub.join("x")).unwrap();
+            std::fs::remove_dir(&sub).unwrap();
+        }
+
+        let events = collect_events(&mut stream, Duration::from_millis(500)).await;
+        kanshi.close().unwrap();
+        handle.await.unwrap().unwrap();
+
+        let names: Vec<_> = target_paths(events)
+            .into_iter()
+            .map(|x| x.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+
+        // Entries whose directory was already gone are reported by name.
+        for i in 0..COUNT {
+            assert!(names.contains(&format!("d{i}")), "{names:?}");
+        }
+        assert!(names.iter().filter(|x| *x == "x").count() >= COUNT, "{names:?}");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn mark_count_follows_watches() {
+        assert!(KanshiOptions::default()
+            .with_mark_quota_warn_threshold(1.5)
+            .build()
+            .is_err());
+        // fanotify needs CAP_SYS_ADMIN.
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let top = dir.path().canonicalize().unwrap();
+        std::fs::create_dir(top.join("a")).unwrap();
+        std::fs::create_dir(top.join("b")).unwrap();
+
+        let tracer = FanotifyTracer::new(KanshiOptions::default()).unwrap();
+        tracer.watch(&top).await.unwrap();
+        assert_eq!(tracer.mark_count(), 3);
+        // The group is created with `FAN_UNLIMITED_MARKS`.
+        assert_eq!(tracer.marks_remaining(), None);
+
+        tracer.unwatch(&top).await.unwrap();
+        assert_eq!(tracer.mark_count(), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn mark_quota_warning_is_reported_once() {
+        // fanotify needs CAP_SYS_ADMIN, and the limit is exposed since Linux
+        // 5.13.
+        if unsafe { libc::geteuid() } != 0
+            || !std::path::Path::new("/proc/sys/fs/fanotify/max_user_marks").exists()
+        {
+            return;
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let top = dir.path().canonicalize().unwrap();
+        std::fs::create_dir(top.join("a")).unwrap();
+        std::fs::create_dir(top.join("b")).unwrap();
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        // Any mark at all is past a threshold of 0.
+        let options = KanshiOptions::default()
+            .with_mark_quota_warn_threshold(0.0)
+            .with_on_error(move |e| {
+                let _ = tx.send(e);
+            });
+        let tracer = FanotifyTracer::new(options).unwrap();
+        tracer.watch(&top).await.unwrap();
+        assert!(tracer.marks_remaining().is_some());
+
+        let warnings: Vec<_> = rx.try_iter().collect();
+        assert!(
+            matches!(
+                warnings.as_slice(),
+                [KanshiError::MarkQuotaNearlyExhausted { used: 1, .. }]
+            ),
+            "{warnings:?}"
+        );
+        tracer.close().unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn deleted_and_moved_away_directories_release_their_marks() {
+        // fanotify needs CAP_SYS_ADMIN.
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        let top = dir.path().canonicalize().unwrap();
+        std::fs::create_dir_all(top.join("a/b")).unwrap();
+        std::fs::create_dir_all(top.join("c/d")).unwrap();
+
+        let tracer = FanotifyTracer::new(KanshiOptions::default()).unwrap();
+        tracer.watch(&top).await.unwrap();
+        assert_eq!(tracer.mark_count(), 5);
+        let mut stream = tracer.get_events_stream();
+        let runner = tracer.clone();
+        let handle = tokio::spawn(async move { runner.start().await });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        std::fs::remove_dir_all(top.join("a")).unwrap();
+        std::fs::rename(top.join("c"), outside.path().join("c")).unwrap();
+        // Wait for the events of both, up to the move.
+        loop {
+            let event = next_event(&mut stream, Duration::from_secs(5)).await;
+            let event = event.expect("no move reported");
+            if matches!(event.event_type, FileSystemEventType::MovedTo { .. }) {
+                break;
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(tracer.mark_count(), 1);
+
+        // The moved directories are no longer reported.
+        std::fs::write(outside.path().join("c/d/x"), b"x").unwrap();
+        std::fs::write(top.join("y"), b"y").unwrap();
+        let event = next_event(&mut stream, Duration::from_secs(5)).await;
+        let event = event.expect("no event reported");
+        assert_eq!(event.target.unwrap().path, top.join("y").into_os_string());
+
+        tracer.close().unwrap();
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn stats_count_sent_events() {
+        // fanotify needs CAP_SYS_ADMIN.
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let top = dir.path().canonicalize().unwrap();
+        let tracer = FanotifyTracer::new(KanshiOptions::default()).unwrap();
+        let stats = tracer.stats();
+        assert_eq!(stats.events_sent, 0);
+        assert_eq!(stats.last_event_at, None);
+        assert_eq!(stats.channel_capacity, tracer.options().channel_capacity);
+
+        tracer.watch(&top).await.unwrap();
+        let mut stream = tracer.get_events_stream();
+        let runner = tracer.clone();
+        let handle = tokio::spawn(async move { runner.start().await });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        std::fs::write(top.join("a"), b"a").unwrap();
+        let event = next_event(&mut stream, Duration::from_secs(5)).await;
+        assert!(event.is_some());
+
+        let stats = tracer.stats();
+        assert!(stats.events_sent >= 1);
+        assert_eq!(stats.events_dropped, 0);
+        assert!(stats.last_event_at.is_some());
+        assert_eq!(stats.marks_active, tracer.mark_count());
+        tracer.close().unwrap();
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn start_releases_its_clones_when_it_returns() {
+        // fanotify needs CAP_SYS_ADMIN.
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let options = KanshiOptions::default()
+            .with_handle_sigint(true)
+            .with_reconnect_timeout(Duration::from_millis(100));
+        let tracer = FanotifyTracer::new(options).unwrap();
+        tracer.watch(dir.path()).await.unwrap();
+
+        // Nothing subscribes, so `start` gives up after the reconnect timeout.
+        assert!(matches!(tracer.start().await, Err(KanshiError::StreamClosed)));
+        // The SIGINT handler drops its clone once its task sees the guard go.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(tracer.clone_count(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn clones_share_the_running_tracer() {
+        for engine in engines() {
+            let dir = tempfile::tempdir().unwrap();
+            let file_path = dir.path().canonicalize().unwrap().join("x");
+
+            let kanshi = Kanshi::new(KanshiOptions::default().with_engine(engine)).unwrap();
+            kanshi.watch(dir.path()).await.unwrap();
+            let clone = kanshi.clone();
+
+            let created = |x: &FileSystemEvent| x.event_type == FileSystemEventType::Create;
+            let timeout = Some(Duration::from_secs(5));
+            let waits = [
+                wait_for(&kanshi, created, timeout),
+                wait_for(&clone, created, timeout),
+            ];
+            start_in_background(&kanshi);
+
+            std::fs::File::create(&file_path).unwrap();
+            for wait in waits {
+                let event = wait.await.unwrap();
+                assert_eq!(target_paths(vec![event]), [file_path.clone()]);
+            }
+
+            assert!(matches!(
+                clone.start().await,
+                Err(KanshiError::ListenerAlreadyStarted)
+            ));
+            kanshi.close().unwrap();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn watch_guard_closes_the_tracer_on_panic() {
+        for engine in engines() {
+            let dir = tempfile::tempdir().unwrap();
+            let kanshi = Kanshi::new(KanshiOptions::default().with_engine(engine)).unwrap();
+            kanshi.watch(dir.path()).await.unwrap();
+
+            let mut stream = kanshi.get_events_stream();
+            let handle = start_in_background(&kanshi);
+            tokio::time::sleep(Duration::from_millis(100)).await;
+
+            let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                let _guard = kanshi.guarded();
+                panic!("dropped while unwinding");
+            }));
+            assert!(panicked.is_err());
+
+            // `start` returned, releasing the last clone and its descriptors.
+            let started = tokio::time::timeout(Duration::from_secs(5), handle).await;
+            assert!(matches!(started, Ok(Ok(Ok(())))));
+            let next = tokio::time::timeout(Duration::from_secs(1), stream.next()).await;
+            assert!(matches!(next, Ok(None)));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn wait_for_skips_other_events() {
+        for engine in engines() {
+            let dir = tempfile::tempdir().unwrap();
+            let root = dir.path().canonicalize().unwrap();
+            let kanshi = Kanshi::new(KanshiOptions::default().with_engine(engine)).unwrap();
+            kanshi.watch(&root).await.unwrap();
+            start_in_background(&kanshi);
+
+            let target = root.join("b").into_os_string();
+            let created = wait_for(
+                &kanshi,
+                move |x| x.target.as_ref().is_some_and(|x| x.path == target),
+                Some(Duration::from_secs(5)),
+            );
+            std::fs::write(root.join("a"), b"a").unwrap();
+            std::fs::write(root.join("b"), b"b").unwrap();
+            let event = created.await.unwrap();
+            assert_eq!(event.event_type, FileSystemEventType::Create);
+
+            let never = wait_for(&kanshi, |_| false, Some(Duration::from_millis(100)));
+            assert!(matches!(never.await, Err(KanshiError::Timeout)));
+            kanshi.close().unwrap();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn merge_tags_events_with_their_tracer() {
+        for engine in engines() {
+            let dirs = [tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap()];
+            let mut tracers = Vec::new();
+            for dir in dirs.iter() {
+                let kanshi = Kanshi::new(KanshiOptions::default().with_engine(engine)).unwrap();
+                kanshi.watch(dir.path()).await.unwrap();
+                start_in_background(&kanshi);
+                tracers.push(kanshi);
+            }
+            let mut events = merge(&tracers);
+
+            std::fs::create_dir(dirs[1].path().join("b")).unwrap();
+            std::fs::create_dir(dirs[0].path().join("a")).unwrap();
+            let mut seen = Vec::new();
+            while seen.len() < 2 {
+                let next = tokio::time::timeout(Duration::from_secs(5), events.next()).await;
+                let (index, event) = next.unwrap().unwrap();
+                if event.event_type != FileSystemEventType::Create {
+                    continue;
+                }
+                let path = PathBuf::from(event.target.unwrap().path);
+                seen.push((index, path.file_name().unwrap().to_owned()));
+            }
+            seen.sort();
+            assert_eq!(seen, [(0, "a".into()), (1, "b".into())]);
+
+            for tracer in tracers.iter() {
+                tracer.close().unwrap();
+            }
+            let next = tokio::time::timeout(Duration::from_secs(1), events.next()).await;
+            assert!(matches!(next, Ok(None)));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn fanout_streams_fill_up_independently() {
+        for engine in engines() {
+            let dir = tempfile::tempdir().unwrap();
+            let root = dir.path().canonicalize().unwrap();
+            let kanshi = Kanshi::new(KanshiOptions::default().with_engine(engine)).unwrap();
+            let fanout = Fanout::wrap(kanshi.clone(), 2);
+            fanout.watch(&root).await.unwrap();
+
+            let creates = |x: &FileSystemEvent| x.event_type == FileSystemEventType::Create;
+            let mut fast = fanout.filtered(creates);
+            let slow = fanout.filtered(creates);
+            let unread = fanout.filtered(creates);
+            drop(unread);
+            // Starting the wrapped tracer starts the fanout.
+            start_in_background(&kanshi);
+
+            for name in ["a", "b", "c", "d"] {
+                std::fs::write(root.join(name), b"x").unwrap();
+                let event = next_event(&mut fast, Duration::from_secs(5)).await;
+                let event = event.unwrap_or_else(|| panic!("no event for {name}"));
+                assert_eq!(event.target.unwrap().path, root.join(name).into_os_string());
+            }
+            // Only the first two fit.
+            assert_eq!(slow.dropped_count(), 2);
+            assert_eq!(fast.dropped_count(), 0);
+
+            fanout.close().unwrap();
+            let next = tokio::time::timeout(Duration::from_secs(1), fast.next()).await;
+            assert!(matches!(next, Ok(None)));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn permission_mode_denies_opens() {
+        assert!(KanshiOptions::default()
+            .with_permission_mode(true)
+            .with_use_filesystem_mark(true)
+            .build()
+            .is_err());
+        // Permission events need CAP_SYS_ADMIN.
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let top = dir.path().canonicalize().unwrap();
+        std::fs::write(top.join("secret"), "x").unwrap();
+
+        let options = KanshiOptions::default()
+            .with_engine(KanshiEngines::Fanotify)
+            .with_permission_mode(true);
+        let kanshi = Kanshi::new(options).unwrap();
+        kanshi.watch(&top).await.unwrap();
+
+        let mut stream = kanshi.get_events_stream();
+        start_in_background(&kanshi);
+
+        // Blocks until the request is answered.
+        let path = top.join("secret");
+        let open = tokio::task::spawn_blocking(move || std::fs::File::open(path));
+
+        let request = loop {
+            let next = tokio::time::timeout(Duration::from_secs(2), stream.next()).await;
+            match next {
+                Ok(Some(KanshiStreamItem::PermissionRequest(request))) => break request,
+                Ok(Some(KanshiStreamItem::Event(_))) => {}
+                _ => panic!("no permission request"),
+            }
+        };
+        assert_eq!(request.event.event_type, FileSystemEventType::Open);
+        assert_eq!(
+            request.event.target.as_ref().unwrap().path,
+            top.join("secret").into_os_string()
+        );
+        request.respond(false).unwrap();
+
+        let error = open.await.unwrap().unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::PermissionDenied);
+        kanshi.close().unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn filesystem_mark_reports_only_watched_dirs() {
+        assert!(KanshiOptions::default()
+            .with_use_filesystem_mark(true)
+            .with_path_resolution_mode(PathResolutionMode::NameOnly)
+            .build()
+            .is_err());
+        // Marking a filesystem needs CAP_SYS_ADMIN.
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let top = dir.path().canonicalize().unwrap();
+        let watched = top.join("watched");
+        let other = top.join("other");
+        std::fs::create_dir(&watched).unwrap();
+        std::fs::create_dir(&other).unwrap();
+
+        let options = KanshiOptions::default()
+            .with_engine(KanshiEngines::Fanotify)
+            .with_use_filesystem_mark(true);
+        let kanshi = Kanshi::new(options).unwrap();
+        kanshi.watch(&watched).await.unwrap();
+
+        let mut stream = kanshi.get_events_stream();
+        let handle = start_in_background(&kanshi);
+
+        std::fs::create_dir(watched.join("sub")).unwrap();
+        std::fs::File::create(watched.join("sub").join("a")).unwrap();
+        std::fs::File::create(other.join("b")).unwrap();
+
+        let paths = target_paths(collect_events(&mut stream, Duration::from_millis(500)).await);
+        kanshi.close().unwrap();
+        handle.await.unwrap().unwrap();
+
+        // Directories created after `watch` are covered without being marked.
+        assert!(paths.contains(&watched.join("sub").join("a")), "{paths:?}");
+        assert!(paths.iter().all(|x| x.starts_with(&watched)), "{paths:?}");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn mount_level_watch_reports_subdirectories() {
+        assert!(KanshiOptions::default()
+            .with_mount_level_watch(true)
+            .with_use_filesystem_mark(true)
+            .build()
+            .is_err());
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let top = dir.path().canonicalize().unwrap();
+        let mounted = std::process::Command::new("mount")
+            .args(["-t", "tmpfs", "tmpfs"])
+            .arg(&top)
+            .status();
+        // Mounting may still be denied, e.g. inside a container.
+        if !mounted.is_ok_and(|x| x.success()) {
+            return;
+        }
+        let sub = top.join("a").join("b");
+        std::fs::create_dir_all(&sub).unwrap();
+
+        let options = KanshiOptions::default()
+            .with_engine(KanshiEngines::Fanotify)
+            .with_mount_level_watch(true);
+        let kanshi = Kanshi::new(options).unwrap();
+        kanshi.watch(&top).await.unwrap();
+
+        let mut stream = kanshi.get_events_stream();
+        let handle = start_in_background(&kanshi);
+
+        std::fs::write(sub.join("file"), b"data").unwrap();
+
+        let mut events = collect_events(&mut stream, Duration::from_millis(500)).await;
+        events.retain(|x| x.event_type == FileSystemEventType::Modify);
+        let paths = target_paths(events);
+
+        kanshi.close().unwrap();
+        handle.await.unwrap().unwrap();
+        let _ = std::process::Command::new("umount").arg(&top).status();
+
+        assert!(paths.contains(&sub.join("file")), "{paths:?}");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn pause_discards_or_buffers_events() {
+        for engine in engines() {
+            let options = KanshiOptions::default().with_engine(engine);
+            assert_eq!(create_while_paused(options.clone()).await, ["after"]);
+
+            let options = options.with_buffer_while_paused(true);
+            assert_eq!(create_while_paused(options).await, ["before", "after"]);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn on_event_runs_alongside_streams() {
+        for engine in engines() {
+            let dir = tempfile::tempdir().unwrap();
+            let file_path = dir.path().canonicalize().unwrap().join("x");
+
+            let kanshi = Kanshi::new(KanshiOptions::default().with_engine(engine)).unwrap();
+            kanshi.watch(dir.path()).await.unwrap();
+
+            let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+            kanshi.on_event(move |event| {
+                let _ = tx.send(event);
+            });
+            let mut stream = kanshi.get_events_stream();
+            start_in_background(&kanshi);
+
+            std::fs::write(&file_path, "x").unwrap();
+
+            let from_callback = tokio::time::timeout(Duration::from_secs(1), rx.recv()).await;
+            let from_stream = next_event(&mut stream, Duration::from_secs(1)).await;
+            kanshi.close().unwrap();
+
+            let from_stream = from_stream.expect("no event from the stream");
+            assert_eq!(from_callback.unwrap(), Some(from_stream));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn symlinks_are_reported_as_symlinks() {
+        for engine in engines() {
+            let dir = tempfile::tempdir().unwrap();
+            let top = dir.path().canonicalize().unwrap();
+            std::fs::write(top.join("target"), "x").unwrap();
+
+            let kanshi = Kanshi::new(KanshiOptions::default().with_engine(engine)).unwrap();
+            kanshi.watch(&top).await.unwrap();
+
+            let mut stream = kanshi.get_events_stream();
+            start_in_background(&kanshi);
+
+            std::os::unix::fs::symlink(top.join("target"), top.join("link")).unwrap();
+
+            let event = next_event(&mut stream, Duration::from_secs(2)).await;
+            kanshi.close().unwrap();
+
+            let event = event.expect("no event for the symlink");
+            assert_eq!(event.event_type, FileSystemEventType::Create);
+            let target = event.target.unwrap();
+            assert_eq!(target.path, top.join("link").into_os_string());
+            assert_eq!(target.kind, FileSystemTargetKind::Symlink);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn detect_hard_links_reports_new_links() {
+        use std::os::unix::fs::MetadataExt;
+
+        for engine in engines() {
+            let dir = tempfile::tempdir().unwrap();
+            let top = dir.path().canonicalize().unwrap();
+            std::fs::write(top.join("original"), "x").unwrap();
+            let inode = std::fs::metadata(top.join("original")).unwrap().ino();
+
+            let options = KanshiOptions::default()
+                .with_engine(engine)
+                .with_detect_hard_links(true);
+            let kanshi = Kanshi::new(options).unwrap();
+            kanshi.watch(&top).await.unwrap();
+
+            let mut stream = kanshi.get_events_stream();
+            start_in_background(&kanshi);
+
+            std::fs::hard_link(top.join("original"), top.join("link")).unwrap();
+
+            let event = next_event(&mut stream, Duration::from_secs(2)).await;
+            kanshi.close().unwrap();
+
+            let event = event.expect("no event for the hard link");
+            assert_eq!(event.event_type, FileSystemEventType::Create);
+            assert_eq!(
+                event.target.unwrap().kind,
+                FileSystemTargetKind::HardLink {
+                    original_inode: inode
+                }
+            );
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn sequence_numbers_have_no_gaps() {
+        for engine in engines() {
+            let dir = tempfile::tempdir().unwrap();
+            let top = dir.path().canonicalize().unwrap();
+
+            let options = KanshiOptions::default()
+                .with_engine(engine)
+                .with_initial_sequence(100);
+            let kanshi = Kanshi::new(options).unwrap();
+            kanshi.watch(&top).await.unwrap();
+
+            let mut stream = kanshi.get_events_stream();
+            start_in_background(&kanshi);
+
+            let writers: Vec<_> = (0..8)
+                .map(|i| {
+                    let top = top.clone();
+                    std::thread::spawn(move || {
+                        for j in 0..10 {
+                            std::fs::write(top.join(format!("{i}-{j}")), "x").unwrap();
+                        }
+                    })
+                })
+                .collect();
+            for writer in writers {
+                writer.join().unwrap();
+            }
+
+            let events = collect_events(&mut stream, Duration::from_secs(2)).await;
+            kanshi.close().unwrap();
+
+            let sequences: Vec<_> = events.iter().map(|x| x.sequence).collect();
+
+            assert!(!sequences.is_empty());
+            assert!(sequences.windows(2).all(|pair| pair[1] == pair[0] + 1));
+            assert_eq!(sequences[0], 100);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn watch_open_reports_opens() {
+        for engine in engines() {
+            let dir = tempfile::tempdir().unwrap();
+            let file_path = dir.path().canonicalize().unwrap().join("x");
+            std::fs::write(&file_path, "x").unwrap();
+
+            let options = KanshiOptions::default().with_engine(engine).with_watch_open(true);
+            let kanshi = Kanshi::new(options).unwrap();
+            kanshi.watch(dir.path()).await.unwrap();
+            start_in_background(&kanshi);
+
+            let opened = wait_for(
+                &kanshi,
+                |x| {
+                    x.event_type == FileSystemEventType::Open
+                        && x.target.as_ref().is_some_and(|x| x.path == file_path)
+                },
+                Some(Duration::from_secs(5)),
+            );
+            drop(std::fs::File::open(&file_path).unwrap());
+
+            let opened = opened.await;
+            kanshi.close().unwrap();
+            assert!(opened.is_ok());
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn chmod_reports_attribute_change() {
+        use std::os::unix::fs::PermissionsExt;
+
+        for engine in engines() {
+            let dir = tempfile::tempdir().unwrap();
+            let file_path = dir.path().canonicalize().unwrap().join("x");
+            std::fs::write(&file_path, "x").unwrap();
+
+            let kanshi = Kanshi::new(KanshiOptions::default().with_engine(engine)).unwrap();
+            kanshi.watch(dir.path()).await.unwrap();
+
+            let mut stream = kanshi.get_events_stream();
+            start_in_background(&kanshi);
+
+            std::fs::set_permissions(&file_path, std::fs::Permissions::from_mode(0o600)).unwrap();
+
+            let event = next_event(&mut stream, Duration::from_secs(1)).await;
+            kanshi.close().unwrap();
+
+            let event = event.unwrap();
+            assert_eq!(event.event_type, FileSystemEventType::AttributeChange);
+            assert_eq!(event.target.unwrap().path, file_path);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn watch_close_write_reports_completed_writes() {
+        for engine in engines() {
+            let dir = tempfile::tempdir().unwrap();
+            let file_path = dir.path().canonicalize().unwrap().join("x");
+
+            let options = KanshiOptions::default().with_engine(engine).with_watch_close_write(true);
+            let kanshi = Kanshi::new(options).unwrap();
+            kanshi.watch(dir.path()).await.unwrap();
+            start_in_background(&kanshi);
+
+            let closed = wait_for(
+                &kanshi,
+                |x| {
+                    x.event_type == FileSystemEventType::CloseWrite
+                        && x.target.as_ref().is_some_and(|x| x.path == file_path)
+                },
+                Some(Duration::from_secs(5)),
+            );
+            std::fs::write(&file_path, "x").unwrap();
+
+            let closed = closed.await;
+            kanshi.close().unwrap();
+            assert!(closed.is_ok());
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn include_metadata_reports_size() {
+        for engine in engines() {
+            let dir = tempfile::tempdir().unwrap();
+            let file_path = dir.path().canonicalize().unwrap().join("x");
+
+            let options = KanshiOptions::default().with_engine(engine).with_include_metadata(true);
+            let kanshi = Kanshi::new(options).unwrap();
+            kanshi.watch(dir.path()).await.unwrap();
+
+            let mut stream = kanshi.get_events_stream();
+            start_in_background(&kanshi);
+
+            std::fs::write(&file_path, "hello").unwrap();
+
+            let mut size = None;
+            while size != Some(5) {
+                let Some(event) = next_event(&mut stream, Duration::from_secs(1)).await else {
+                    break;
+                };
+                if event.event_type == FileSystemEventType::Modify {
+                    size = event.target.and_then(|x| x.metadata).map(|x| x.size);
+                }
+            }
+
+            kanshi.close().unwrap();
+            assert_eq!(size, Some(5));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn compute_hash_on_modify_reports_sha256() {
+        // SHA-256 of "hello".
+        let expected = [
+            0x2c, 0xf2, 0x4d, 0xba, 0x5f, 0xb0, 0xa3, 0x0e, 0x26, 0xe8, 0x3b, 0x2a, 0xc5, 0xb9,
+            0xe2, 0x9e, 0x1b, 0x16, 0x1e, 0x5c, 0x1f, 0xa7, 0x42, 0x5e, 0x73, 0x04, 0x33, 0x62,
+            0x93, 0x8b, 0x98, 0x24,
+        ];
+
+        for engine in engines() {
+            let dir = tempfile::tempdir().unwrap();
+            let file_path = dir.path().canonicalize().unwrap().join("x");
+
+            let options = KanshiOptions::default()
+                .with_engine(engine)
+                .with_compute_hash_on_modify(true);
+            let kanshi = Kanshi::new(options).unwrap();
+            kanshi.watch(dir.path()).await.unwrap();
+
+            let mut stream = kanshi.get_events_stream();
+            start_in_background(&kanshi);
+
+            std::fs::write(&file_path, "hello").unwrap();
+
+            let mut hash = None;
+            while hash != Some(expected) {
+                let Some(event) = next_event(&mut stream, Duration::from_secs(1)).await else {
+                    break;
+                };
+                if event.event_type == FileSystemEventType::Modify {
+                    hash = event.target.and_then(|x| x.metadata).and_then(|x| x.hash);
+                }
+            }
+
+            kanshi.close().unwrap();
+            assert_eq!(hash, Some(expected));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn fanotify_reports_pid() {
+        // fanotify needs CAP_SYS_ADMIN.
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().canonicalize().unwrap().join("x");
+
+        let options = KanshiOptions::default().with_engine(KanshiEngines::Fanotify);
+        let kanshi = Kanshi::new(options).unwrap();
+        kanshi.watch(dir.path()).await.unwrap();
+
+        let mut stream = kanshi.get_events_stream();
+        start_in_background(&kanshi);
+
+        let mut child = std::process::Command::new("touch").arg(&file_path).spawn().unwrap();
+        let child_pid = child.id();
+        child.wait().unwrap();
+
+        let mut pid = None;
+        while pid.is_none() {
+            let Some(event) = next_event(&mut stream, Duration::from_secs(1)).await else {
+                break;
+            };
+            if event.event_type == FileSystemEventType::Create {
+                pid = event.pid;
+            }
+        }
+
+        kanshi.close().unwrap();
+        assert_eq!(pid, Some(child_pid));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn watch_file_ignores_siblings() {
+        for engine in engines() {
+            let dir = tempfile::tempdir().unwrap();
+            let file_path = dir.path().canonicalize().unwrap().join("watched");
+            let sibling_path = dir.path().canonicalize().unwrap().join("sibling");
+            std::fs::write(&file_path, "").unwrap();
+            std::fs::write(&sibling_path, "").unwrap();
+
+            let kanshi = Kanshi::new(KanshiOptions::default().with_engine(engine)).unwrap();
+            kanshi.watch(&file_path).await.unwrap();
+
+            let mut stream = kanshi.get_events_stream();
+            start_in_background(&kanshi);
+
+            std::fs::write(&sibling_path, "x").unwrap();
+            std::fs::write(&file_path, "x").unwrap();
+
+            let paths = target_paths(collect_events(&mut stream, Duration::from_millis(500)).await);
+            kanshi.close().unwrap();
+            assert!(!paths.is_empty());
+            assert!(paths.iter().all(|x| *x == file_path), "{paths:?}");
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn watch_with_filter_reports_only_selected_event_types() {
+        for engine in engines() {
+            let filtered = tempfile::tempdir().unwrap();
+            let filtered_path = filtered.path().canonicalize().unwrap();
+            let unfiltered = tempfile::tempdir().unwrap();
+            let unfiltered_path = unfiltered.path().canonicalize().unwrap();
+
+            let kanshi = Kanshi::new(KanshiOptions::default().with_engine(engine)).unwrap();
+            kanshi
+                .watch_with_filter(&filtered_path, EventTypeMask::CREATE)
+                .await
+                .unwrap();
+            kanshi.watch(&unfiltered_path).await.unwrap();
+
+            let mut stream = kanshi.get_events_stream();
+            start_in_background(&kanshi);
+
+            for dir in [&filtered_path, &unfiltered_path] {
+                let mut file = std::fs::File::create(dir.join("x")).unwrap();
+                file.write_all(b"x").unwrap();
+            }
+
+            let mut filtered_events = Vec::new();
+            let mut unfiltered_events = Vec::new();
+            for event in collect_events(&mut stream, Duration::from_millis(500)).await {
+                let Some(target) = event.target else {
+                    continue;
+                };
+                if PathBuf::from(target.path).starts_with(&filtered_path) {
+                    filtered_events.push(event.event_type);
+                } else {
+                    unfiltered_events.push(event.event_type);
+                }
+            }
+
+            kanshi.close().unwrap();
+            assert!(!filtered_events.is_empty());
+            let create = EventTypeMask::CREATE;
+            assert!(
+                filtered_events.iter().all(|x| create.matches(x)),
+                "{filtered_events:?}"
+            );
+            assert!(
+                unfiltered_events.iter().any(|x| !create.matches(x)),
+                "{unfiltered_events:?}"
+            );
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn restart_keeps_paths_and_streams() {
+        for engine in engines() {
+            let dir = tempfile::tempdir().unwrap();
+            let dir_path = dir.path().canonicalize().unwrap();
+            std::fs::create_dir(dir_path.join("sub")).unwrap();
+
+            let kanshi = Kanshi::new(KanshiOptions::default().with_engine(engine)).unwrap();
+            kanshi.watch(&dir_path).await.unwrap();
+            let watched = kanshi.get_watched_paths();
+
+            let mut stream = kanshi.get_events_stream();
+            start_in_background(&kanshi);
+
+            kanshi.restart().await.unwrap();
+            assert_eq!(kanshi.get_watched_paths(), watched);
+
+            let file_path = dir_path.join("sub/x");
+            std::fs::File::create(&file_path).unwrap();
+            let mut paths = Vec::new();
+            while !paths.contains(&file_path) {
+                let Some(event) = next_event(&mut stream, Duration::from_secs(1)).await else {
+                    break;
+                };
+                paths.extend(target_paths(vec![event]));
+            }
+            assert!(paths.contains(&file_path), "{paths:?}");
+
+            kanshi.close().unwrap();
+            assert!(matches!(kanshi.restart().await, Err(KanshiError::StreamClosed)));
+        }
+    }
+
+    #[tokio::test]
+    async fn watch_many_watches_all_or_nothing() {
+        for engine in engines() {
+            let dir = tempfile::tempdir().unwrap();
+            let first = dir.path().join("first");
+            let second = dir.path().join("second");
+            std::fs::create_dir(&first).unwrap();
+            std::fs::create_dir(&second).unwrap();
+
+            let kanshi = Kanshi::new(KanshiOptions::default().with_engine(engine)).unwrap();
+
+            let missing = dir.path().join("missing");
+            assert!(matches!(
+                kanshi.watch_many(&[&first, &missing]).await,
+                Err(KanshiError::PathNotFound(_))
+            ));
+            assert!(matches!(
+                kanshi.unwatch(&first).await,
+                Err(KanshiError::PathNotWatched(_))
+            ));
+
+            kanshi.watch_many(&[&first, &second]).await.unwrap();
+            kanshi.unwatch(&first).await.unwrap();
+            kanshi.unwatch(&second).await.unwrap();
+
+            kanshi.close().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn try_watch_validates_before_marking() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("file");
+        std::fs::write(&file_path, "").unwrap();
+
+        let kanshi = Kanshi::new(KanshiOptions {
+            force_engine: Some(KanshiEngines::Inotify),
+            ..KanshiOptions::default()
+        })
+        .unwrap();
+
+        assert!(matches!(
+            kanshi.try_watch(dir.path().join("missing")).await,
+            Err(KanshiError::PathNotFound(_))
+        ));
+        assert!(kanshi.try_watch(&file_path).await.is_err());
+
+        let handle = kanshi.try_watch(dir.path()).await.unwrap();
+        assert_eq!(handle.path, dir.path().canonicalize().unwrap());
+        assert!(kanshi.try_watch(dir.path()).await.is_err());
+
+        kanshi.close().unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn bursts_do_not_lag() {
+        let dir = tempfile::tempdir().unwrap();
+        let kanshi = Kanshi::new(KanshiOptions {
+            force_engine: Some(KanshiEngines::Inotify),
+            ..KanshiOptions::default()
+        })
+        .unwrap();
+        kanshi.watch(dir.path()).await.unwrap();
+
+        let mut stream = kanshi.get_events_stream();
+        start_in_background(&kanshi);
+
+        // Far more events than the old capacity of 32, before reading any.
+        for idx in 0..200 {
+            std::fs::File::create(dir.path().join(idx.to_string())).unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let mut creates = 0;
+        while creates < 200 {
+            let Some(event) = next_event(&mut stream, Duration::from_secs(1)).await else {
+                break;
+            };
+            if event.event_type == FileSystemEventType::Create {
+                creates += 1;
+            }
+        }
+
+        kanshi.close().unwrap();
+        assert_eq!(creates, 200);
+    }
+
+    #[test]
+    fn builder_validates_options() {
+        let opts = KanshiOptions::default()
+            .with_engine(KanshiEngines::Inotify)
+            .with_channel_capacity(16)
+            .build()
+            .unwrap();
+        assert_eq!(opts.channel_capacity, 16);
+
+        let invalid = KanshiOptions::default().with_channel_capacity(0).build();
+        assert!(invalid.is_err());
+
+        let invalid = KanshiOptions::default().with_poll_interval_ms(0).build();
+        assert!(invalid.is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn poll_reports_changes_between_scans() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        std::fs::write(root.join("existing"), b"x").unwrap();
+
+        let kanshi = Kanshi::new(
+            KanshiOptions::default()
+                .with_engine(KanshiEngines::Poll)
+                .with_poll_interval_ms(50),
+        )
+        .unwrap();
+        kanshi.watch(&root).await.unwrap();
+
+        let mut stream = kanshi.get_events_stream();
+        start_in_background(&kanshi);
+
+        std::fs::write(root.join("new"), b"x").unwrap();
+        std::fs::write(root.join("existing"), b"xy").unwrap();
+        std::fs::create_dir(root.join("sub")).unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        std::fs::remove_file(root.join("new")).unwrap();
+
+        let events: Vec<_> = collect_events(&mut stream, Duration::from_millis(500))
+            .await
+            .into_iter()
+            .map(|x| {
+                let path = PathBuf::from(x.target.unwrap().path);
+                (x.event_type, path.strip_prefix(&root).unwrap().to_owned())
+            })
+            .collect();
+
+        kanshi.close().unwrap();
+        let expected = [
+            (FileSystemEventType::Modify, PathBuf::from("existing")),
+            (FileSystemEventType::Create, PathBuf::from("new")),
+            (FileSystemEventType::Create, PathBuf::from("sub")),
+            (FileSystemEventType::Delete, PathBuf::from("new")),
+        ];
+        assert_eq!(events, expected);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn changes_between_watch_and_start_are_reported() {
+        for engine in engines().into_iter().chain([KanshiEngines::Poll]) {
+            let dir = tempfile::tempdir().unwrap();
+            let root = dir.path().canonicalize().unwrap();
+            std::fs::write(root.join("existing"), b"x").unwrap();
+
+            let kanshi = Kanshi::new(
+                KanshiOptions::default()
+                    .with_engine(engine)
+                    .with_poll_interval_ms(50),
+            )
+            .unwrap();
+            kanshi.watch(&root).await.unwrap();
+            let mut stream = kanshi.get_events_stream();
+
+            // Files directly inside the watched directory, changed before
+            // `start` runs.
+            std::fs::write(root.join("existing"), b"xy").unwrap();
+            std::fs::write(root.join("new"), b"x").unwrap();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+
+            start_in_background(&kanshi);
+
+            let events: Vec<_> = collect_events(&mut stream, Duration::from_millis(500))
+                .await
+                .into_iter()
+                .map(|x| {
+                    let path = PathBuf::from(x.target.unwrap().path);
+                    (x.event_type, path.strip_prefix(&root).unwrap().to_owned())
+                })
+                .collect();
+
+            kanshi.close().unwrap();
+            let modified = (FileSystemEventType::Modify, PathBuf::from("existing"));
+            let created = (FileSystemEventType::Create, PathBuf::from("new"));
+            assert!(events.contains(&modified), "{events:?}");
+            assert!(events.contains(&created), "{events:?}");
         }
     }
 }
@@ -0,0 +1,99 @@
+use nix::{
+    fcntl::AT_FDCWD,
+    sys::{
+        fanotify::{EventFFlags, Fanotify, InitFlags, MarkFlags, MaskFlags},
+        inotify::{self, Inotify},
+    },
+};
+
+use crate::KanshiError;
+
+/// Optional fanotify features supported by the running kernel. See
+/// [`check_fanotify_availability`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FanotifyCapabilities {
+    /// `FAN_REPORT_FID` (Linux 5.1).
+    pub fan_report_fid: bool,
+    /// `FAN_REPORT_DFID_NAME` (Linux 5.9). Required by the fanotify engine.
+    pub fan_report_dfid_name: bool,
+    /// `FAN_REPORT_DFID_NAME_TARGET` (Linux 5.17).
+    pub fan_report_target_fid: bool,
+    /// `FAN_REPORT_PIDFD` (Linux 5.15).
+    pub fan_pidfd: bool,
+    /// `FAN_RENAME` events (Linux 5.17). Required by the fanotify engine.
+    pub fan_rename: bool,
+    /// `FAN_MARK_FILESYSTEM` marks (Linux 4.20).
+    pub fan_mark_filesystem: bool,
+    /// `FAN_MARK_EVICTABLE` marks (Linux 5.19).
+    pub fan_mark_evictable: bool,
+}
+
+impl FanotifyCapabilities {
+    /// Whether everything the fanotify engine needs is supported.
+    pub fn supports_engine(&self) -> bool {
+        self.fan_report_dfid_name && self.fan_rename
+    }
+}
+
+/// Checks whether fanotify can be used and which of its optional features the
+/// kernel supports.
+///
+/// Features are detected by creating throwaway fanotify groups with different
+/// flags, and adding marks on `/` to them, which are removed again when the
+/// group is closed. Returns an error if fanotify is unavailable altogether,
+/// e.g. when not running with `CAP_SYS_ADMIN`.
+pub fn check_fanotify_availability() -> Result<FanotifyCapabilities, KanshiError> {
+    let basic = init(InitFlags::empty())?;
+    let dfid_name = init(InitFlags::FAN_REPORT_DFID_NAME).ok();
+
+    Ok(FanotifyCapabilities {
+        fan_report_fid: init(InitFlags::FAN_REPORT_FID).is_ok(),
+        fan_report_dfid_name: dfid_name.is_some(),
+        fan_report_target_fid: init(InitFlags::FAN_REPORT_DFID_NAME_TARGET).is_ok(),
+        fan_pidfd: init(InitFlags::FAN_REPORT_PIDFD).is_ok(),
+        fan_rename: dfid_name.is_some_and(|x| {
+            mark(
+                &x,
+                MarkFlags::empty(),
+                MaskFlags::FAN_RENAME | MaskFlags::FAN_ONDIR,
+            )
+        }),
+        fan_mark_filesystem: mark(
+            &basic,
+            MarkFlags::FAN_MARK_FILESYSTEM,
+            MaskFlags::FAN_MODIFY,
+        ),
+        fan_mark_evictable: mark(&basic, MarkFlags::FAN_MARK_EVICTABLE, MaskFlags::FAN_MODIFY),
+    })
+}
+
+/// Checks whether an inotify instance can be created, which fails e.g. when
+/// `fs.inotify.max_user_instances` has been reached.
+pub fn check_inotify_availability() -> Result<(), KanshiError> {
+    Inotify::init(inotify::InitFlags::IN_CLOEXEC)?;
+    Ok(())
+}
+
+fn init(flags: InitFlags) -> Result<Fanotify, KanshiError> {
+    let fanotify = Fanotify::init(
+        InitFlags::FAN_CLASS_NOTIF | InitFlags::FAN_CLOEXEC | flags,
+        EventFFlags::O_RDONLY | EventFFlags::O_CLOEXEC,
+    )?;
+    Ok(fanotify)
+}
+
+fn mark(fanotify: &Fanotify, flags: MarkFlags, mask: MaskFlags) -> bool {
+    fanotify
+        .mark(MarkFlags::FAN_MARK_ADD | flags, mask, AT_FDCWD, Some("/"))
+        .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::check_inotify_availability;
+
+    #[test]
+    fn inotify_is_available() {
+        assert!(check_inotify_availability().is_ok());
+    }
+}
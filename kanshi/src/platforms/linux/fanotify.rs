@@ -1,8 +1,18 @@
 use std::{
-    collections::{HashSet, VecDeque}, ffi::{OsStr, OsString}, fs, io, os::{
-        fd::{AsFd, AsRawFd},
-        unix::fs::MetadataExt,
-    }, path::{Path, PathBuf}, pin::Pin, sync::Arc
+    collections::{HashMap, HashSet},
+    ffi::{CString, OsStr, OsString},
+    mem::MaybeUninit,
+    os::{
+        fd::{AsFd, AsRawFd, BorrowedFd},
+        unix::ffi::OsStrExt,
+    },
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
 };
 
 use async_stream::stream;
@@ -11,201 +21,643 @@ use nix::{
     fcntl::AT_FDCWD,
     sys::{
         epoll::Epoll,
-        fanotify::{Fanotify, FanotifyFidEventInfoType, FanotifyFidRecord, FanotifyInfoRecord},
+        fanotify::{
+            Fanotify, FanotifyFidEventInfoType, FanotifyFidRecord, FanotifyInfoRecord,
+            FanotifyResponse, MarkFlags, Response,
+        },
     },
 };
 use tokio::sync::broadcast::error::RecvError;
 use tokio_util::sync::CancellationToken;
 
 use crate::{
-    FileSystemEvent, FileSystemEventType, FileSystemTarget, FileSystemTargetKind, KanshiError,
-    KanshiImpl,
+    platforms::{
+        canonicalize_all, close_on_sigint, create_watched_dir, filter::PathFilter, report_error,
+        RunningGuard,
+    },
+    runtime,
+    sender::{EventCallbacks, EventSender, EventStats},
+    EventStatistics, FileSystemEvent, FileSystemEventType, FileSystemTarget, FileSystemTargetKind,
+    KanshiError, KanshiImpl, KanshiStreamItem, PermissionEvent,
 };
 
-use super::KanshiOptions;
+use super::{
+    path_remap::{unescape_mountinfo, OverlayLayers},
+    resolve::{resolve_beneath, WatchRoot},
+    traverse::{
+        depth_below, for_each_subdirectory, for_each_subdirectory_from, is_excluded, is_watched,
+        within_depth_limit,
+    },
+    uring::Uring,
+    KanshiOptions, LinuxBackend, PathResolutionMode,
+};
 
 #[derive(Clone)]
 pub struct FanotifyTracer {
     fanotify: Arc<Fanotify>,
     epoll: Arc<Epoll>,
-    sender: tokio::sync::broadcast::Sender<FileSystemEvent>,
+    // Waits for events instead of `epoll` with `LinuxBackend::IoUring`.
+    uring: Option<Arc<Uring>>,
+    sender: tokio::sync::broadcast::Sender<KanshiStreamItem>,
     cancellation_token: CancellationToken,
+    paused: Arc<AtomicBool>,
+    // Set while `start` runs, shared with clones.
+    running: Arc<AtomicBool>,
+    callbacks: EventCallbacks,
+    sequence: Arc<AtomicU64>,
+    options: Arc<KanshiOptions>,
+    roots: Arc<Mutex<Vec<WatchRoot>>>,
+    excluded: Arc<Mutex<HashSet<PathBuf>>>,
+    // `KanshiOptions::exclude_patterns` and `exclude_regexes`, compiled once
+    // in `new`.
+    exclude_filter: Arc<PathFilter>,
+    // Canonical paths of the directories passed to `watch`.
+    watched: Arc<Mutex<HashSet<PathBuf>>>,
+    // Canonical paths of the files passed to `watch`. Only their parent
+    // directories are marked.
+    files: Arc<Mutex<HashSet<PathBuf>>>,
+    // Paths of files marked for `FAN_DELETE_SELF`, keyed by their file handle.
+    file_handles: Arc<Mutex<HashMap<Vec<u8>, OsString>>>,
+    // Directories watched with `KanshiOptions::use_filesystem_mark` or
+    // `mount_level_watch`, and the path marked for each one along with the
+    // kind of mark, `FAN_MARK_FILESYSTEM` or `FAN_MARK_MOUNT`.
+    wide_marks: Arc<Mutex<HashMap<PathBuf, (PathBuf, MarkFlags)>>>,
+    // The group permission events are read from, with
+    // `KanshiOptions::permission_mode`. It marks the same directories.
+    permissions: Option<Arc<Fanotify>>,
+    // The number of directories marked through `mark` and not unmarked or
+    // deleted since.
+    mark_count: Arc<AtomicUsize>,
+    // Paths of the directories marked through `mark`, keyed by their file
+    // handle, so that the marks of deleted and moved directories can be
+    // accounted for.
+    dir_handles: Arc<Mutex<HashMap<Vec<u8>, PathBuf>>>,
+    // The counters behind `stats`.
+    stats: Arc<EventStats>,
+    // The overlay layers event paths are remapped from, with
+    // `KanshiOptions::overlay_remap`. Empty otherwise.
+    overlays: Arc<OverlayLayers>,
+    // Whether `MarkQuotaNearlyExhausted` was reported already.
+    quota_warned: Arc<AtomicBool>,
+    // Whether the group was created with `FAN_UNLIMITED_MARKS`, which exempts
+    // it from `fs.fanotify.max_user_marks`.
+    unlimited_marks: bool,
 }
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct FileHandle {
-    pub handle_bytes: u32,
-    pub handle_type: i32,
-    pub f_handle: [u8; 0],
-}
+impl FanotifyTracer {
+    /// The options this tracer was created with.
+    pub(crate) fn options(&self) -> &KanshiOptions {
+        &self.options
+    }
 
-impl KanshiImpl<KanshiOptions> for FanotifyTracer {
-    fn new(_opts: KanshiOptions) -> Result<FanotifyTracer, KanshiError> {
-        use nix::sys::epoll::{EpollCreateFlags, EpollEvent, EpollFlags};
-        use nix::sys::fanotify::{EventFFlags, InitFlags};
+    /// The number of directories currently marked. Marking a directory that
+    /// is already marked, e.g. the parent of two watched files, counts twice.
+    /// Marks of `watch_filesystem_device`, `use_filesystem_mark` and
+    /// `mount_level_watch` are not counted.
+    pub fn mark_count(&self) -> usize {
+        self.mark_count.load(Ordering::SeqCst)
+    }
 
-        #[allow(non_snake_case)]
-        let INIT_FLAGS: InitFlags = InitFlags::FAN_CLASS_NOTIF
-            | InitFlags::FAN_REPORT_DFID_NAME
-            | InitFlags::FAN_UNLIMITED_QUEUE
-            // | InitFlags::FAN_REPORT_TARGET_FID
-            // | InitFlags::FAN_REPORT_FID
-            | InitFlags::FAN_UNLIMITED_MARKS;
-        #[allow(non_snake_case)]
-        let EVENT_FLAGS: EventFFlags =
-            EventFFlags::O_RDONLY | EventFFlags::O_NONBLOCK | EventFFlags::O_CLOEXEC;
+    /// A snapshot of the events sent and dropped so far, and of the marks in
+    /// use, counted like `mark_count`.
+    pub fn stats(&self) -> EventStatistics {
+        self.stats
+            .snapshot(self.mark_count(), self.options.channel_capacity)
+    }
 
-        let fanotify_fd = Fanotify::init(INIT_FLAGS, EVENT_FLAGS);
+    /// `stats` in the Prometheus text exposition format.
+    #[cfg(feature = "metrics")]
+    pub fn stats_as_prometheus(&self) -> String {
+        self.stats().to_prometheus()
+    }
 
-        if let Ok(fanotify) = fanotify_fd {
-            // Setup epoll
-            let epoll_event =
-                EpollEvent::new(EpollFlags::EPOLLIN, fanotify.as_fd().as_raw_fd() as u64);
+    /// The number of clones of this tracer that are alive, including itself.
+    #[cfg(test)]
+    pub(crate) fn clone_count(&self) -> usize {
+        Arc::strong_count(&self.fanotify)
+    }
 
-            let epoll_fd = Epoll::new(EpollCreateFlags::EPOLL_CLOEXEC);
+    /// How many more marks can be added before `fs.fanotify.max_user_marks`
+    /// is reached, counting only this tracer's marks. `None` if the limit
+    /// can't be read, or doesn't apply because the group was created with
+    /// `FAN_UNLIMITED_MARKS`, which the engine requests unless
+    /// `KanshiOptions::mark_quota_warn_threshold` is set.
+    pub fn marks_remaining(&self) -> Option<usize> {
+        if self.unlimited_marks {
+            return None;
+        }
+        let limit = std::fs::read_to_string("/proc/sys/fs/fanotify/max_user_marks").ok()?;
+        let limit = limit.trim().parse::<usize>().ok()?;
+        Some(limit.saturating_sub(self.mark_count()))
+    }
 
-            if let Ok(epoll) = epoll_fd {
-                if let Err(e) = epoll.add(fanotify.as_fd(), epoll_event) {
-                    Err(KanshiError::FileSystemError(e.to_string()))
-                } else {
-                    let (tx, _rx) = tokio::sync::broadcast::channel(32);
-                    let engine = FanotifyTracer {
-                        // mark_set: HashSet::new(),
-                        fanotify: Arc::new(fanotify),
-                        epoll: Arc::new(epoll),
-                        sender: tx,
-                        // reciever: rx,
-                        cancellation_token: CancellationToken::new(),
+    /// Reports `MarkQuotaNearlyExhausted` the first time the share of marks
+    /// in use exceeds `KanshiOptions::mark_quota_warn_threshold`.
+    fn check_mark_quota(&self) {
+        let Some(threshold) = self.options.mark_quota_warn_threshold else {
+            return;
+        };
+        let Some(remaining) = self.marks_remaining() else {
+            return;
+        };
+        let used = self.mark_count();
+        let limit = used + remaining;
+        if used as f32 > limit as f32 * threshold && !self.quota_warned.swap(true, Ordering::SeqCst)
+        {
+            (self.options.on_error)(KanshiError::MarkQuotaNearlyExhausted { used, limit });
+        }
+    }
+
+    /// Marks the directory at `path`, in the permission group as well with
+    /// `KanshiOptions::permission_mode`.
+    fn mark(&self, path: &Path) -> Result<(), KanshiError> {
+        mark(&self.fanotify, path, &self.options)?;
+        self.mark_count.fetch_add(1, Ordering::SeqCst);
+        if let Some(handle) = file_handle(path) {
+            self.dir_handles.lock().unwrap().insert(handle, path.to_path_buf());
+        }
+        self.check_mark_quota();
+        if let Some(permissions) = self.permissions.as_ref() {
+            permissions
+                .mark(MarkFlags::FAN_MARK_ADD, permission_mask_flags(), AT_FDCWD, Some(path))
+                .map_err(|e| KanshiError::from_errno_at(e, path))?;
+        }
+        Ok(())
+    }
+
+    /// Removes the marks added by `mark`.
+    fn unmark(&self, path: &Path) -> Result<(), KanshiError> {
+        if let Some(permissions) = self.permissions.as_ref() {
+            let _ = permissions.mark(
+                MarkFlags::FAN_MARK_REMOVE,
+                permission_mask_flags(),
+                AT_FDCWD,
+                Some(path),
+            );
+        }
+        unmark(&self.fanotify, path, &self.options)?;
+        self.dir_handles.lock().unwrap().retain(|_, x| x.as_path() != path);
+        let _ = self
+            .mark_count
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |x| x.checked_sub(1));
+        Ok(())
+    }
+
+    /// Accounts for a marked directory that was deleted or moved, reported by
+    /// `FAN_DELETE_SELF` or `FAN_MOVE_SELF` on the directory itself. Marks
+    /// belong to the inode, so the kernel drops the mark of a deleted
+    /// directory on its own, and the mark of a moved one keeps working at its
+    /// new path. A directory moved out of the watched trees is unmarked along
+    /// with the marked directories and files below it. Its parent reports the
+    /// deletion or move, so nothing is sent here.
+    fn handle_dir_self_event(&self, mask: nix::sys::fanotify::MaskFlags, handle: &[u8]) {
+        use nix::sys::fanotify::MaskFlags;
+
+        if mask.contains(MaskFlags::FAN_DELETE_SELF) {
+            let removed = self.dir_handles.lock().unwrap().remove(handle);
+            if let Some(path) = removed {
+                let _ = self
+                    .mark_count
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |x| x.checked_sub(1));
+                // The files below it are gone as well, unless still held open.
+                self.file_handles.lock().unwrap().retain(|handle, x| {
+                    !Path::new(x).starts_with(&path) || handle_path(handle).is_some()
+                });
+            }
+            return;
+        }
+
+        let new_path =
+            handle_path(handle).map(|x| PathBuf::from(self.overlays.remap(x.into_os_string())));
+        let Some(new_path) = new_path else {
+            return;
+        };
+        let still_watched = {
+            let excluded = self.excluded.lock().unwrap();
+            let watched = self.watched.lock().unwrap();
+            watched.iter().any(|x| new_path.starts_with(x))
+                && within_depth_limit(&self.options, &*watched, &new_path)
+                && !is_excluded(&excluded, &new_path)
+                && !self.exclude_filter.is_match_dir(&new_path)
+        };
+        let old_path = self.dir_handles.lock().unwrap().get(handle).cloned();
+        if let Some(old_path) = old_path {
+            self.move_file_marks(&old_path, &new_path, still_watched);
+        }
+
+        let moved = {
+            let mut dir_handles = self.dir_handles.lock().unwrap();
+            let Some(old_path) = dir_handles.get(handle).cloned() else {
+                return;
+            };
+            // The directories below it moved along with it.
+            for path in dir_handles.values_mut() {
+                if let Ok(relative) = path.strip_prefix(&old_path) {
+                    *path = match relative.as_os_str().is_empty() {
+                        true => new_path.clone(),
+                        false => new_path.join(relative),
                     };
-                    Ok(engine)
                 }
+            }
+            if still_watched {
+                return;
+            }
+            dir_handles
+                .values()
+                .filter(|x| x.starts_with(&new_path))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        for path in moved {
+            let _ = self.unmark(&path);
+        }
+    }
+
+    /// Follows the files marked by `mark_file` at or below `from` to `to`
+    /// after a rename, or unmarks them if `to` is no longer watched.
+    fn move_file_marks(&self, from: &Path, to: &Path, still_watched: bool) {
+        use nix::sys::fanotify::MaskFlags;
+
+        self.file_handles.lock().unwrap().retain(|_, path| {
+            let Ok(relative) = Path::new(path).strip_prefix(from) else {
+                return true;
+            };
+            let new_path = match relative.as_os_str().is_empty() {
+                true => to.to_path_buf(),
+                false => to.join(relative),
+            };
+            if still_watched {
+                *path = new_path.into_os_string();
+                return true;
+            }
+            let _ = self.fanotify.mark(
+                MarkFlags::FAN_MARK_REMOVE,
+                MaskFlags::FAN_DELETE_SELF,
+                AT_FDCWD,
+                Some(&new_path),
+            );
+            false
+        });
+    }
+
+    /// Reads the pending permission requests and broadcasts the ones for
+    /// watched files. The others, and every request while the tracer is
+    /// paused, are allowed right away by dropping them.
+    fn forward_permission_requests(&self, permissions: &Arc<Fanotify>) -> Result<(), KanshiError> {
+        use nix::sys::fanotify::MaskFlags;
+
+        for event in permissions.read_events()? {
+            // Only an overflow comes without a file, and needs no response.
+            let Some(fd) = event.fd() else {
+                continue;
+            };
+            let path = std::fs::read_link(format!("/proc/self/fd/{}", fd.as_raw_fd()))
+                .ok()
+                .map(|x| PathBuf::from(self.overlays.remap(x.into_os_string())));
+            let forward = path.as_ref().is_some_and(|path| {
+                let watched = self.watched.lock().unwrap();
+                is_watched(&self.files.lock().unwrap(), &*watched, path)
+                    && !self.exclude_filter.is_match(path)
+                    && !self.paused.load(Ordering::SeqCst)
+            });
+            let event_type = if event.mask().contains(MaskFlags::FAN_ACCESS_PERM) {
+                FileSystemEventType::Access
             } else {
-                let e = epoll_fd.err().unwrap();
-                Err(KanshiError::FileSystemError(e.to_string()))
+                FileSystemEventType::Open
+            };
+            let target = path.map(|path| FileSystemTarget {
+                kind: FileSystemTargetKind::File,
+                path: path.into_os_string(),
+                device_inode: None,
+                metadata: None,
+            });
+            let pid = u32::try_from(event.pid()).ok().filter(|x| *x > 0);
+
+            let permissions = permissions.clone();
+            let request = FileSystemEvent {
+                event_type,
+                target,
+                pid,
+                timestamp: None,
+                sequence: 0,
+            };
+            let request = PermissionEvent::new(request, move |allow| {
+                let response = if allow {
+                    Response::FAN_ALLOW
+                } else {
+                    Response::FAN_DENY
+                };
+                // `event` owns the descriptor, so it is still open.
+                if let Some(fd) = event.fd() {
+                    permissions.write_response(FanotifyResponse::new(fd, response))?;
+                }
+                Ok(())
+            });
+            if forward {
+                let _ = self.sender.send(KanshiStreamItem::PermissionRequest(request));
             }
-        } else {
-            Err(KanshiError::FileSystemError(
-                io::Error::last_os_error().to_string(),
-            ))
         }
+        Ok(())
     }
 
-    async fn watch(&self, dir: &str) -> Result<(), KanshiError> {
+    /// Watches the entire filesystem that `path` lives on with a single
+    /// `FAN_MARK_FILESYSTEM` mark, covering every mount of that device. Any
+    /// path on the filesystem can be used. Requires Linux 4.20.
+    ///
+    /// The kernel only allows `FAN_MARK_EVICTABLE` on inode marks, so this mark
+    /// is not evictable. Being a single mark, it does not count against the
+    /// per-directory mark limit either.
+    pub fn watch_filesystem_device(&self, path: &str) -> Result<(), KanshiError> {
         if self.cancellation_token.is_cancelled() {
-            return Err(KanshiError::StreamClosedError);
+            return Err(KanshiError::StreamClosed);
         }
 
-        let mark_top_dir = mark(&self.fanotify, Path::new(dir));
+        #[allow(non_snake_case)]
+        let MARK_FLAGS = MarkFlags::FAN_MARK_ADD | MarkFlags::FAN_MARK_FILESYSTEM;
 
-        if let Ok(_) = mark_top_dir {
-            let mut traversal_queue = VecDeque::from([PathBuf::from(dir)]);
-            let mut visited = HashSet::<u64>::new();
-
-            'outer: loop {
-                if let Some(next_dir) = traversal_queue.pop_front() {
-                    if let Ok(dir_items) = fs::read_dir(next_dir) {
-                        for dir_item in dir_items {
-                            if let Ok(dir_item_unwrapped) = dir_item {
-                                if let Ok(metadata) = dir_item_unwrapped.metadata() {
-                                    let inode_number = metadata.ino();
-                                    if !visited.contains(&inode_number) && !metadata.is_symlink() {
-                                        visited.insert(inode_number);
-                                        if dir_item_unwrapped.path().is_dir() {
-                                            if let Err(e) =
-                                                mark(&self.fanotify, &dir_item_unwrapped.path())
-                                            {
-                                                return Err(e);
-                                            }
-                                            traversal_queue.push_back(dir_item_unwrapped.path());
-                                        }
-                                    }
-                                }
-                            } else {
-                                break 'outer;
-                            }
-                        }
-                    } else {
-                        break 'outer;
-                    }
-                } else {
-                    break 'outer;
-                }
+        self.fanotify
+            .mark(MARK_FLAGS, mask_flags(&self.options), AT_FDCWD, Some(path))
+            .map_err(|e| KanshiError::from_errno_at(e, path))
+    }
+
+    /// Starts the tracer and returns an iterator over its events, for callers
+    /// that don't use async. See [`KanshiBlockingIter`](crate::KanshiBlockingIter).
+    #[cfg(feature = "runtime-tokio")]
+    pub fn into_blocking_iter(self) -> Result<crate::KanshiBlockingIter, KanshiError> {
+        crate::KanshiBlockingIter::new(self, None)
+    }
+
+    /// Removes every mark and marks the watched directories and files again,
+    /// e.g. after the filesystem they live on was unmounted and mounted again.
+    /// The group, `start` and the event streams are kept, so subscribers keep
+    /// receiving events without subscribing again.
+    ///
+    /// Paths that can't be marked again stay watched, so a later `restart`
+    /// retries them, and their errors are returned together. Marks added with
+    /// `watch_filesystem_device` are removed and not restored.
+    pub async fn restart(&self) -> Result<(), KanshiError> {
+        if self.cancellation_token.is_cancelled() {
+            return Err(KanshiError::StreamClosed);
+        }
+
+        let dirs: Vec<PathBuf> = self.watched.lock().unwrap().drain().collect();
+        let files: Vec<PathBuf> = self.files.lock().unwrap().drain().collect();
+        self.wide_marks.lock().unwrap().clear();
+        self.roots.lock().unwrap().clear();
+        self.file_handles.lock().unwrap().clear();
+        self.dir_handles.lock().unwrap().clear();
+        flush_marks(&self.fanotify)?;
+        if let Some(permissions) = self.permissions.as_ref() {
+            flush_marks(permissions)?;
+        }
+        self.mark_count.store(0, Ordering::SeqCst);
+
+        let mut errors = Vec::new();
+        for dir in dirs {
+            if let Err(e) = self.watch(&dir).await {
+                self.watched.lock().unwrap().insert(dir);
+                errors.push(e);
             }
+        }
+        for file in files {
+            if let Err(e) = self.watch_file(&file) {
+                self.files.lock().unwrap().insert(file);
+                errors.push(e);
+            }
+        }
+        KanshiError::from_errors(errors)
+    }
 
-            Ok(())
+    /// Watches `dir` with a single mark of `mark_type`, either
+    /// `FAN_MARK_FILESYSTEM` on the filesystem it lives on, or `FAN_MARK_MOUNT`
+    /// on the mount it lives on. Each filesystem or mount is only marked once,
+    /// however many watched directories live on it.
+    fn mark_wide(&self, dir: &Path, mark_type: MarkFlags) -> Result<(), KanshiError> {
+        let target = if mark_type == MarkFlags::FAN_MARK_MOUNT {
+            mount_root(dir)?
         } else {
-            mark_top_dir
+            mount_point(dir)?
+        };
+        let mark = (target, mark_type);
+        let mut marks = self.wide_marks.lock().unwrap();
+        if !marks.values().any(|x| *x == mark) {
+            self.fanotify
+                .mark(
+                    MarkFlags::FAN_MARK_ADD | mark_type,
+                    wide_mask_flags(&self.options, mark_type),
+                    AT_FDCWD,
+                    Some(&mark.0),
+                )
+                .map_err(|e| KanshiError::from_errno_at(e, &mark.0))?;
         }
+        marks.insert(dir.to_path_buf(), mark);
+        Ok(())
     }
 
-    fn get_events_stream(&self) -> Pin<Box<dyn futures::Stream<Item = FileSystemEvent> + Send>> {
-        let mut listener = self.sender.subscribe();
-        let cancel_token = self.cancellation_token.clone();
+    /// Whether an event for `path` should be reported, given that
+    /// `FAN_MARK_FILESYSTEM` and `FAN_MARK_MOUNT` marks report every change on
+    /// their filesystem or mount.
+    /// `path` must be below a watched directory, within
+    /// `KanshiOptions::depth_limit`, and not below an excluded directory.
+    /// Always true when no filesystem is marked.
+    fn is_covered(&self, path: &Path) -> bool {
+        if self.wide_marks.lock().unwrap().is_empty() {
+            return true;
+        }
 
-        let events_stream = stream! {
-            loop {
-                tokio::select! {
-                    _ = cancel_token.cancelled() => {
-                        break;
-                    }
-                    val = listener.recv() => {
-                        match val {
-                            Ok(x) => yield x,
-                            Err(e) => match e {
-                                RecvError::Closed => break,
-                                _ => ()
-                            }
-                        }
-                    }
-                }
-            }
+        // Lock order is `excluded`, `watched`, then `files`, as elsewhere.
+        let excluded = self.excluded.lock().unwrap();
+        let watched = self.watched.lock().unwrap();
+        if self.files.lock().unwrap().contains(path) {
+            return true;
+        }
+        let Some(parent) = path.parent() else {
+            return false;
         };
+        if !watched.iter().any(|x| parent.starts_with(x))
+            || !within_depth_limit(&self.options, &*watched, parent)
+        {
+            return false;
+        }
+        // The directories between `parent` and the watched directory above
+        // it would not have been marked.
+        !parent
+            .ancestors()
+            .take_while(|x| !watched.contains(*x))
+            .any(|x| excluded.contains(x) || self.exclude_filter.is_match_dir(x))
+    }
 
-        Box::pin(events_stream)
+    /// Watches a single file by marking its parent directory. Marking the
+    /// directory instead of the file keeps reporting the file after it is
+    /// replaced by a rename. `start` drops events for the other entries in
+    /// that directory, which needs the full path of every event, so this is not
+    /// supported with `PathResolutionMode::NameOnly`.
+    fn watch_file(&self, file: &Path) -> Result<(), KanshiError> {
+        if self.options.path_resolution_mode == PathResolutionMode::NameOnly {
+            return Err(KanshiError::InvalidParameter(
+                "Watching a single file is not supported with PathResolutionMode::NameOnly."
+                    .to_owned(),
+            ));
+        }
+
+        let file = file.canonicalize()?;
+        let parent = file.parent().unwrap_or(Path::new("/"));
+        if self.options.path_resolution_mode == PathResolutionMode::Beneath {
+            let root = WatchRoot::open(parent)?;
+            self.roots.lock().unwrap().push(root);
+        }
+
+        self.mark(parent)?;
+        self.files.lock().unwrap().insert(file);
+        Ok(())
     }
 
-    async fn start(&self) -> Result<(), KanshiError> {
+    /// Marks the subdirectories of `dir`, a directory `start` just marked,
+    /// within `KanshiOptions::max_depth`. Entries created before the mark was
+    /// added are not reported, so without this the directories of a
+    /// `mkdir -p a/b/c` below `a` would never be marked.
+    fn mark_created_subdirectories(&self, dir: &Path) -> Result<(), KanshiError> {
+        let excluded = self.excluded.lock().unwrap();
+        let depth = depth_below(&*self.watched.lock().unwrap(), dir).unwrap_or(0);
+        for_each_subdirectory_from(
+            dir,
+            depth,
+            &self.options,
+            &excluded,
+            &self.exclude_filter,
+            |path| match self.mark(path) {
+                // Removed again since it was read.
+                Ok(()) | Err(KanshiError::PathNotFound(_)) => Ok(()),
+                Err(err) => Err(err),
+            },
+        )
+    }
+
+    /// The body of `start`, whose error `start` forwards to the streams.
+    async fn run(&self) -> Result<(), KanshiError> {
         use nix::sys::epoll::EpollEvent;
 
+        let _running = RunningGuard::acquire(&self.running)?;
         let cancel_token = self.cancellation_token.clone();
-        let sender = self.sender.clone();
+        let mut sender = EventSender::new(
+            self.sender.clone(),
+            self.options.reconnect_timeout,
+            self.options
+                .reconnect_buffer_size
+                .min(self.options.channel_capacity),
+            self.options.grace_period,
+            self.options.debounce_ms.map(Duration::from_millis),
+            self.options.coalesce_window_ms.map(Duration::from_millis),
+            self.options.max_events_per_second,
+        )
+        .with_metadata(self.options.include_metadata)
+        .with_hashes(self.options.compute_hash_on_modify)
+        .with_hard_links(self.options.detect_hard_links)
+        .with_timestamps(self.options.approximate_timestamp)
+        .with_pause(self.paused.clone(), self.options.buffer_while_paused)
+        .with_callbacks(self.callbacks.clone())
+        .with_sequence(self.sequence.clone())
+        .with_stats(self.stats.clone());
 
-        let mut events = [EpollEvent::empty(); 1];
+        let _sigint = self.options.handle_sigint.then(|| {
+            let tracer = self.clone();
+            close_on_sigint(cancel_token.clone(), move || {
+                if let Err(e) = tracer.close() {
+                    (tracer.options.on_error)(e);
+                }
+            })
+        });
+
+        let fanotify_token = self.fanotify.as_fd().as_raw_fd() as u64;
+        let permissions_token = self.permissions.as_ref().map(|x| x.as_fd().as_raw_fd() as u64);
+        let mut events = [EpollEvent::empty(); 2];
+        // What `uring` polls, with the same tokens as `epoll`.
+        let fds: Vec<_> = std::iter::once(self.fanotify.as_fd())
+            .chain(self.permissions.as_ref().map(|x| x.as_fd()))
+            .map(|fd| (fd, fd.as_raw_fd() as u64))
+            .collect();
 
         while !cancel_token.is_cancelled() {
             use nix::sys::fanotify::MaskFlags;
 
-            events.fill(EpollEvent::empty());
-            let res = tokio::task::block_in_place(move || self.epoll.wait(&mut events, 16u8));
-            if let Err(e) = res {
-                println!("epoll failed {e}");
-                res?;
+            sender.poll()?;
+
+            let ready: Vec<u64> = match self.uring.as_ref() {
+                Some(uring) => uring.wait(&fds, Duration::from_millis(16)).await?,
+                None => {
+                    events.fill(EpollEvent::empty());
+                    let count = runtime::block_in_place(|| self.epoll.wait(&mut events, 16u8))
+                        .inspect_err(|e| tracing::error!(error = %e, "epoll_wait failed"))?;
+                    events[..count].iter().map(|x| x.data()).collect()
+                }
+            };
+            if let Some(permissions) = self.permissions.as_ref() {
+                if permissions_token.is_some_and(|x| ready.contains(&x)) {
+                    self.forward_permission_requests(permissions)?;
+                }
             }
-            if res.ok().unwrap() > 0 {
+            if ready.contains(&fanotify_token) {
                 let all_records = self.fanotify.read_events_with_info_records()?;
+                let mode = self.options.path_resolution_mode;
+                let stale_retries = self.options.stale_retry_count;
+                let roots = self.roots.lock().unwrap();
+                // Paths are relative in `NameOnly` mode, so they can't be marked.
+                let can_mark_paths = mode != PathResolutionMode::NameOnly;
+                let filesystem_marked = !self.wide_marks.lock().unwrap().is_empty();
                 'outer: for (event, records) in all_records {
                     let kind = if event.mask().contains(MaskFlags::FAN_ONDIR) {
                         FileSystemTargetKind::Directory
                     } else {
                         FileSystemTargetKind::File
                     };
+                    // Zero when the process is outside our pid namespace.
+                    let pid = u32::try_from(event.pid()).ok().filter(|x| *x > 0);
+                    if kind == FileSystemTargetKind::Directory
+                        && event.mask().intersects(MaskFlags::FAN_DELETE_SELF | MaskFlags::FAN_MOVE_SELF)
+                    {
+                        let handle = records.iter().find_map(|x| match x {
+                            FanotifyInfoRecord::Fid(record) => Some(record.handle()),
+                            _ => None,
+                        });
+                        if let Some(handle) = handle {
+                            self.handle_dir_self_event(event.mask(), &handle);
+                        }
+                        continue 'outer;
+                    }
                     // Handle Moves/Renames separately
                     if event.mask().contains(MaskFlags::FAN_RENAME) {
                         let mut moved_from = None;
                         let mut moved_to = None;
+                        let mut device_inode = None;
+                        let mut is_symlink = false;
                         for record in records {
                             if let FanotifyInfoRecord::Fid(record) = record {
-                                let path = {
-                                    let path = get_path_from_record(&record);
-                                    if let Err(e) = path {
-                                        if e == Errno::ESTALE {
-                                            break;
+                                if record.info_type() == FanotifyFidEventInfoType::FAN_EVENT_INFO_TYPE_FID {
+                                    continue;
+                                }
+                                let (path, identity, symlink) = match get_path_from_record(&record, mode, &roots, stale_retries) {
+                                    Ok((path, identity, symlink)) => (self.overlays.remap(path), identity, symlink),
+                                    Err(Errno::ESTALE) => {
+                                        let event = stale_event(&record, kind.clone(), pid);
+                                        // Without its directory, there is no telling whether an
+                                        // entry on a marked filesystem is watched.
+                                        if filesystem_marked {
+                                            continue 'outer;
+                                        }
+                                        if !self.exclude_filter.is_match(&event.target.as_ref().unwrap().path) {
+                                            sender.send(event)?;
                                         }
-                                        println!("another error occurred ${e}");
+                                        continue 'outer;
+                                    }
+                                    Err(e) => {
+                                        (self.options.on_error)(e.into());
+                                        continue 'outer;
                                     }
-                                    path?
                                 };
+                                // Only the new name still exists, but both halves of a rename
+                                // refer to the same inode.
+                                device_inode = device_inode.or(identity);
+                                is_symlink |= symlink;
                                 if record.info_type() == FanotifyFidEventInfoType::FAN_EVENT_INFO_TYPE_OLD_DFID_NAME {
                                     moved_from = Some(path);
                                 } else if record.info_type() == FanotifyFidEventInfoType::FAN_EVENT_INFO_TYPE_NEW_DFID_NAME {
@@ -214,106 +666,203 @@ impl KanshiImpl<KanshiOptions> for FanotifyTracer {
                             }
                         }
 
+                        if self.options.watch_open_file_deletion && kind == FileSystemTargetKind::File {
+                            if let (Some(from), Some(to)) = (&moved_from, &moved_to) {
+                                let to = Path::new(to);
+                                let still_watched = self.is_covered(to) && !self.exclude_filter.is_match(to) && {
+                                    let watched = self.watched.lock().unwrap();
+                                    is_watched(&self.files.lock().unwrap(), &*watched, to)
+                                };
+                                self.move_file_marks(Path::new(from), to, still_watched);
+                            }
+                        }
+
+                        let covered = [&moved_from, &moved_to]
+                            .into_iter()
+                            .flatten()
+                            .any(|x| self.is_covered(Path::new(x)));
+                        let reported = covered && {
+                            let watched = self.watched.lock().unwrap();
+                            let files = self.files.lock().unwrap();
+                            files.is_empty()
+                                || [&moved_from, &moved_to].into_iter().flatten().any(|x| {
+                                    is_watched(&files, &*watched, Path::new(x))
+                                })
+                        };
+                        // Moves are only dropped when both names are excluded.
+                        let names: Vec<&OsString> = moved_from.iter().chain(&moved_to).collect();
+                        let excluded_names = !names.is_empty()
+                            && names.iter().all(|x| self.exclude_filter.is_match(x));
+                        if !reported || excluded_names {
+                            continue 'outer;
+                        }
+
+                        let kind = if is_symlink { FileSystemTargetKind::Symlink } else { kind };
                         if moved_from.is_none() || moved_to.is_none() {
                             let tracer_event = FileSystemEvent {
                                 event_type: FileSystemEventType::Move,
                                 target: Some(FileSystemTarget {
                                     path: moved_from.or(moved_to).unwrap_or(OsString::new()),
                                     kind,
+                                    device_inode,
+                                    metadata: None,
                                 }),
+                                pid,
+                                timestamp: None,
+                                sequence: 0,
                             };
-                            if let Err(_) = sender.send(tracer_event) {
-                                return Err(KanshiError::StreamClosedError);
-                            }
+                            sender.send(tracer_event)?;
                         } else {
                             let tracer_event1 = FileSystemEvent {
-                                event_type: FileSystemEventType::MovedTo(moved_to.clone().unwrap()),
+                                event_type: FileSystemEventType::MovedTo {
+                                    from: moved_from.clone().unwrap(),
+                                    to: moved_to.clone().unwrap(),
+                                },
                                 target: Some(FileSystemTarget {
                                     path: moved_from.clone().unwrap(),
                                     kind: kind.clone(),
+                                    device_inode,
+                                    metadata: None,
                                 }),
+                                pid,
+                                timestamp: None,
+                                sequence: 0,
                             };
 
                             let tracer_event2 = FileSystemEvent {
-                                event_type: FileSystemEventType::MovedFrom(moved_from.unwrap()),
+                                event_type: FileSystemEventType::MovedFrom {
+                                    from: moved_from.unwrap(),
+                                    to: moved_to.clone().unwrap(),
+                                },
                                 target: Some(FileSystemTarget {
                                     path: moved_to.clone().unwrap(),
                                     kind,
+                                    device_inode,
+                                    metadata: None,
                                 }),
+                                pid,
+                                timestamp: None,
+                                sequence: 0,
                             };
 
-                            if let Err(_) = sender.send(tracer_event1) {
-                                return Err(KanshiError::StreamClosedError);
-                            }
+                            sender.send(tracer_event1)?;
 
-                            if let Err(_) = sender.send(tracer_event2) {
-                                return Err(KanshiError::StreamClosedError);
-                            }
+                            sender.send(tracer_event2)?;
                         }
                     } else {
                         let mut tracer_event = FileSystemEvent {
-                            event_type: match event.mask() {
-                                x if x.contains(MaskFlags::FAN_CREATE) => {
-                                    FileSystemEventType::Create
-                                }
-                                x if x.contains(MaskFlags::FAN_DELETE_SELF) => {
-                                    FileSystemEventType::Delete
-                                }
-                                x if x.contains(MaskFlags::FAN_DELETE) => {
-                                    FileSystemEventType::Delete
-                                }
-                                x if x.contains(MaskFlags::FAN_MODIFY) => {
-                                    FileSystemEventType::Modify
-                                }
-                                x if x.contains(MaskFlags::FAN_MOVE_SELF) => {
-                                    FileSystemEventType::Move
-                                }
-                                x => {
-                                    eprintln!("Unknown Mask Received - {:?}", x);
-                                    FileSystemEventType::Unknown
-                                }
-                            },
+                            event_type: FileSystemEventType::Unknown,
                             target: None,
+                            pid,
+                            timestamp: None,
+                            sequence: 0,
                         };
                         let mut path = None;
+                        let mut device_inode = None;
+                        let mut is_symlink = false;
+                        let mut object_handle = None;
                         for record in records {
                             if let FanotifyInfoRecord::Fid(record) = record {
-                                path = Some({
-                                    let path = get_path_from_record(&record);
-                                    if let Err(e) = path {
-                                        if e == Errno::ESTALE {
+                                // The object itself may already be gone, so its fid is only
+                                // used below when no directory entry was reported.
+                                if record.info_type() == FanotifyFidEventInfoType::FAN_EVENT_INFO_TYPE_FID {
+                                    object_handle = Some(record.handle());
+                                    continue;
+                                }
+                                let (record_path, identity, symlink) = match get_path_from_record(&record, mode, &roots, stale_retries) {
+                                    Ok((path, identity, symlink)) => (self.overlays.remap(path), identity, symlink),
+                                    Err(Errno::ESTALE) => {
+                                        let event = stale_event(&record, kind.clone(), pid);
+                                        if filesystem_marked {
                                             continue 'outer;
                                         }
-                                        println!("another error occurred ${e}");
+                                        if !self.exclude_filter.is_match(&event.target.as_ref().unwrap().path) {
+                                            sender.send(event)?;
+                                        }
+                                        continue 'outer;
                                     }
-                                    path?
-                                });
+                                    Err(e) => {
+                                        (self.options.on_error)(e.into());
+                                        continue 'outer;
+                                    }
+                                };
+                                path = Some(record_path);
+                                device_inode = identity;
+                                is_symlink = symlink;
+                            }
+                        }
+                        if path.is_none() && event.mask().contains(MaskFlags::FAN_DELETE_SELF) {
+                            if let Some(handle) = object_handle {
+                                path = self.file_handles.lock().unwrap().remove(&handle);
+                            }
+                        }
+                        if let Some(path) = path.as_ref() {
+                            if !self.is_covered(Path::new(path)) {
+                                continue 'outer;
+                            }
+                            let watched = self.watched.lock().unwrap();
+                            if !is_watched(&self.files.lock().unwrap(), &*watched, Path::new(path))
+                                || self.exclude_filter.is_match(path)
+                            {
+                                continue 'outer;
+                            }
+                        }
+                        if self.options.watch_open_file_deletion
+                            && can_mark_paths
+                            && kind == FileSystemTargetKind::File
+                            && event.mask().contains(MaskFlags::FAN_CREATE)
+                        {
+                            if let Some(path) = path.as_ref() {
+                                mark_file(&self.fanotify, Path::new(path), &self.file_handles);
                             }
                         }
                         if path.is_some() && path.as_ref().unwrap().len() > 0 {
                             if event.mask().contains(MaskFlags::FAN_CREATE)
                                 && kind == FileSystemTargetKind::Directory
+                                && can_mark_paths
                             {
                                 let path = Path::new(path.as_ref().unwrap());
+                                // The filesystem or mount mark already covers it.
+                                let on_marked_filesystem = self
+                                    .wide_marks
+                                    .lock()
+                                    .unwrap()
+                                    .keys()
+                                    .any(|x| path.starts_with(x));
 
-                                // Add new directory to fanotify
-                                if let Err(err) = mark(&self.fanotify, path) {
-                                    // We ignore ENOENT errors as it likely means a file was immediately created and deleted
-                                    if let KanshiError::FileSystemError(e) = err.clone() {
-                                        if !e.contains("ENOENT") {
-                                            return Err(err);
-                                        }
+                                // Add new directory to fanotify, unless it was excluded or is too deep
+                                if !on_marked_filesystem
+                                    && !is_excluded(&self.excluded.lock().unwrap(), path)
+                                    && !self.exclude_filter.is_match_dir(path)
+                                    && within_depth_limit(&self.options, &*self.watched.lock().unwrap(), path)
+                                {
+                                    match self.mark(path) {
+                                        Ok(()) => self.mark_created_subdirectories(path)?,
+                                        // We ignore missing directories as it likely means it was immediately created and deleted
+                                        Err(KanshiError::PathNotFound(_)) => {}
+                                        Err(err) => return Err(err),
                                     }
                                 }
                             }
                             tracer_event.target = Some(FileSystemTarget {
-                                kind: kind.clone(),
+                                kind: if is_symlink { FileSystemTargetKind::Symlink } else { kind.clone() },
                                 path: path.unwrap(),
+                                device_inode,
+                                metadata: None,
                             });
                         }
 
-                        if let Err(_) = sender.send(tracer_event) {
-                            return Err(KanshiError::StreamClosedError);
+                        let mut event_types = split_mask(event.mask(), device_inode.is_some());
+                        if event_types.is_empty() {
+                            (self.options.on_error)(KanshiError::UnknownEventMask(format!(
+                                "{:?}",
+                                event.mask()
+                            )));
+                            event_types.push(FileSystemEventType::Unknown);
+                        }
+                        for event_type in event_types {
+                            tracer_event.event_type = event_type;
+                            sender.send(tracer_event.clone())?;
                         }
                     }
                 }
@@ -322,12 +871,315 @@ impl KanshiImpl<KanshiOptions> for FanotifyTracer {
 
         Ok(())
     }
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct FileHandle {
+    pub handle_bytes: u32,
+    pub handle_type: i32,
+    pub f_handle: [u8; 0],
+}
+
+impl KanshiImpl<KanshiOptions> for FanotifyTracer {
+    fn new(opts: KanshiOptions) -> Result<FanotifyTracer, KanshiError> {
+        use nix::sys::epoll::{EpollCreateFlags, EpollEvent, EpollFlags};
+        use nix::sys::fanotify::{EventFFlags, InitFlags};
+
+        let exclude_filter = PathFilter::new(
+            &opts.exclude_patterns,
+            &opts.exclude_regexes,
+            opts.use_gitignore,
+        )?;
+        let uring = match opts.backend {
+            LinuxBackend::IoUring => Some(Arc::new(Uring::new()?)),
+            LinuxBackend::Epoll => None,
+        };
+
+        #[allow(non_snake_case)]
+        let mut INIT_FLAGS: InitFlags = InitFlags::FAN_CLASS_NOTIF
+            | InitFlags::FAN_REPORT_DFID_NAME
+            // | InitFlags::FAN_REPORT_TARGET_FID
+            // | InitFlags::FAN_REPORT_FID
+            | InitFlags::FAN_UNLIMITED_QUEUE;
+        // The mark limit only applies without `FAN_UNLIMITED_MARKS`, so it is
+        // only requested when there is no quota to warn about.
+        if opts.mark_quota_warn_threshold.is_none() {
+            INIT_FLAGS |= InitFlags::FAN_UNLIMITED_MARKS;
+        }
+        if opts.watch_open_file_deletion {
+            // Self events on files carry no directory entry, only the file's own fid.
+            INIT_FLAGS |= InitFlags::FAN_REPORT_FID;
+        }
+        #[allow(non_snake_case)]
+        let EVENT_FLAGS: EventFFlags =
+            EventFFlags::O_RDONLY | EventFFlags::O_NONBLOCK | EventFFlags::O_CLOEXEC;
+
+        let unlimited_marks = INIT_FLAGS.contains(InitFlags::FAN_UNLIMITED_MARKS);
+        let fanotify = Fanotify::init(INIT_FLAGS, EVENT_FLAGS)?;
+
+        // Setup epoll
+        let epoll_event = EpollEvent::new(EpollFlags::EPOLLIN, fanotify.as_fd().as_raw_fd() as u64);
+        let epoll = Epoll::new(EpollCreateFlags::EPOLL_CLOEXEC)?;
+        epoll.add(fanotify.as_fd(), epoll_event)?;
+
+        let permissions = match opts.permission_mode {
+            true => Some(Arc::new(permission_group(&epoll, unlimited_marks)?)),
+            false => None,
+        };
+        let overlays = match opts.overlay_remap {
+            true => OverlayLayers::load()?,
+            false => OverlayLayers::default(),
+        };
+        let (tx, _rx) = tokio::sync::broadcast::channel(opts.channel_capacity.max(1));
+        Ok(FanotifyTracer {
+            // mark_set: HashSet::new(),
+            fanotify: Arc::new(fanotify),
+            epoll: Arc::new(epoll),
+            uring,
+            sender: tx,
+            // reciever: rx,
+            cancellation_token: CancellationToken::new(),
+            paused: Arc::new(AtomicBool::new(false)),
+            running: Arc::new(AtomicBool::new(false)),
+            callbacks: EventCallbacks::default(),
+            sequence: Arc::new(AtomicU64::new(opts.initial_sequence)),
+            options: Arc::new(opts),
+            roots: Arc::new(Mutex::new(Vec::new())),
+            excluded: Arc::new(Mutex::new(HashSet::new())),
+            exclude_filter: Arc::new(exclude_filter),
+            watched: Arc::new(Mutex::new(HashSet::new())),
+            files: Arc::new(Mutex::new(HashSet::new())),
+            file_handles: Arc::new(Mutex::new(HashMap::new())),
+            wide_marks: Arc::new(Mutex::new(HashMap::new())),
+            permissions,
+            mark_count: Arc::new(AtomicUsize::new(0)),
+            dir_handles: Arc::new(Mutex::new(HashMap::new())),
+            stats: Arc::default(),
+            overlays: Arc::new(overlays),
+            quota_warned: Arc::new(AtomicBool::new(false)),
+            unlimited_marks,
+        })
+    }
+
+    async fn watch(&self, dir: impl AsRef<Path> + Send) -> Result<(), KanshiError> {
+        self.watch_with_exclude_dirs(dir, HashSet::new()).await
+    }
+
+    async fn watch_with_exclude_dirs(
+        &self,
+        dir: impl AsRef<Path> + Send,
+        exclude: HashSet<PathBuf>,
+    ) -> Result<(), KanshiError> {
+        let dir = dir.as_ref();
+        if self.cancellation_token.is_cancelled() {
+            return Err(KanshiError::StreamClosed);
+        }
+
+        if self.options.create_watched_dirs {
+            create_watched_dir(Path::new(dir))?;
+        }
+
+        if dir.is_file() {
+            return self.watch_file(dir);
+        }
+        // A symlinked directory is watched under its target, which is the
+        // path events are reported for.
+        let dir = &dir
+            .canonicalize()
+            .map_err(|e| KanshiError::from_io_at(e, dir))?;
+        self.exclude_filter.add_ignore_files(dir);
+
+        if self.options.path_resolution_mode == PathResolutionMode::Beneath {
+            let root = WatchRoot::open(Path::new(dir))?;
+            self.roots.lock().unwrap().push(root);
+        }
+
+        let mut excluded = self.excluded.lock().unwrap();
+        excluded.extend(canonicalize_all(exclude));
+
+        if self.options.use_filesystem_mark {
+            let path = dir.clone();
+            match self.mark_wide(&path, MarkFlags::FAN_MARK_FILESYSTEM) {
+                Ok(()) => {
+                    self.watched.lock().unwrap().insert(path);
+                    return Ok(());
+                }
+                // Filesystem marks need Linux 4.20.
+                Err(KanshiError::Nix(Errno::EINVAL, _)) => {
+                    tracing::warn!(
+                        path = %path.display(),
+                        "could not mark the filesystem, marking every directory instead"
+                    );
+                }
+                Err(err) => return Err(err),
+            }
+        } else if self.options.mount_level_watch {
+            let path = dir.clone();
+            self.mark_wide(&path, MarkFlags::FAN_MARK_MOUNT)?;
+            self.watched.lock().unwrap().insert(path);
+            return Ok(());
+        }
+
+        let mark_top_dir = self.mark(Path::new(dir));
+
+        if let Ok(_) = mark_top_dir {
+            for_each_subdirectory(
+                Path::new(dir),
+                &self.options,
+                &excluded,
+                &self.exclude_filter,
+                |path| self.mark(path),
+            )?;
+            self.watched.lock().unwrap().insert(dir.clone());
+            Ok(())
+        } else {
+            mark_top_dir
+        }
+    }
+
+    async fn unwatch(&self, dir: impl AsRef<Path> + Send) -> Result<(), KanshiError> {
+        let dir = dir.as_ref();
+        let path = dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf());
+
+        // Copied first, as `watch_with_exclude_dirs` locks `watched` while holding `excluded`.
+        let mut skip = self.excluded.lock().unwrap().clone();
+        let mut watched = self.watched.lock().unwrap();
+        let mut files = self.files.lock().unwrap();
+        if files.remove(&path) {
+            // The parent directory stays marked while it is still needed.
+            let parent = path.parent().unwrap_or(Path::new("/"));
+            let needed = files.iter().any(|x| x.parent() == Some(parent))
+                || (depth_below(&*watched, parent).is_some()
+                    && within_depth_limit(&self.options, &*watched, parent));
+            if !needed {
+                let _ = self.unmark(parent);
+            }
+            return Ok(());
+        }
+        if !watched.remove(&path) {
+            return Err(KanshiError::PathNotWatched(dir.to_path_buf()));
+        }
+        {
+            let mut marks = self.wide_marks.lock().unwrap();
+            if let Some(mark) = marks.remove(&path) {
+                // The filesystem or mount stays marked while another watched
+                // directory lives on it.
+                if !marks.values().any(|x| *x == mark) {
+                    let (target, mark_type) = mark;
+                    let _ = self.fanotify.mark(
+                        MarkFlags::FAN_MARK_REMOVE | mark_type,
+                        wide_mask_flags(&self.options, mark_type),
+                        AT_FDCWD,
+                        Some(&target),
+                    );
+                }
+                return Ok(());
+            }
+        }
+        if watched.iter().any(|x| path.starts_with(x)) {
+            return Ok(());
+        }
+
+        // Directories watched on their own keep their marks.
+        skip.extend(watched.iter().filter(|x| x.starts_with(&path)).cloned());
+
+        // Directories created after `watch` were marked by `start`, so walking
+        // the tree again finds every mark. Errors mean the mark is already gone.
+        let _ = self.unmark(&path);
+        for_each_subdirectory(&path, &self.options, &skip, &self.exclude_filter, |x| {
+            let _ = self.unmark(x);
+            Ok(())
+        })?;
+
+        // Watched files below `path` lost the marks on their parents as well.
+        for parent in files.iter().filter(|x| x.starts_with(&path)).filter_map(|x| x.parent()) {
+            let _ = self.mark(parent);
+        }
+
+        self.file_handles.lock().unwrap().retain(|_, x| {
+            let x = Path::new(x);
+            if !x.starts_with(&path) || skip.iter().any(|y| x.starts_with(y)) {
+                return true;
+            }
+            let _ = self.fanotify.mark(
+                nix::sys::fanotify::MarkFlags::FAN_MARK_REMOVE,
+                nix::sys::fanotify::MaskFlags::FAN_DELETE_SELF,
+                AT_FDCWD,
+                Some(x),
+            );
+            false
+        });
+        Ok(())
+    }
+
+    fn get_watched_paths(&self) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = self.watched.lock().unwrap().iter().cloned().collect();
+        paths.extend(self.files.lock().unwrap().iter().cloned());
+        paths.sort();
+        paths
+    }
 
-    fn close(&self) -> bool {
-        use nix::sys::fanotify::{MarkFlags, MaskFlags};
+    fn get_events_stream(&self) -> Pin<Box<dyn futures::Stream<Item = KanshiStreamItem> + Send>> {
+        let mut listener = self.sender.subscribe();
+        let cancel_token = self.cancellation_token.clone();
+        let stats = self.stats.clone();
+
+        let events_stream = stream! {
+            loop {
+                tokio::select! {
+                    _ = cancel_token.cancelled() => {
+                        break;
+                    }
+                    val = listener.recv() => {
+                        match val {
+                            Ok(x) => {
+                                let failed = x.is_error();
+                                yield x;
+                                if failed {
+                                    break;
+                                }
+                            }
+                            Err(RecvError::Closed) => break,
+                            Err(RecvError::Lagged(count)) => stats.record_dropped(count),
+                        }
+                    }
+                }
+            }
+        };
+
+        Box::pin(events_stream)
+    }
+
+    async fn start(&self) -> Result<(), KanshiError> {
+        let result = self.run().await;
+        report_error(&self.sender, result)
+    }
+
+    fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+    }
+
+    fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    fn on_event<F>(&self, callback: F)
+    where
+        F: Fn(FileSystemEvent) + Send + Sync + 'static,
+    {
+        self.callbacks.write().unwrap().push(Box::new(callback));
+    }
+
+    fn close(&self) -> Result<(), KanshiError> {
+        use nix::sys::fanotify::MaskFlags;
 
         if self.cancellation_token.is_cancelled() {
-            return true;
+            return Ok(());
         }
 
         self.cancellation_token.cancel();
@@ -335,73 +1187,391 @@ impl KanshiImpl<KanshiOptions> for FanotifyTracer {
         #[allow(non_snake_case)]
         let MARK_FLAGS = MarkFlags::FAN_MARK_FLUSH;
 
-        let mut has_error = false;
-
-        if self.epoll.delete(self.fanotify.as_fd()).is_err() {
-            println!("epoll.delete returned error");
-            has_error = true;
+        // Both steps are attempted even if the first one fails.
+        let mut errors = Vec::new();
+        if let Err(e) = self.epoll.delete(self.fanotify.as_fd()) {
+            errors.push(e.into());
         }
-        if self
+        if let Err(e) = self
             .fanotify
             .mark(MARK_FLAGS, MaskFlags::empty(), AT_FDCWD, Some("/"))
-            .is_err()
         {
-            println!("fanotify.mark returned error");
-            has_error = true;
+            errors.push(e.into());
         }
-        !has_error
+        KanshiError::from_errors(errors)
     }
 }
 
 impl Drop for FanotifyTracer {
     fn drop(&mut self) {
-        // println!("dropped!");
+        // Every clone shares the same group, so only the last one closes it.
+        if Arc::strong_count(&self.fanotify) == 1 {
+            if let Err(e) = self.close() {
+                tracing::warn!(error = %e, "failed to close the fanotify tracer");
+            }
+        }
     }
 }
 
-fn mark(fanotify: &Fanotify, path: &Path) -> Result<(), KanshiError> {
-    use nix::sys::fanotify::{MarkFlags, MaskFlags};
+fn mark(fanotify: &Fanotify, path: &Path, options: &KanshiOptions) -> Result<(), KanshiError> {
     #[allow(non_snake_case)]
     let MARK_FLAGS = MarkFlags::FAN_MARK_ADD;
+
+    if let Err(e) = fanotify.mark(MARK_FLAGS, dir_mask_flags(options), AT_FDCWD, Some(path)) {
+        Err(KanshiError::from_errno_at(e, path))
+    } else {
+        Ok(())
+    }
+}
+
+/// The mount point of the filesystem `path` lives on: the topmost ancestor of
+/// `path` with the same filesystem id. Some filesystems report an id of zero,
+/// so the device is compared as well.
+fn mount_point(path: &Path) -> Result<PathBuf, KanshiError> {
+    use nix::sys::statvfs::statvfs;
+    use std::os::unix::fs::MetadataExt;
+
+    let identity = |x: &Path| -> Result<(u64, u64), KanshiError> {
+        let id = statvfs(x)
+            .map_err(|e| KanshiError::from_errno_at(e, x))?
+            .filesystem_id();
+        let dev = x.metadata().map_err(|e| KanshiError::from_io_at(e, x))?.dev();
+        Ok((id as u64, dev))
+    };
+
+    let id = identity(path)?;
+    let mut mount = path;
+    while let Some(parent) = mount.parent() {
+        match identity(parent) {
+            Ok(x) if x == id => mount = parent,
+            _ => break,
+        }
+    }
+    Ok(mount.to_path_buf())
+}
+
+/// The mount point of the mount `path` lives on, read from
+/// `/proc/self/mountinfo`: the longest mount point that `path` is below.
+fn mount_root(path: &Path) -> Result<PathBuf, KanshiError> {
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo")
+        .map_err(|e| KanshiError::from_io_at(e, "/proc/self/mountinfo"))?;
+
+    mountinfo
+        .lines()
+        // The mount point is the fifth field, with spaces, tabs, newlines and
+        // backslashes escaped as octal.
+        .filter_map(|line| line.split(' ').nth(4))
+        .map(|x| PathBuf::from(unescape_mountinfo(x)))
+        .filter(|x| path.starts_with(x))
+        .max_by_key(|x| x.components().count())
+        .ok_or_else(|| KanshiError::PathNotFound(path.to_path_buf()))
+}
+
+/// Removes the inode, mount and filesystem marks of `fanotify`.
+fn flush_marks(fanotify: &Fanotify) -> Result<(), KanshiError> {
+    use nix::sys::fanotify::MaskFlags;
+
+    for mark_type in [
+        MarkFlags::empty(),
+        MarkFlags::FAN_MARK_MOUNT,
+        MarkFlags::FAN_MARK_FILESYSTEM,
+    ] {
+        let flushed = fanotify.mark(
+            MarkFlags::FAN_MARK_FLUSH | mark_type,
+            MaskFlags::empty(),
+            AT_FDCWD,
+            Some("/"),
+        );
+        match flushed {
+            // Filesystem marks need Linux 4.20, so there are none to remove.
+            Err(Errno::EINVAL) if mark_type == MarkFlags::FAN_MARK_FILESYSTEM => {}
+            flushed => flushed?,
+        }
+    }
+    Ok(())
+}
+
+fn unmark(fanotify: &Fanotify, path: &Path, options: &KanshiOptions) -> Result<(), KanshiError> {
+
+    fanotify.mark(
+        MarkFlags::FAN_MARK_REMOVE,
+        dir_mask_flags(options),
+        AT_FDCWD,
+        Some(path),
+    )?;
+    Ok(())
+}
+
+/// Creates the group permission events are read from and adds it to `epoll`.
+/// Groups that report directory entries are limited to `FAN_CLASS_NOTIF`, so
+/// permission events need a group of their own, which reports descriptors.
+/// `unlimited_marks` is whether the notification group was created with
+/// `FAN_UNLIMITED_MARKS`.
+fn permission_group(epoll: &Epoll, unlimited_marks: bool) -> Result<Fanotify, KanshiError> {
+    use nix::sys::epoll::{EpollEvent, EpollFlags};
+    use nix::sys::fanotify::{EventFFlags, InitFlags};
+
+    let mut flags = InitFlags::FAN_CLASS_PRE_CONTENT | InitFlags::FAN_CLOEXEC;
+    if unlimited_marks {
+        flags |= InitFlags::FAN_UNLIMITED_MARKS;
+    }
+    let fanotify = Fanotify::init(flags, EventFFlags::O_RDONLY | EventFFlags::O_CLOEXEC)?;
+    let token = fanotify.as_fd().as_raw_fd() as u64;
+    epoll.add(fanotify.as_fd(), EpollEvent::new(EpollFlags::EPOLLIN, token))?;
+    Ok(fanotify)
+}
+
+/// The events the permission group is marked for.
+fn permission_mask_flags() -> nix::sys::fanotify::MaskFlags {
+    use nix::sys::fanotify::MaskFlags;
+
+    MaskFlags::FAN_OPEN_PERM | MaskFlags::FAN_ACCESS_PERM | MaskFlags::FAN_EVENT_ON_CHILD
+}
+
+/// fanotify merges queued events for the same entry into one event with all of
+/// their mask bits set, which loses the order they happened in. Splits `mask`
+/// back into one event type per bit, in the order they most likely happened:
+/// created, opened, read, modified, attributes changed, closed and then
+/// deleted, unless the entry still `exists`, in which case it was deleted
+/// before being created again. An open is left out when the entry was also
+/// read or modified, which already implies it.
+fn split_mask(mask: nix::sys::fanotify::MaskFlags, exists: bool) -> Vec<FileSystemEventType> {
+    use nix::sys::fanotify::MaskFlags;
+
+    let mut event_types = Vec::new();
+    let deleted = mask.intersects(MaskFlags::FAN_DELETE | MaskFlags::FAN_DELETE_SELF);
+    let created = mask.contains(MaskFlags::FAN_CREATE);
+
+    if deleted && (!created || exists) {
+        event_types.push(FileSystemEventType::Delete);
+    }
+    if created {
+        event_types.push(FileSystemEventType::Create);
+    }
+    if mask.contains(MaskFlags::FAN_OPEN)
+        && !mask.intersects(MaskFlags::FAN_ACCESS | MaskFlags::FAN_MODIFY)
+    {
+        event_types.push(FileSystemEventType::Open);
+    }
+    if mask.contains(MaskFlags::FAN_ACCESS) {
+        event_types.push(FileSystemEventType::Access);
+    }
+    if mask.contains(MaskFlags::FAN_MODIFY) {
+        event_types.push(FileSystemEventType::Modify);
+    }
+    if mask.contains(MaskFlags::FAN_ATTRIB) {
+        event_types.push(FileSystemEventType::AttributeChange);
+    }
+    if mask.contains(MaskFlags::FAN_CLOSE_WRITE) {
+        event_types.push(FileSystemEventType::CloseWrite);
+    }
+    if deleted && created && !exists {
+        event_types.push(FileSystemEventType::Delete);
+    }
+    if mask.contains(MaskFlags::FAN_MOVE_SELF) {
+        event_types.push(FileSystemEventType::Move);
+    }
+
+    event_types
+}
+
+/// The events requested by a `mark_type` mark. Mount marks can't report
+/// directory entry events, so they only keep the ones about file contents.
+fn wide_mask_flags(options: &KanshiOptions, mark_type: MarkFlags) -> nix::sys::fanotify::MaskFlags {
+    use nix::sys::fanotify::MaskFlags;
+
+    if mark_type != MarkFlags::FAN_MARK_MOUNT {
+        return mask_flags(options);
+    }
+    mask_flags(options).difference(
+        MaskFlags::FAN_CREATE
+            | MaskFlags::FAN_ATTRIB
+            | MaskFlags::FAN_DELETE
+            | MaskFlags::FAN_RENAME
+            | MaskFlags::FAN_DELETE_SELF
+            | MaskFlags::FAN_MOVE_SELF
+            | MaskFlags::FAN_EVENT_ON_CHILD,
+    )
+}
+
+fn mask_flags(options: &KanshiOptions) -> nix::sys::fanotify::MaskFlags {
+    use nix::sys::fanotify::MaskFlags;
     #[allow(non_snake_case)]
-    let MASK_FLAGS = MaskFlags::FAN_ONDIR
+    let mut MASK_FLAGS = MaskFlags::FAN_ONDIR
         | MaskFlags::FAN_EVENT_ON_CHILD
         | MaskFlags::FAN_CREATE
         | MaskFlags::FAN_MODIFY
+        | MaskFlags::FAN_ATTRIB
         | MaskFlags::FAN_DELETE
         | MaskFlags::FAN_RENAME;
+    if options.watch_open_file_deletion {
+        MASK_FLAGS |= MaskFlags::FAN_DELETE_SELF | MaskFlags::FAN_CLOSE_WRITE;
+    }
+    if options.watch_close_write {
+        MASK_FLAGS |= MaskFlags::FAN_CLOSE_WRITE;
+    }
+    if options.watch_access {
+        MASK_FLAGS |= MaskFlags::FAN_ACCESS;
+    }
+    if options.watch_open {
+        MASK_FLAGS |= MaskFlags::FAN_OPEN;
+    }
+    MASK_FLAGS
+}
 
-    if let Err(e) = fanotify.mark(MARK_FLAGS, MASK_FLAGS, AT_FDCWD, Some(path)) {
-        Err(KanshiError::FileSystemError(e.to_string()))
-    } else {
-        Ok(())
+/// The events requested by the mark of a single directory: `mask_flags`, along
+/// with the deletion and moves of the directory itself, which
+/// `handle_dir_self_event` keeps the marks in step with.
+fn dir_mask_flags(options: &KanshiOptions) -> nix::sys::fanotify::MaskFlags {
+    use nix::sys::fanotify::MaskFlags;
+
+    mask_flags(options) | MaskFlags::FAN_DELETE_SELF | MaskFlags::FAN_MOVE_SELF
+}
+
+/// Marks a single file for `FAN_DELETE_SELF` so its deletion is reported even
+/// while it is still held open, and remembers its path by file handle. Files
+/// that are already marked, or that disappear before being marked, are skipped.
+fn mark_file(fanotify: &Fanotify, path: &Path, file_handles: &Mutex<HashMap<Vec<u8>, OsString>>) {
+    use nix::sys::fanotify::MaskFlags;
+
+    let Some(handle) = file_handle(path) else {
+        return;
+    };
+
+    let mut file_handles = file_handles.lock().unwrap();
+    if file_handles.contains_key(&handle) {
+        return;
+    }
+
+    let marked = fanotify.mark(
+        MarkFlags::FAN_MARK_ADD,
+        MaskFlags::FAN_DELETE_SELF,
+        AT_FDCWD,
+        Some(path),
+    );
+    if marked.is_ok() {
+        file_handles.insert(handle, path.as_os_str().to_os_string());
     }
 }
 
-fn get_path_from_record(record: &FanotifyFidRecord) -> Result<OsString, Errno> {
-    let mut path = OsString::new();
+/// Reads the `struct file_handle` of `path` with `name_to_handle_at`, in the
+/// same layout fanotify reports in fid records.
+fn file_handle(path: &Path) -> Option<Vec<u8>> {
+    const MAX_HANDLE_SZ: usize = 128;
+    const HEADER_SZ: usize = std::mem::size_of::<FileHandle>();
 
-    let handle = &record.handle();
-    let fh = handle.as_ptr() as *mut FileHandle;
-    let fd = unsafe {
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut buf = vec![0u8; HEADER_SZ + MAX_HANDLE_SZ];
+    buf[..4].copy_from_slice(&(MAX_HANDLE_SZ as u32).to_ne_bytes());
+    let mut mount_id: libc::c_int = 0;
+
+    let res = unsafe {
         libc::syscall(
-            libc::SYS_open_by_handle_at,
-            AT_FDCWD,
-            fh,
-            libc::O_RDONLY | libc::O_CLOEXEC | libc::O_PATH | libc::O_NONBLOCK,
+            libc::SYS_name_to_handle_at,
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            buf.as_mut_ptr(),
+            &mut mount_id as *mut libc::c_int,
+            0,
         )
     };
+    if res != 0 {
+        return None;
+    }
 
+    let handle_bytes = u32::from_ne_bytes(buf[..4].try_into().unwrap()) as usize;
+    buf.truncate(HEADER_SZ + handle_bytes);
+    Some(buf)
+}
+
+/// The current path of the object a file handle from `file_handle`, or from a
+/// fid record, refers to. `None` once it no longer exists.
+fn handle_path(handle: &[u8]) -> Option<PathBuf> {
+    let fh = handle.as_ptr() as *mut FileHandle;
+    let flags = libc::O_RDONLY | libc::O_CLOEXEC | libc::O_PATH;
+    let fd = unsafe { libc::syscall(libc::SYS_open_by_handle_at, AT_FDCWD, fh, flags) };
+    if fd < 0 {
+        return None;
+    }
+    let path = std::fs::read_link(format!("/proc/self/fd/{fd}")).ok();
+    unsafe { libc::close(fd as i32) };
+    path
+}
+
+/// Resolves the path of a fid record, along with the `(st_dev, st_ino)` pair of
+/// the target, which is read from the directory fd before it is closed. How
+/// the path is resolved depends on `mode`; see [`PathResolutionMode`].
+/// Opens the directory of `record` by handle and returns the path of its
+/// entry. A stale handle is retried up to `stale_retries` more times, without
+/// following symlinks, before `ESTALE` is returned.
+///
+/// Along with the path, returns the entry's `(st_dev, st_ino)` and whether it
+/// is a symlink, when it can still be looked up.
+fn get_path_from_record(
+    record: &FanotifyFidRecord,
+    mode: PathResolutionMode,
+    roots: &[WatchRoot],
+    stale_retries: usize,
+) -> Result<(OsString, Option<(u64, u64)>, bool), Errno> {
+    let mut path = OsString::new();
+    let file_name = record.name();
+
+    if mode == PathResolutionMode::NameOnly {
+        if let Some(name) = file_name {
+            if name != "." {
+                path.push(name);
+            }
+        }
+        return Ok((path, None, false));
+    }
+
+    let handle = &record.handle();
+    let fh = handle.as_ptr() as *mut FileHandle;
+    let mut flags = libc::O_RDONLY | libc::O_CLOEXEC | libc::O_PATH | libc::O_NONBLOCK;
+    let mut fd = unsafe { libc::syscall(libc::SYS_open_by_handle_at, AT_FDCWD, fh, flags) };
+    for _ in 0..stale_retries {
+        if fd >= 0 || Errno::last() != Errno::ESTALE {
+            break;
+        }
+        flags |= libc::O_NOFOLLOW;
+        fd = unsafe { libc::syscall(libc::SYS_open_by_handle_at, AT_FDCWD, fh, flags) };
+    }
+
+    let device_inode;
+    let is_symlink;
     if fd > 0 {
-        let fd_path = format!("/proc/self/fd/{fd}");
-        path.push(nix::fcntl::readlink::<OsStr>(fd_path.as_ref())?);
+        let dir_path = match mode {
+            PathResolutionMode::Beneath => {
+                resolve_beneath(unsafe { BorrowedFd::borrow_raw(fd as i32) }, roots)
+                    .map(PathBuf::into_os_string)
+            }
+            _ => {
+                let fd_path = format!("/proc/self/fd/{fd}");
+                nix::fcntl::readlink::<OsStr>(fd_path.as_ref())
+            }
+        };
+        let target_path = match &file_name {
+            Some(name) if mode == PathResolutionMode::Full && name.as_bytes() != b"." => {
+                resolve_entry(fd as i32, name.as_bytes())
+            }
+            _ => None,
+        };
+        let stat = match &file_name {
+            Some(name) if name.as_bytes() != b"." => stat_at(fd as i32, name.as_bytes()),
+            _ => stat_at(fd as i32, b""),
+        };
         unsafe { libc::close(fd as i32) };
+        device_inode = stat.map(|(dev, ino, _)| (dev, ino));
+        is_symlink = stat.is_some_and(|(_, _, mode)| mode & libc::S_IFMT == libc::S_IFLNK);
+
+        if let Some(target_path) = target_path {
+            return Ok((target_path, device_inode, is_symlink));
+        }
+        path.push(dir_path?);
     } else {
         return Err(Errno::last());
     }
 
-    let file_name = record.name();
-
     if let Some(name) = file_name {
         if name != "." {
             path.push("/");
@@ -409,5 +1579,68 @@ fn get_path_from_record(record: &FanotifyFidRecord) -> Result<OsString, Errno> {
         }
     }
 
-    Ok(path)
+    Ok((path, device_inode, is_symlink))
+}
+
+/// The event reported for `record` when its handle stays stale: `Unknown`,
+/// with only the entry name as the path, since its directory is gone.
+fn stale_event(record: &FanotifyFidRecord, kind: FileSystemTargetKind, pid: Option<u32>) -> FileSystemEvent {
+    let path = match record.name() {
+        Some(name) if name != "." => name.to_os_string(),
+        _ => OsString::new(),
+    };
+
+    FileSystemEvent {
+        event_type: FileSystemEventType::Unknown,
+        target: Some(FileSystemTarget {
+            kind,
+            path,
+            device_inode: None,
+            metadata: None,
+        }),
+        pid,
+        timestamp: None,
+        sequence: 0,
+    }
+}
+
+/// Opens the entry `name` inside `dir_fd` and reads its path back through
+/// `/proc/self/fd`. Returns `None` if the entry no longer exists.
+fn resolve_entry(dir_fd: i32, name: &[u8]) -> Option<OsString> {
+    let name = CString::new(name).ok()?;
+    let fd = unsafe {
+        libc::openat(
+            dir_fd,
+            name.as_ptr(),
+            libc::O_PATH | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return None;
+    }
+
+    let fd_path = format!("/proc/self/fd/{fd}");
+    let path = nix::fcntl::readlink::<OsStr>(fd_path.as_ref()).ok();
+    unsafe { libc::close(fd) };
+    path
+}
+
+/// `fstatat` relative to `dir_fd`, or on `dir_fd` itself when `name` is empty.
+/// Returns the entry's `st_dev`, `st_ino` and `st_mode`.
+fn stat_at(dir_fd: i32, name: &[u8]) -> Option<(u64, u64, libc::mode_t)> {
+    let name = CString::new(name).ok()?;
+    let flags = if name.is_empty() {
+        libc::AT_EMPTY_PATH | libc::AT_SYMLINK_NOFOLLOW
+    } else {
+        libc::AT_SYMLINK_NOFOLLOW
+    };
+
+    let mut stat = MaybeUninit::<libc::stat>::uninit();
+    let res = unsafe { libc::fstatat(dir_fd, name.as_ptr(), stat.as_mut_ptr(), flags) };
+    if res == 0 {
+        let stat = unsafe { stat.assume_init() };
+        Some((stat.st_dev, stat.st_ino, stat.st_mode))
+    } else {
+        None
+    }
 }
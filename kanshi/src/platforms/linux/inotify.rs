@@ -1,18 +1,17 @@
 use std::{
-    collections::{HashMap, HashSet, VecDeque},
+    collections::{HashMap, HashSet},
     ffi::OsString,
-    fs,
-    os::{
-        fd::{AsFd, AsRawFd},
-        unix::fs::MetadataExt,
-    },
+    os::fd::{AsFd, AsRawFd},
     path::{self, Path, PathBuf},
     pin::Pin,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
 use async_stream::stream;
-use futures::io;
 use nix::sys::{
     epoll::Epoll,
     inotify::{Inotify, InotifyEvent, WatchDescriptor},
@@ -21,160 +20,224 @@ use tokio::sync::{broadcast::error::RecvError, Mutex};
 use tokio_util::sync::CancellationToken;
 
 use crate::{
-    FileSystemEvent, FileSystemEventType, FileSystemTarget, FileSystemTargetKind, KanshiImpl,
-    KanshiError,
+    platforms::{
+        canonicalize_all, close_on_sigint, create_watched_dir, device_inode, filter::PathFilter,
+        report_error, RunningGuard,
+    },
+    runtime,
+    sender::{EventCallbacks, EventSender},
+    FileSystemEvent, FileSystemEventType, FileSystemTarget, FileSystemTargetKind, KanshiError,
+    KanshiImpl, KanshiStreamItem,
 };
 
-use super::KanshiOptions;
+use super::{
+    traverse::{
+        depth_below, for_each_subdirectory, for_each_subdirectory_from, is_excluded,
+        is_watched, within_depth_limit,
+    },
+    uring::Uring,
+    KanshiOptions, LinuxBackend,
+};
 
 #[derive(Clone)]
 pub struct INotifyTracer {
     inotify: Arc<Inotify>,
     epoll: Arc<Epoll>,
-    sender: tokio::sync::broadcast::Sender<FileSystemEvent>,
+    // Waits for events instead of `epoll` with `LinuxBackend::IoUring`.
+    uring: Option<Arc<Uring>>,
+    sender: tokio::sync::broadcast::Sender<KanshiStreamItem>,
     cancellation_token: CancellationToken,
+    paused: Arc<AtomicBool>,
+    // Set while `start` runs, shared with clones.
+    running: Arc<AtomicBool>,
+    callbacks: EventCallbacks,
+    sequence: Arc<AtomicU64>,
+    // Lock order is `excluded`, `watch_descriptors`, `watched`, then `files`,
+    // everywhere more than one of them is held.
     watch_descriptors: Arc<Mutex<HashMap<WatchDescriptor, PathBuf>>>,
+    options: Arc<KanshiOptions>,
+    excluded: Arc<Mutex<HashSet<PathBuf>>>,
+    // `KanshiOptions::exclude_patterns` and `exclude_regexes`, compiled once
+    // in `new`.
+    exclude_filter: Arc<PathFilter>,
+    // Absolute paths of the directories passed to `watch`.
+    watched: Arc<Mutex<HashSet<PathBuf>>>,
+    // Absolute paths of the files passed to `watch`. Only their parent
+    // directories are watched.
+    files: Arc<Mutex<HashSet<PathBuf>>>,
+    // Canonical paths of the directories and files passed to `watch`, sorted.
+    // Kept apart from `watched` and `files` so it can be read without an
+    // async lock.
+    watched_paths: Arc<std::sync::RwLock<Vec<PathBuf>>>,
 }
 
-impl KanshiImpl<KanshiOptions> for INotifyTracer {
-    fn new(_opts: KanshiOptions) -> Result<INotifyTracer, KanshiError> {
-        use nix::sys::epoll::{EpollCreateFlags, EpollEvent, EpollFlags};
-        use nix::sys::inotify::InitFlags;
+impl INotifyTracer {
+    /// The options this tracer was created with.
+    pub(crate) fn options(&self) -> &KanshiOptions {
+        &self.options
+    }
 
-        #[allow(non_snake_case)]
-        let INIT_FLAGS = InitFlags::IN_CLOEXEC;
+    /// Records `path` in `watched_paths`, unless it is already there.
+    fn add_watched_path(&self, path: &Path) {
+        let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        let mut watched_paths = self.watched_paths.write().unwrap();
+        if let Err(idx) = watched_paths.binary_search(&path) {
+            watched_paths.insert(idx, path);
+        }
+    }
 
-        let inotify_fd = Inotify::init(INIT_FLAGS);
-
-        if let Ok(inotify) = inotify_fd {
-            // Setup epoll
-            let epoll_event =
-                EpollEvent::new(EpollFlags::EPOLLIN, inotify.as_fd().as_raw_fd() as u64);
-
-            let epoll_fd = Epoll::new(EpollCreateFlags::EPOLL_CLOEXEC);
-
-            if let Ok(epoll) = epoll_fd {
-                if let Err(e) = epoll.add(inotify.as_fd(), epoll_event) {
-                    Err(KanshiError::FileSystemError(e.to_string()))
-                } else {
-                    let (tx, _rx) = tokio::sync::broadcast::channel(32);
-                    Ok(INotifyTracer {
-                        inotify: Arc::new(inotify),
-                        epoll: Arc::new(epoll),
-                        sender: tx,
-                        cancellation_token: CancellationToken::new(),
-                        watch_descriptors: Arc::new(Mutex::new(HashMap::new())),
-                    })
-                }
-            } else {
-                let e = epoll_fd.err().unwrap();
-                Err(KanshiError::FileSystemError(e.to_string()))
-            }
-        } else {
-            Err(KanshiError::FileSystemError(
-                io::Error::last_os_error().to_string(),
-            ))
+    /// Checks that watching `dir` and its subdirectories would stay within
+    /// `fs.inotify.max_user_watches`. Watches held by other inotify instances
+    /// of the same user are not known, so this is only a lower bound.
+    pub(crate) async fn check_watch_limit(&self, dir: &Path) -> Result<(), KanshiError> {
+        let limit = std::fs::read_to_string("/proc/sys/fs/inotify/max_user_watches")
+            .ok()
+            .and_then(|x| x.trim().parse::<usize>().ok());
+        let Some(limit) = limit else {
+            return Ok(());
+        };
+
+        let mut needed = 1;
+        let excluded = self.excluded.lock().await;
+        for_each_subdirectory(dir, &self.options, &excluded, &self.exclude_filter, |_| {
+            needed += 1;
+            Ok(())
+        })?;
+
+        let remaining = limit.saturating_sub(self.watch_descriptors.lock().await.len());
+        if needed > remaining {
+            tracing::debug!(
+                dir = %dir.display(),
+                needed,
+                remaining,
+                "not enough inotify watches left"
+            );
+            return Err(KanshiError::MarkQuotaExceeded);
         }
+        Ok(())
     }
 
-    async fn watch(&self, dir: &str) -> Result<(), crate::KanshiError> {
+    /// Removes every watch and watches the watched directories and files
+    /// again, e.g. after the filesystem they live on was unmounted and mounted
+    /// again. `start` and the event streams are kept, so subscribers keep
+    /// receiving events without subscribing again.
+    ///
+    /// Paths that can't be watched again stay watched, so a later `restart`
+    /// retries them, and their errors are returned together.
+    pub async fn restart(&self) -> Result<(), KanshiError> {
         if self.cancellation_token.is_cancelled() {
-            return Err(KanshiError::StreamClosedError);
+            return Err(KanshiError::StreamClosed);
         }
 
-        let absolute_path = path::absolute(Path::new(dir))?;
-        let mut watchers = self.watch_descriptors.lock().await;
-        let mark_top_dir = mark(&self.inotify, &mut watchers, absolute_path.as_path());
-
-        if let Ok(_) = mark_top_dir {
-            let mut traversal_queue = VecDeque::from([absolute_path]);
-            let mut visited = HashSet::<u64>::new();
-
-            'outer: loop {
-                if let Some(next_dir) = traversal_queue.pop_front() {
-                    if let Ok(dir_items) = fs::read_dir(next_dir) {
-                        for dir_item in dir_items {
-                            if let Ok(dir_item_unwrapped) = dir_item {
-                                if let Ok(metadata) = dir_item_unwrapped.metadata() {
-                                    let inode_number = metadata.ino();
-                                    if !visited.contains(&inode_number) && !metadata.is_symlink() {
-                                        visited.insert(inode_number);
-                                        if dir_item_unwrapped.path().is_dir() {
-                                            if let Err(e) = mark(
-                                                &self.inotify,
-                                                &mut watchers,
-                                                &dir_item_unwrapped.path(),
-                                            ) {
-                                                return Err(e);
-                                            }
-                                            traversal_queue.push_back(dir_item_unwrapped.path());
-                                        }
-                                    }
-                                }
-                            } else {
-                                break 'outer;
-                            }
-                        }
-                    } else {
-                        break 'outer;
-                    }
-                } else {
-                    break 'outer;
-                }
+        // Locked in the same order as in `watch_with_exclude_dirs`. The
+        // `IN_IGNORED` events caused by removing the watches are skipped by
+        // `start`.
+        let (dirs, files) = {
+            let mut watchers = self.watch_descriptors.lock().await;
+            for wd in watchers.keys() {
+                let _ = unmark(&self.inotify, wd);
+            }
+            watchers.clear();
+            let dirs: Vec<PathBuf> = self.watched.lock().await.drain().collect();
+            let files: Vec<PathBuf> = self.files.lock().await.drain().collect();
+            self.watched_paths.write().unwrap().clear();
+            (dirs, files)
+        };
+
+        let mut errors = Vec::new();
+        for dir in dirs {
+            if let Err(e) = self.watch(&dir).await {
+                self.add_watched_path(&dir);
+                self.watched.lock().await.insert(dir);
+                errors.push(e);
+            }
+        }
+        for file in files {
+            if let Err(e) = self.watch(&file).await {
+                self.add_watched_path(&file);
+                self.files.lock().await.insert(file);
+                errors.push(e);
             }
-
-            Ok(())
-        } else {
-            mark_top_dir
         }
+        KanshiError::from_errors(errors)
     }
 
-    fn get_events_stream(&self) -> Pin<Box<dyn futures::Stream<Item = FileSystemEvent> + Send>> {
-        let mut listener = self.sender.subscribe();
-        let cancel_token = self.cancellation_token.clone();
-
-        Box::pin(stream! {
-            loop {
-                tokio::select! {
-                    _ = cancel_token.cancelled() => {
-                        break;
-                    }
-                    val = listener.recv() => {
-                        match val {
-                            Ok(x) => yield x,
-                            Err(e) => match e {
-                                RecvError::Closed => break,
-                                _ => ()
-                            }
-                        }
-                    }
-                }
-            }
-        })
+    /// Adds watches for `dir`, which is `depth` levels below a watched
+    /// directory, and every directory below it within
+    /// `KanshiOptions::max_depth`.
+    fn mark_tree(
+        &self,
+        dir: &Path,
+        depth: usize,
+        watchers: &mut HashMap<WatchDescriptor, PathBuf>,
+        excluded: &HashSet<PathBuf>,
+    ) -> Result<(), KanshiError> {
+        mark(&self.inotify, watchers, dir, &self.options)?;
+        for_each_subdirectory_from(
+            dir,
+            depth,
+            &self.options,
+            excluded,
+            &self.exclude_filter,
+            |path| mark(&self.inotify, watchers, path, &self.options),
+        )
     }
 
-    async fn start(&self) -> Result<(), crate::KanshiError> {
+    /// The body of `start`, whose error `start` forwards to the streams.
+    async fn run(&self) -> Result<(), crate::KanshiError> {
         use nix::sys::epoll::EpollEvent;
 
+        let _running = RunningGuard::acquire(&self.running)?;
         let cancel_token = self.cancellation_token.clone();
-        let sender = self.sender.clone();
+        let mut sender = EventSender::new(
+            self.sender.clone(),
+            self.options.reconnect_timeout,
+            self.options
+                .reconnect_buffer_size
+                .min(self.options.channel_capacity),
+            self.options.grace_period,
+            self.options.debounce_ms.map(Duration::from_millis),
+            self.options.coalesce_window_ms.map(Duration::from_millis),
+            self.options.max_events_per_second,
+        )
+        .with_metadata(self.options.include_metadata)
+        .with_hashes(self.options.compute_hash_on_modify)
+        .with_hard_links(self.options.detect_hard_links)
+        .with_symlink_lookup(true)
+        .with_timestamps(self.options.approximate_timestamp)
+        .with_pause(self.paused.clone(), self.options.buffer_while_paused)
+        .with_callbacks(self.callbacks.clone())
+        .with_sequence(self.sequence.clone());
+
+        let _sigint = self.options.handle_sigint.then(|| {
+            let tracer = self.clone();
+            close_on_sigint(cancel_token.clone(), move || {
+                if let Err(e) = tracer.close() {
+                    (tracer.options.on_error)(e);
+                }
+            })
+        });
 
         let mut events = [EpollEvent::empty(); 1];
+        let fds = [(self.inotify.as_fd(), self.inotify.as_fd().as_raw_fd() as u64)];
         let mut cookie_map: HashMap<u32, InotifyEvent> = HashMap::new();
         // let mut cookie_map_old: HashMap<u32, InotifyEvent>;
 
         while !cancel_token.is_cancelled() {
             use nix::sys::inotify::AddWatchFlags;
 
-            events.fill(EpollEvent::empty());
-            let res = tokio::task::block_in_place(move || self.epoll.wait(&mut events, 16u8));
+            sender.poll()?;
 
-            if let Err(e) = res {
-                println!("epoll failed {e}");
-                res?;
-            }
+            let ready = match self.uring.as_ref() {
+                Some(uring) => uring.wait(&fds, Duration::from_millis(16)).await?.len(),
+                None => {
+                    events.fill(EpollEvent::empty());
+                    runtime::block_in_place(move || self.epoll.wait(&mut events, 16u8))
+                        .inspect_err(|e| tracing::error!(error = %e, "epoll_wait failed"))?
+                }
+            };
 
-            if res.ok().unwrap() > 0 {
+            if ready > 0 {
                 // cookie_map_old = cookie_map;
                 // cookie_map = HashMap::new();
 
@@ -188,6 +251,7 @@ impl KanshiImpl<KanshiOptions> for INotifyTracer {
 
                     // Is a normal Inotify event
                     if record.cookie == 0 {
+                        let excluded = self.excluded.lock().await;
                         let mut wd = self.watch_descriptors.lock().await;
                         let path = wd.get(&record.wd);
 
@@ -209,10 +273,22 @@ impl KanshiImpl<KanshiOptions> for INotifyTracer {
                                 FileSystemEventType::Modify
                             }
                             x if x.contains(AddWatchFlags::IN_ATTRIB) => {
-                                FileSystemEventType::Modify
+                                FileSystemEventType::AttributeChange
+                            }
+                            x if x.contains(AddWatchFlags::IN_ACCESS) => {
+                                FileSystemEventType::Access
+                            }
+                            x if x.contains(AddWatchFlags::IN_OPEN) => {
+                                FileSystemEventType::Open
+                            }
+                            x if x.contains(AddWatchFlags::IN_CLOSE_WRITE) => {
+                                FileSystemEventType::CloseWrite
                             }
                             x => {
-                                eprintln!("Unknown Mask Received - {:?}", x);
+                                (self.options.on_error)(KanshiError::UnknownEventMask(format!(
+                                    "{:?}",
+                                    x
+                                )));
                                 FileSystemEventType::Unknown
                             }
                         };
@@ -228,24 +304,53 @@ impl KanshiImpl<KanshiOptions> for INotifyTracer {
                             full_path.push(name);
                         }
 
+                        let watched = self.watched.lock().await;
+                        if !is_watched(&*self.files.lock().await, &*watched, Path::new(&full_path))
+                            || self.exclude_filter.is_match(&full_path)
+                        {
+                            continue;
+                        }
+
                         if record.mask.contains(AddWatchFlags::IN_CREATE)
                             && kind == FileSystemTargetKind::Directory
+                            && !is_excluded(&excluded, Path::new(&full_path))
+                            && !self.exclude_filter.is_match_dir(Path::new(&full_path))
+                            && within_depth_limit(&self.options, &*watched, Path::new(&full_path))
                         {
                             let absolute_path = path::absolute(Path::new(&full_path))?;
-                            mark(&self.inotify, &mut wd, absolute_path.as_path())?;
+                            mark(&self.inotify, &mut wd, absolute_path.as_path(), &self.options)?;
+                            // Entries created before the watch was added are not
+                            // reported, e.g. the directories of a `mkdir -p a/b/c`
+                            // below `a`.
+                            let depth = depth_below(&*watched, &absolute_path).unwrap_or(0);
+                            for_each_subdirectory_from(
+                                &absolute_path,
+                                depth,
+                                &self.options,
+                                &excluded,
+                                &self.exclude_filter,
+                                |path| match mark(&self.inotify, &mut wd, path, &self.options) {
+                                    // Removed again since it was read.
+                                    Ok(()) | Err(KanshiError::PathNotFound(_)) => Ok(()),
+                                    Err(err) => Err(err),
+                                },
+                            )?;
                         }
 
                         let tracer_event = FileSystemEvent {
                             event_type,
                             target: Some(FileSystemTarget {
                                 kind,
+                                device_inode: device_inode(&full_path),
+                                metadata: None,
                                 path: full_path,
                             }),
+                            pid: None,
+                            timestamp: None,
+                            sequence: 0,
                         };
 
-                        if let Err(_) = sender.send(tracer_event) {
-                            return Err(KanshiError::StreamClosedError);
-                        }
+                        sender.send(tracer_event)?;
 
                     // Is a MOVED_FROM or MOVED_TO event.
                     } else if cookie_map.get(&record.cookie).is_none() {
@@ -288,6 +393,23 @@ impl KanshiImpl<KanshiOptions> for INotifyTracer {
                             moved_to = Some(other_full_path);
                         }
 
+                        {
+                            let watched = self.watched.lock().await;
+                            let files = self.files.lock().await;
+                            if !is_watched(&files, &*watched, Path::new(moved_from.as_ref().unwrap()))
+                                && !is_watched(&files, &*watched, Path::new(moved_to.as_ref().unwrap()))
+                            {
+                                continue;
+                            }
+                        }
+
+                        // Moves are only dropped when both names are excluded.
+                        if self.exclude_filter.is_match(moved_from.as_ref().unwrap())
+                            && self.exclude_filter.is_match(moved_to.as_ref().unwrap())
+                        {
+                            continue;
+                        }
+
                         if kind == FileSystemTargetKind::Directory {
                             let moved_from_as_path_buf =
                                 PathBuf::from(moved_from.as_ref().unwrap());
@@ -307,29 +429,45 @@ impl KanshiImpl<KanshiOptions> for INotifyTracer {
                             }
                         }
 
+                        // Only the new name still exists, but both halves of a rename refer
+                        // to the same inode.
+                        let identity = device_inode(moved_to.as_ref().unwrap());
+
                         let tracer_event1 = FileSystemEvent {
-                            event_type: FileSystemEventType::MovedTo(moved_to.clone().unwrap()),
+                            event_type: FileSystemEventType::MovedTo {
+                                from: moved_from.clone().unwrap(),
+                                to: moved_to.clone().unwrap(),
+                            },
                             target: Some(FileSystemTarget {
                                 path: moved_from.clone().unwrap(),
                                 kind: kind.clone(),
+                                device_inode: identity,
+                                metadata: None,
                             }),
+                            pid: None,
+                            timestamp: None,
+                            sequence: 0,
                         };
 
                         let tracer_event2 = FileSystemEvent {
-                            event_type: FileSystemEventType::MovedFrom(moved_from.unwrap()),
+                            event_type: FileSystemEventType::MovedFrom {
+                                from: moved_from.unwrap(),
+                                to: moved_to.clone().unwrap(),
+                            },
                             target: Some(FileSystemTarget {
                                 path: moved_to.clone().unwrap(),
                                 kind,
+                                device_inode: identity,
+                                metadata: None,
                             }),
+                            pid: None,
+                            timestamp: None,
+                            sequence: 0,
                         };
 
-                        if let Err(_) = sender.send(tracer_event1) {
-                            return Err(KanshiError::StreamClosedError);
-                        }
+                        sender.send(tracer_event1)?;
 
-                        if let Err(_) = sender.send(tracer_event2) {
-                            return Err(KanshiError::StreamClosedError);
-                        }
+                        sender.send(tracer_event2)?;
                     }
                 }
             } else if !cookie_map.is_empty() {
@@ -355,6 +493,14 @@ impl KanshiImpl<KanshiOptions> for INotifyTracer {
 
                     let path_as_path_buf = PathBuf::from(full_path.clone());
 
+                    let watched = self.watched.lock().await;
+                    if !is_watched(&*self.files.lock().await, &*watched, &path_as_path_buf)
+                        || self.exclude_filter.is_match(&path_as_path_buf)
+                    {
+                        continue;
+                    }
+                    drop(watched);
+
                     if kind == FileSystemTargetKind::Directory {
                         if let Some(_) = wd
                             .values()
@@ -368,9 +514,15 @@ impl KanshiImpl<KanshiOptions> for INotifyTracer {
                             });
                             drop(wd);
                         } else {
+                            // Moved in from outside, so it is not a watched directory itself.
                             drop(wd);
-                            self.watch(path_as_path_buf.clone().to_str().unwrap())
-                                .await?;
+                            let excluded = self.excluded.lock().await;
+                            let mut wd = self.watch_descriptors.lock().await;
+                            let watched = self.watched.lock().await;
+                            if within_depth_limit(&self.options, &*watched, &path_as_path_buf) {
+                                let depth = depth_below(&*watched, &path_as_path_buf).unwrap_or(0);
+                                self.mark_tree(&path_as_path_buf, depth, &mut wd, &excluded)?;
+                            }
                         }
                     }
 
@@ -384,14 +536,17 @@ impl KanshiImpl<KanshiOptions> for INotifyTracer {
                     let tracer_event = FileSystemEvent {
                         event_type: FileSystemEventType::Move,
                         target: Some(FileSystemTarget {
+                            device_inode: device_inode(&full_path),
+                            metadata: None,
                             path: full_path,
                             kind,
                         }),
+                        pid: None,
+                        timestamp: None,
+                        sequence: 0,
                     };
 
-                    if let Err(_) = sender.send(tracer_event) {
-                        return Err(KanshiError::StreamClosedError);
-                    }
+                    sender.send(tracer_event)?;
                 }
                 cookie_map.clear();
             }
@@ -399,24 +554,216 @@ impl KanshiImpl<KanshiOptions> for INotifyTracer {
 
         Ok(())
     }
+}
+
+impl KanshiImpl<KanshiOptions> for INotifyTracer {
+    fn new(opts: KanshiOptions) -> Result<INotifyTracer, KanshiError> {
+        use nix::sys::epoll::{EpollCreateFlags, EpollEvent, EpollFlags};
+        use nix::sys::inotify::InitFlags;
+
+        let exclude_filter = PathFilter::new(
+            &opts.exclude_patterns,
+            &opts.exclude_regexes,
+            opts.use_gitignore,
+        )?;
+        let uring = match opts.backend {
+            LinuxBackend::IoUring => Some(Arc::new(Uring::new()?)),
+            LinuxBackend::Epoll => None,
+        };
+
+        #[allow(non_snake_case)]
+        let INIT_FLAGS = InitFlags::IN_CLOEXEC;
+
+        let inotify = Inotify::init(INIT_FLAGS)?;
+
+        // Setup epoll
+        let epoll_event = EpollEvent::new(EpollFlags::EPOLLIN, inotify.as_fd().as_raw_fd() as u64);
+        let epoll = Epoll::new(EpollCreateFlags::EPOLL_CLOEXEC)?;
+        epoll.add(inotify.as_fd(), epoll_event)?;
+
+        let (tx, _rx) = tokio::sync::broadcast::channel(opts.channel_capacity.max(1));
+        Ok(INotifyTracer {
+            inotify: Arc::new(inotify),
+            epoll: Arc::new(epoll),
+            uring,
+            sender: tx,
+            cancellation_token: CancellationToken::new(),
+            paused: Arc::new(AtomicBool::new(false)),
+            running: Arc::new(AtomicBool::new(false)),
+            callbacks: EventCallbacks::default(),
+            sequence: Arc::new(AtomicU64::new(opts.initial_sequence)),
+            watch_descriptors: Arc::new(Mutex::new(HashMap::new())),
+            options: Arc::new(opts),
+            excluded: Arc::new(Mutex::new(HashSet::new())),
+            exclude_filter: Arc::new(exclude_filter),
+            watched: Arc::new(Mutex::new(HashSet::new())),
+            files: Arc::new(Mutex::new(HashSet::new())),
+            watched_paths: Arc::new(std::sync::RwLock::new(Vec::new())),
+        })
+    }
 
-    fn close(&self) -> bool {
+    async fn watch(&self, dir: impl AsRef<Path> + Send) -> Result<(), crate::KanshiError> {
+        self.watch_with_exclude_dirs(dir, HashSet::new()).await
+    }
+
+    async fn watch_with_exclude_dirs(
+        &self,
+        dir: impl AsRef<Path> + Send,
+        exclude: HashSet<PathBuf>,
+    ) -> Result<(), KanshiError> {
+        let dir = dir.as_ref();
         if self.cancellation_token.is_cancelled() {
-            return true;
+            return Err(KanshiError::StreamClosed);
         }
 
-        self.cancellation_token.cancel();
+        if self.options.create_watched_dirs {
+            create_watched_dir(Path::new(dir))?;
+        }
 
-        let mut has_error = false;
+        let mut excluded = self.excluded.lock().await;
+        excluded.extend(canonicalize_all(exclude));
 
-        if self.epoll.delete(self.inotify.as_fd()).is_err() {
-            eprintln!("epoll.delete returned error");
-            has_error = true;
+        let absolute_path = path::absolute(Path::new(dir))?;
+        let mut watchers = self.watch_descriptors.lock().await;
+        if absolute_path.is_file() {
+            // Watching the parent directory instead of the file itself keeps
+            // reporting the file after it is replaced by a rename.
+            let parent = absolute_path.parent().unwrap_or(Path::new("/"));
+            mark(&self.inotify, &mut watchers, parent, &self.options)?;
+            self.add_watched_path(&absolute_path);
+            self.files.lock().await.insert(absolute_path);
+            return Ok(());
         }
+        // A symlinked directory is watched under its target, so that its
+        // events are reported for the same paths as with fanotify.
+        let path = absolute_path
+            .canonicalize()
+            .map_err(|e| KanshiError::from_io_at(e, &absolute_path))?;
+        self.exclude_filter.add_ignore_files(&path);
+        self.mark_tree(&path, 0, &mut watchers, &excluded)?;
+        self.add_watched_path(&path);
+        self.watched.lock().await.insert(path);
+        Ok(())
+    }
 
-        // Inotify is automatically closed on drop.
+    async fn unwatch(&self, dir: impl AsRef<Path> + Send) -> Result<(), KanshiError> {
+        let path = path::absolute(dir.as_ref())?;
+        let canonical_path = path.canonicalize().unwrap_or_else(|_| path.clone());
 
-        !has_error
+        // Locked in the same order as in `watch_with_exclude_dirs`.
+        let mut watchers = self.watch_descriptors.lock().await;
+        let mut watched = self.watched.lock().await;
+        let mut files = self.files.lock().await;
+        if files.remove(&path) {
+            // The parent directory stays watched while it is still needed.
+            let parent = path.parent().unwrap_or(Path::new("/"));
+            let needed = files.iter().any(|x| x.parent() == Some(parent))
+                || (depth_below(&*watched, parent).is_some()
+                    && within_depth_limit(&self.options, &*watched, parent));
+            if !needed {
+                watchers.retain(|wd, x| {
+                    if x != parent {
+                        return true;
+                    }
+                    let _ = unmark(&self.inotify, wd);
+                    false
+                });
+            }
+            self.watched_paths.write().unwrap().retain(|x| *x != canonical_path);
+            return Ok(());
+        }
+        if !watched.remove(&path) {
+            return Err(KanshiError::PathNotWatched(dir.as_ref().to_path_buf()));
+        }
+        self.watched_paths.write().unwrap().retain(|x| *x != canonical_path);
+        if watched.iter().any(|x| path.starts_with(x)) {
+            return Ok(());
+        }
+
+        // Directories watched on their own keep their watches. The `IN_IGNORED`
+        // events caused by removing a watch are skipped by `start`.
+        let nested: Vec<&PathBuf> = watched.iter().filter(|x| x.starts_with(&path)).collect();
+        watchers.retain(|wd, x| {
+            if !x.starts_with(&path)
+                || nested.iter().any(|y| x.starts_with(y))
+                || files.iter().any(|y| y.parent() == Some(x.as_path()))
+            {
+                return true;
+            }
+            let _ = unmark(&self.inotify, wd);
+            false
+        });
+        Ok(())
+    }
+
+    fn get_watched_paths(&self) -> Vec<PathBuf> {
+        self.watched_paths.read().unwrap().clone()
+    }
+
+    fn get_events_stream(&self) -> Pin<Box<dyn futures::Stream<Item = KanshiStreamItem> + Send>> {
+        let mut listener = self.sender.subscribe();
+        let cancel_token = self.cancellation_token.clone();
+
+        Box::pin(stream! {
+            loop {
+                tokio::select! {
+                    _ = cancel_token.cancelled() => {
+                        break;
+                    }
+                    val = listener.recv() => {
+                        match val {
+                            Ok(x) => {
+                                let failed = x.is_error();
+                                yield x;
+                                if failed {
+                                    break;
+                                }
+                            }
+                            Err(e) => match e {
+                                RecvError::Closed => break,
+                                _ => ()
+                            }
+                        }
+                    }
+                }
+            }
+        })
+    }
+
+    async fn start(&self) -> Result<(), crate::KanshiError> {
+        let result = self.run().await;
+        report_error(&self.sender, result)
+    }
+
+    fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+    }
+
+    fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    fn on_event<F>(&self, callback: F)
+    where
+        F: Fn(FileSystemEvent) + Send + Sync + 'static,
+    {
+        self.callbacks.write().unwrap().push(Box::new(callback));
+    }
+
+    fn close(&self) -> Result<(), KanshiError> {
+        if self.cancellation_token.is_cancelled() {
+            return Ok(());
+        }
+
+        self.cancellation_token.cancel();
+
+        // Inotify is automatically closed on drop.
+        self.epoll.delete(self.inotify.as_fd())?;
+        Ok(())
     }
 }
 
@@ -424,22 +771,30 @@ fn mark(
     inotify: &Inotify,
     watchers: &mut HashMap<WatchDescriptor, PathBuf>,
     path: &Path,
+    options: &KanshiOptions,
 ) -> Result<(), KanshiError> {
     use nix::sys::inotify::AddWatchFlags;
     #[allow(non_snake_case)]
-    let MASK_FLAGS = AddWatchFlags::IN_CREATE
+    let mut MASK_FLAGS = AddWatchFlags::IN_CREATE
         | AddWatchFlags::IN_MODIFY
+        | AddWatchFlags::IN_ATTRIB
         | AddWatchFlags::IN_MOVE
         | AddWatchFlags::IN_DELETE;
-
-    let wd = inotify.add_watch(path, MASK_FLAGS);
-    if let Err(e) = wd {
-        Err(KanshiError::FileSystemError(e.to_string()))
-    } else {
-        let wd = wd.ok().unwrap();
-        watchers.insert(wd, path.to_path_buf());
-        Ok(())
+    if options.watch_close_write {
+        MASK_FLAGS |= AddWatchFlags::IN_CLOSE_WRITE;
+    }
+    if options.watch_access {
+        MASK_FLAGS |= AddWatchFlags::IN_ACCESS;
+    }
+    if options.watch_open {
+        MASK_FLAGS |= AddWatchFlags::IN_OPEN;
     }
+
+    let wd = inotify
+        .add_watch(path, MASK_FLAGS)
+        .map_err(|e| KanshiError::from_errno_at(e, path))?;
+    watchers.insert(wd, path.to_path_buf());
+    Ok(())
 }
 
 fn unmark(inotify: &Inotify, wd: &WatchDescriptor) -> Result<(), KanshiError> {
@@ -0,0 +1,184 @@
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use crate::KanshiError;
+
+const MOUNTINFO: &str = "/proc/self/mountinfo";
+
+/// The upper and lower directories of the overlay mounts in
+/// `/proc/self/mountinfo`, for `KanshiOptions::overlay_remap`.
+///
+/// Paths read back from `/proc/self/fd` can point into one of these layers
+/// instead of the overlay mount the file was changed through. `remap` turns
+/// them back into paths below the mount. Layers are resolved to their
+/// canonical paths, since `/proc/self/fd` never reports the symlinks Docker
+/// lists its lower layers by. A lower layer shared by several mounts, e.g. an
+/// image layer used by several containers, is not remapped, since there is no
+/// telling which mount a change was made through.
+#[derive(Default)]
+pub(crate) struct OverlayLayers {
+    // Each layer with the mount point of its overlay, or `None` when it
+    // belongs to more than one. Deepest layers come first, so that nested
+    // layers are matched before their parents.
+    layers: Vec<(PathBuf, Option<PathBuf>)>,
+}
+
+impl OverlayLayers {
+    /// Reads the overlay mounts of this process' mount namespace.
+    pub(crate) fn load() -> Result<OverlayLayers, KanshiError> {
+        let mountinfo = std::fs::read_to_string(MOUNTINFO)
+            .map_err(|e| KanshiError::from_io_at(e, MOUNTINFO))?;
+        Ok(OverlayLayers::from_mountinfo(&mountinfo))
+    }
+
+    /// The layers of the overlay mounts in `mountinfo`. Layers that can't be
+    /// resolved, e.g. because they are outside this mount namespace, are left
+    /// out.
+    fn from_mountinfo(mountinfo: &str) -> OverlayLayers {
+        let mut mount_points: HashMap<PathBuf, Option<PathBuf>> = HashMap::new();
+        for (layer, mount_point) in parse(mountinfo) {
+            let Ok(layer) = layer.canonicalize() else {
+                continue;
+            };
+            mount_points
+                .entry(layer)
+                .and_modify(|x| {
+                    if x.as_ref() != Some(&mount_point) {
+                        *x = None;
+                    }
+                })
+                .or_insert_with(|| Some(mount_point.clone()));
+        }
+
+        let mut layers: Vec<_> = mount_points.into_iter().collect();
+        layers.sort_by_key(|(layer, _)| Reverse(layer.components().count()));
+        OverlayLayers { layers }
+    }
+
+    /// `path` below the overlay mount if it is inside one of its layers,
+    /// otherwise `path` unchanged.
+    pub(crate) fn remap(&self, path: OsString) -> OsString {
+        for (layer, mount_point) in self.layers.iter() {
+            if let Ok(rest) = Path::new(&path).strip_prefix(layer) {
+                return match mount_point {
+                    Some(mount_point) => mount_point.join(rest).into_os_string(),
+                    None => path,
+                };
+            }
+        }
+        path
+    }
+}
+
+/// The upper and lower directories of the overlay mounts in `mountinfo`, as
+/// listed, each with the mount point of its overlay.
+fn parse(mountinfo: &str) -> Vec<(PathBuf, PathBuf)> {
+    let mut layers = Vec::new();
+    for line in mountinfo.lines() {
+        // The optional fields before the separator vary in number, the mount
+        // point is always the fifth field though.
+        let Some((mount, source)) = line.split_once(" - ") else {
+            continue;
+        };
+        let Some(mount_point) = mount.split(' ').nth(4) else {
+            continue;
+        };
+        // The filesystem type, the source and the superblock options.
+        let mut source = source.split(' ');
+        if source.next() != Some("overlay") {
+            continue;
+        }
+        let Some(options) = source.nth(1) else {
+            continue;
+        };
+
+        let mount_point = PathBuf::from(unescape_mountinfo(mount_point));
+        for option in options.split(',') {
+            let dirs = match option.split_once('=') {
+                Some(("upperdir", dir)) => vec![dir],
+                // Lower layers are separated by colons, or two for the
+                // data-only ones.
+                Some(("lowerdir", dirs)) => dirs.split(':').filter(|x| !x.is_empty()).collect(),
+                _ => continue,
+            };
+            for dir in dirs {
+                layers.push((PathBuf::from(unescape_mountinfo(dir)), mount_point.clone()));
+            }
+        }
+    }
+    layers
+}
+
+/// Undoes the octal escapes of spaces, tabs, newlines and backslashes in the
+/// fields of `/proc/self/mountinfo`.
+pub(crate) fn unescape_mountinfo(field: &str) -> OsString {
+    let bytes = field.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes
+            .get(i + 1..i + 4)
+            .and_then(|x| std::str::from_utf8(x).ok());
+        match octal.map(|x| u8::from_str_radix(x, 8)) {
+            Some(Ok(byte)) if bytes[i] == b'\\' => {
+                unescaped.push(byte);
+                i += 4;
+            }
+            _ => {
+                unescaped.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    OsStr::from_bytes(&unescaped).to_os_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::symlink};
+
+    use super::OverlayLayers;
+
+    #[test]
+    fn remaps_layer_paths_to_the_mount() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let overlay2 = root.join("overlay2");
+        for layer in ["first", "second", "own", "shared"] {
+            fs::create_dir_all(overlay2.join(layer).join("diff")).unwrap();
+        }
+        fs::create_dir_all(overlay2.join("first/work")).unwrap();
+        // Docker lists lower layers by short symlinks to their directories.
+        fs::create_dir(overlay2.join("l")).unwrap();
+        symlink("../own/diff", overlay2.join("l/OWN")).unwrap();
+        symlink("../shared/diff", overlay2.join("l/SHARED")).unwrap();
+
+        let o = overlay2.display();
+        let r = root.display();
+        let mountinfo = format!(
+            "22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+73 22 0:45 / {r}/first\\040merged rw,relatime - overlay overlay rw,lowerdir={o}/l/OWN:{o}/l/SHARED,upperdir={o}/first/diff,workdir={o}/first/work
+74 22 0:46 / {r}/second rw,relatime - overlay overlay rw,lowerdir={o}/l/SHARED,upperdir={o}/second/diff,workdir={o}/second/work
+"
+        );
+        let layers = OverlayLayers::from_mountinfo(&mountinfo);
+        let remap = |x: &str| layers.remap(overlay2.join(x).into()).into_string().unwrap();
+
+        assert_eq!(
+            remap("first/diff/etc/hosts"),
+            format!("{r}/first merged/etc/hosts")
+        );
+        assert_eq!(remap("own/diff/usr"), format!("{r}/first merged/usr"));
+        assert_eq!(remap("second/diff/x"), format!("{r}/second/x"));
+        // Neither a layer of both mounts, the work directory nor other paths
+        // are remapped.
+        assert_eq!(remap("shared/diff/bin"), format!("{o}/shared/diff/bin"));
+        assert_eq!(remap("first/work/x"), format!("{o}/first/work/x"));
+        assert_eq!(remap("other"), format!("{o}/other"));
+    }
+}
@@ -0,0 +1,226 @@
+use std::{
+    ffi::{CStr, CString, OsStr, OsString},
+    mem::MaybeUninit,
+    os::{
+        fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd},
+        unix::ffi::OsStrExt,
+    },
+    path::{Path, PathBuf},
+};
+
+use nix::errno::Errno;
+
+// Upper bound on how many parent directories are walked before giving up.
+const MAX_DEPTH: usize = 4096;
+
+/// A directory passed to `watch`. Paths are resolved relative to these instead
+/// of being read back from `/proc/self/fd`.
+pub(crate) struct WatchRoot {
+    path: PathBuf,
+    fd: OwnedFd,
+    device_inode: (u64, u64),
+}
+
+impl WatchRoot {
+    pub(crate) fn open(path: &Path) -> Result<WatchRoot, Errno> {
+        let path = path.canonicalize().map_err(|_| Errno::last())?;
+        let c_path = CString::new(path.as_os_str().as_bytes()).map_err(|_| Errno::EINVAL)?;
+
+        let fd = unsafe {
+            libc::open(
+                c_path.as_ptr(),
+                libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(Errno::last());
+        }
+
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let device_inode = fstat(fd.as_raw_fd())?;
+
+        Ok(WatchRoot {
+            path,
+            fd,
+            device_inode,
+        })
+    }
+}
+
+/// Resolves the path of the directory `dir_fd` without using `/proc`.
+///
+/// The directory's name is found by opening `..` and searching it with
+/// `getdents64` for an entry with a matching inode, repeating until one of
+/// `roots` is reached. The result is then re-opened with `openat2` and
+/// `RESOLVE_BENEATH` relative to that root to confirm it still refers to the
+/// same directory and does not escape the root.
+pub(crate) fn resolve_beneath(dir_fd: BorrowedFd, roots: &[WatchRoot]) -> Result<PathBuf, Errno> {
+    let target = fstat(dir_fd.as_raw_fd())?;
+    let mut current = dir_fd.try_clone_to_owned().map_err(|_| Errno::last())?;
+    let mut current_identity = target;
+    let mut components = Vec::<OsString>::new();
+
+    for _ in 0..MAX_DEPTH {
+        if let Some(root) = roots.iter().find(|x| x.device_inode == current_identity) {
+            let relative: PathBuf = components.iter().rev().collect();
+            if open_beneath(root, &relative)? != target {
+                return Err(Errno::ESTALE);
+            }
+            return Ok(if components.is_empty() {
+                root.path.clone()
+            } else {
+                root.path.join(relative)
+            });
+        }
+
+        let parent = open_at(
+            current.as_raw_fd(),
+            c"..",
+            libc::O_RDONLY | libc::O_DIRECTORY,
+        )?;
+        let parent_identity = fstat(parent.as_raw_fd())?;
+        if parent_identity == current_identity {
+            // Reached "/" without passing through a watched root.
+            return Err(Errno::ENOENT);
+        }
+
+        components.push(find_entry(&parent, current_identity)?);
+        current = parent;
+        current_identity = parent_identity;
+    }
+
+    Err(Errno::ELOOP)
+}
+
+/// Searches the directory `dir` for an entry referring to `identity`.
+fn find_entry(dir: &OwnedFd, identity: (u64, u64)) -> Result<OsString, Errno> {
+    let mut buf = vec![0u8; 8192];
+
+    loop {
+        let read = unsafe {
+            libc::syscall(
+                libc::SYS_getdents64,
+                dir.as_raw_fd(),
+                buf.as_mut_ptr(),
+                buf.len(),
+            )
+        };
+        if read < 0 {
+            return Err(Errno::last());
+        }
+        if read == 0 {
+            return Err(Errno::ENOENT);
+        }
+
+        // Each record is a `linux_dirent64`: d_ino (u64), d_off (i64),
+        // d_reclen (u16), d_type (u8), then the nul-terminated name.
+        let mut offset = 0;
+        while offset < read as usize {
+            let record = &buf[offset..];
+            let d_ino = u64::from_ne_bytes(record[0..8].try_into().unwrap());
+            let d_reclen = u16::from_ne_bytes(record[16..18].try_into().unwrap()) as usize;
+            let name = CStr::from_bytes_until_nul(&record[19..d_reclen]).map_err(|_| Errno::EIO)?;
+            offset += d_reclen;
+
+            if d_ino != identity.1 || name == c"." || name == c".." {
+                continue;
+            }
+
+            // d_ino is the inode of the underlying entry, which differs from the
+            // mounted directory's for mount points, so confirm with a stat.
+            if stat_at(dir.as_raw_fd(), name) == Some(identity) {
+                return Ok(OsStr::from_bytes(name.to_bytes()).to_os_string());
+            }
+        }
+    }
+}
+
+/// Opens `relative` beneath `root` with `openat2(RESOLVE_BENEATH)` and returns
+/// the identity of what was opened.
+fn open_beneath(root: &WatchRoot, relative: &Path) -> Result<(u64, u64), Errno> {
+    let relative = if relative.as_os_str().is_empty() {
+        CString::from(c".")
+    } else {
+        CString::new(relative.as_os_str().as_bytes()).map_err(|_| Errno::EINVAL)?
+    };
+
+    let mut how: libc::open_how = unsafe { std::mem::zeroed() };
+    how.flags = (libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC) as u64;
+    how.resolve = libc::RESOLVE_BENEATH | libc::RESOLVE_NO_MAGICLINKS;
+
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_openat2,
+            root.fd.as_raw_fd(),
+            relative.as_ptr(),
+            &how as *const libc::open_how,
+            std::mem::size_of::<libc::open_how>(),
+        )
+    };
+    if fd < 0 {
+        return Err(Errno::last());
+    }
+
+    let fd = unsafe { OwnedFd::from_raw_fd(fd as RawFd) };
+    fstat(fd.as_raw_fd())
+}
+
+fn open_at(dir_fd: RawFd, name: &CStr, flags: libc::c_int) -> Result<OwnedFd, Errno> {
+    let fd = unsafe { libc::openat(dir_fd, name.as_ptr(), flags | libc::O_CLOEXEC) };
+    if fd < 0 {
+        Err(Errno::last())
+    } else {
+        Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+    }
+}
+
+fn fstat(fd: RawFd) -> Result<(u64, u64), Errno> {
+    let mut stat = MaybeUninit::<libc::stat>::uninit();
+    if unsafe { libc::fstat(fd, stat.as_mut_ptr()) } != 0 {
+        return Err(Errno::last());
+    }
+    let stat = unsafe { stat.assume_init() };
+    Ok((stat.st_dev, stat.st_ino))
+}
+
+fn stat_at(dir_fd: RawFd, name: &CStr) -> Option<(u64, u64)> {
+    let mut stat = MaybeUninit::<libc::stat>::uninit();
+    let res = unsafe {
+        libc::fstatat(
+            dir_fd,
+            name.as_ptr(),
+            stat.as_mut_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if res != 0 {
+        return None;
+    }
+    let stat = unsafe { stat.assume_init() };
+    Some((stat.st_dev, stat.st_ino))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::fd::AsFd};
+
+    use super::{resolve_beneath, WatchRoot};
+
+    #[test]
+    fn resolves_nested_directory() {
+        let root = std::env::temp_dir().join(format!("kanshi-resolve-{}", std::process::id()));
+        let nested = root.join("a").join("b");
+        fs::create_dir_all(&nested).unwrap();
+        let nested = nested.canonicalize().unwrap();
+
+        let roots = [WatchRoot::open(&root).unwrap()];
+        let dir = fs::File::open(&nested).unwrap();
+        let resolved = resolve_beneath(dir.as_fd(), &roots);
+        let outside = resolve_beneath(fs::File::open("/").unwrap().as_fd(), &roots);
+
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(resolved.unwrap(), nested);
+        assert!(outside.is_err());
+    }
+}
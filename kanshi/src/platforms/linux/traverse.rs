@@ -0,0 +1,220 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    fs,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use rayon::prelude::*;
+
+use crate::{platforms::filter::PathFilter, KanshiError};
+
+use super::KanshiOptions;
+
+/// Walks the tree below `root` breadth-first and calls `visit` on every
+/// subdirectory found, at most once per inode.
+///
+/// Symlinks are skipped unless `options.follow_symlinks` is set, in which case
+/// symlinked directories are visited under their resolved target path.
+/// Directories in `exclude` or matching `filter` (see
+/// [`PathFilter::is_match_dir`]) are neither visited nor descended into. Only
+/// directories within `KanshiOptions::depth_limit` of `root` are visited.
+///
+/// With `options.parallel_scan`, each level of the tree is read in parallel on
+/// the rayon thread pool. `visit` is still only ever called from this thread.
+pub(crate) fn for_each_subdirectory(
+    root: &Path,
+    options: &KanshiOptions,
+    exclude: &HashSet<PathBuf>,
+    filter: &PathFilter,
+    visit: impl FnMut(&Path) -> Result<(), KanshiError>,
+) -> Result<(), KanshiError> {
+    for_each_subdirectory_from(root, 0, options, exclude, filter, visit)
+}
+
+/// Same as [`for_each_subdirectory`] for a `root` that is itself `root_depth`
+/// levels below a watched directory.
+pub(crate) fn for_each_subdirectory_from(
+    root: &Path,
+    root_depth: usize,
+    options: &KanshiOptions,
+    exclude: &HashSet<PathBuf>,
+    filter: &PathFilter,
+    mut visit: impl FnMut(&Path) -> Result<(), KanshiError>,
+) -> Result<(), KanshiError> {
+    let max_depth = options.depth_limit().unwrap_or(usize::MAX);
+    if root_depth >= max_depth {
+        return Ok(());
+    }
+
+    let mut visited = HashSet::<u64>::new();
+
+    if let Ok(metadata) = fs::metadata(root) {
+        visited.insert(metadata.ino());
+    }
+
+    if options.parallel_scan {
+        let mut level = vec![root.to_path_buf()];
+        let mut depth = root_depth;
+
+        while !level.is_empty() && depth < max_depth {
+            depth += 1;
+
+            // Unreadable directories are skipped rather than ending the walk,
+            // since the rest of the level has already been read.
+            let subdirectories: Vec<_> = level
+                .par_iter()
+                .filter_map(|dir| read_subdirectories(dir, options.follow_symlinks))
+                .collect();
+
+            level = Vec::new();
+            for (path, inode_number) in subdirectories.into_iter().flatten() {
+                if visited.insert(inode_number)
+                    && !is_excluded(exclude, &path)
+                    && !filter.is_match_dir(&path)
+                {
+                    visit(&path)?;
+                    level.push(path);
+                }
+            }
+        }
+    } else {
+        let mut traversal_queue = VecDeque::from([(root.to_path_buf(), root_depth)]);
+
+        while let Some((next_dir, depth)) = traversal_queue.pop_front() {
+            if depth >= max_depth {
+                continue;
+            }
+            // Unreadable directories, or ones deleted since, are skipped.
+            let Some(subdirectories) = read_subdirectories(&next_dir, options.follow_symlinks)
+            else {
+                continue;
+            };
+
+            for (path, inode_number) in subdirectories {
+                if visited.insert(inode_number)
+                    && !is_excluded(exclude, &path)
+                    && !filter.is_match_dir(&path)
+                {
+                    visit(&path)?;
+                    traversal_queue.push_back((path, depth + 1));
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// How many levels `path` is below the closest of `roots`, or `None` if it is
+/// not below any of them.
+pub(crate) fn depth_below<'a>(
+    roots: impl IntoIterator<Item = &'a PathBuf>,
+    path: &Path,
+) -> Option<usize> {
+    roots
+        .into_iter()
+        .filter_map(|root| path.strip_prefix(root).ok())
+        .map(|relative| relative.components().count())
+        .min()
+}
+
+/// Whether a directory at `path` is within `KanshiOptions::depth_limit` of
+/// one of the watched `roots`, and should be watched as well.
+pub(crate) fn within_depth_limit<'a>(
+    options: &KanshiOptions,
+    roots: impl IntoIterator<Item = &'a PathBuf>,
+    path: &Path,
+) -> bool {
+    match options.depth_limit() {
+        Some(limit) => depth_below(roots, path).is_some_and(|depth| depth <= limit),
+        None => true,
+    }
+}
+
+/// Whether an event for `path` should be reported. Watching a file marks its
+/// parent directory, so events for the other entries in that directory are
+/// dropped unless they are below one of the watched `roots` as well.
+pub(crate) fn is_watched<'a>(
+    files: &HashSet<PathBuf>,
+    roots: impl IntoIterator<Item = &'a PathBuf>,
+    path: &Path,
+) -> bool {
+    files.is_empty() || files.contains(path) || roots.into_iter().any(|x| path.starts_with(x))
+}
+
+/// Lists the subdirectories of `dir` along with their inode numbers. Returns
+/// `None` if `dir` could not be read.
+fn read_subdirectories(dir: &Path, follow_symlinks: bool) -> Option<Vec<(PathBuf, u64)>> {
+    let mut subdirectories = Vec::new();
+
+    for dir_item in fs::read_dir(dir).ok()? {
+        let dir_item = dir_item.ok()?;
+        let Ok(mut metadata) = dir_item.metadata() else {
+            continue;
+        };
+        let mut path = dir_item.path();
+
+        if metadata.is_symlink() {
+            if !follow_symlinks {
+                continue;
+            }
+            let (Ok(target), Ok(target_metadata)) = (fs::canonicalize(&path), fs::metadata(&path))
+            else {
+                continue;
+            };
+            path = target;
+            metadata = target_metadata;
+        }
+
+        if metadata.is_dir() {
+            subdirectories.push((path, metadata.ino()));
+        }
+    }
+
+    Some(subdirectories)
+}
+
+/// Whether `path` is one of the canonical paths in `exclude`.
+pub(crate) fn is_excluded(exclude: &HashSet<PathBuf>, path: &Path) -> bool {
+    !exclude.is_empty()
+        && path
+            .canonicalize()
+            .is_ok_and(|path| exclude.contains(&path))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, fs};
+
+    use crate::platforms::filter::PathFilter;
+
+    use super::{for_each_subdirectory, KanshiOptions};
+
+    #[test]
+    fn skips_directories_that_cannot_be_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir(root.join("a")).unwrap();
+        fs::create_dir_all(root.join("b/c/d")).unwrap();
+
+        for parallel_scan in [false, true] {
+            let options = KanshiOptions::default().with_parallel_scan(parallel_scan);
+            let filter = PathFilter::new(&[], &[], false).unwrap();
+            let mut visited = Vec::new();
+            // `a` is gone by the time it is read, whichever of `a` and `b` is
+            // read first.
+            for_each_subdirectory(root, &options, &HashSet::new(), &filter, |path| {
+                if path.ends_with("a") {
+                    fs::remove_dir(path).unwrap();
+                }
+                visited.push(path.strip_prefix(root).unwrap().to_path_buf());
+                Ok(())
+            })
+            .unwrap();
+
+            assert!(visited.iter().any(|x| x.ends_with("b/c/d")), "{visited:?}");
+            fs::create_dir(root.join("a")).unwrap();
+        }
+    }
+}
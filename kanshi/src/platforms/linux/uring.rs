@@ -0,0 +1,170 @@
+use std::{
+    collections::HashSet,
+    ffi::CStr,
+    io,
+    mem::MaybeUninit,
+    os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd},
+    sync::Mutex,
+    time::Duration,
+};
+
+use io_uring::{opcode, types, IoUring};
+
+use crate::{runtime, KanshiError};
+
+/// The oldest kernel with `IORING_OP_POLL_ADD` and `IORING_REGISTER_EVENTFD`.
+const MIN_KERNEL_VERSION: (u32, u32) = (5, 1);
+
+/// Waits for file descriptors to become readable with `IORING_OP_POLL_ADD`,
+/// for [`LinuxBackend::IoUring`](super::LinuxBackend::IoUring).
+///
+/// The ring signals an eventfd whenever it posts a completion, and that
+/// eventfd is awaited through the runtime, so no thread is blocked while
+/// waiting.
+pub(super) struct Uring {
+    inner: Mutex<Inner>,
+    eventfd: OwnedFd,
+}
+
+struct Inner {
+    ring: IoUring,
+    // Tokens of the polls submitted to the ring that haven't completed yet.
+    // Polls are one-shot, so a token is submitted again once it completed.
+    pending: HashSet<u64>,
+}
+
+impl Uring {
+    pub(super) fn new() -> Result<Uring, KanshiError> {
+        let release = kernel_release()?;
+        match parse_release(&release) {
+            Some(version) if version >= MIN_KERNEL_VERSION => {}
+            _ => {
+                return Err(KanshiError::InvalidParameter(format!(
+                    "LinuxBackend::IoUring requires Linux {}.{} or newer, found {release}.",
+                    MIN_KERNEL_VERSION.0, MIN_KERNEL_VERSION.1
+                )))
+            }
+        }
+
+        let ring = IoUring::new(8)?;
+        let eventfd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if eventfd == -1 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let eventfd = unsafe { OwnedFd::from_raw_fd(eventfd) };
+        ring.submitter().register_eventfd(eventfd.as_raw_fd())?;
+
+        Ok(Uring {
+            inner: Mutex::new(Inner {
+                ring,
+                pending: HashSet::new(),
+            }),
+            eventfd,
+        })
+    }
+
+    /// Waits up to `timeout` for any of `fds` to become readable and returns
+    /// the tokens paired with the ones that did. May return early without any
+    /// tokens.
+    pub(super) async fn wait(
+        &self,
+        fds: &[(BorrowedFd<'_>, u64)],
+        timeout: Duration,
+    ) -> io::Result<Vec<u64>> {
+        let ready = self.submit(fds)?;
+        if !ready.is_empty() {
+            return Ok(ready);
+        }
+
+        runtime::readable(self.eventfd.as_fd(), timeout).await?;
+        self.clear_eventfd()?;
+        self.inner.lock().unwrap().reap()
+    }
+
+    /// Submits a poll for every fd in `fds` that has none in flight, and
+    /// returns the tokens of the polls that completed already.
+    fn submit(&self, fds: &[(BorrowedFd<'_>, u64)]) -> io::Result<Vec<u64>> {
+        let mut inner = self.inner.lock().unwrap();
+        let Inner { ring, pending } = &mut *inner;
+        for (fd, token) in fds {
+            if !pending.insert(*token) {
+                continue;
+            }
+            let entry = opcode::PollAdd::new(types::Fd(fd.as_raw_fd()), libc::POLLIN as u32)
+                .build()
+                .user_data(*token);
+            // Safety: polls don't point to any memory the ring could outlive.
+            unsafe { ring.submission().push(&entry) }.map_err(io::Error::other)?;
+        }
+        ring.submit()?;
+        inner.reap()
+    }
+
+    /// Resets the eventfd's counter, so it only becomes readable again when
+    /// the next completion is posted.
+    fn clear_eventfd(&self) -> io::Result<()> {
+        let mut counter = [0u8; 8];
+        let res = unsafe {
+            libc::read(
+                self.eventfd.as_raw_fd(),
+                counter.as_mut_ptr().cast(),
+                counter.len(),
+            )
+        };
+        if res == -1 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::WouldBlock {
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Inner {
+    /// Takes the completed polls off the completion queue and returns their
+    /// tokens.
+    fn reap(&mut self) -> io::Result<Vec<u64>> {
+        let mut ready = Vec::new();
+        for entry in self.ring.completion() {
+            self.pending.remove(&entry.user_data());
+            match entry.result() {
+                res if res == -libc::ECANCELED => {}
+                res if res < 0 => return Err(io::Error::from_raw_os_error(-res)),
+                _ => ready.push(entry.user_data()),
+            }
+        }
+        Ok(ready)
+    }
+}
+
+/// The release of the running kernel, e.g. `6.8.0-45-generic`.
+fn kernel_release() -> io::Result<String> {
+    let mut uts = MaybeUninit::<libc::utsname>::uninit();
+    if unsafe { libc::uname(uts.as_mut_ptr()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let uts = unsafe { uts.assume_init() };
+    let release = unsafe { CStr::from_ptr(uts.release.as_ptr()) };
+    Ok(release.to_string_lossy().into_owned())
+}
+
+/// The major and minor version in a kernel release.
+fn parse_release(release: &str) -> Option<(u32, u32)> {
+    let mut parts = release.split(|c: char| !c.is_ascii_digit());
+    Some((parts.next()?.parse().ok()?, parts.next()?.parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_release;
+
+    #[test]
+    fn parses_kernel_releases() {
+        assert_eq!(parse_release("6.8.0-45-generic"), Some((6, 8)));
+        assert_eq!(parse_release("5.10.226"), Some((5, 10)));
+        assert_eq!(parse_release("4.19"), Some((4, 19)));
+        assert_eq!(parse_release("5"), None);
+        assert_eq!(parse_release("unknown"), None);
+    }
+}
@@ -11,8 +11,219 @@ pub mod darwin;
 #[cfg(target_os = "macos")]
 pub use darwin::*;
 
+#[cfg(target_os = "linux")]
+pub mod poll;
+
+#[cfg(target_os = "linux")]
+pub use poll::*;
+
+#[cfg(any(target_os = "freebsd", target_os = "netbsd", target_os = "openbsd"))]
+pub mod bsd;
+
+#[cfg(any(target_os = "freebsd", target_os = "netbsd", target_os = "openbsd"))]
+pub use bsd::*;
+
 #[cfg(target_os = "windows")]
 pub mod windows;
 
 #[cfg(target_os = "windows")]
 pub use windows::*;
+
+#[cfg(unix)]
+mod filter;
+
+/// Resolves once SIGINT is received if `enabled`, and never otherwise. The
+/// signal handler is only installed when enabled, since it stops Ctrl+C from
+/// terminating the process.
+#[cfg(unix)]
+pub(crate) async fn sigint(enabled: bool) {
+    if enabled && crate::runtime::ctrl_c().await.is_ok() {
+        return;
+    }
+    std::future::pending().await
+}
+
+/// Calls `close` when SIGINT is received, unless `cancellation_token` is
+/// cancelled or the returned guard is dropped first. `start` holds the guard
+/// while it runs, so that `close`, which usually owns a clone of the tracer,
+/// doesn't keep the tracer alive once `start` returned.
+#[cfg(unix)]
+#[must_use]
+pub(crate) fn close_on_sigint(
+    cancellation_token: tokio_util::sync::CancellationToken,
+    close: impl FnOnce() + Send + 'static,
+) -> tokio_util::sync::DropGuard {
+    let cancellation_token = cancellation_token.child_token();
+    let guard = cancellation_token.clone().drop_guard();
+    crate::runtime::spawn(async move {
+        tokio::select! {
+            _ = cancellation_token.cancelled() => {}
+            _ = sigint(true) => close(),
+        }
+    });
+    guard
+}
+
+/// The event types reported for each watched path, keyed by canonical path.
+/// Set by `watch_with_filter`, and to every event type by `watch`, and applied
+/// to the streams and callbacks of the `Kanshi` façades.
+#[cfg(unix)]
+#[derive(Clone, Default)]
+pub(crate) struct WatchFilters(
+    std::sync::Arc<
+        std::sync::RwLock<std::collections::HashMap<std::path::PathBuf, crate::EventTypeMask>>,
+    >,
+);
+
+#[cfg(unix)]
+impl WatchFilters {
+    /// Reports only the event types in `mask` below `path`.
+    pub(crate) fn set(&self, path: std::path::PathBuf, mask: crate::EventTypeMask) {
+        self.0.write().unwrap().insert(path, mask);
+    }
+
+    pub(crate) fn remove(&self, path: &std::path::Path) {
+        self.0.write().unwrap().remove(path);
+    }
+
+    /// Whether `event` is reported. Its type must be in the mask of one of the
+    /// watched paths its target is below. Events without a target, or whose
+    /// target is not below any watched path, e.g. the relative paths of
+    /// `PathResolutionMode::NameOnly`, are always reported.
+    pub(crate) fn allows(&self, event: &crate::FileSystemEvent) -> bool {
+        let Some(target) = event.target.as_ref() else {
+            return true;
+        };
+        let path = std::path::Path::new(&target.path);
+        let mut mask = None;
+        for (watched, x) in self.0.read().unwrap().iter() {
+            if path.starts_with(watched) {
+                mask = Some(mask.unwrap_or(crate::EventTypeMask::empty()) | *x);
+            }
+        }
+        mask.is_none_or(|x| x.matches(&event.event_type))
+    }
+
+    /// Drops the events of `stream` that `allows` rejects.
+    pub(crate) fn apply(
+        &self,
+        stream: std::pin::Pin<Box<dyn futures::Stream<Item = crate::KanshiStreamItem> + Send>>,
+    ) -> std::pin::Pin<Box<dyn futures::Stream<Item = crate::KanshiStreamItem> + Send>> {
+        use futures::StreamExt;
+
+        let filters = self.clone();
+        Box::pin(stream.filter(move |item| {
+            std::future::ready(match item {
+                crate::KanshiStreamItem::Event(event) => filters.allows(event),
+                _ => true,
+            })
+        }))
+    }
+
+    /// Wraps `callback` to skip the events that `allows` rejects.
+    pub(crate) fn wrap(
+        &self,
+        callback: impl Fn(crate::FileSystemEvent) + Send + Sync + 'static,
+    ) -> impl Fn(crate::FileSystemEvent) + Send + Sync + 'static {
+        let filters = self.clone();
+        move |event| {
+            if filters.allows(&event) {
+                callback(event);
+            }
+        }
+    }
+}
+
+/// Sends the error `start` failed with to every stream, which then ends, and
+/// returns `result` unchanged. `ListenerAlreadyStarted` is only returned, since
+/// the tracer started earlier keeps running.
+pub(crate) fn report_error(
+    sender: &tokio::sync::broadcast::Sender<crate::KanshiStreamItem>,
+    result: Result<(), crate::KanshiError>,
+) -> Result<(), crate::KanshiError> {
+    match &result {
+        Err(crate::KanshiError::ListenerAlreadyStarted) | Ok(_) => {}
+        // Fails only when nothing is subscribed.
+        Err(e) => {
+            let _ = sender.send(crate::KanshiStreamItem::Error(e.clone()));
+        }
+    }
+    result
+}
+
+/// Held by a tracer's `start` while it runs. Clones of a tracer share its
+/// file descriptors and streams, so a second event loop over them would only
+/// compete with the first for events; `start` on any clone of a running
+/// tracer fails with `ListenerAlreadyStarted` instead.
+pub(crate) struct RunningGuard(std::sync::Arc<std::sync::atomic::AtomicBool>);
+
+impl RunningGuard {
+    pub(crate) fn acquire(
+        running: &std::sync::Arc<std::sync::atomic::AtomicBool>,
+    ) -> Result<RunningGuard, crate::KanshiError> {
+        if running.swap(true, std::sync::atomic::Ordering::SeqCst) {
+            return Err(crate::KanshiError::ListenerAlreadyStarted);
+        }
+        Ok(RunningGuard(running.clone()))
+    }
+}
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        self.0.store(false, std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+/// Reads the `(st_dev, st_ino)` pair of `path` without following symlinks.
+#[cfg(unix)]
+pub(crate) fn device_inode(path: impl AsRef<std::path::Path>) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    std::fs::symlink_metadata(path)
+        .ok()
+        .map(|metadata| (metadata.dev(), metadata.ino()))
+}
+
+/// Canonicalises every path in `paths`, keeping paths that cannot be resolved
+/// as they are.
+#[cfg(unix)]
+pub(crate) fn canonicalize_all(
+    paths: std::collections::HashSet<std::path::PathBuf>,
+) -> std::collections::HashSet<std::path::PathBuf> {
+    paths
+        .into_iter()
+        .map(|path| path.canonicalize().unwrap_or(path))
+        .collect()
+}
+
+/// Checks that `dir` is an existing directory that can be listed, and returns
+/// its canonical path. Used by `try_watch` before anything is marked.
+#[cfg(unix)]
+pub(crate) fn validate_watch_dir(
+    dir: &std::path::Path,
+) -> Result<std::path::PathBuf, crate::KanshiError> {
+    use nix::unistd::{access, AccessFlags};
+
+    let path = dir
+        .canonicalize()
+        .map_err(|e| crate::KanshiError::from_io_at(e, dir))?;
+    if !path.is_dir() {
+        return Err(crate::KanshiError::InvalidParameter(format!(
+            "{:?} is not a directory",
+            dir
+        )));
+    }
+    access(&path, AccessFlags::R_OK | AccessFlags::X_OK)
+        .map_err(|e| crate::KanshiError::from_errno_at(e, &path))?;
+    Ok(path)
+}
+
+/// Creates `dir` and its missing parents if it does not exist yet. Used by
+/// `watch` when `KanshiOptions::create_watched_dirs` is set.
+#[cfg(unix)]
+pub(crate) fn create_watched_dir(dir: &std::path::Path) -> Result<(), crate::KanshiError> {
+    if !dir.exists() {
+        std::fs::create_dir_all(dir).map_err(|e| crate::KanshiError::from_io_at(e, dir))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,3 @@
+mod poller;
+
+pub use poller::PollTracer;
@@ -0,0 +1,429 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use async_stream::stream;
+use tokio::sync::broadcast::error::RecvError;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    platforms::{
+        canonicalize_all, close_on_sigint, create_watched_dir, device_inode, filter::PathFilter,
+        report_error, RunningGuard,
+    },
+    runtime,
+    sender::{EventCallbacks, EventSender},
+    FileSystemEvent, FileSystemEventType, FileSystemTarget, FileSystemTargetKind, KanshiError,
+    KanshiImpl, KanshiOptions, KanshiStreamItem,
+};
+
+/// Watches paths by `stat`ing everything below them every
+/// `KanshiOptions::poll_interval_ms` and comparing the size and modification
+/// time of each entry with the previous scan.
+///
+/// This works wherever the files can be read, including network filesystems
+/// and containers without `CAP_SYS_ADMIN`, but changes are only noticed once
+/// per interval. Several changes to an entry within one interval are reported
+/// as a single event, and renames are reported as a `Delete` and a `Create`.
+#[derive(Clone)]
+pub struct PollTracer {
+    sender: tokio::sync::broadcast::Sender<KanshiStreamItem>,
+    cancellation_token: CancellationToken,
+    paused: Arc<AtomicBool>,
+    // Set while `start` runs, shared with clones.
+    running: Arc<AtomicBool>,
+    callbacks: EventCallbacks,
+    sequence: Arc<AtomicU64>,
+    options: Arc<KanshiOptions>,
+    excluded: Arc<Mutex<HashSet<PathBuf>>>,
+    // `KanshiOptions::exclude_patterns` and `exclude_regexes`, compiled once
+    // in `new`.
+    exclude_filter: Arc<PathFilter>,
+    // Canonical paths of the directories passed to `watch`.
+    watched: Arc<Mutex<HashSet<PathBuf>>>,
+    // Canonical paths of the files passed to `watch`.
+    files: Arc<Mutex<HashSet<PathBuf>>>,
+    snapshot: Arc<Mutex<Snapshot>>,
+}
+
+/// The state of every watched entry as of the last scan.
+#[derive(Default)]
+struct Snapshot {
+    // The size and modification time of each entry.
+    entries: HashMap<PathBuf, (u64, SystemTime)>,
+    directories: HashSet<PathBuf>,
+}
+
+impl PollTracer {
+    /// The options this tracer was created with.
+    pub(crate) fn options(&self) -> &KanshiOptions {
+        &self.options
+    }
+
+    /// Records `dir` and everything below it within `KanshiOptions::depth_limit`
+    /// in `snapshot`. `dir` is `depth` levels below a watched directory.
+    /// Entries that disappear while scanning are skipped.
+    fn scan_dir(
+        &self,
+        snapshot: &mut Snapshot,
+        dir: &Path,
+        depth: usize,
+        excluded: &HashSet<PathBuf>,
+    ) {
+        let Ok(dir_items) = fs::read_dir(dir) else {
+            return;
+        };
+
+        for dir_item in dir_items.flatten() {
+            let path = dir_item.path();
+            // Symlinks are recorded, but never followed.
+            let Ok(metadata) = path.symlink_metadata() else {
+                continue;
+            };
+            if metadata.is_dir() {
+                if excluded.contains(&path) || self.exclude_filter.is_match_dir(&path) {
+                    continue;
+                }
+                snapshot.directories.insert(path.clone());
+                if depth < self.options.depth_limit().unwrap_or(usize::MAX) {
+                    self.scan_dir(snapshot, &path, depth + 1, excluded);
+                }
+            } else if self.exclude_filter.is_match(&path) {
+                continue;
+            }
+            snapshot.entries.insert(path, stamp(&metadata));
+        }
+    }
+
+    /// Scans every watched directory and file.
+    fn scan(&self) -> Snapshot {
+        let mut snapshot = Snapshot::default();
+        let excluded = self.excluded.lock().unwrap().clone();
+
+        for dir in self.watched.lock().unwrap().iter() {
+            self.scan_dir(&mut snapshot, dir, 0, &excluded);
+        }
+        for file in self.files.lock().unwrap().iter() {
+            if let Ok(metadata) = file.symlink_metadata() {
+                snapshot.entries.insert(file.clone(), stamp(&metadata));
+            }
+        }
+        snapshot
+    }
+
+    /// Replaces the stored snapshot with `current` and returns the events for
+    /// the differences. An entry that changed between file and directory is
+    /// reported as a `Delete` followed by a `Create`. Deletes come first,
+    /// deepest entries first, followed by creates and modifications,
+    /// shallowest entries first.
+    fn diff(&self, current: Snapshot) -> Vec<FileSystemEvent> {
+        let previous = std::mem::replace(&mut *self.snapshot.lock().unwrap(), current);
+        let current = self.snapshot.lock().unwrap();
+
+        let kind = |snapshot: &Snapshot, path: &Path| {
+            if snapshot.directories.contains(path) {
+                FileSystemTargetKind::Directory
+            } else {
+                FileSystemTargetKind::File
+            }
+        };
+
+        let replaced = |path: &Path| {
+            previous.directories.contains(path) != current.directories.contains(path)
+        };
+
+        let mut deleted: Vec<&PathBuf> = previous
+            .entries
+            .keys()
+            .filter(|x| !current.entries.contains_key(*x) || replaced(x.as_path()))
+            .collect();
+        deleted.sort_by(|a, b| b.cmp(a));
+
+        let mut changed: Vec<(&PathBuf, FileSystemEventType)> = current
+            .entries
+            .iter()
+            .filter_map(|(path, stamp)| match previous.entries.get(path) {
+                None => Some((path, FileSystemEventType::Create)),
+                Some(_) if replaced(path) => Some((path, FileSystemEventType::Create)),
+                // A directory's modification time changes with its entries,
+                // which are reported on their own.
+                Some(x) if x != stamp && !current.directories.contains(path) => {
+                    Some((path, FileSystemEventType::Modify))
+                }
+                _ => None,
+            })
+            .collect();
+        changed.sort_by(|a, b| a.0.cmp(b.0));
+
+        let mut events = Vec::new();
+        for path in deleted {
+            events.push(event(
+                FileSystemEventType::Delete,
+                path,
+                kind(&previous, path),
+            ));
+        }
+        for (path, event_type) in changed {
+            events.push(event(event_type, path, kind(&current, path)));
+        }
+        events
+    }
+
+    /// The body of `start`, whose error `start` forwards to the streams.
+    async fn run(&self) -> Result<(), KanshiError> {
+        let _running = RunningGuard::acquire(&self.running)?;
+        let cancel_token = self.cancellation_token.clone();
+        let mut sender = EventSender::new(
+            self.sender.clone(),
+            self.options.reconnect_timeout,
+            self.options
+                .reconnect_buffer_size
+                .min(self.options.channel_capacity),
+            self.options.grace_period,
+            self.options.debounce_ms.map(Duration::from_millis),
+            self.options.coalesce_window_ms.map(Duration::from_millis),
+            self.options.max_events_per_second,
+        )
+        .with_metadata(self.options.include_metadata)
+        .with_hashes(self.options.compute_hash_on_modify)
+        .with_hard_links(self.options.detect_hard_links)
+        .with_symlink_lookup(true)
+        .with_timestamps(self.options.approximate_timestamp)
+        .with_pause(self.paused.clone(), self.options.buffer_while_paused)
+        .with_callbacks(self.callbacks.clone())
+        .with_sequence(self.sequence.clone());
+
+        let _sigint = self.options.handle_sigint.then(|| {
+            let tracer = self.clone();
+            close_on_sigint(cancel_token.clone(), move || {
+                if let Err(e) = tracer.close() {
+                    (tracer.options.on_error)(e);
+                }
+            })
+        });
+
+        let interval = Duration::from_millis(self.options.poll_interval_ms);
+        // The sender is polled more often than the tree is scanned, so that
+        // held events are released on time.
+        let mut ticker = runtime::Interval::new(interval.min(Duration::from_millis(16)));
+        let mut scanned_at = Instant::now();
+
+        loop {
+            tokio::select! {
+                _ = cancel_token.cancelled() => break,
+                _ = ticker.tick() => {}
+            }
+            sender.poll()?;
+
+            if scanned_at.elapsed() < interval {
+                continue;
+            }
+            let snapshot = runtime::block_in_place(|| self.scan());
+            scanned_at = Instant::now();
+
+            for event in self.diff(snapshot) {
+                sender.send(event)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl KanshiImpl<KanshiOptions> for PollTracer {
+    fn new(opts: KanshiOptions) -> Result<PollTracer, KanshiError> {
+        let exclude_filter = PathFilter::new(
+            &opts.exclude_patterns,
+            &opts.exclude_regexes,
+            opts.use_gitignore,
+        )?;
+        let (tx, _rx) = tokio::sync::broadcast::channel(opts.channel_capacity.max(1));
+
+        Ok(PollTracer {
+            sender: tx,
+            cancellation_token: CancellationToken::new(),
+            paused: Arc::new(AtomicBool::new(false)),
+            running: Arc::new(AtomicBool::new(false)),
+            callbacks: EventCallbacks::default(),
+            sequence: Arc::new(AtomicU64::new(opts.initial_sequence)),
+            options: Arc::new(opts),
+            excluded: Arc::new(Mutex::new(HashSet::new())),
+            exclude_filter: Arc::new(exclude_filter),
+            watched: Arc::new(Mutex::new(HashSet::new())),
+            files: Arc::new(Mutex::new(HashSet::new())),
+            snapshot: Arc::new(Mutex::new(Snapshot::default())),
+        })
+    }
+
+    async fn watch(&self, dir: impl AsRef<Path> + Send) -> Result<(), KanshiError> {
+        self.watch_with_exclude_dirs(dir, HashSet::new()).await
+    }
+
+    async fn watch_with_exclude_dirs(
+        &self,
+        dir: impl AsRef<Path> + Send,
+        exclude: HashSet<PathBuf>,
+    ) -> Result<(), KanshiError> {
+        let dir = dir.as_ref();
+        if self.cancellation_token.is_cancelled() {
+            return Err(KanshiError::StreamClosed);
+        }
+
+        if self.options.create_watched_dirs {
+            create_watched_dir(dir)?;
+        }
+
+        let path = dir.canonicalize()?;
+        let metadata = path.symlink_metadata()?;
+        if metadata.is_dir() {
+            self.exclude_filter.add_ignore_files(&path);
+        }
+
+        let excluded = {
+            let mut excluded = self.excluded.lock().unwrap();
+            excluded.extend(canonicalize_all(exclude));
+            excluded.clone()
+        };
+
+        // Lock order is `watched`, `files`, then `snapshot`, as in `unwatch`.
+        let mut watched = self.watched.lock().unwrap();
+        let mut files = self.files.lock().unwrap();
+        // Changes are reported relative to the state at the time of `watch`.
+        let mut snapshot = self.snapshot.lock().unwrap();
+        if metadata.is_dir() {
+            self.scan_dir(&mut snapshot, &path, 0, &excluded);
+            watched.insert(path);
+        } else {
+            snapshot.entries.insert(path.clone(), stamp(&metadata));
+            files.insert(path);
+        }
+        Ok(())
+    }
+
+    async fn unwatch(&self, dir: impl AsRef<Path> + Send) -> Result<(), KanshiError> {
+        let path = dir
+            .as_ref()
+            .canonicalize()
+            .unwrap_or_else(|_| dir.as_ref().to_path_buf());
+
+        let mut watched = self.watched.lock().unwrap();
+        let mut files = self.files.lock().unwrap();
+        if !files.remove(&path) && !watched.remove(&path) {
+            return Err(KanshiError::PathNotWatched(dir.as_ref().to_path_buf()));
+        }
+
+        // Forget entries nothing watches anymore, so they are not reported as
+        // deleted by the next scan.
+        let is_covered = |x: &Path| {
+            files.contains(x) || watched.iter().any(|root| x.starts_with(root) && x != root)
+        };
+        let mut snapshot = self.snapshot.lock().unwrap();
+        snapshot.entries.retain(|x, _| !x.starts_with(&path) || is_covered(x));
+        snapshot.directories.retain(|x| !x.starts_with(&path) || is_covered(x));
+        Ok(())
+    }
+
+    fn get_watched_paths(&self) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = self.watched.lock().unwrap().iter().cloned().collect();
+        paths.extend(self.files.lock().unwrap().iter().cloned());
+        paths.sort();
+        paths
+    }
+
+    fn get_events_stream(&self) -> Pin<Box<dyn futures::Stream<Item = KanshiStreamItem> + Send>> {
+        let mut listener = self.sender.subscribe();
+        let cancel_token = self.cancellation_token.clone();
+
+        Box::pin(stream! {
+            loop {
+                tokio::select! {
+                    _ = cancel_token.cancelled() => {
+                        break;
+                    }
+                    val = listener.recv() => {
+                        match val {
+                            Ok(x) => {
+                                let failed = x.is_error();
+                                yield x;
+                                if failed {
+                                    break;
+                                }
+                            }
+                            Err(e) => match e {
+                                RecvError::Closed => break,
+                                _ => ()
+                            }
+                        }
+                    }
+                }
+            }
+        })
+    }
+
+    async fn start(&self) -> Result<(), KanshiError> {
+        let result = self.run().await;
+        report_error(&self.sender, result)
+    }
+
+    fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+    }
+
+    fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    fn on_event<F>(&self, callback: F)
+    where
+        F: Fn(FileSystemEvent) + Send + Sync + 'static,
+    {
+        self.callbacks.write().unwrap().push(Box::new(callback));
+    }
+
+    fn close(&self) -> Result<(), KanshiError> {
+        self.cancellation_token.cancel();
+        Ok(())
+    }
+}
+
+/// The size and modification time recorded for an entry.
+fn stamp(metadata: &fs::Metadata) -> (u64, SystemTime) {
+    (
+        metadata.len(),
+        metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+    )
+}
+
+fn event(
+    event_type: FileSystemEventType,
+    path: &Path,
+    kind: FileSystemTargetKind,
+) -> FileSystemEvent {
+    FileSystemEvent {
+        target: Some(FileSystemTarget {
+            kind,
+            device_inode: match event_type {
+                FileSystemEventType::Delete => None,
+                _ => device_inode(path),
+            },
+            path: path.into(),
+            metadata: None,
+        }),
+        event_type,
+        pid: None,
+        timestamp: None,
+        sequence: 0,
+    }
+}
@@ -1,6 +1,6 @@
 use std::pin::Pin;
 
-use crate::{FileSystemEvent, KanshiError, KanshiImpl};
+use crate::{KanshiError, KanshiImpl, KanshiStreamItem};
 
 use super::KanshiOptions;
 
@@ -21,7 +21,7 @@ impl KanshiImpl<KanshiOptions> for ReadDirectoryChangesTracer {
       
   }
 
-  fn get_events_stream(&self) -> Pin<Box<dyn futures::Stream<Item = FileSystemEvent> + Send>> {
+  fn get_events_stream(&self) -> Pin<Box<dyn futures::Stream<Item = KanshiStreamItem> + Send>> {
       
   }
 
@@ -29,7 +29,7 @@ impl KanshiImpl<KanshiOptions> for ReadDirectoryChangesTracer {
       
   }
 
-  fn close(&self) -> bool {
+  fn close(&self) -> Result<(), KanshiError> {
       
   }
 }
\ No newline at end of file
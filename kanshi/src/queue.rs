@@ -0,0 +1,217 @@
+use std::{
+    collections::{HashMap, HashSet},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures::StreamExt;
+use tokio::sync::mpsc;
+use tokio_util::sync::{CancellationToken, DropGuard};
+use uuid::Uuid;
+
+use crate::{runtime, FileSystemEvent, KanshiError, KanshiStreamItem};
+
+type AckSet = Arc<Mutex<HashSet<Uuid>>>;
+
+/// An event delivered by an [`EventQueue`].
+///
+/// The event will be delivered again if [`QueuedEvent::ack`] is not called
+/// before the queue's redelivery timeout elapses. Redelivered events keep the
+/// same `id`, so consumers can use it to recognise duplicates.
+#[derive(Clone, Debug)]
+pub struct QueuedEvent {
+    pub id: Uuid,
+    pub event: FileSystemEvent,
+    acked: AckSet,
+}
+
+impl QueuedEvent {
+    /// Marks this event as processed so it is not delivered again.
+    pub fn ack(&self) {
+        self.acked.lock().unwrap().insert(self.id);
+    }
+}
+
+/// A queue over a Kanshi event stream where every event must be acknowledged
+/// by the consumer.
+///
+/// Events that are not acknowledged within `redelivery_timeout` are delivered
+/// again until they are. Every event is kept until it is acknowledged, so
+/// memory use grows with the number of outstanding events. Once the
+/// underlying stream ends, the queue keeps redelivering outstanding events and
+/// closes when all of them are acknowledged. If the stream ended because the
+/// tracer failed, the error is kept for [`EventQueue::error`].
+pub struct EventQueue {
+    receiver: mpsc::Receiver<QueuedEvent>,
+    error: Arc<Mutex<Option<KanshiError>>>,
+    _guard: DropGuard,
+}
+
+impl EventQueue {
+    /// Wraps a stream returned by `KanshiImpl::get_events_stream`. At most
+    /// `capacity` events are buffered for the consumer; the stream is not
+    /// polled while the buffer is full. `redelivery_timeout` must not be zero.
+    pub fn new(
+        stream: Pin<Box<dyn futures::Stream<Item = KanshiStreamItem> + Send>>,
+        capacity: usize,
+        redelivery_timeout: Duration,
+    ) -> Result<EventQueue, KanshiError> {
+        if redelivery_timeout.is_zero() {
+            return Err(KanshiError::InvalidParameter(
+                "redelivery_timeout must be greater than zero.".to_owned(),
+            ));
+        }
+
+        let (sender, receiver) = mpsc::channel(capacity.max(1));
+        let error = Arc::new(Mutex::new(None));
+        let cancellation_token = CancellationToken::new();
+
+        runtime::spawn(deliver(
+            stream,
+            sender,
+            redelivery_timeout,
+            error.clone(),
+            cancellation_token.clone(),
+        ));
+
+        Ok(EventQueue {
+            receiver,
+            error,
+            _guard: cancellation_token.drop_guard(),
+        })
+    }
+
+    /// Receives the next event, or `None` once the underlying stream has ended
+    /// and every delivered event has been acknowledged.
+    pub async fn recv(&mut self) -> Option<QueuedEvent> {
+        self.receiver.recv().await
+    }
+
+    /// The error the underlying stream ended with, if the tracer failed.
+    pub fn error(&self) -> Option<KanshiError> {
+        self.error.lock().unwrap().clone()
+    }
+}
+
+async fn deliver(
+    mut stream: Pin<Box<dyn futures::Stream<Item = KanshiStreamItem> + Send>>,
+    sender: mpsc::Sender<QueuedEvent>,
+    redelivery_timeout: Duration,
+    error: Arc<Mutex<Option<KanshiError>>>,
+    cancellation_token: CancellationToken,
+) {
+    let acked: AckSet = Arc::new(Mutex::new(HashSet::new()));
+    let mut pending: HashMap<Uuid, (Instant, FileSystemEvent)> = HashMap::new();
+    let mut stream_ended = false;
+    let mut ticker = runtime::Interval::new(redelivery_timeout);
+
+    loop {
+        tokio::select! {
+            _ = cancellation_token.cancelled() => {
+                break;
+            }
+            next = stream.next(), if !stream_ended => {
+                let event = match next {
+                    Some(KanshiStreamItem::Event(event)) => event,
+                    Some(KanshiStreamItem::Error(e)) => {
+                        *error.lock().unwrap() = Some(e);
+                        stream_ended = true;
+                        continue;
+                    }
+                    // Queued as its event, which allows the access.
+                    Some(KanshiStreamItem::PermissionRequest(request)) => request.event.clone(),
+                    None => {
+                        stream_ended = true;
+                        continue;
+                    }
+                };
+
+                let queued = QueuedEvent {
+                    id: Uuid::new_v4(),
+                    event: event.clone(),
+                    acked: acked.clone(),
+                };
+
+                pending.insert(queued.id, (Instant::now(), event));
+                if sender.send(queued).await.is_err() {
+                    break;
+                }
+            }
+            _ = ticker.tick() => {
+                // Forget everything acknowledged since the last tick.
+                for id in acked.lock().unwrap().drain() {
+                    pending.remove(&id);
+                }
+
+                if stream_ended && pending.is_empty() {
+                    break;
+                }
+
+                let now = Instant::now();
+                for (id, (sent_at, event)) in pending.iter_mut() {
+                    if now.duration_since(*sent_at) < redelivery_timeout {
+                        continue;
+                    }
+
+                    *sent_at = now;
+                    let queued = QueuedEvent {
+                        id: *id,
+                        event: event.clone(),
+                        acked: acked.clone(),
+                    };
+
+                    if sender.send(queued).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{FileSystemEvent, FileSystemEventType, KanshiError, KanshiStreamItem};
+
+    use super::EventQueue;
+
+    #[tokio::test]
+    async fn redelivers_until_acked() {
+        let items = vec![
+            KanshiStreamItem::Event(FileSystemEvent {
+                event_type: FileSystemEventType::Create,
+                target: None,
+                pid: None,
+                timestamp: None,
+                sequence: 0,
+            }),
+            KanshiStreamItem::Error(KanshiError::StreamClosed),
+        ];
+        let mut queue = EventQueue::new(
+            Box::pin(futures::stream::iter(items)),
+            8,
+            Duration::from_millis(20),
+        )
+        .unwrap();
+
+        let first = queue.recv().await.unwrap();
+        let second = queue.recv().await.unwrap();
+        assert_eq!(first.id, second.id);
+
+        // The queue closes once the only event has been acknowledged.
+        second.ack();
+        while let Some(event) = queue.recv().await {
+            assert_eq!(event.id, first.id);
+        }
+        assert!(matches!(queue.error(), Some(KanshiError::StreamClosed)));
+    }
+
+    #[test]
+    fn rejects_a_zero_redelivery_timeout() {
+        let queue = EventQueue::new(Box::pin(futures::stream::empty()), 8, Duration::ZERO);
+        assert!(matches!(queue, Err(KanshiError::InvalidParameter(_))));
+    }
+}
@@ -0,0 +1,412 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    fs,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+use async_stream::stream;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    platforms::RunningGuard,
+    runtime,
+    sender::{notify, EventCallbacks},
+    FileSystemEvent, KanshiError, KanshiImpl, KanshiStreamItem,
+};
+
+/// A line of an event log read by [`EventLogReplayer`]: a serialized
+/// [`FileSystemEvent`], optionally along with when it was received.
+///
+/// A plain serialized `FileSystemEvent` is a valid line as well, and is
+/// replayed without any delay.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LoggedEvent {
+    /// When the event was received, in milliseconds since the Unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp_ms: Option<u64>,
+    #[serde(flatten)]
+    pub event: FileSystemEvent,
+}
+
+impl LoggedEvent {
+    /// Wraps `event`, timestamped with the current time.
+    pub fn now(event: FileSystemEvent) -> LoggedEvent {
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+
+        LoggedEvent {
+            timestamp_ms: Some(timestamp.as_millis() as u64),
+            event,
+        }
+    }
+}
+
+/// Options for [`EventLogReplayer`].
+pub struct ReplayOptions {
+    /// The log to replay, with one [`LoggedEvent`] serialized as JSON per line.
+    /// Empty lines are skipped.
+    pub log_path: PathBuf,
+    /// How fast to replay events relative to their timestamps: `1.0` keeps the
+    /// original timing, `2.0` replays twice as fast, and `f64::INFINITY` sends
+    /// every event without waiting. Must be greater than zero. Defaults to
+    /// `1.0`.
+    pub speed_multiplier: f64,
+}
+
+impl ReplayOptions {
+    /// Options replaying `log_path` in real time.
+    pub fn new(log_path: impl Into<PathBuf>) -> ReplayOptions {
+        ReplayOptions {
+            log_path: log_path.into(),
+            speed_multiplier: 1.0,
+        }
+    }
+
+    /// Sets [`ReplayOptions::speed_multiplier`].
+    pub fn with_speed_multiplier(mut self, speed_multiplier: f64) -> ReplayOptions {
+        self.speed_multiplier = speed_multiplier;
+        self
+    }
+
+    /// Checks the options for invalid values and returns them.
+    pub fn build(self) -> Result<ReplayOptions, KanshiError> {
+        if self.speed_multiplier.is_nan() || self.speed_multiplier <= 0.0 {
+            return Err(KanshiError::InvalidParameter(
+                "speed_multiplier must be greater than zero.".to_owned(),
+            ));
+        }
+        Ok(self)
+    }
+}
+
+/// Replays events recorded in an event log through `get_events_stream`, e.g.
+/// to reproduce a sequence of events captured in production in a test.
+///
+/// The log is read and parsed in `new`. `start` then sends its events in
+/// order, keeping the time between them as recorded, scaled by
+/// [`ReplayOptions::speed_multiplier`], and waits for `close` like any other
+/// tracer once all events were sent. Events are only replayed once, even if
+/// `start` is called again after it returned, and calling it on a clone while
+/// it runs fails with `ListenerAlreadyStarted`. While paused, the remaining
+/// events are held until `resume`. `watch` always succeeds and does not filter
+/// which events are replayed.
+///
+/// # Examples
+///
+/// ```
+/// use futures::StreamExt;
+/// use kanshi::{
+///     EventLogReplayer, FileSystemEventType, KanshiError, KanshiImpl, ReplayOptions,
+/// };
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), KanshiError> {
+///     let dir = tempfile::tempdir()?;
+///     let log = dir.path().join("events.ndjson");
+///     std::fs::write(&log, r#"{"event_type":"Create","target":null,"pid":null}"#)?;
+///
+///     let replayer = EventLogReplayer::new(
+///         ReplayOptions::new(log).with_speed_multiplier(f64::INFINITY),
+///     )?;
+///     let mut stream = replayer.get_events_stream();
+///     let tracer = replayer.clone();
+///     tokio::spawn(async move { tracer.start().await });
+///
+///     let event = stream.next().await.unwrap().into_result()?;
+///     assert_eq!(event.event_type, FileSystemEventType::Create);
+///     replayer.close().unwrap();
+///     Ok(())
+/// }
+/// ```
+#[derive(Clone)]
+pub struct EventLogReplayer {
+    sender: tokio::sync::broadcast::Sender<KanshiStreamItem>,
+    cancellation_token: CancellationToken,
+    // Events not replayed yet, each with the scaled delay to wait before
+    // sending it.
+    events: Arc<Mutex<VecDeque<(Duration, FileSystemEvent)>>>,
+    paused: Arc<AtomicBool>,
+    running: Arc<AtomicBool>,
+    callbacks: EventCallbacks,
+    watched: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+/// Parses `log` and returns its events, each with the time since the previous
+/// timestamped event divided by `speed_multiplier`. Events without a
+/// timestamp, or with one earlier than the previous, are not delayed.
+fn read_log(
+    log: &str,
+    log_path: &Path,
+    speed_multiplier: f64,
+) -> Result<VecDeque<(Duration, FileSystemEvent)>, KanshiError> {
+    let mut events = VecDeque::new();
+    let mut previous: Option<u64> = None;
+
+    for (idx, line) in log.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let logged: LoggedEvent = serde_json::from_str(line).map_err(|e| {
+            KanshiError::InvalidParameter(format!("{:?} line {}: {}", log_path, idx + 1, e))
+        })?;
+
+        let delay = match (previous, logged.timestamp_ms) {
+            (Some(previous), Some(timestamp)) => {
+                let elapsed = Duration::from_millis(timestamp.saturating_sub(previous));
+                Duration::from_secs_f64(elapsed.as_secs_f64() / speed_multiplier)
+            }
+            _ => Duration::ZERO,
+        };
+        if let Some(timestamp) = logged.timestamp_ms {
+            previous = Some(timestamp);
+        }
+        events.push_back((delay, logged.event));
+    }
+    Ok(events)
+}
+
+impl KanshiImpl<ReplayOptions> for EventLogReplayer {
+    fn new(opts: ReplayOptions) -> Result<EventLogReplayer, KanshiError> {
+        let opts = opts.build()?;
+        let log = fs::read_to_string(&opts.log_path)
+            .map_err(|e| KanshiError::from_io_at(e, &opts.log_path))?;
+        let events = read_log(&log, &opts.log_path, opts.speed_multiplier)?;
+        // Large enough that no stream can lag behind and lose events.
+        let (tx, _rx) = tokio::sync::broadcast::channel(events.len().max(1));
+
+        Ok(EventLogReplayer {
+            sender: tx,
+            cancellation_token: CancellationToken::new(),
+            events: Arc::new(Mutex::new(events)),
+            paused: Arc::new(AtomicBool::new(false)),
+            running: Arc::new(AtomicBool::new(false)),
+            callbacks: EventCallbacks::default(),
+            watched: Arc::new(Mutex::new(HashSet::new())),
+        })
+    }
+
+    async fn watch(&self, dir: impl AsRef<Path> + Send) -> Result<(), KanshiError> {
+        self.watch_with_exclude_dirs(dir, HashSet::new()).await
+    }
+
+    async fn watch_with_exclude_dirs(
+        &self,
+        dir: impl AsRef<Path> + Send,
+        _exclude: HashSet<PathBuf>,
+    ) -> Result<(), KanshiError> {
+        self.watched
+            .lock()
+            .unwrap()
+            .insert(dir.as_ref().to_path_buf());
+        Ok(())
+    }
+
+    async fn unwatch(&self, dir: impl AsRef<Path> + Send) -> Result<(), KanshiError> {
+        if !self.watched.lock().unwrap().remove(dir.as_ref()) {
+            return Err(KanshiError::PathNotWatched(dir.as_ref().to_path_buf()));
+        }
+        Ok(())
+    }
+
+    fn get_watched_paths(&self) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = self.watched.lock().unwrap().iter().cloned().collect();
+        paths.sort();
+        paths
+    }
+
+    fn get_events_stream(&self) -> Pin<Box<dyn futures::Stream<Item = KanshiStreamItem> + Send>> {
+        let mut listener = self.sender.subscribe();
+        let cancel_token = self.cancellation_token.clone();
+
+        Box::pin(stream! {
+            loop {
+                tokio::select! {
+                    _ = cancel_token.cancelled() => {
+                        break;
+                    }
+                    val = listener.recv() => {
+                        match val {
+                            Ok(x) => {
+                                let failed = x.is_error();
+                                yield x;
+                                if failed {
+                                    break;
+                                }
+                            }
+                            Err(e) => match e {
+                                RecvError::Closed => break,
+                                _ => ()
+                            }
+                        }
+                    }
+                }
+            }
+        })
+    }
+
+    async fn start(&self) -> Result<(), KanshiError> {
+        let _running = RunningGuard::acquire(&self.running)?;
+        loop {
+            let next = self.events.lock().unwrap().pop_front();
+            let Some((delay, event)) = next else {
+                break;
+            };
+
+            tokio::select! {
+                _ = self.cancellation_token.cancelled() => return Ok(()),
+                _ = runtime::sleep(delay) => {}
+            }
+            // The remaining events are held while paused.
+            while self.is_paused() {
+                tokio::select! {
+                    _ = self.cancellation_token.cancelled() => return Ok(()),
+                    _ = runtime::sleep(Duration::from_millis(10)) => {}
+                }
+            }
+            notify(&self.callbacks, &event);
+            let _ = self.sender.send(KanshiStreamItem::Event(event));
+        }
+
+        self.cancellation_token.cancelled().await;
+        Ok(())
+    }
+
+    fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+    }
+
+    fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    fn on_event<F>(&self, callback: F)
+    where
+        F: Fn(FileSystemEvent) + Send + Sync + 'static,
+    {
+        self.callbacks.write().unwrap().push(Box::new(callback));
+    }
+
+    fn close(&self) -> Result<(), KanshiError> {
+        self.cancellation_token.cancel();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, path::Path, time::Duration};
+
+    use futures::StreamExt;
+
+    use crate::{
+        FileSystemEvent, FileSystemEventType, FileSystemTarget, FileSystemTargetKind, KanshiImpl,
+    };
+
+    use super::{read_log, EventLogReplayer, LoggedEvent, ReplayOptions};
+
+    fn event(event_type: FileSystemEventType, path: &str) -> FileSystemEvent {
+        FileSystemEvent {
+            event_type,
+            target: Some(FileSystemTarget {
+                kind: FileSystemTargetKind::File,
+                path: path.into(),
+                device_inode: None,
+                metadata: None,
+            }),
+            pid: None,
+            timestamp: None,
+            sequence: 0,
+        }
+    }
+
+    #[test]
+    fn delays_follow_timestamps() {
+        let lines = [
+            LoggedEvent {
+                timestamp_ms: Some(1000),
+                event: event(FileSystemEventType::Create, "/a"),
+            },
+            LoggedEvent {
+                timestamp_ms: None,
+                event: event(FileSystemEventType::Modify, "/a"),
+            },
+            LoggedEvent {
+                timestamp_ms: Some(3000),
+                event: event(FileSystemEventType::Delete, "/a"),
+            },
+        ];
+        let log = lines
+            .iter()
+            .map(|x| serde_json::to_string(x).unwrap())
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let delays: Vec<Duration> = read_log(&log, Path::new("log"), 4.0)
+            .unwrap()
+            .into_iter()
+            .map(|(delay, _)| delay)
+            .collect();
+        assert_eq!(
+            delays,
+            [Duration::ZERO, Duration::ZERO, Duration::from_millis(500)]
+        );
+
+        assert!(read_log("{", Path::new("log"), 1.0).is_err());
+        assert!(ReplayOptions::new("log")
+            .with_speed_multiplier(0.0)
+            .build()
+            .is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn replays_logged_events_in_order() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let mut non_utf8 = event(FileSystemEventType::Create, "");
+        non_utf8.target.as_mut().unwrap().path = OsStr::from_bytes(b"/tmp/\xff").into();
+        let events = [
+            non_utf8,
+            event(FileSystemEventType::Modify, "/tmp/a"),
+            event(FileSystemEventType::Delete, "/tmp/a"),
+        ];
+
+        let mut log = tempfile::NamedTempFile::new().unwrap();
+        for event in events.iter() {
+            let logged = LoggedEvent::now(event.clone());
+            writeln!(log, "{}", serde_json::to_string(&logged).unwrap()).unwrap();
+        }
+
+        let replayer = EventLogReplayer::new(
+            ReplayOptions::new(log.path()).with_speed_multiplier(f64::INFINITY),
+        )
+        .unwrap();
+        let mut stream = replayer.get_events_stream();
+        let tracer = replayer.clone();
+        tokio::spawn(async move { tracer.start().await });
+
+        let replayed: Vec<FileSystemEvent> = stream
+            .by_ref()
+            .take(events.len())
+            .filter_map(|x| futures::future::ready(x.into_event()))
+            .collect()
+            .await;
+        assert_eq!(replayed, events);
+
+        replayer.close().unwrap();
+        assert!(stream.next().await.is_none());
+    }
+}
@@ -0,0 +1,154 @@
+//! The parts of the async runtime the tracers depend on, implemented with
+//! tokio by default, or with async-std when only the `runtime-async-std`
+//! feature is enabled.
+//!
+//! The broadcast channel and `CancellationToken` come from `tokio::sync` and
+//! `tokio_util`, which work on any executor, so they are used with both.
+
+use std::time::{Duration, Instant};
+
+#[cfg(not(any(feature = "runtime-tokio", feature = "runtime-async-std")))]
+compile_error!("Either the `runtime-tokio` or the `runtime-async-std` feature must be enabled.");
+
+pub(crate) use imp::*;
+
+#[cfg(feature = "runtime-tokio")]
+mod imp {
+    use std::{future::Future, io, time::Duration, time::Instant};
+
+    /// Runs `future` in the background.
+    pub(crate) fn spawn(future: impl Future<Output = ()> + Send + 'static) {
+        tokio::task::spawn(future);
+    }
+
+    /// Runs `f` on a thread where blocking is allowed and waits for its result.
+    pub(crate) async fn spawn_blocking<T: Send + 'static>(
+        f: impl FnOnce() -> T + Send + 'static,
+    ) -> io::Result<T> {
+        tokio::task::spawn_blocking(f)
+            .await
+            .map_err(io::Error::other)
+    }
+
+    /// Runs `f`, which blocks for a short while, without holding up the other
+    /// tasks on this thread.
+    pub(crate) fn block_in_place<T>(f: impl FnOnce() -> T) -> T {
+        tokio::task::block_in_place(f)
+    }
+
+    pub(crate) async fn sleep(duration: Duration) {
+        tokio::time::sleep(duration).await
+    }
+
+    pub(crate) async fn sleep_until(deadline: Instant) {
+        tokio::time::sleep_until(deadline.into()).await
+    }
+
+    /// Waits up to `timeout` for `fd` to become readable, without blocking
+    /// this thread.
+    #[cfg(target_os = "linux")]
+    pub(crate) async fn readable(
+        fd: std::os::fd::BorrowedFd<'_>,
+        timeout: Duration,
+    ) -> io::Result<()> {
+        let fd = tokio::io::unix::AsyncFd::with_interest(fd, tokio::io::Interest::READABLE)?;
+        match tokio::time::timeout(timeout, fd.readable()).await {
+            Ok(guard) => guard.map(|_| ()),
+            Err(_) => Ok(()),
+        }
+    }
+
+    /// Resolves when SIGINT is received.
+    pub(crate) async fn ctrl_c() -> io::Result<()> {
+        tokio::signal::ctrl_c().await
+    }
+}
+
+#[cfg(all(feature = "runtime-async-std", not(feature = "runtime-tokio")))]
+mod imp {
+    use std::{future::Future, io, time::Duration, time::Instant};
+
+    /// Runs `future` in the background.
+    pub(crate) fn spawn(future: impl Future<Output = ()> + Send + 'static) {
+        async_std::task::spawn(future);
+    }
+
+    /// Runs `f` on a thread where blocking is allowed and waits for its result.
+    pub(crate) async fn spawn_blocking<T: Send + 'static>(
+        f: impl FnOnce() -> T + Send + 'static,
+    ) -> io::Result<T> {
+        Ok(async_std::task::spawn_blocking(f).await)
+    }
+
+    /// Runs `f`. async-std can't move the other tasks off this thread, which
+    /// is why everything passed here gives up after a few milliseconds.
+    pub(crate) fn block_in_place<T>(f: impl FnOnce() -> T) -> T {
+        f()
+    }
+
+    pub(crate) async fn sleep(duration: Duration) {
+        async_std::task::sleep(duration).await
+    }
+
+    pub(crate) async fn sleep_until(deadline: Instant) {
+        async_std::task::sleep(deadline.saturating_duration_since(Instant::now())).await
+    }
+
+    /// Waits up to `timeout` for `fd` to become readable. async-std has no way
+    /// to register a file descriptor, so this polls it on the blocking thread
+    /// pool.
+    #[cfg(target_os = "linux")]
+    pub(crate) async fn readable(
+        fd: std::os::fd::BorrowedFd<'_>,
+        timeout: Duration,
+    ) -> io::Result<()> {
+        use std::os::fd::AsRawFd;
+
+        let fd = fd.as_raw_fd();
+        let timeout = timeout.as_millis().try_into().unwrap_or(libc::c_int::MAX);
+        // A cancelled wait keeps polling until `timeout` runs out. If `fd` is
+        // closed in the meantime, `poll` just reports `POLLNVAL`.
+        spawn_blocking(move || {
+            let mut pollfd = libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            match unsafe { libc::poll(&mut pollfd, 1, timeout) } {
+                -1 => Err(io::Error::last_os_error()),
+                _ => Ok(()),
+            }
+        })
+        .await?
+    }
+
+    /// async-std can't listen for signals, so this fails immediately.
+    pub(crate) async fn ctrl_c() -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "SIGINT can't be handled with async-std",
+        ))
+    }
+}
+
+/// Ticks every `period`, starting immediately. A late tick delays the ones
+/// after it instead of being made up for.
+pub(crate) struct Interval {
+    period: Duration,
+    next: Instant,
+}
+
+impl Interval {
+    pub(crate) fn new(period: Duration) -> Interval {
+        Interval {
+            period,
+            next: Instant::now(),
+        }
+    }
+
+    /// Waits for the next tick. Cancelling the wait leaves the tick due.
+    pub(crate) async fn tick(&mut self) {
+        sleep_until(self.next).await;
+        self.next = Instant::now().max(self.next) + self.period;
+    }
+}
@@ -0,0 +1,1091 @@
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    ffi::OsString,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{self, TryRecvError},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use tokio::sync::broadcast::{self, error::SendError};
+
+use crate::{
+    EventMetadata, EventStatistics, FileSystemEvent, FileSystemEventType, FileSystemTargetKind,
+    KanshiError, KanshiStreamItem,
+};
+
+/// The callbacks registered with `KanshiImpl::on_event`, shared between a
+/// tracer and its sender.
+pub(crate) type EventCallbacks = Arc<RwLock<Vec<Box<dyn Fn(FileSystemEvent) + Send + Sync>>>>;
+
+/// Calls every callback in `callbacks` with a copy of `event`.
+pub(crate) fn notify(callbacks: &EventCallbacks, event: &FileSystemEvent) {
+    for callback in callbacks.read().unwrap().iter() {
+        callback(event.clone());
+    }
+}
+
+/// The counters behind a tracer's `stats`, shared between the tracer, its
+/// sender and its streams.
+pub(crate) struct EventStats {
+    created_at: Instant,
+    events_sent: AtomicU64,
+    events_dropped: AtomicU64,
+    /// Nanoseconds from `created_at` to when the last event was broadcast,
+    /// plus one, or zero before the first.
+    last_event: AtomicU64,
+}
+
+impl Default for EventStats {
+    fn default() -> EventStats {
+        EventStats {
+            created_at: Instant::now(),
+            events_sent: AtomicU64::new(0),
+            events_dropped: AtomicU64::new(0),
+            last_event: AtomicU64::new(0),
+        }
+    }
+}
+
+impl EventStats {
+    fn record_sent(&self) {
+        self.events_sent.fetch_add(1, Ordering::Relaxed);
+        let since_created = self.created_at.elapsed().as_nanos() as u64;
+        self.last_event.store(since_created + 1, Ordering::Relaxed);
+    }
+
+    /// Counts `count` events that a stream or the reconnect buffer lost.
+    pub(crate) fn record_dropped(&self, count: u64) {
+        self.events_dropped.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// The current values, along with the ones only the tracer knows.
+    pub(crate) fn snapshot(&self, marks_active: usize, channel_capacity: usize) -> EventStatistics {
+        let last_event = self.last_event.load(Ordering::Relaxed);
+        EventStatistics {
+            events_sent: self.events_sent.load(Ordering::Relaxed),
+            events_dropped: self.events_dropped.load(Ordering::Relaxed),
+            marks_active,
+            last_event_at: (last_event > 0)
+                .then(|| self.created_at + Duration::from_nanos(last_event - 1)),
+            uptime: self.created_at.elapsed(),
+            channel_capacity,
+        }
+    }
+}
+
+/// Wraps the broadcast sender used by the tracers.
+///
+/// While nobody is subscribed, events are buffered instead of failing the
+/// tracer, and handed to the next subscriber once one appears. The tracer only
+/// gives up with `StreamClosed` if no subscriber shows up within
+/// `reconnect_timeout`.
+///
+/// With a non-zero `grace_period`, `Delete` events are held back for that long
+/// and dropped if a `Create` for the same path arrives in the meantime, which
+/// hides the delete-then-create pairs produced by atomic saves.
+///
+/// With `debounce` set, events are held per path until that path has seen no
+/// further events for the debounce window, and only the last one is sent.
+/// Held events are released by `poll`, before the grace period is applied.
+///
+/// With `coalesce_window` set, the events a path sees within the window after
+/// its first event are merged into one, before debouncing. A path that was
+/// created and then deleted within the window reports nothing.
+///
+/// With `rate_limit` set, incoming events are limited by a token bucket that
+/// holds up to one second's worth of events. Once the bucket runs dry, events
+/// are dropped until it has refilled halfway, which only happens once the
+/// incoming rate falls below the limit. A single `RateLimitExceeded` event then
+/// reports how many were dropped.
+///
+/// With `lookup_symlinks`, `File` targets that turn out to be symbolic links
+/// when the event is sent are reported as `Symlink`. Only inotify and polling
+/// need this, the other engines tell symlinks apart themselves.
+///
+/// With `include_metadata`, the target of `Create` and `Modify` events is
+/// `stat`ed as soon as the event is sent, to fill in
+/// `FileSystemTarget::metadata`.
+///
+/// With `hash_on_modify`, the target of `Modify` events is read as well, to
+/// fill in `EventMetadata::hash`. Files are hashed on a thread of their own,
+/// see [`HashWorker`], and the event waits there along with every event sent
+/// after it, to keep them in order. Files over
+/// `EventMetadata::MAX_HASHED_SIZE` are not hashed.
+///
+/// With `detect_hard_links`, the target of `Create` events is `stat`ed to
+/// report files that have more than one link as `HardLink`.
+///
+/// Every event is numbered from `sequence` as soon as it is sent, before any
+/// of the stages below can drop, merge or hold it back, so gaps in the
+/// numbers received mean events were dropped. Holding events back can reorder
+/// them, so the numbers received are only increasing without debouncing,
+/// coalescing or a grace period.
+///
+/// With `timestamps`, events are stamped with the current time as soon as they
+/// are sent, unless the tracer already set `FileSystemEvent::timestamp`.
+///
+/// While `paused` is set, incoming events are either discarded or, with
+/// `buffer_while_paused`, kept (up to `buffer_limit`, dropping the oldest) and
+/// passed through the other stages by the first `poll` after it is cleared.
+/// Events that were already held by an earlier stage are still released.
+///
+/// Every event that makes it through is passed to the `callbacks` right before
+/// it is broadcast. While any callback is registered, the tracer keeps running
+/// without subscribers instead of giving up after `reconnect_timeout`.
+pub(crate) struct EventSender {
+    sender: broadcast::Sender<KanshiStreamItem>,
+    buffer: VecDeque<KanshiStreamItem>,
+    buffer_limit: usize,
+    reconnect_timeout: Duration,
+    disconnected_at: Option<Instant>,
+    grace_period: Duration,
+    held: VecDeque<(Instant, FileSystemEvent)>,
+    debounce: Option<Duration>,
+    debounced: HashMap<OsString, (Instant, FileSystemEvent)>,
+    coalesce_window: Option<Duration>,
+    coalesced: HashMap<OsString, Coalesced>,
+    rate_limit: Option<u32>,
+    tokens: f64,
+    refilled_at: Instant,
+    dropped: u64,
+    include_metadata: bool,
+    hash_on_modify: bool,
+    // Started with the first file to hash.
+    hasher: Option<HashWorker>,
+    // Events sent while a hash is pending, and whether each waits for one.
+    hashing: VecDeque<(bool, FileSystemEvent)>,
+    detect_hard_links: bool,
+    lookup_symlinks: bool,
+    timestamps: bool,
+    sequence: Arc<AtomicU64>,
+    paused: Arc<AtomicBool>,
+    buffer_while_paused: bool,
+    paused_events: VecDeque<FileSystemEvent>,
+    callbacks: EventCallbacks,
+    stats: Arc<EventStats>,
+}
+
+/// Hashes the files it is sent on a thread of its own, replying in the order
+/// they were sent. Paths requested again before the thread got to them are
+/// only read once, so a large file written in many chunks is not read in full
+/// after every chunk. The thread ends once the worker is dropped.
+struct HashWorker {
+    requests: mpsc::Sender<PathBuf>,
+    hashes: mpsc::Receiver<Option<[u8; 32]>>,
+}
+
+impl HashWorker {
+    fn spawn() -> HashWorker {
+        let (requests, pending) = mpsc::channel::<PathBuf>();
+        let (replies, hashes) = mpsc::channel();
+        std::thread::spawn(move || {
+            while let Ok(first) = pending.recv() {
+                let mut hashed = HashMap::new();
+                for path in std::iter::once(first).chain(pending.try_iter()) {
+                    let hash = *hashed
+                        .entry(path)
+                        .or_insert_with_key(|x: &PathBuf| EventMetadata::hash(x));
+                    if replies.send(hash).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        HashWorker { requests, hashes }
+    }
+}
+
+/// The events seen for one path during a coalescing window.
+struct Coalesced {
+    opened_at: Instant,
+    event_types: BTreeSet<FileSystemEventType>,
+    /// Whether the window was opened by a `Create`.
+    created: bool,
+    /// The last event seen, whose target is reported.
+    last: FileSystemEvent,
+}
+
+impl Coalesced {
+    fn new(event: FileSystemEvent) -> Coalesced {
+        Coalesced {
+            opened_at: Instant::now(),
+            event_types: BTreeSet::from([event.event_type.clone()]),
+            created: event.event_type == FileSystemEventType::Create,
+            last: event,
+        }
+    }
+
+    /// The merged event, or `None` if the path did not exist before the
+    /// window and no longer exists after it.
+    fn into_event(self) -> Option<FileSystemEvent> {
+        let Coalesced {
+            event_types,
+            created,
+            mut last,
+            ..
+        } = self;
+
+        if created && last.event_type == FileSystemEventType::Delete {
+            return None;
+        }
+        if event_types.len() > 1 {
+            last.event_type = FileSystemEventType::Compound(event_types);
+        }
+        Some(last)
+    }
+}
+
+impl EventSender {
+    pub(crate) fn new(
+        sender: broadcast::Sender<KanshiStreamItem>,
+        reconnect_timeout: Duration,
+        buffer_limit: usize,
+        grace_period: Duration,
+        debounce: Option<Duration>,
+        coalesce_window: Option<Duration>,
+        rate_limit: Option<u32>,
+    ) -> EventSender {
+        EventSender {
+            sender,
+            buffer: VecDeque::new(),
+            buffer_limit,
+            reconnect_timeout,
+            disconnected_at: None,
+            grace_period,
+            held: VecDeque::new(),
+            debounce,
+            debounced: HashMap::new(),
+            coalesce_window,
+            coalesced: HashMap::new(),
+            rate_limit,
+            tokens: rate_limit.unwrap_or_default() as f64,
+            refilled_at: Instant::now(),
+            dropped: 0,
+            include_metadata: false,
+            hash_on_modify: false,
+            hasher: None,
+            hashing: VecDeque::new(),
+            detect_hard_links: false,
+            lookup_symlinks: false,
+            timestamps: false,
+            sequence: Arc::new(AtomicU64::new(0)),
+            paused: Arc::new(AtomicBool::new(false)),
+            buffer_while_paused: false,
+            paused_events: VecDeque::new(),
+            callbacks: EventCallbacks::default(),
+            stats: Arc::default(),
+        }
+    }
+
+    /// Sets the counters reported by the tracer's `stats`.
+    pub(crate) fn with_stats(mut self, stats: Arc<EventStats>) -> EventSender {
+        self.stats = stats;
+        self
+    }
+
+    /// Sets whether `FileSystemTarget::metadata` is filled in.
+    pub(crate) fn with_metadata(mut self, include_metadata: bool) -> EventSender {
+        self.include_metadata = include_metadata;
+        self
+    }
+
+    /// Sets whether `EventMetadata::hash` is filled in for `Modify` events.
+    pub(crate) fn with_hashes(mut self, hash_on_modify: bool) -> EventSender {
+        self.hash_on_modify = hash_on_modify;
+        self
+    }
+
+    /// Sets whether newly created hard links are reported as `HardLink`.
+    pub(crate) fn with_hard_links(mut self, detect_hard_links: bool) -> EventSender {
+        self.detect_hard_links = detect_hard_links;
+        self
+    }
+
+    /// Sets whether `File` targets are `lstat`ed to report symlinks as
+    /// `Symlink`.
+    pub(crate) fn with_symlink_lookup(mut self, lookup_symlinks: bool) -> EventSender {
+        self.lookup_symlinks = lookup_symlinks;
+        self
+    }
+
+    /// Sets whether `FileSystemEvent::timestamp` is filled in.
+    pub(crate) fn with_timestamps(mut self, timestamps: bool) -> EventSender {
+        self.timestamps = timestamps;
+        self
+    }
+
+    /// Sets the counter events are numbered from, shared with the tracer so
+    /// numbering carries on across restarts.
+    pub(crate) fn with_sequence(mut self, sequence: Arc<AtomicU64>) -> EventSender {
+        self.sequence = sequence;
+        self
+    }
+
+    /// Sets the flag that pauses the sender, shared with the tracer, and
+    /// whether events are kept while it is set.
+    pub(crate) fn with_pause(
+        mut self,
+        paused: Arc<AtomicBool>,
+        buffer_while_paused: bool,
+    ) -> EventSender {
+        self.paused = paused;
+        self.buffer_while_paused = buffer_while_paused;
+        self
+    }
+
+    /// Sets the callbacks every event is passed to, shared with the tracer.
+    pub(crate) fn with_callbacks(mut self, callbacks: EventCallbacks) -> EventSender {
+        self.callbacks = callbacks;
+        self
+    }
+
+    /// Sends `event`, or buffers it if there are no subscribers.
+    pub(crate) fn send(&mut self, mut event: FileSystemEvent) -> Result<(), KanshiError> {
+        event.sequence = self.sequence.fetch_add(1, Ordering::SeqCst);
+
+        if let Some(target) = event.target.as_mut().filter(|_| self.lookup_symlinks) {
+            let path = Path::new(&target.path);
+            if target.kind == FileSystemTargetKind::File
+                && path.is_absolute()
+                && path.symlink_metadata().is_ok_and(|x| x.is_symlink())
+            {
+                target.kind = FileSystemTargetKind::Symlink;
+            }
+        }
+        if self.timestamps {
+            event.timestamp.get_or_insert_with(SystemTime::now);
+        }
+
+        let hash = self.hash_on_modify && event.event_type == FileSystemEventType::Modify;
+        let read_metadata = match event.event_type {
+            FileSystemEventType::Create | FileSystemEventType::Modify => self.include_metadata,
+            _ => false,
+        };
+        if read_metadata || hash {
+            if let Some(target) = event.target.as_mut() {
+                target.metadata = target
+                    .metadata
+                    .take()
+                    .or_else(|| EventMetadata::read(&target.path));
+            }
+        }
+
+        // Relative paths from `NameOnly` mode can't be looked up.
+        let hash_path = event
+            .target
+            .as_ref()
+            .filter(|x| hash && Path::new(&x.path).is_absolute())
+            .filter(|x| {
+                x.metadata
+                    .as_ref()
+                    .is_some_and(|x| x.size <= EventMetadata::MAX_HASHED_SIZE)
+            })
+            .map(|x| PathBuf::from(&x.path));
+        let needs_hash = hash_path.is_some();
+        if let Some(path) = hash_path {
+            let hasher = self.hasher.get_or_insert_with(HashWorker::spawn);
+            // Only fails if the thread panicked, in which case the event is
+            // passed on without a hash.
+            let _ = hasher.requests.send(path);
+        }
+        if needs_hash || !self.hashing.is_empty() {
+            self.hashing.push_back((needs_hash, event));
+            return self.poll();
+        }
+
+        self.pass_on(event)
+    }
+
+    /// Passes on the events whose hashes are ready, up to the first one still
+    /// waiting for its hash.
+    fn release_hashed(&mut self) -> Result<(), KanshiError> {
+        let mut ready = Vec::new();
+        while let Some(&(needs_hash, _)) = self.hashing.front() {
+            let hash = match self.hasher.as_ref().filter(|_| needs_hash) {
+                Some(hasher) => match hasher.hashes.try_recv() {
+                    Ok(hash) => hash,
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => None,
+                },
+                None => None,
+            };
+            let (_, mut event) = self.hashing.pop_front().unwrap();
+            if let Some(metadata) = event.target.as_mut().and_then(|x| x.metadata.as_mut()) {
+                if needs_hash {
+                    metadata.hash = hash;
+                }
+            }
+            ready.push(event);
+        }
+        // Taken all at once, as `process` polls again.
+        for event in ready {
+            self.pass_on(event)?;
+        }
+        Ok(())
+    }
+
+    /// Detects hard links and passes `event` on, unless paused.
+    fn pass_on(&mut self, mut event: FileSystemEvent) -> Result<(), KanshiError> {
+        #[cfg(unix)]
+        if self.detect_hard_links && event.event_type == FileSystemEventType::Create {
+            use std::os::unix::fs::MetadataExt;
+
+            if let Some(target) = event.target.as_mut() {
+                if let Ok(metadata) = Path::new(&target.path).symlink_metadata() {
+                    if metadata.is_file() && metadata.nlink() > 1 {
+                        target.kind = FileSystemTargetKind::HardLink {
+                            original_inode: metadata.ino(),
+                        };
+                    }
+                }
+            }
+        }
+
+        if self.paused.load(Ordering::SeqCst) {
+            if self.buffer_while_paused && self.buffer_limit > 0 {
+                if self.paused_events.len() >= self.buffer_limit {
+                    self.paused_events.pop_front();
+                }
+                self.paused_events.push_back(event);
+            }
+            return self.poll();
+        }
+
+        self.process(event)
+    }
+
+    /// Passes `event` through rate limiting, coalescing and debouncing.
+    fn process(&mut self, event: FileSystemEvent) -> Result<(), KanshiError> {
+        if self.rate_limit.is_some() {
+            self.refill()?;
+            if self.dropped > 0 || self.tokens < 1.0 {
+                // Dropped events still drain the bucket, so it only refills
+                // once the incoming rate is below the limit.
+                self.tokens = (self.tokens - 1.0).max(0.0);
+                self.dropped += 1;
+                return self.poll();
+            }
+            self.tokens -= 1.0;
+        }
+
+        if self.coalesce_window.is_some() {
+            if let Some(target) = event.target.as_ref() {
+                let path = target.path.clone();
+
+                // Moves carry paths of their own and are not merged. Anything
+                // pending for the path is sent first to keep the order.
+                if matches!(
+                    event.event_type,
+                    FileSystemEventType::Move
+                        | FileSystemEventType::MovedTo { .. }
+                        | FileSystemEventType::MovedFrom { .. }
+                ) {
+                    if let Some(merged) =
+                        self.coalesced.remove(&path).and_then(Coalesced::into_event)
+                    {
+                        self.debounce(merged)?;
+                    }
+                    return self.debounce(event);
+                }
+
+                match self.coalesced.get_mut(&path) {
+                    Some(pending) => {
+                        pending.event_types.insert(event.event_type.clone());
+                        pending.last = event;
+                    }
+                    None => {
+                        self.coalesced.insert(path, Coalesced::new(event));
+                    }
+                }
+                return self.poll();
+            }
+        }
+
+        self.debounce(event)
+    }
+
+    /// Holds `event` for the debounce window, if one is set, or forwards it.
+    fn debounce(&mut self, event: FileSystemEvent) -> Result<(), KanshiError> {
+        if self.debounce.is_some() {
+            if let Some(target) = event.target.as_ref() {
+                // Replacing the held event restarts the window for its path.
+                self.debounced
+                    .insert(target.path.clone(), (Instant::now(), event));
+                return self.poll();
+            }
+        }
+
+        self.forward(event)
+    }
+
+    /// Applies the grace period to `event` and emits it.
+    fn forward(&mut self, event: FileSystemEvent) -> Result<(), KanshiError> {
+        if !self.grace_period.is_zero() {
+            if let Some(target) = event.target.as_ref() {
+                match event.event_type {
+                    FileSystemEventType::Delete => {
+                        self.held.push_back((Instant::now(), event));
+                        return self.poll();
+                    }
+                    FileSystemEventType::Create => {
+                        self.held.retain(|(_, held)| {
+                            held.target.as_ref().map(|x| &x.path) != Some(&target.path)
+                        });
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        self.release_held()?;
+        self.emit(event)
+    }
+
+    /// Flushes buffered events to any new subscriber and checks whether the
+    /// reconnect timeout has elapsed. Tracers call this periodically so a
+    /// missing subscriber is noticed even when no new events arrive.
+    pub(crate) fn poll(&mut self) -> Result<(), KanshiError> {
+        self.release_hashed()?;
+        self.release_paused()?;
+        self.refill()?;
+        self.release_coalesced()?;
+        self.release_debounced()?;
+        self.release_held()?;
+        self.flush();
+        self.check_timeout()
+    }
+
+    /// Passes on the events kept while paused, once no longer paused.
+    fn release_paused(&mut self) -> Result<(), KanshiError> {
+        if self.paused_events.is_empty() || self.paused.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        // Taken all at once, as `process` polls again.
+        for event in std::mem::take(&mut self.paused_events) {
+            self.process(event)?;
+        }
+        Ok(())
+    }
+
+    /// Adds the tokens earned since the last refill, and reports the events
+    /// dropped so far once the bucket is at least half full again.
+    fn refill(&mut self) -> Result<(), KanshiError> {
+        let Some(rate_limit) = self.rate_limit else {
+            return Ok(());
+        };
+
+        let now = Instant::now();
+        let earned = now.duration_since(self.refilled_at).as_secs_f64() * rate_limit as f64;
+        self.tokens = (self.tokens + earned).min(rate_limit as f64);
+        self.refilled_at = now;
+
+        if self.dropped > 0 && self.tokens >= (rate_limit as f64 / 2.0).max(1.0) {
+            let dropped = std::mem::take(&mut self.dropped);
+            self.forward(FileSystemEvent {
+                event_type: FileSystemEventType::RateLimitExceeded { dropped },
+                target: None,
+                pid: None,
+                timestamp: self.timestamps.then(SystemTime::now),
+                sequence: self.sequence.fetch_add(1, Ordering::SeqCst),
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Passes on the merged events of paths whose coalescing window has
+    /// closed, oldest first.
+    fn release_coalesced(&mut self) -> Result<(), KanshiError> {
+        let Some(coalesce_window) = self.coalesce_window else {
+            return Ok(());
+        };
+
+        let due: Vec<OsString> = self
+            .coalesced
+            .iter()
+            .filter(|(_, pending)| pending.opened_at.elapsed() >= coalesce_window)
+            .map(|(path, _)| path.clone())
+            .collect();
+        let mut due: Vec<_> = due
+            .into_iter()
+            .filter_map(|path| self.coalesced.remove(&path))
+            .collect();
+        due.sort_by_key(|pending| pending.opened_at);
+
+        for merged in due.into_iter().filter_map(Coalesced::into_event) {
+            self.debounce(merged)?;
+        }
+        Ok(())
+    }
+
+    /// Forwards debounced events whose path has been quiet for the debounce
+    /// window, oldest first.
+    fn release_debounced(&mut self) -> Result<(), KanshiError> {
+        let Some(debounce) = self.debounce else {
+            return Ok(());
+        };
+
+        let due: Vec<OsString> = self
+            .debounced
+            .iter()
+            .filter(|(_, (updated_at, _))| updated_at.elapsed() >= debounce)
+            .map(|(path, _)| path.clone())
+            .collect();
+        let mut due: Vec<_> = due
+            .into_iter()
+            .filter_map(|path| self.debounced.remove(&path))
+            .collect();
+        due.sort_by_key(|(updated_at, _)| *updated_at);
+
+        for (_, event) in due {
+            self.forward(event)?;
+        }
+        Ok(())
+    }
+
+    /// Emits held `Delete` events whose grace period has elapsed.
+    fn release_held(&mut self) -> Result<(), KanshiError> {
+        while let Some((held_at, _)) = self.held.front() {
+            if held_at.elapsed() < self.grace_period {
+                break;
+            }
+            let (_, event) = self.held.pop_front().unwrap();
+            self.emit(event)?;
+        }
+        Ok(())
+    }
+
+    fn emit(&mut self, event: FileSystemEvent) -> Result<(), KanshiError> {
+        notify(&self.callbacks, &event);
+        self.flush();
+
+        if self.buffer.is_empty() {
+            match self.sender.send(KanshiStreamItem::Event(event)) {
+                Ok(_) => {
+                    self.stats.record_sent();
+                    return Ok(());
+                }
+                Err(SendError(item)) => self.push(item),
+            }
+        } else {
+            self.push(KanshiStreamItem::Event(event));
+        }
+
+        self.check_timeout()
+    }
+
+    fn push(&mut self, item: KanshiStreamItem) {
+        self.disconnected_at.get_or_insert_with(Instant::now);
+
+        // Keep the most recent events if nobody subscribes for a while.
+        if self.buffer.len() >= self.buffer_limit {
+            if let Some(KanshiStreamItem::Event(_)) = self.buffer.pop_front() {
+                self.stats.record_dropped(1);
+            }
+        }
+        if self.buffer_limit > 0 {
+            self.buffer.push_back(item);
+        } else if let KanshiStreamItem::Event(_) = item {
+            self.stats.record_dropped(1);
+        }
+    }
+
+    fn flush(&mut self) {
+        while let Some(item) = self.buffer.pop_front() {
+            let is_event = matches!(item, KanshiStreamItem::Event(_));
+            if let Err(SendError(item)) = self.sender.send(item) {
+                self.buffer.push_front(item);
+                return;
+            }
+            if is_event {
+                self.stats.record_sent();
+            }
+        }
+        // Only a subscriber ends the wait, not running out of buffered
+        // events, which never happens without a buffer.
+        if self.sender.receiver_count() > 0 {
+            self.disconnected_at = None;
+        }
+    }
+
+    fn check_timeout(&self) -> Result<(), KanshiError> {
+        match self.disconnected_at {
+            Some(since)
+                if since.elapsed() >= self.reconnect_timeout
+                    && self.callbacks.read().unwrap().is_empty() =>
+            {
+                Err(KanshiError::StreamClosed)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeSet,
+        time::{Duration, Instant, SystemTime},
+    };
+
+    use tokio::sync::broadcast;
+
+    use crate::{
+        FileSystemEvent, FileSystemEventType, FileSystemTarget, FileSystemTargetKind,
+        KanshiStreamItem,
+    };
+
+    use super::{EventCallbacks, EventSender};
+
+    fn next(rx: &mut broadcast::Receiver<KanshiStreamItem>) -> Option<FileSystemEvent> {
+        rx.try_recv().ok().and_then(KanshiStreamItem::into_event)
+    }
+
+    fn event() -> FileSystemEvent {
+        FileSystemEvent {
+            event_type: FileSystemEventType::Create,
+            target: None,
+            pid: None,
+            timestamp: None,
+            sequence: 0,
+        }
+    }
+
+    fn event_at(event_type: FileSystemEventType, path: &str) -> FileSystemEvent {
+        FileSystemEvent {
+            event_type,
+            target: Some(FileSystemTarget {
+                kind: FileSystemTargetKind::File,
+                path: path.into(),
+                device_inode: None,
+                metadata: None,
+            }),
+            pid: None,
+            timestamp: None,
+            sequence: 0,
+        }
+    }
+
+    #[test]
+    fn buffers_until_resubscribed() {
+        let (tx, rx) = tokio::sync::broadcast::channel(32);
+        drop(rx);
+
+        let mut sender = EventSender::new(
+            tx.clone(),
+            Duration::from_secs(60),
+            2,
+            Duration::ZERO,
+            None,
+            None,
+            None,
+        );
+        for _ in 0..3 {
+            sender.send(event()).unwrap();
+        }
+
+        let mut rx = tx.subscribe();
+        sender.poll().unwrap();
+        assert!(next(&mut rx).is_some());
+        assert!(next(&mut rx).is_some());
+        assert!(next(&mut rx).is_none());
+    }
+
+    #[test]
+    fn times_out_without_subscribers() {
+        let (tx, rx) = tokio::sync::broadcast::channel(32);
+        drop(rx);
+
+        let mut sender = EventSender::new(tx, Duration::ZERO, 8, Duration::ZERO, None, None, None);
+        assert!(sender.send(event()).is_err());
+    }
+
+    #[test]
+    fn times_out_without_subscribers_or_buffer() {
+        let (tx, rx) = tokio::sync::broadcast::channel(32);
+        drop(rx);
+
+        let timeout = Duration::from_millis(50);
+        let mut sender = EventSender::new(tx, timeout, 0, Duration::ZERO, None, None, None);
+        sender.send(event()).unwrap();
+        std::thread::sleep(timeout);
+        assert!(sender.send(event()).is_err());
+    }
+
+    #[test]
+    fn hashed_events_keep_their_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("x");
+        std::fs::write(&path, b"hello").unwrap();
+
+        let (tx, mut rx) = tokio::sync::broadcast::channel(32);
+        let mut sender = EventSender::new(
+            tx,
+            Duration::from_secs(60),
+            8,
+            Duration::ZERO,
+            None,
+            None,
+            None,
+        )
+        .with_hashes(true);
+        let modified = event_at(FileSystemEventType::Modify, path.to_str().unwrap());
+        sender.send(modified).unwrap();
+        sender.send(event_at(FileSystemEventType::Create, "/y")).unwrap();
+
+        let mut received = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while received.len() < 2 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(1));
+            sender.poll().unwrap();
+            received.extend(std::iter::from_fn(|| next(&mut rx)));
+        }
+
+        let event_types: Vec<_> = received.iter().map(|x| x.event_type.clone()).collect();
+        assert_eq!(
+            event_types,
+            [FileSystemEventType::Modify, FileSystemEventType::Create]
+        );
+        let metadata = received[0].target.as_ref().unwrap().metadata.as_ref();
+        assert!(metadata.is_some_and(|x| x.hash.is_some()));
+    }
+
+    #[test]
+    fn callbacks_receive_events_without_subscribers() {
+        use std::sync::{Arc, Mutex};
+
+        let (tx, rx) = tokio::sync::broadcast::channel(32);
+        drop(rx);
+
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let callbacks = EventCallbacks::default();
+        let log = received.clone();
+        callbacks.write().unwrap().push(Box::new(move |event| {
+            log.lock().unwrap().push(event.event_type)
+        }));
+
+        let mut sender = EventSender::new(tx, Duration::ZERO, 8, Duration::ZERO, None, None, None)
+            .with_callbacks(callbacks);
+        sender.send(event()).unwrap();
+        sender.poll().unwrap();
+
+        assert_eq!(*received.lock().unwrap(), [FileSystemEventType::Create]);
+    }
+
+    #[test]
+    fn timestamps_are_set_when_sent() {
+        let (tx, mut rx) = tokio::sync::broadcast::channel(32);
+        let mut sender = EventSender::new(tx, Duration::ZERO, 8, Duration::ZERO, None, None, None)
+            .with_timestamps(true);
+
+        let before = SystemTime::now();
+        sender.send(event()).unwrap();
+        let timestamp = next(&mut rx).unwrap().timestamp.unwrap();
+        assert!(timestamp >= before && timestamp <= SystemTime::now());
+    }
+
+    #[test]
+    fn grace_period_cancels_delete_before_create() {
+        let (tx, mut rx) = tokio::sync::broadcast::channel(32);
+        let grace_period = Duration::from_millis(20);
+
+        let mut sender = EventSender::new(
+            tx,
+            Duration::from_secs(60),
+            8,
+            grace_period,
+            None,
+            None,
+            None,
+        );
+        sender
+            .send(event_at(FileSystemEventType::Delete, "/a"))
+            .unwrap();
+        sender
+            .send(event_at(FileSystemEventType::Create, "/a"))
+            .unwrap();
+        sender
+            .send(event_at(FileSystemEventType::Delete, "/b"))
+            .unwrap();
+
+        assert_eq!(
+            next(&mut rx).unwrap().event_type,
+            FileSystemEventType::Create
+        );
+        assert!(next(&mut rx).is_none());
+
+        std::thread::sleep(grace_period);
+        sender.poll().unwrap();
+        let deleted = next(&mut rx).unwrap();
+        assert_eq!(deleted.event_type, FileSystemEventType::Delete);
+        assert_eq!(deleted.target.unwrap().path, "/b");
+    }
+
+    #[test]
+    fn debounce_sends_last_event_per_path() {
+        let (tx, mut rx) = tokio::sync::broadcast::channel(32);
+        let debounce = Duration::from_millis(20);
+
+        let mut sender = EventSender::new(
+            tx,
+            Duration::from_secs(60),
+            8,
+            Duration::ZERO,
+            Some(debounce),
+            None,
+            None,
+        );
+        sender
+            .send(event_at(FileSystemEventType::Create, "/a"))
+            .unwrap();
+        sender
+            .send(event_at(FileSystemEventType::Modify, "/a"))
+            .unwrap();
+        sender.send(event()).unwrap();
+
+        // Events without a target are not debounced.
+        assert!(next(&mut rx).unwrap().target.is_none());
+        assert!(next(&mut rx).is_none());
+
+        std::thread::sleep(debounce);
+        sender.poll().unwrap();
+        let debounced = next(&mut rx).unwrap();
+        assert_eq!(debounced.event_type, FileSystemEventType::Modify);
+        assert_eq!(debounced.target.unwrap().path, "/a");
+        assert!(next(&mut rx).is_none());
+    }
+
+    #[test]
+    fn coalesce_merges_events_per_path() {
+        let (tx, mut rx) = tokio::sync::broadcast::channel(32);
+        let window = Duration::from_millis(20);
+
+        let mut sender = EventSender::new(
+            tx,
+            Duration::from_secs(60),
+            8,
+            Duration::ZERO,
+            None,
+            Some(window),
+            None,
+        );
+        for event_type in [
+            FileSystemEventType::Create,
+            FileSystemEventType::Modify,
+            FileSystemEventType::AttributeChange,
+        ] {
+            sender.send(event_at(event_type, "/a")).unwrap();
+        }
+        sender
+            .send(event_at(FileSystemEventType::Create, "/b"))
+            .unwrap();
+        sender
+            .send(event_at(FileSystemEventType::Delete, "/b"))
+            .unwrap();
+        assert!(next(&mut rx).is_none());
+
+        std::thread::sleep(window);
+        sender.poll().unwrap();
+        let merged = next(&mut rx).unwrap();
+        assert_eq!(
+            merged.event_type,
+            FileSystemEventType::Compound(BTreeSet::from([
+                FileSystemEventType::Create,
+                FileSystemEventType::Modify,
+                FileSystemEventType::AttributeChange,
+            ]))
+        );
+        assert_eq!(merged.target.unwrap().path, "/a");
+        // "/b" was created and deleted within the window.
+        assert!(next(&mut rx).is_none());
+    }
+
+    #[test]
+    fn rate_limit_drops_excess_events() {
+        let (tx, mut rx) = tokio::sync::broadcast::channel(1 << 16);
+        let rate_limit = 1000;
+        let total = 50_000;
+
+        let started_at = std::time::Instant::now();
+        let mut sender = EventSender::new(
+            tx,
+            Duration::from_secs(60),
+            8,
+            Duration::ZERO,
+            None,
+            None,
+            Some(rate_limit),
+        );
+        for _ in 0..total {
+            sender.send(event()).unwrap();
+        }
+        let elapsed = started_at.elapsed().as_secs_f64();
+
+        // Give the bucket time to refill halfway so the drops are reported.
+        std::thread::sleep(Duration::from_millis(600));
+        sender.poll().unwrap();
+
+        let (mut sent, mut dropped, mut reports) = (0, 0, 0);
+        while let Some(event) = next(&mut rx) {
+            match event.event_type {
+                FileSystemEventType::RateLimitExceeded { dropped: x } => {
+                    dropped += x;
+                    reports += 1;
+                }
+                _ => sent += 1,
+            }
+        }
+        assert!(reports >= 1);
+        assert_eq!(sent + dropped, total);
+        // The bucket starts full and refills at `rate_limit` per second.
+        assert!(sent as f64 <= rate_limit as f64 * (1.0 + elapsed) + 1.0);
+    }
+
+    #[test]
+    fn pause_buffers_or_discards_events() {
+        use std::sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        };
+
+        for buffer_while_paused in [false, true] {
+            let (tx, mut rx) = tokio::sync::broadcast::channel(32);
+            let paused = Arc::new(AtomicBool::new(false));
+            let mut sender = EventSender::new(
+                tx,
+                Duration::from_secs(60),
+                8,
+                Duration::ZERO,
+                None,
+                None,
+                None,
+            )
+            .with_pause(paused.clone(), buffer_while_paused);
+
+            paused.store(true, Ordering::SeqCst);
+            sender.send(event_at(FileSystemEventType::Create, "/a")).unwrap();
+            sender.poll().unwrap();
+            assert!(next(&mut rx).is_none());
+
+            paused.store(false, Ordering::SeqCst);
+            sender.poll().unwrap();
+            sender.send(event_at(FileSystemEventType::Modify, "/a")).unwrap();
+
+            let mut event_types = Vec::new();
+            while let Some(event) = next(&mut rx) {
+                event_types.push(event.event_type);
+            }
+            if buffer_while_paused {
+                assert_eq!(
+                    event_types,
+                    [FileSystemEventType::Create, FileSystemEventType::Modify]
+                );
+            } else {
+                assert_eq!(event_types, [FileSystemEventType::Modify]);
+            }
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use crate::{
+    FileSystemEvent, FileSystemEventType, FileSystemTarget, FileSystemTargetKind, KanshiError,
+};
+
+/// A single entry of a [`WatchedTree`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TreeEntry {
+    pub kind: FileSystemTargetKind,
+    pub modified: Option<SystemTime>,
+    pub device_inode: Option<(u64, u64)>,
+}
+
+/// A point-in-time view of every entry below a directory.
+///
+/// Entries are keyed by their path relative to `root`, so two trees captured
+/// from different directories can be compared with [`diff_snapshots`].
+#[derive(Clone, Debug, Default)]
+pub struct WatchedTree {
+    pub root: PathBuf,
+    pub entries: BTreeMap<PathBuf, TreeEntry>,
+}
+
+impl WatchedTree {
+    /// Recursively walks `root` and records every entry below it.
+    /// Symlinks are recorded as files and are not followed.
+    pub fn capture(root: impl AsRef<Path>) -> Result<WatchedTree, KanshiError> {
+        let root = root.as_ref().to_path_buf();
+        let mut entries = BTreeMap::new();
+        let mut traversal_queue = VecDeque::from([root.clone()]);
+
+        while let Some(next_dir) = traversal_queue.pop_front() {
+            for dir_item in fs::read_dir(next_dir)? {
+                let dir_item = dir_item?;
+                let path = dir_item.path();
+
+                // Entries can disappear while walking; skip them rather than failing.
+                let Ok(metadata) = fs::symlink_metadata(&path) else {
+                    continue;
+                };
+
+                let kind = if metadata.is_dir() {
+                    traversal_queue.push_back(path.clone());
+                    FileSystemTargetKind::Directory
+                } else if metadata.is_symlink() {
+                    FileSystemTargetKind::Symlink
+                } else {
+                    FileSystemTargetKind::File
+                };
+
+                #[cfg(unix)]
+                let device_inode = {
+                    use std::os::unix::fs::MetadataExt;
+                    Some((metadata.dev(), metadata.ino()))
+                };
+                #[cfg(not(unix))]
+                let device_inode = None;
+
+                if let Ok(relative_path) = path.strip_prefix(&root) {
+                    entries.insert(
+                        relative_path.to_path_buf(),
+                        TreeEntry {
+                            kind,
+                            modified: metadata.modified().ok(),
+                            device_inode,
+                        },
+                    );
+                }
+            }
+        }
+
+        Ok(WatchedTree { root, entries })
+    }
+}
+
+/// Computes the events that transform `before` into `after`.
+///
+/// Entries only present in `after` produce `Create`, entries only present in
+/// `before` produce `Delete`, and files whose modification time changed produce
+/// `Modify`. An entry that changed between file and directory produces a
+/// `Delete` followed by a `Create`.
+///
+/// Deletes are emitted first, deepest paths first, followed by creates and
+/// modifies with parents before their children.
+pub fn diff_snapshots(before: &WatchedTree, after: &WatchedTree) -> Vec<FileSystemEvent> {
+    let mut deleted = Vec::new();
+    let mut changed = Vec::new();
+
+    for (path, entry) in before.entries.iter().rev() {
+        let replaced = after
+            .entries
+            .get(path)
+            .is_some_and(|after_entry| after_entry.kind != entry.kind);
+
+        if replaced || !after.entries.contains_key(path) {
+            deleted.push(snapshot_event(
+                FileSystemEventType::Delete,
+                &before.root,
+                path,
+                entry,
+            ));
+        }
+    }
+
+    for (path, entry) in after.entries.iter() {
+        match before.entries.get(path) {
+            Some(before_entry) if before_entry.kind == entry.kind => {
+                if entry.kind == FileSystemTargetKind::File
+                    && before_entry.modified != entry.modified
+                {
+                    changed.push(snapshot_event(
+                        FileSystemEventType::Modify,
+                        &after.root,
+                        path,
+                        entry,
+                    ));
+                }
+            }
+            _ => changed.push(snapshot_event(
+                FileSystemEventType::Create,
+                &after.root,
+                path,
+                entry,
+            )),
+        }
+    }
+
+    deleted.extend(changed);
+    deleted
+}
+
+fn snapshot_event(
+    event_type: FileSystemEventType,
+    root: &Path,
+    relative_path: &Path,
+    entry: &TreeEntry,
+) -> FileSystemEvent {
+    FileSystemEvent {
+        event_type,
+        target: Some(FileSystemTarget {
+            kind: entry.kind.clone(),
+            path: root.join(relative_path).into_os_string(),
+            device_inode: entry.device_inode,
+            metadata: None,
+        }),
+        pid: None,
+        timestamp: None,
+        sequence: 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::PathBuf,
+        time::{Duration, SystemTime},
+    };
+
+    use super::{diff_snapshots, TreeEntry, WatchedTree};
+    use crate::{FileSystemEventType, FileSystemTargetKind};
+
+    fn tree(root: &str, entries: &[(&str, FileSystemTargetKind, u64)]) -> WatchedTree {
+        WatchedTree {
+            root: PathBuf::from(root),
+            entries: entries
+                .iter()
+                .map(|(path, kind, mtime)| {
+                    (
+                        PathBuf::from(path),
+                        TreeEntry {
+                            kind: kind.clone(),
+                            modified: Some(SystemTime::UNIX_EPOCH + Duration::from_secs(*mtime)),
+                            device_inode: None,
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn diff() {
+        use FileSystemTargetKind::{Directory, File};
+
+        let before = tree(
+            "/before",
+            &[
+                ("a", File, 1),
+                ("b", File, 1),
+                ("d", Directory, 1),
+                ("d/c", File, 1),
+                ("x", File, 1),
+            ],
+        );
+        let after = tree(
+            "/after",
+            &[
+                ("a", File, 1),
+                ("b", File, 2),
+                ("e", File, 1),
+                ("x", Directory, 1),
+            ],
+        );
+
+        let events: Vec<(FileSystemEventType, String)> = diff_snapshots(&before, &after)
+            .into_iter()
+            .map(|e| (e.event_type, e.target.unwrap().path.into_string().unwrap()))
+            .collect();
+
+        assert_eq!(
+            events,
+            vec![
+                (FileSystemEventType::Delete, "/before/x".to_owned()),
+                (FileSystemEventType::Delete, "/before/d/c".to_owned()),
+                (FileSystemEventType::Delete, "/before/d".to_owned()),
+                (FileSystemEventType::Modify, "/after/b".to_owned()),
+                (FileSystemEventType::Create, "/after/e".to_owned()),
+                (FileSystemEventType::Create, "/after/x".to_owned()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,299 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use async_stream::stream;
+use tokio::sync::broadcast::error::RecvError;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    platforms::RunningGuard,
+    runtime,
+    sender::{notify, EventCallbacks},
+    FileSystemEvent, KanshiError, KanshiImpl, KanshiStreamItem,
+};
+
+/// A tracer that reports a fixed list of events instead of watching the
+/// filesystem, for testing code that consumes a [`KanshiImpl`].
+///
+/// `start` sends the events to every stream in order, waiting for the delay
+/// given with each one first, and then waits for `close` like any other
+/// tracer. Events are only sent once, even if `start` is called again after it
+/// returned, and calling it on a clone while it runs fails with
+/// `ListenerAlreadyStarted`. While paused, the remaining events are held until
+/// `resume`. `watch` always succeeds and has no effect on which events are
+/// sent.
+///
+/// # Examples
+///
+/// ```
+/// use futures::StreamExt;
+/// use kanshi::{
+///     testing::MockTracer, FileSystemEvent, FileSystemEventType, KanshiImpl, KanshiStreamItem,
+/// };
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let event = FileSystemEvent {
+///         event_type: FileSystemEventType::Create,
+///         target: None,
+///         pid: None,
+///         timestamp: None,
+///         sequence: 0,
+///     };
+///     let mock = MockTracer::new(vec![event.clone()]).unwrap();
+///
+///     let mut stream = mock.get_events_stream();
+///     let tracer = mock.clone();
+///     tokio::spawn(async move { tracer.start().await });
+///
+///     let next = stream.next().await.and_then(KanshiStreamItem::into_event);
+///     assert_eq!(next, Some(event));
+///     assert_eq!(mock.events_sent(), 1);
+///     mock.close().unwrap();
+/// }
+/// ```
+#[derive(Clone)]
+pub struct MockTracer {
+    sender: tokio::sync::broadcast::Sender<KanshiStreamItem>,
+    cancellation_token: CancellationToken,
+    // Events not sent yet, each with the delay to wait before sending it.
+    events: Arc<Mutex<VecDeque<(Duration, FileSystemEvent)>>>,
+    events_sent: Arc<AtomicUsize>,
+    paused: Arc<AtomicBool>,
+    running: Arc<AtomicBool>,
+    callbacks: EventCallbacks,
+    watched: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+impl MockTracer {
+    /// Creates a mock that waits for the given delay before sending each
+    /// event. Delays are relative to the previous event, or to `start` for
+    /// the first one.
+    pub fn with_delays(events: Vec<(Duration, FileSystemEvent)>) -> MockTracer {
+        // Large enough that no stream can lag behind and lose events.
+        let (tx, _rx) = tokio::sync::broadcast::channel(events.len().max(1));
+
+        MockTracer {
+            sender: tx,
+            cancellation_token: CancellationToken::new(),
+            events: Arc::new(Mutex::new(events.into())),
+            events_sent: Arc::new(AtomicUsize::new(0)),
+            paused: Arc::new(AtomicBool::new(false)),
+            running: Arc::new(AtomicBool::new(false)),
+            callbacks: EventCallbacks::default(),
+            watched: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    /// How many events `start` has sent so far.
+    pub fn events_sent(&self) -> usize {
+        self.events_sent.load(Ordering::SeqCst)
+    }
+}
+
+impl KanshiImpl<Vec<FileSystemEvent>> for MockTracer {
+    /// Creates a mock that sends `events` without any delay.
+    fn new(events: Vec<FileSystemEvent>) -> Result<MockTracer, KanshiError> {
+        Ok(MockTracer::with_delays(
+            events.into_iter().map(|x| (Duration::ZERO, x)).collect(),
+        ))
+    }
+
+    async fn watch(&self, dir: impl AsRef<Path> + Send) -> Result<(), KanshiError> {
+        self.watch_with_exclude_dirs(dir, HashSet::new()).await
+    }
+
+    async fn watch_with_exclude_dirs(
+        &self,
+        dir: impl AsRef<Path> + Send,
+        _exclude: HashSet<PathBuf>,
+    ) -> Result<(), KanshiError> {
+        self.watched
+            .lock()
+            .unwrap()
+            .insert(dir.as_ref().to_path_buf());
+        Ok(())
+    }
+
+    async fn unwatch(&self, dir: impl AsRef<Path> + Send) -> Result<(), KanshiError> {
+        if !self.watched.lock().unwrap().remove(dir.as_ref()) {
+            return Err(KanshiError::PathNotWatched(dir.as_ref().to_path_buf()));
+        }
+        Ok(())
+    }
+
+    fn get_watched_paths(&self) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = self.watched.lock().unwrap().iter().cloned().collect();
+        paths.sort();
+        paths
+    }
+
+    fn get_events_stream(&self) -> Pin<Box<dyn futures::Stream<Item = KanshiStreamItem> + Send>> {
+        let mut listener = self.sender.subscribe();
+        let cancel_token = self.cancellation_token.clone();
+
+        Box::pin(stream! {
+            loop {
+                tokio::select! {
+                    _ = cancel_token.cancelled() => {
+                        break;
+                    }
+                    val = listener.recv() => {
+                        match val {
+                            Ok(x) => {
+                                let failed = x.is_error();
+                                yield x;
+                                if failed {
+                                    break;
+                                }
+                            }
+                            Err(e) => match e {
+                                RecvError::Closed => break,
+                                _ => ()
+                            }
+                        }
+                    }
+                }
+            }
+        })
+    }
+
+    async fn start(&self) -> Result<(), KanshiError> {
+        let _running = RunningGuard::acquire(&self.running)?;
+        loop {
+            let next = self.events.lock().unwrap().pop_front();
+            let Some((delay, event)) = next else {
+                break;
+            };
+
+            tokio::select! {
+                _ = self.cancellation_token.cancelled() => return Ok(()),
+                _ = runtime::sleep(delay) => {}
+            }
+            // The remaining events are held while paused.
+            while self.is_paused() {
+                tokio::select! {
+                    _ = self.cancellation_token.cancelled() => return Ok(()),
+                    _ = runtime::sleep(Duration::from_millis(10)) => {}
+                }
+            }
+            // Counted first, so the count is up to date by the time a stream
+            // receives the event.
+            self.events_sent.fetch_add(1, Ordering::SeqCst);
+            // Fails only when nothing is subscribed, in which case the event
+            // is dropped like with the real tracers.
+            notify(&self.callbacks, &event);
+            let _ = self.sender.send(KanshiStreamItem::Event(event));
+        }
+
+        self.cancellation_token.cancelled().await;
+        Ok(())
+    }
+
+    fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+    }
+
+    fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    fn on_event<F>(&self, callback: F)
+    where
+        F: Fn(FileSystemEvent) + Send + Sync + 'static,
+    {
+        self.callbacks.write().unwrap().push(Box::new(callback));
+    }
+
+    fn close(&self) -> Result<(), KanshiError> {
+        self.cancellation_token.cancel();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::StreamExt;
+
+    use crate::{FileSystemEvent, FileSystemEventType, KanshiError, KanshiImpl};
+
+    use super::MockTracer;
+
+    fn event(event_type: FileSystemEventType) -> FileSystemEvent {
+        FileSystemEvent {
+            event_type,
+            target: None,
+            pid: None,
+            timestamp: None,
+            sequence: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn sends_events_after_their_delays() {
+        let mock = MockTracer::with_delays(vec![
+            (Duration::ZERO, event(FileSystemEventType::Create)),
+            (Duration::from_millis(300), event(FileSystemEventType::Modify)),
+        ]);
+        mock.watch("/does/not/exist").await.unwrap();
+
+        let mut stream = mock.get_events_stream();
+        let tracer = mock.clone();
+        let handle = tokio::spawn(async move { tracer.start().await });
+
+        let first = stream.next().await.unwrap().into_event().unwrap();
+        assert_eq!(first.event_type, FileSystemEventType::Create);
+        assert_eq!(mock.events_sent(), 1);
+
+        let second = stream.next().await.unwrap().into_event().unwrap();
+        assert_eq!(second.event_type, FileSystemEventType::Modify);
+        assert_eq!(mock.events_sent(), 2);
+
+        mock.close().unwrap();
+        assert!(stream.next().await.is_none());
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn start_runs_once_at_a_time() {
+        let mock = MockTracer::new(vec![event(FileSystemEventType::Create)]).unwrap();
+        let tracer = mock.clone();
+        let handle = tokio::spawn(async move { tracer.start().await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert!(matches!(
+            mock.start().await,
+            Err(KanshiError::ListenerAlreadyStarted)
+        ));
+        assert_eq!(mock.events_sent(), 1);
+
+        mock.close().unwrap();
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn unwatch_requires_watch() {
+        let mock = MockTracer::new(Vec::new()).unwrap();
+        assert!(matches!(
+            mock.unwatch("/x").await,
+            Err(KanshiError::PathNotWatched(_))
+        ));
+
+        mock.watch("/x").await.unwrap();
+        mock.unwatch("/x").await.unwrap();
+    }
+}
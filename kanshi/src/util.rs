@@ -0,0 +1,122 @@
+//! Helpers for code that consumes a tracer's events, such as tests and
+//! scripts.
+
+use std::{future::Future, time::Duration};
+
+use futures::{stream, Stream, StreamExt};
+
+use crate::{runtime, FileSystemEvent, KanshiError, KanshiImpl};
+
+/// Waits for the first event of `tracer` that `predicate` accepts, discarding
+/// the others. Fails with `Timeout` if none arrives within `timeout`, with the
+/// error the tracer failed with, or with `StreamClosed` if the tracer is
+/// closed first.
+///
+/// The stream is subscribed to when `wait_for` is called rather than when the
+/// future is first polled, so events caused between the two are seen.
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use kanshi::{
+///     util::wait_for, FileSystemEventType, Kanshi, KanshiError, KanshiImpl, KanshiOptions,
+/// };
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), KanshiError> {
+///     let dir = tempfile::tempdir()?;
+///     let kanshi = Kanshi::new(KanshiOptions::default())?;
+///     kanshi.watch(dir.path()).await?;
+///     let tracer = kanshi.clone();
+///     tokio::spawn(async move { tracer.start().await });
+///
+///     let created = wait_for(
+///         &kanshi,
+///         |x| x.event_type == FileSystemEventType::Create,
+///         Some(Duration::from_secs(5)),
+///     );
+///     std::fs::write(dir.path().join("x"), b"x")?;
+///     created.await?;
+///     kanshi.close()
+/// }
+/// ```
+pub fn wait_for<O, T, F>(
+    tracer: &T,
+    predicate: F,
+    timeout: Option<Duration>,
+) -> impl Future<Output = Result<FileSystemEvent, KanshiError>> + Send
+where
+    T: KanshiImpl<O>,
+    F: Fn(&FileSystemEvent) -> bool + Send,
+{
+    let mut stream = tracer.get_events_stream();
+    let wait = async move {
+        while let Some(item) = stream.next().await {
+            let event = item.into_result()?;
+            if predicate(&event) {
+                return Ok(event);
+            }
+        }
+        Err(KanshiError::StreamClosed)
+    };
+
+    async move {
+        let Some(timeout) = timeout else {
+            return wait.await;
+        };
+        tokio::select! {
+            result = wait => result,
+            _ = runtime::sleep(timeout) => Err(KanshiError::Timeout),
+        }
+    }
+}
+
+/// Interleaves the events of `tracers`, each paired with the index of its
+/// tracer in `tracers`. Events of a tracer keep their order, and the stream
+/// ends once every tracer is closed.
+///
+/// Permission requests are turned into their event, which allows the access.
+/// A tracer that fails has its error logged and contributes no more events.
+///
+/// # Examples
+///
+/// ```no_run
+/// use futures::StreamExt;
+/// use kanshi::{util::merge, Kanshi, KanshiError, KanshiImpl, KanshiOptions};
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), KanshiError> {
+///     let sources = Kanshi::new(KanshiOptions::default())?;
+///     let outputs = Kanshi::new(KanshiOptions::default())?;
+///     sources.watch("src").await?;
+///     outputs.watch("target").await?;
+///     let mut events = merge(&[sources.clone(), outputs.clone()]);
+///     for tracer in [sources, outputs] {
+///         tokio::spawn(async move { tracer.start().await });
+///     }
+///
+///     while let Some((index, event)) = events.next().await {
+///         println!("{index}: {event}");
+///     }
+///     Ok(())
+/// }
+/// ```
+pub fn merge<O, T>(tracers: &[T]) -> impl Stream<Item = (usize, FileSystemEvent)> + Send
+where
+    T: KanshiImpl<O>,
+{
+    stream::select_all(tracers.iter().enumerate().map(|(index, tracer)| {
+        tracer
+            .get_events_stream()
+            .map_while(move |item| match item.into_result() {
+                Ok(event) => Some((index, event)),
+                Err(e) => {
+                    tracing::warn!(index, error = %e, "tracer failed");
+                    None
+                }
+            })
+            .boxed()
+    }))
+}
@@ -0,0 +1,182 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+
+use crate::KanshiError;
+
+/// An ordered, de-duplicated set of paths to watch.
+///
+/// Paths keep the order in which they were first inserted. A `WatchSet` can be
+/// built by hand, collected from an iterator, or populated from a glob pattern
+/// evaluated against a directory tree.
+#[derive(Clone, Debug, Default)]
+pub struct WatchSet {
+    paths: Vec<PathBuf>,
+    seen: HashSet<PathBuf>,
+}
+
+impl WatchSet {
+    pub fn new() -> WatchSet {
+        WatchSet::default()
+    }
+
+    /// Walks `root` and collects every path whose location relative to `root`
+    /// matches `globset`. Symlinks are not followed.
+    pub fn from_globset(globset: GlobSet, root: &Path) -> Result<WatchSet, KanshiError> {
+        let mut set = WatchSet::new();
+        let mut traversal_queue = VecDeque::from([root.to_path_buf()]);
+
+        while let Some(next_dir) = traversal_queue.pop_front() {
+            let entries =
+                fs::read_dir(&next_dir).map_err(|e| KanshiError::from_io_at(e, &next_dir))?;
+            for dir_item in entries {
+                let dir_item = dir_item.map_err(|e| KanshiError::from_io_at(e, &next_dir))?;
+                let path = dir_item.path();
+                let file_type = dir_item
+                    .file_type()
+                    .map_err(|e| KanshiError::from_io_at(e, &path))?;
+
+                if let Ok(relative_path) = path.strip_prefix(root) {
+                    if globset.is_match(relative_path) {
+                        set.insert(path.clone());
+                    }
+                }
+
+                if file_type.is_dir() {
+                    traversal_queue.push_back(path);
+                }
+            }
+        }
+
+        Ok(set)
+    }
+
+    /// Shorthand for [`WatchSet::from_globset`] with a single glob pattern,
+    /// e.g. `WatchSet::from_dir("/src", "**/*.rs")`.
+    pub fn from_dir(root: impl AsRef<Path>, pattern: &str) -> Result<WatchSet, KanshiError> {
+        let glob = Glob::new(pattern).map_err(|e| KanshiError::InvalidParameter(e.to_string()))?;
+        let globset = GlobSetBuilder::new()
+            .add(glob)
+            .build()
+            .map_err(|e| KanshiError::InvalidParameter(e.to_string()))?;
+
+        WatchSet::from_globset(globset, root.as_ref())
+    }
+
+    /// Adds a path to the set. Returns `false` if it was already present.
+    pub fn insert(&mut self, path: PathBuf) -> bool {
+        if self.seen.insert(path.clone()) {
+            self.paths.push(path);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn contains(&self, path: &Path) -> bool {
+        self.seen.contains(path)
+    }
+
+    pub fn len(&self) -> usize {
+        self.paths.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, PathBuf> {
+        self.paths.iter()
+    }
+}
+
+impl Extend<PathBuf> for WatchSet {
+    fn extend<T: IntoIterator<Item = PathBuf>>(&mut self, iter: T) {
+        for path in iter {
+            self.insert(path);
+        }
+    }
+}
+
+impl FromIterator<PathBuf> for WatchSet {
+    fn from_iter<T: IntoIterator<Item = PathBuf>>(iter: T) -> Self {
+        let mut set = WatchSet::new();
+        set.extend(iter);
+        set
+    }
+}
+
+impl IntoIterator for WatchSet {
+    type Item = PathBuf;
+    type IntoIter = std::vec::IntoIter<PathBuf>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.paths.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a WatchSet {
+    type Item = &'a PathBuf;
+    type IntoIter = std::slice::Iter<'a, PathBuf>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.paths.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::symlink, path::PathBuf};
+
+    use super::WatchSet;
+
+    fn sorted(set: WatchSet) -> Vec<PathBuf> {
+        let mut paths: Vec<_> = set.into_iter().collect();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn from_dir_matches_below_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("sub/deeper")).unwrap();
+        for file in ["a.rs", "sub/b.rs", "sub/c.txt", "sub/deeper/d.rs"] {
+            fs::write(root.join(file), "").unwrap();
+        }
+        // Symlinked directories are listed, but not walked into.
+        symlink(root.join("sub"), root.join("link")).unwrap();
+
+        // Patterns are matched against the path relative to `root`.
+        let set = WatchSet::from_dir(root, "sub/*.rs").unwrap();
+        assert_eq!(sorted(set), [root.join("sub/b.rs")]);
+
+        let set = WatchSet::from_dir(root, "**/*.rs").unwrap();
+        assert_eq!(
+            sorted(set),
+            [
+                root.join("a.rs"),
+                root.join("sub/b.rs"),
+                root.join("sub/deeper/d.rs")
+            ]
+        );
+
+        let set = WatchSet::from_dir(root, "link*").unwrap();
+        assert_eq!(sorted(set), [root.join("link")]);
+    }
+
+    #[test]
+    fn keeps_the_first_insertion_order() {
+        let mut set: WatchSet = ["/b", "/a", "/b"].into_iter().map(PathBuf::from).collect();
+        set.extend(["/c", "/a"].into_iter().map(PathBuf::from));
+
+        assert_eq!(set.len(), 3);
+        assert!(set.contains("/a".as_ref()));
+        let paths: Vec<_> = set.iter().cloned().collect();
+        assert_eq!(paths, [PathBuf::from("/b"), "/a".into(), "/c".into()]);
+    }
+}